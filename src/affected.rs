@@ -0,0 +1,110 @@
+//! `configure affected`, which works out which projects use secrets that changed in the secrets
+//! repository – so automation that rotates secrets knows whose pins to bump.
+
+use crate::ConfigurationFile;
+use serde::Serialize;
+use std::collections::HashSet;
+
+/// Whether one project uses any of the changed secrets. Anything that couldn't be found out is
+/// `None`, and `error` says why.
+#[derive(Debug, Default, Serialize, Eq, PartialEq)]
+pub struct AffectedProject {
+    /// The path to the project, as it was given
+    pub project: String,
+
+    pub project_name: Option<String>,
+    pub branch: Option<String>,
+    pub pinned_hash: Option<String>,
+
+    /// The changed sources the project uses, relative to the secrets repository's root
+    pub sources: Vec<String>,
+
+    pub error: Option<String>,
+}
+
+impl AffectedProject {
+    pub fn is_affected(&self) -> bool {
+        !self.sources.is_empty()
+    }
+}
+
+/// The sources in `configuration` that are among the `changed` paths, in the order they're listed
+/// and without repeats
+pub fn changed_sources(configuration: &ConfigurationFile, changed: &HashSet<String>) -> Vec<String> {
+    let mut sources: Vec<String> = Vec::new();
+
+    for file in &configuration.files_to_copy {
+        let source = configuration.source_path(file);
+        if changed.contains(&source) && !sources.contains(&source) {
+            sources.push(source);
+        }
+    }
+
+    sources
+}
+
+/// One line for each affected project, then a line for each one that couldn't be read
+pub fn format_affected(projects: &[AffectedProject]) -> Vec<String> {
+    let mut lines: Vec<String> = projects
+        .iter()
+        .filter(|project| project.is_affected())
+        .map(|project| format!("{}: {}", project.project, project.sources.join(", ")))
+        .collect();
+
+    if lines.is_empty() {
+        lines.push("No projects use the changed secrets".to_string());
+    }
+
+    for project in projects {
+        if let Some(error) = &project.error {
+            lines.push(format!("{}: couldn't be checked: {}", project.project, error));
+        }
+    }
+
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::File;
+
+    #[test]
+    fn test_only_changed_sources_are_reported() {
+        let configuration = ConfigurationFile {
+            project_name: "WordPress".to_string(),
+            files_to_copy: ["./a.json", "WordPress/a.json", "b.json", "shared/c.json"]
+                .iter()
+                .map(|source| File {
+                    source: source.to_string(),
+                    destination: source.trim_start_matches("./").to_string(),
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        };
+
+        let changed: HashSet<String> = ["WordPress/a.json", "shared/c.json", "other/d.json"]
+            .iter()
+            .map(|path| path.to_string())
+            .collect();
+        assert_eq!(
+            changed_sources(&configuration, &changed),
+            vec!["WordPress/a.json", "shared/c.json"]
+        );
+
+        let unaffected = AffectedProject {
+            project: "../simplenote".to_string(),
+            ..Default::default()
+        };
+        let affected = AffectedProject {
+            project: "../wordpress".to_string(),
+            sources: changed_sources(&configuration, &changed),
+            ..Default::default()
+        };
+        assert_eq!(
+            format_affected(&[unaffected, affected]),
+            vec!["../wordpress: WordPress/a.json, shared/c.json"]
+        );
+    }
+}
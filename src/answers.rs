@@ -0,0 +1,197 @@
+//! Answer files, which answer `configure`'s prompts ahead of time so a session can be replayed
+//! exactly, and recordings of live sessions in the same format.
+//!
+//! An answer file is a JSON object keyed by prompt identifier, like
+//! `{ "project_name": "MyApp", "branch": "trunk", "confirms": { "add_more_files": false } }`.
+//! Yes/no questions live under `confirms`. Each answer is used once – a prompt that's asked
+//! several times takes its answers from a list, in order.
+
+use crate::ConfigureError;
+use serde_json::{Map, Value};
+use std::path::Path;
+
+/// The section yes/no answers live in
+const CONFIRMS: &str = "confirms";
+
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct Answers {
+    values: Map<String, Value>,
+}
+
+impl Answers {
+    pub fn read(path: &Path) -> Result<Answers, ConfigureError> {
+        let invalid = |message: String| ConfigureError::AnswersInvalid(message);
+
+        let contents = std::fs::read_to_string(path)
+            .map_err(|err| invalid(format!("unable to read {:?}: {}", path, err)))?;
+
+        match serde_json::from_str(&contents) {
+            Ok(Value::Object(values)) => Ok(Answers { values }),
+            Ok(_) => Err(invalid(format!("{:?} isn't a JSON object", path))),
+            Err(err) => Err(invalid(format!("{:?} isn't valid JSON: {}", path, err))),
+        }
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), ConfigureError> {
+        let serialized = serde_json::to_string_pretty(&self.values).unwrap();
+        std::fs::write(path, serialized)?;
+        Ok(())
+    }
+
+    /// Uses up the next answer to the prompt `id`
+    pub fn take(&mut self, id: &str) -> Option<Value> {
+        take_from(&mut self.values, id)
+    }
+
+    /// Uses up the next answer to the prompt `id`, whose answers are lists (like a multiple
+    /// choice). If it's asked several times, its answers are a list of lists.
+    pub fn take_list(&mut self, id: &str) -> Option<Vec<Value>> {
+        let repeated = match self.values.get(id)? {
+            Value::Array(list) => list.first().is_some_and(Value::is_array),
+            _ => return None,
+        };
+
+        let answer = if repeated {
+            self.take(id)
+        } else {
+            self.values.remove(id)
+        };
+
+        match answer {
+            Some(Value::Array(list)) => Some(list),
+            _ => None,
+        }
+    }
+
+    /// Uses up the next answer to the yes/no question `id`
+    pub fn take_confirm(&mut self, id: &str) -> Option<bool> {
+        match self.values.get_mut(CONFIRMS) {
+            Some(Value::Object(confirms)) => take_from(confirms, id)?.as_bool(),
+            _ => None,
+        }
+    }
+
+    /// Adds an answer to the prompt `id`. Answering it again turns its answer into a list.
+    pub fn record(&mut self, id: &str, answer: Value) {
+        record_in(&mut self.values, id, answer)
+    }
+
+    pub fn record_confirm(&mut self, id: &str, answer: bool) {
+        let confirms = self
+            .values
+            .entry(CONFIRMS)
+            .or_insert_with(|| Value::Object(Map::new()));
+
+        if let Value::Object(confirms) = confirms {
+            record_in(confirms, id, Value::Bool(answer))
+        }
+    }
+}
+
+fn take_from(values: &mut Map<String, Value>, id: &str) -> Option<Value> {
+    let answer = match values.get_mut(id)? {
+        Value::Array(list) if !list.is_empty() => list.remove(0),
+        Value::Array(_) => return None,
+        _ => return values.remove(id),
+    };
+
+    // Once every answer in a list is used up, the prompt has no answers left (rather than an
+    // empty list, which could be mistaken for an answer to a multiple choice)
+    if values.get(id) == Some(&Value::Array(Vec::new())) {
+        values.remove(id);
+    }
+
+    Some(answer)
+}
+
+fn record_in(values: &mut Map<String, Value>, id: &str, answer: Value) {
+    match values.remove(id) {
+        None => values.insert(id.to_string(), answer),
+
+        // Already answered more than once, so the answers are a list to add to
+        Some(Value::Array(mut list))
+            if !list.is_empty() && list.iter().all(Value::is_array) == answer.is_array() =>
+        {
+            list.push(answer);
+            values.insert(id.to_string(), Value::Array(list))
+        }
+
+        Some(existing) => values.insert(id.to_string(), Value::Array(vec![existing, answer])),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn answers(value: Value) -> Answers {
+        match value {
+            Value::Object(values) => Answers { values },
+            _ => panic!("Answers must be an object"),
+        }
+    }
+
+    #[test]
+    fn test_answers_are_used_once_each() {
+        let mut answers = answers(json!({
+            "project_name": "MyApp",
+            "source": ["a.json", "b.json"],
+            "confirms": { "add_more_files": [true, true, false] }
+        }));
+
+        assert_eq!(answers.take("project_name"), Some(json!("MyApp")));
+        assert_eq!(answers.take("project_name"), None);
+
+        assert_eq!(answers.take("source"), Some(json!("a.json")));
+        assert_eq!(answers.take("source"), Some(json!("b.json")));
+        assert_eq!(answers.take("source"), None);
+
+        assert_eq!(answers.take_confirm("add_more_files"), Some(true));
+        assert_eq!(answers.take_confirm("add_more_files"), Some(true));
+        assert_eq!(answers.take_confirm("add_more_files"), Some(false));
+        assert_eq!(answers.take_confirm("add_more_files"), None);
+        assert_eq!(answers.take_confirm("replace_key"), None);
+    }
+
+    #[test]
+    fn test_list_answers_can_be_repeated() {
+        let mut answers = answers(json!({
+            "template_files": ["a", "b"],
+            "variants": [["release"], ["debug", "release"]]
+        }));
+
+        assert_eq!(answers.take_list("template_files"), Some(vec![json!("a"), json!("b")]));
+        assert_eq!(answers.take_list("template_files"), None);
+
+        assert_eq!(answers.take_list("variants"), Some(vec![json!("release")]));
+        assert_eq!(
+            answers.take_list("variants"),
+            Some(vec![json!("debug"), json!("release")])
+        );
+        assert_eq!(answers.take_list("variants"), None);
+    }
+
+    #[test]
+    fn test_recorded_answers_replay_in_order() {
+        let mut recording = Answers::default();
+        recording.record("project_name", json!("MyApp"));
+        recording.record("source", json!("a.json"));
+        recording.record("source", json!("b.json"));
+        recording.record("template_files", json!(["a", "b"]));
+        recording.record("template_files", json!(["c"]));
+        recording.record("template_files", json!([]));
+        recording.record_confirm("add_more_files", true);
+        recording.record_confirm("add_more_files", false);
+
+        assert_eq!(
+            recording,
+            answers(json!({
+                "project_name": "MyApp",
+                "source": ["a.json", "b.json"],
+                "template_files": [["a", "b"], ["c"], []],
+                "confirms": { "add_more_files": [true, false] }
+            }))
+        );
+    }
+}
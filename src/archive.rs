@@ -0,0 +1,94 @@
+use crate::ConfigureError;
+use log::debug;
+use std::path::Path;
+use std::process::Command;
+
+/// Lists the files in a tar archive, as paths relative to the archive root
+pub fn list_archive_entries(archive_path: &Path) -> Result<Vec<String>, ConfigureError> {
+    let entries = list_archive_entries_raw(archive_path)?
+        .iter()
+        .filter(|name| !name.ends_with('/')) // Skip directories
+        .map(|name| name.trim_start_matches("./").to_string())
+        .collect::<Vec<String>>();
+
+    debug!("Archive {:?} contains {:?}", archive_path, entries);
+
+    Ok(entries)
+}
+
+/// Reads the contents of a single file from a tar archive
+pub fn read_archive_entry(archive_path: &Path, entry: &str) -> Result<Vec<u8>, ConfigureError> {
+    // Archives made with `tar -C dir .` prefix every entry with `./`, so look for both forms
+    let entries = list_archive_entries_raw(archive_path)?;
+    let name = entries
+        .into_iter()
+        .find(|name| name.trim_start_matches("./") == entry)
+        .ok_or_else(|| ConfigureError::ArchiveIncomplete(entry.to_string()))?;
+
+    run_tar(Command::new("tar").arg("-xOf").arg(archive_path).arg(name))
+}
+
+/// Writes everything in `contents` to a tar archive at `archive_path`, with paths relative to it
+pub fn create_archive(archive_path: &Path, contents: &Path) -> Result<(), ConfigureError> {
+    run_tar(
+        Command::new("tar")
+            .arg("-cf")
+            .arg(archive_path)
+            .arg("-C")
+            .arg(contents)
+            .arg("."),
+    )?;
+    Ok(())
+}
+
+fn list_archive_entries_raw(archive_path: &Path) -> Result<Vec<String>, ConfigureError> {
+    let output = run_tar(Command::new("tar").arg("-tf").arg(archive_path))?;
+    Ok(String::from_utf8_lossy(&output)
+        .lines()
+        .map(String::from)
+        .collect())
+}
+
+fn run_tar(command: &mut Command) -> Result<Vec<u8>, ConfigureError> {
+    let output = command
+        .output()
+        .map_err(|err| ConfigureError::ArchiveError(err.to_string()))?;
+
+    if !output.status.success() {
+        return Err(ConfigureError::ArchiveError(
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ));
+    }
+
+    Ok(output.stdout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir_all, write};
+
+    #[test]
+    fn test_archive_entries_mirror_destinations() {
+        let dir = tempfile::tempdir().unwrap();
+        let contents = dir.path().join("contents");
+        create_dir_all(contents.join("app/config")).unwrap();
+        write(contents.join("app/config/secrets.json"), "{}").unwrap();
+
+        let archive = dir.path().join("secrets.tar");
+        Command::new("tar")
+            .arg("-cf")
+            .arg(&archive)
+            .arg("-C")
+            .arg(&contents)
+            .arg(".")
+            .status()
+            .unwrap();
+
+        let entries = list_archive_entries(&archive).unwrap();
+        assert_eq!(entries, vec!["app/config/secrets.json".to_string()]);
+
+        let entry = read_archive_entry(&archive, "app/config/secrets.json").unwrap();
+        assert_eq!(entry, b"{}");
+    }
+}
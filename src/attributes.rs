@@ -0,0 +1,164 @@
+//! Checks that git won't change the project's encrypted files on checkout. A filter like
+//! git-crypt's or transcrypt's, or line-ending conversion, leaves them undecryptable for everyone
+//! who checks them out.
+
+use git2::{AttrCheckFlags, Error, Repository};
+use std::path::Path;
+
+/// The `.gitattributes` rule that makes git leave encrypted files byte-for-byte as they are
+pub const SUGGESTED_ATTRIBUTES: &str = "*.enc -filter binary";
+
+/// Something in `.gitattributes` that would change an encrypted file
+#[derive(Debug, Eq, PartialEq)]
+pub struct AttributeProblem {
+    /// The encrypted file, relative to the repository root
+    pub path: String,
+
+    pub problem: String,
+}
+
+/// A git attribute's value for a path
+#[derive(Debug, Eq, PartialEq)]
+enum Attribute {
+    Unspecified,
+    Set,
+    Unset,
+    Value(String),
+}
+
+impl Attribute {
+    fn read(repo: &Repository, path: &str, name: &str) -> Result<Attribute, Error> {
+        // libgit2 reports set and unset attributes with these placeholder values
+        Ok(
+            match repo.get_attr(Path::new(path), name, AttrCheckFlags::FILE_THEN_INDEX)? {
+                None | Some("[internal]__UNSET__") => Attribute::Unspecified,
+                Some("[internal]__TRUE__") => Attribute::Set,
+                Some("[internal]__FALSE__") => Attribute::Unset,
+                Some(value) => Attribute::Value(value.to_string()),
+            },
+        )
+    }
+}
+
+/// Checks how git treats each of `paths` (relative to the repository root)
+pub fn find_attribute_problems(
+    repo: &Repository,
+    paths: &[String],
+) -> Result<Vec<AttributeProblem>, Error> {
+    let mut problems = Vec::new();
+
+    for path in paths {
+        let mut problem = |problem: String| {
+            problems.push(AttributeProblem {
+                path: path.clone(),
+                problem,
+            })
+        };
+
+        if let Attribute::Value(filter) = Attribute::read(repo, path, "filter")? {
+            problem(format!("uses the `{}` filter, which changes it on checkout", filter));
+        }
+
+        // Setting `eol` implies `text`, unless `text` is explicitly unset
+        match (
+            Attribute::read(repo, path, "text")?,
+            Attribute::read(repo, path, "eol")?,
+        ) {
+            (Attribute::Unset, _) => {}
+            (Attribute::Set, _) => {
+                problem("is treated as text, so its line endings can be converted".to_string())
+            }
+            (Attribute::Value(text), _) => problem(format!(
+                "may be treated as text (`text={}`), so its line endings can be converted",
+                text
+            )),
+            (Attribute::Unspecified, Attribute::Value(eol)) => {
+                problem(format!("has its line endings converted to {}", eol))
+            }
+            (Attribute::Unspecified, _) => {}
+        }
+    }
+
+    Ok(problems)
+}
+
+/// Adds `SUGGESTED_ATTRIBUTES` to the end of the `.gitattributes` in `directory`, where it takes
+/// precedence over earlier rules
+pub fn add_suggested_attributes(directory: &Path) -> Result<(), std::io::Error> {
+    let path = directory.join(".gitattributes");
+    let mut contents = std::fs::read_to_string(&path).unwrap_or_default();
+
+    if !contents.is_empty() && !contents.ends_with('\n') {
+        contents.push('\n');
+    }
+
+    contents.push_str("# Keep configure's encrypted secrets exactly as they're committed\n");
+    contents.push_str(SUGGESTED_ATTRIBUTES);
+    contents.push('\n');
+
+    std::fs::write(path, contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    #[test]
+    fn test_filters_and_line_ending_conversion_are_problems() {
+        let (dir, _repo) = fixtures::empty_repo();
+        std::fs::write(
+            dir.path().join(".gitattributes"),
+            "secrets/** filter=git-crypt diff=git-crypt\n\
+             text.enc text\n\
+             auto.enc text=auto\n\
+             crlf.enc eol=crlf\n\
+             binary.enc eol=crlf binary\n",
+        )
+        .unwrap();
+
+        let paths: Vec<String> = [
+            "secrets/a.json.enc",
+            "text.enc",
+            "auto.enc",
+            "crlf.enc",
+            "binary.enc",
+            "plain.enc",
+        ]
+        .iter()
+        .map(|path| path.to_string())
+        .collect();
+
+        let repo = Repository::open(dir.path()).unwrap();
+        let problems: Vec<(String, String)> = find_attribute_problems(&repo, &paths)
+            .unwrap()
+            .into_iter()
+            .map(|problem| (problem.path, problem.problem))
+            .collect();
+
+        let problem = |path: &str, problem: &str| (path.to_string(), problem.to_string());
+        assert_eq!(
+            problems,
+            vec![
+                problem(
+                    "secrets/a.json.enc",
+                    "uses the `git-crypt` filter, which changes it on checkout"
+                ),
+                problem(
+                    "text.enc",
+                    "is treated as text, so its line endings can be converted"
+                ),
+                problem(
+                    "auto.enc",
+                    "may be treated as text (`text=auto`), so its line endings can be converted"
+                ),
+                problem("crlf.enc", "has its line endings converted to crlf"),
+            ]
+        );
+
+        // The suggested rule comes last, so it overrides all of them
+        add_suggested_attributes(dir.path()).unwrap();
+        let repo = Repository::open(dir.path()).unwrap();
+        assert_eq!(find_attribute_problems(&repo, &paths).unwrap(), vec![]);
+    }
+}
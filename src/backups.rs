@@ -0,0 +1,253 @@
+//! Names for the backups configure makes of edited secrets, and tidying up the backups older
+//! versions left behind with other names.
+//!
+//! Backups are named `<stem>-<timestamp>.<extension>.bak`, beside the secret they're a backup of.
+//! Older versions also wrote names like `.env-<timestamp>..bak` for files without an extension.
+
+use crate::{ConfigureError, File};
+use chrono::NaiveDateTime;
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+
+/// The timestamp format in backup names
+const TIMESTAMP_FORMAT: &str = "%Y-%m-%d-%H-%M-%S";
+
+/// The name of a backup of `file_name` made at `timestamp`
+pub fn backup_file_name(file_name: &str, timestamp: &NaiveDateTime) -> String {
+    let path = Path::new(file_name);
+    let stem = path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or(file_name);
+    let timestamp = timestamp.format(TIMESTAMP_FORMAT);
+
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some(extension) => format!("{}-{}.{}.bak", stem, timestamp, extension),
+        None => format!("{}-{}.bak", stem, timestamp),
+    }
+}
+
+/// What to do about one backup file
+#[derive(Debug, Eq, PartialEq)]
+pub enum BackupRepair {
+    /// The backup has an old-style name, and should be renamed
+    Rename { from: PathBuf, to: PathBuf },
+
+    /// The backup has an old-style name, but something already has the name it should have – or
+    /// another backup is being renamed to it
+    Clash { from: PathBuf, to: PathBuf },
+
+    /// The file looks like a backup of a secret, but there's no telling when it was made
+    Unclassified(PathBuf),
+}
+
+/// Finds the backups of `files` in the project that don't have the current naming scheme.
+/// Backups that are already named properly aren't included.
+pub fn plan_backup_repairs(
+    project_root: &Path,
+    files: &[File],
+) -> Result<Vec<BackupRepair>, ConfigureError> {
+    // Backups live beside their secret, so each directory only needs to be read once
+    let mut directories: BTreeMap<PathBuf, Vec<String>> = BTreeMap::new();
+    for file in files {
+        let destination = project_root.join(file.get_decrypted_destination()?);
+        if let (Some(directory), Some(name)) = (destination.parent(), destination.file_name()) {
+            directories
+                .entry(directory.to_path_buf())
+                .or_default()
+                .push(name.to_string_lossy().to_string());
+        }
+    }
+
+    let mut repairs = Vec::new();
+
+    // Two old names can mean the same new one, and only the first can have it
+    let mut planned = HashSet::new();
+
+    for (directory, destinations) in directories {
+        let entries = match std::fs::read_dir(&directory) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(err.into()),
+        };
+
+        let mut names: Vec<String> = entries
+            .filter_map(Result::ok)
+            .filter(|entry| entry.path().is_file())
+            .map(|entry| entry.file_name().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+
+        for name in names {
+            let destination = match backed_up_destination(&name, &destinations) {
+                Some(destination) => destination,
+                None => continue,
+            };
+
+            let from = directory.join(&name);
+            let timestamp = match find_timestamp(&name[stem(destination).len()..]) {
+                Some(timestamp) => timestamp,
+                None => {
+                    repairs.push(BackupRepair::Unclassified(from));
+                    continue;
+                }
+            };
+
+            let expected = backup_file_name(destination, &timestamp);
+            if name == expected {
+                continue;
+            }
+
+            let to = directory.join(expected);
+            if to.exists() || !planned.insert(to.clone()) {
+                repairs.push(BackupRepair::Clash { from, to });
+            } else {
+                repairs.push(BackupRepair::Rename { from, to });
+            }
+        }
+    }
+
+    Ok(repairs)
+}
+
+fn stem(file_name: &str) -> &str {
+    Path::new(file_name)
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or(file_name)
+}
+
+/// Which of `destinations` the file `name` is a backup of, if any. The longest match wins, so a
+/// backup of `a.b.json` isn't mistaken for one of `a.json`.
+fn backed_up_destination<'a>(name: &str, destinations: &'a [String]) -> Option<&'a str> {
+    if !name.to_lowercase().contains("bak") {
+        return None;
+    }
+
+    destinations
+        .iter()
+        .filter(|destination| name != destination.as_str())
+        .filter(|destination| {
+            let rest = match name.strip_prefix(stem(destination)) {
+                Some(rest) => rest,
+                None => return false,
+            };
+            rest.starts_with(['-', '_', '.'])
+        })
+        .max_by_key(|destination| stem(destination).len())
+        .map(String::as_str)
+}
+
+/// Finds a timestamp like `2020-11-03-14-05-09` or `20201103T140509` in part of a file name
+fn find_timestamp(text: &str) -> Option<NaiveDateTime> {
+    let chars: Vec<char> = text.chars().collect();
+
+    for start in 0..chars.len() {
+        if !chars[start].is_ascii_digit() || (start > 0 && chars[start - 1].is_ascii_digit()) {
+            continue;
+        }
+
+        let digits: String = chars[start..]
+            .iter()
+            .take_while(|c| c.is_ascii_digit() || "-_:T ".contains(**c))
+            .filter(|c| c.is_ascii_digit())
+            .take(14)
+            .collect();
+
+        if digits.len() == 14 {
+            if let Ok(timestamp) = NaiveDateTime::parse_from_str(&digits, "%Y%m%d%H%M%S") {
+                return Some(timestamp);
+            }
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    fn timestamp() -> NaiveDateTime {
+        NaiveDate::from_ymd(2020, 11, 3).and_hms(14, 5, 9)
+    }
+
+    #[test]
+    fn test_backup_file_names() {
+        assert_eq!(
+            backup_file_name("secrets.json", &timestamp()),
+            "secrets-2020-11-03-14-05-09.json.bak"
+        );
+        assert_eq!(
+            backup_file_name(".env", &timestamp()),
+            ".env-2020-11-03-14-05-09.bak"
+        );
+        assert_eq!(
+            backup_file_name("Secrets", &timestamp()),
+            "Secrets-2020-11-03-14-05-09.bak"
+        );
+    }
+
+    #[test]
+    fn test_old_backup_names_are_repaired() {
+        let dir = tempfile::tempdir().unwrap();
+        let write = |name: &str| std::fs::write(dir.path().join(name), "").unwrap();
+
+        write("a.json");
+        write("a.json.enc");
+        write("a-2020-11-03-14-05-09.json.bak");
+        write("a.json-20201104T100000.bak");
+        write(".env-2020-11-03-14-05-09..bak");
+        write(".env.bak");
+        write("a.b-2020-11-03-14-05-09.json.bak");
+        write("unrelated.bak");
+
+        write("b-2020-11-03-14-05-09.json.bak");
+        write("b.json.2020-11-03-14-05-09.bak");
+
+        write("c.json-20201103T140509.bak");
+        write("c.json.2020-11-03-14-05-09.bak");
+
+        let file = |destination: &str| File {
+            source: destination.to_string(),
+            destination: destination.to_string(),
+            ..Default::default()
+        };
+        let files = vec![
+            file("a.json"),
+            file(".env"),
+            file("a.b.json"),
+            file("b.json"),
+            file("c.json"),
+        ];
+        let path = |name: &str| dir.path().join(name);
+
+        assert_eq!(
+            plan_backup_repairs(dir.path(), &files).unwrap(),
+            vec![
+                BackupRepair::Rename {
+                    from: path(".env-2020-11-03-14-05-09..bak"),
+                    to: path(".env-2020-11-03-14-05-09.bak"),
+                },
+                BackupRepair::Unclassified(path(".env.bak")),
+                BackupRepair::Rename {
+                    from: path("a.json-20201104T100000.bak"),
+                    to: path("a-2020-11-04-10-00-00.json.bak"),
+                },
+                BackupRepair::Clash {
+                    from: path("b.json.2020-11-03-14-05-09.bak"),
+                    to: path("b-2020-11-03-14-05-09.json.bak"),
+                },
+                BackupRepair::Rename {
+                    from: path("c.json-20201103T140509.bak"),
+                    to: path("c-2020-11-03-14-05-09.json.bak"),
+                },
+                BackupRepair::Clash {
+                    from: path("c.json.2020-11-03-14-05-09.bak"),
+                    to: path("c-2020-11-03-14-05-09.json.bak"),
+                },
+            ]
+        );
+    }
+}
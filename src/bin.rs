@@ -1,8 +1,9 @@
-use log::{debug, LevelFilter};
+use log::{debug, error, LevelFilter};
 use simplelog::CombinedLogger;
 use simplelog::Config;
 use simplelog::TermLogger;
 use simplelog::TerminalMode;
+use std::path::PathBuf;
 use structopt::StructOpt;
 use structopt_flags::GetWithDefault;
 
@@ -17,6 +18,45 @@ struct Options {
 
     #[structopt(flatten)]
     verbose: structopt_flags::VerboseNoDef,
+
+    /// Never prompt – use defaults or the values passed as flags instead
+    #[structopt(long, global = true)]
+    non_interactive: bool,
+
+    /// The name of a file or directory that marks the project root. configure looks for it in the
+    /// current directory and its parents. Defaults to the root of the git repository.
+    #[structopt(long, global = true, env = "CONFIGURE_PROJECT_ROOT_MARKER")]
+    project_root_marker: Option<String>,
+
+    /// Fail if `.configure` has a field configure doesn't know about, instead of ignoring it
+    #[structopt(long, global = true)]
+    reject_unknown_fields: bool,
+
+    /// Never change the secrets checkout – don't fetch, switch branches, or write to it – and read
+    /// secrets from its history instead. This is automatic when it can't be written to.
+    #[structopt(long, global = true)]
+    secrets_read_only: bool,
+
+    /// Answer prompts from this JSON file instead of asking, failing if one isn't answered
+    #[structopt(long, global = true, parse(from_os_str))]
+    answers: Option<PathBuf>,
+
+    /// Save the answers given to prompts to this JSON file, for use with `--answers`
+    #[structopt(long, global = true, parse(from_os_str))]
+    record_answers: Option<PathBuf>,
+
+    /// Show each git command configure runs, with its exit code and output, whatever the
+    /// verbosity. Credentials in URLs are hidden.
+    #[structopt(long, global = true)]
+    trace_git: bool,
+
+    /// How many characters of commit hashes to show
+    #[structopt(long, global = true, default_value = "8")]
+    hash_length: usize,
+
+    /// Show commit hashes in full
+    #[structopt(long, global = true)]
+    full_hash: bool,
 }
 
 #[derive(StructOpt)]
@@ -36,42 +76,247 @@ enum Command {
     /// This command will download the latest secrets commits from the repo
     /// and update the pinned commit hash in the `.configure` file to the newest commit
     /// in the branch specified by `.configure`.
-    Update,
+    Update(configure::UpdateOptions),
 
     /// Decrypt the current secrets for this project.
     ///
-    Apply,
+    Apply(configure::ApplyOptions),
 
     /// Change secrets settings
     ///
     /// This command will provide step-by-step help to make changes to the secrets configuration.
-    Init,
+    Init(configure::InitOptions),
 
     /// Ensure the `.configure` file is valid
-    Validate,
+    ///
+    /// Problems are grouped by the owner of the file they're with.
+    Validate(configure::ValidateOptions),
+
+    /// List this project's secrets, grouped by owner
+    List,
+
+    /// Show whether each of this project's secrets has been applied, grouped by owner
+    ///
+    /// With `--fleet`, summarizes several projects instead: each one's branch, how old its pinned
+    /// commit is, how many commits it's behind the latest secrets, and when it was last applied.
+    /// The secrets repository is fetched once for all of them.
+    Status(configure::StatusOptions),
+
+    /// Show which projects use secrets that changed in the secrets repository since a commit
+    ///
+    /// Compares the secrets repository's `HEAD` with `--since`, and lists the projects whose
+    /// `files_to_copy` have a source that changed. It only reads, and needs no keys.
+    Affected(configure::AffectedOptions),
+
+    /// Show everything configure knows about one of this project's secrets, including its owner
+    Explain(configure::ExplainOptions),
+
+    /// Move a plaintext secret from this project into the secrets repository
+    ///
+    /// This copies the file into the secrets repository, adds it to `.configure`, and writes its
+    /// encrypted form into the project.
+    Import(configure::ImportOptions),
+
+    /// Show how `apply` would change this project's decrypted secrets
+    ///
+    /// Exits with an error if anything would change, so it can be used as a check.
+    Diff(configure::DiffOptions),
+
+    /// Regenerate this project's encrypted secrets from the secrets repository at the pinned hash
+    ///
+    /// Use this when the encrypted files in the project have been corrupted or deleted (by a bad
+    /// merge, for example). It doesn't change the pinned hash or `.configure`, and reads the secrets
+    /// straight from the repository's history, so it doesn't touch its checkout either.
+    Reencrypt(configure::ReencryptOptions),
+
+    /// Write this project's encrypted secrets and a manifest to a tarball, to take them somewhere
+    /// that can't reach the secrets repository's server
+    ///
+    /// Fails if any required secret's encrypted file is missing. Pass `--transfer-key` to
+    /// re-encrypt the files with a key of their own, so the project's key needn't go with them.
+    ExportBundle(configure::ExportBundleOptions),
+
+    /// Decrypt the secrets in a bundle from `configure export-bundle` into the secrets repository
+    ///
+    /// When run in a project, checks the bundle has every secret its `.configure` needs first.
+    ImportBundle(configure::ImportBundleOptions),
+
+    /// Copy encrypted files staged by `configure update --stage-dir` into the project
+    ///
+    /// Checks every staged file against the manifest written beside them, and every project file
+    /// against what it was when they were staged, before copying anything. Run `configure apply`
+    /// afterwards to decrypt them.
+    Promote(configure::PromoteOptions),
+
+    /// Remove stale branches from the secrets repository's checkout
+    ///
+    /// Prunes remote-tracking branches that are gone from the remote, and local branches whose
+    /// upstream is gone. Branches with unpushed commits and the current branch are kept. Only lists
+    /// what would be removed unless you pass `--yes`.
+    PruneBranches(configure::PruneBranchesOptions),
+
+    /// Remove keys from the secrets repository's `keys.json` for projects that are gone
+    ///
+    /// Lists the keys that none of the active projects (given with `--project` or
+    /// `--active-file`) use, and fails if there are any. Pass `--delete` to remove them. Keys an
+    /// active project uses are never removed, even when its name only matches ignoring case.
+    PruneKeys(configure::PruneKeysOptions),
+
+    /// Give backups of this project's secrets made by older versions the current names
+    ///
+    /// Backups are named `<stem>-<timestamp>.<extension>.bak`. Backups with other names are
+    /// renamed when the time they were made is in their name, and listed otherwise. Nothing is
+    /// deleted. Only lists what would be renamed unless you pass `--yes`.
+    RepairBackups(configure::RepairBackupsOptions),
+
+    /// Forget what was last applied in this checkout of the project
+    ///
+    /// Removes `.configure-state`, which records what `apply` wrote so that `status` and `diff`
+    /// don't need to decrypt everything. Secrets and their backups are left alone.
+    Clean,
+
+    /// Make this project's decrypted secrets read-only
+    Lock,
+
+    /// Make this project's decrypted secrets writable, so they can be edited
+    Unlock,
 
     /// Create a new encryption key for use with a project
-    CreateKey,
+    ///
+    /// The key is stored in your secrets repository's `keys.json` under the project's name, which
+    /// defaults to the one in `.configure`. Pass `--stdout` to print a key instead.
+    CreateKey(configure::CreateKeyOptions),
+
+    /// Rename this project, with its key and (optionally) its secrets
+    ///
+    /// Changes `project_name` in `.configure`, and copies the project's key in `keys.json` to the
+    /// new name – the old entry stays until you remove it, so other checkouts keep working.
+    /// Pass `--update-sources` to change sources in the old name's directory to the new one's,
+    /// and `--also-move-secrets-dir` to move and commit the directory in the secrets repository.
+    Rename(configure::RenameOptions),
+
+    /// Hand a project's encryption key to someone else securely
+    Keys(KeysCommand),
+
+    /// Transform one secret between stdin and stdout, for use as a git clean/smudge filter
+    #[structopt(setting = structopt::clap::AppSettings::Hidden)]
+    Filter(FilterCommand),
+
+    /// Check this binary works end to end, offline, against throwaway fixtures
+    #[structopt(setting = structopt::clap::AppSettings::Hidden)]
+    SelfTest,
+}
+
+#[derive(StructOpt)]
+enum KeysCommand {
+    /// Seal a project's key from `keys.json` so only the recipient can open it
+    Export(configure::KeysExportOptions),
+
+    /// Install a project's key from a bundle that was exported for you
+    Import(configure::KeysImportOptions),
+
+    /// Create an identity to receive keys with, and print the public key to share with the sender
+    CreateIdentity(configure::KeysCreateIdentityOptions),
+
+    /// List the projects in `keys.json` and how old their keys are, without showing the keys
+    List,
+}
+
+#[derive(StructOpt)]
+enum FilterCommand {
+    /// Decrypt the secret's encrypted contents from stdin to stdout
+    Smudge(configure::FilterOptions),
+
+    /// Encrypt the secret's contents from stdin to stdout
+    Clean(configure::FilterOptions),
 }
 
 pub fn main() {
     let options = Options::from_args();
 
+    // As a git filter, stdout is the file's contents, so everything else goes to stderr – and
+    // there's nobody to answer questions
+    let filtering = matches!(options.command, Command::Filter(_));
+
     CombinedLogger::init(vec![TermLogger::new(
         options.verbose.get_with_default(LevelFilter::Info),
         Config::default(),
-        TerminalMode::Mixed,
+        if filtering {
+            TerminalMode::Stderr
+        } else {
+            TerminalMode::Mixed
+        },
     )
     .unwrap()])
     .unwrap();
 
     debug!("libconfigure initialized");
 
-    match Options::from_args().command {
-        Command::Apply => configure::apply(),
-        Command::Update => configure::update(),
-        Command::Init => configure::init(),
-        Command::Validate => configure::validate(),
-        Command::CreateKey => println!("{:?}", configure::generate_encryption_key()),
+    if filtering {
+        configure::set_output_handler(Some(Box::new(|_, line| eprintln!("{}", line))));
+    }
+
+    configure::set_non_interactive(options.non_interactive || filtering);
+    configure::set_hash_length(Some(options.hash_length).filter(|_| !options.full_hash));
+    configure::set_project_root_marker(options.project_root_marker);
+    configure::set_reject_unknown_fields(options.reject_unknown_fields);
+    configure::set_secrets_read_only(options.secrets_read_only);
+    configure::set_trace_git(options.trace_git);
+
+    if let Some(path) = &options.answers {
+        if let Err(err) = configure::use_answers(path) {
+            error!("{}", err);
+            std::process::exit(1);
+        }
+    }
+
+    if options.record_answers.is_some() {
+        configure::record_answers();
+    }
+
+    let result = match options.command {
+        Command::Apply(apply_options) => configure::apply(apply_options),
+        Command::Update(update_options) => configure::update(update_options),
+        Command::Init(init_options) => configure::init(init_options),
+        Command::Validate(validate_options) => configure::validate(validate_options),
+        Command::List => configure::list(),
+        Command::Status(status_options) => configure::status(status_options),
+        Command::Affected(affected_options) => configure::affected(affected_options),
+        Command::Explain(explain_options) => configure::explain(explain_options),
+        Command::Import(import_options) => configure::import(import_options),
+        Command::Diff(diff_options) => configure::diff(diff_options),
+        Command::Reencrypt(reencrypt_options) => configure::reencrypt(reencrypt_options),
+        Command::Promote(promote_options) => configure::promote(promote_options),
+        Command::ExportBundle(bundle_options) => configure::export_bundle(bundle_options),
+        Command::ImportBundle(bundle_options) => configure::import_bundle(bundle_options),
+        Command::PruneBranches(prune_options) => configure::prune_branches(prune_options),
+        Command::PruneKeys(prune_options) => configure::prune_keys(prune_options),
+        Command::RepairBackups(repair_options) => configure::repair_backups(repair_options),
+        Command::Clean => configure::clean(),
+        Command::Lock => configure::lock(),
+        Command::Unlock => configure::unlock(),
+        Command::Keys(KeysCommand::Export(options)) => configure::export_key(options),
+        Command::Keys(KeysCommand::Import(options)) => configure::import_key(options),
+        Command::Keys(KeysCommand::CreateIdentity(options)) => configure::create_identity(options),
+        Command::Keys(KeysCommand::List) => configure::list_keys(),
+        Command::CreateKey(create_key_options) => configure::create_key(create_key_options),
+        Command::Rename(rename_options) => configure::rename(rename_options),
+        Command::Filter(FilterCommand::Smudge(options)) => configure::filter_smudge(options),
+        Command::Filter(FilterCommand::Clean(options)) => configure::filter_clean(options),
+        Command::SelfTest => std::env::current_exe()
+            .map_err(configure::ConfigureError::from)
+            .and_then(|executable| configure::self_test(&executable)),
+    };
+
+    // Saved even if the command failed, so the session can be replayed to reproduce the failure
+    if let Some(path) = &options.record_answers {
+        if let Err(err) = configure::save_recorded_answers(path) {
+            error!("{}", err);
+        }
+    }
+
+    if let Err(err) = result {
+        error!("{}", err);
+        std::process::exit(err.exit_code());
     }
 }
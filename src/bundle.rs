@@ -0,0 +1,254 @@
+//! Bundles of a project's encrypted secrets, for taking them somewhere `configure update` can't
+//! reach, like an airgapped build machine. Not to be confused with the key bundles in `escrow`.
+//!
+//! A bundle is a tarball of the project's `.enc` files, laid out as they are in the project,
+//! with a manifest saying where each one's secret belongs in the secrets repository. The files can
+//! be re-encrypted with a transfer key, so the bundle can be opened without the project's key.
+
+use crate::archive::{create_archive, list_archive_entries, read_archive_entry};
+use crate::fs::{hash_bytes, temporary_path_for};
+use crate::{ConfigurationFile, ConfigureError, Encoding};
+use serde::{Deserialize, Serialize};
+use std::path::{Component, Path};
+
+/// The manifest's name in the bundle
+pub const BUNDLE_MANIFEST_FILE_NAME: &str = "configure-bundle.json";
+
+/// What's in a bundle, and where it came from
+#[derive(Debug, Default, Serialize, Deserialize, Eq, PartialEq)]
+pub struct BundleManifest {
+    pub project_name: String,
+    pub branch: String,
+    pub pinned_hash: String,
+
+    /// The fingerprint of the transfer key the files were re-encrypted with, or `None` if they're
+    /// encrypted with the project's key
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transfer_key: Option<String>,
+
+    pub files: Vec<BundledFile>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+pub struct BundledFile {
+    /// Where the secret belongs, relative to the secrets repository's root
+    pub source: String,
+
+    /// The encrypted file's path in the bundle, which is also its path in the project
+    pub path: String,
+
+    /// How the secret is wrapped inside the encrypted file
+    #[serde(default, skip_serializing_if = "Encoding::is_plain")]
+    pub encoding: Encoding,
+
+    /// The SHA-256 hash of the encrypted file
+    pub digest: String,
+}
+
+/// A file in a bundle, with its encrypted contents
+pub type BundleEntry = (BundledFile, Vec<u8>);
+
+/// Writes a bundle of `files` (each with its encrypted contents) to `bundle_path`, filling in
+/// the manifest's digests
+pub fn write_bundle(
+    bundle_path: &Path,
+    mut manifest: BundleManifest,
+    files: Vec<BundleEntry>,
+) -> Result<BundleManifest, ConfigureError> {
+    let staging_dir = temporary_path_for(bundle_path);
+    if staging_dir.exists() {
+        std::fs::remove_dir_all(&staging_dir)?;
+    }
+
+    let result = stage_bundle(&staging_dir, &mut manifest, files)
+        .and_then(|_| create_archive(bundle_path, &staging_dir));
+    let _ = std::fs::remove_dir_all(&staging_dir);
+
+    result.map(|_| manifest)
+}
+
+fn stage_bundle(
+    staging_dir: &Path,
+    manifest: &mut BundleManifest,
+    files: Vec<BundleEntry>,
+) -> Result<(), ConfigureError> {
+    for (mut file, contents) in files {
+        let path = staging_dir.join(&file.path);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(path, &contents)?;
+
+        file.digest = hash_bytes(&contents);
+        manifest.files.push(file);
+    }
+
+    std::fs::write(
+        staging_dir.join(BUNDLE_MANIFEST_FILE_NAME),
+        serde_json::to_string_pretty(&manifest).unwrap(),
+    )?;
+
+    Ok(())
+}
+
+/// Reads the bundle at `bundle_path`, returning its manifest and each file's encrypted contents.
+/// Every file in the manifest has to be in the bundle with the contents it was exported with.
+pub fn read_bundle(
+    bundle_path: &Path,
+) -> Result<(BundleManifest, Vec<BundleEntry>), ConfigureError> {
+    let invalid = |message: String| ConfigureError::BundleInvalid(bundle_path.to_path_buf(), message);
+
+    let manifest = read_archive_entry(bundle_path, BUNDLE_MANIFEST_FILE_NAME)
+        .map_err(|err| invalid(err.to_string()))?;
+    let manifest: BundleManifest =
+        serde_json::from_slice(&manifest).map_err(|err| invalid(err.to_string()))?;
+
+    let entries = list_archive_entries(bundle_path)?;
+    let missing: Vec<&str> = manifest
+        .files
+        .iter()
+        .filter(|file| !entries.contains(&file.path))
+        .map(|file| file.path.as_str())
+        .collect();
+    if !missing.is_empty() {
+        return Err(ConfigureError::ArchiveIncomplete(missing.join(", ")));
+    }
+
+    let mut files = Vec::new();
+    for file in &manifest.files {
+        // The bundle may have come from anywhere, so it mustn't write outside the secrets repository
+        if !is_plain_relative_path(&file.source) || !is_plain_relative_path(&file.path) {
+            return Err(invalid(format!("{} isn't a relative path", file.source)));
+        }
+
+        let contents = read_archive_entry(bundle_path, &file.path)?;
+        if hash_bytes(&contents) != file.digest {
+            return Err(invalid(format!("{} has changed since it was exported", file.path)));
+        }
+
+        files.push((file.clone(), contents));
+    }
+
+    Ok((manifest, files))
+}
+
+fn is_plain_relative_path(path: &str) -> bool {
+    let path = Path::new(path);
+    path.components().next().is_some()
+        && path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+}
+
+/// The sources of the configuration's required files that aren't in the bundle
+pub fn find_missing_from_bundle(
+    manifest: &BundleManifest,
+    configuration: &ConfigurationFile,
+) -> Vec<String> {
+    configuration
+        .files_to_copy
+        .iter()
+        .filter(|file| !file.optional)
+        .map(|file| configuration.source_path(file))
+        .filter(|source| !manifest.files.iter().any(|file| &file.source == source))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bundled(source: &str, path: &str) -> BundledFile {
+        BundledFile {
+            source: source.to_string(),
+            path: path.to_string(),
+            encoding: Encoding::None,
+            digest: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_bundles_round_trip_and_are_checked_against_the_configuration() {
+        let dir = tempfile::tempdir().unwrap();
+        let bundle_path = dir.path().join("secrets.tar");
+
+        let manifest = BundleManifest {
+            project_name: "WordPress".to_string(),
+            ..Default::default()
+        };
+        let files = vec![(
+            bundled("WordPress/a.json", "config/a.json.enc"),
+            b"encrypted a".to_vec(),
+        )];
+        let written = write_bundle(&bundle_path, manifest, files).unwrap();
+        assert_eq!(written.files[0].digest, hash_bytes(b"encrypted a"));
+        assert!(!temporary_path_for(&bundle_path).exists());
+
+        let (manifest, files) = read_bundle(&bundle_path).unwrap();
+        assert_eq!(manifest, written);
+        assert_eq!(files[0].1, b"encrypted a");
+
+        let file = |source: &str, optional: bool| crate::File {
+            source: source.to_string(),
+            destination: source.to_string(),
+            optional,
+            ..Default::default()
+        };
+        let configuration = ConfigurationFile {
+            files_to_copy: vec![
+                file("WordPress/a.json", false),
+                file("WordPress/b.json", false),
+                file("WordPress/c.json", true),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(
+            find_missing_from_bundle(&manifest, &configuration),
+            vec!["WordPress/b.json"]
+        );
+    }
+
+    #[test]
+    fn test_bundles_that_were_changed_or_escape_the_secrets_repository_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let bundle_path = dir.path().join("secrets.tar");
+        let staging = dir.path().join("staging");
+        std::fs::create_dir_all(&staging).unwrap();
+        std::fs::write(staging.join("a.json.enc"), "x").unwrap();
+
+        let write = |file: BundledFile| {
+            let manifest = BundleManifest {
+                files: vec![file],
+                ..Default::default()
+            };
+            let manifest = serde_json::to_string(&manifest).unwrap();
+            std::fs::write(staging.join(BUNDLE_MANIFEST_FILE_NAME), manifest).unwrap();
+            create_archive(&bundle_path, &staging).unwrap();
+        };
+        let digest = hash_bytes(b"x");
+
+        write(BundledFile {
+            digest: digest.clone(),
+            ..bundled("a.json", "a.json.enc")
+        });
+        assert!(read_bundle(&bundle_path).is_ok());
+
+        write(BundledFile {
+            digest: digest.clone(),
+            ..bundled("../outside.json", "a.json.enc")
+        });
+        assert!(matches!(read_bundle(&bundle_path), Err(ConfigureError::BundleInvalid(..))));
+
+        write(BundledFile {
+            digest: "tampered".to_string(),
+            ..bundled("a.json", "a.json.enc")
+        });
+        assert!(matches!(read_bundle(&bundle_path), Err(ConfigureError::BundleInvalid(..))));
+
+        write(BundledFile {
+            digest,
+            ..bundled("b.json", "b.json.enc")
+        });
+        assert!(matches!(read_bundle(&bundle_path), Err(ConfigureError::ArchiveIncomplete(..))));
+    }
+}
@@ -1,38 +1,342 @@
+use crate::affected::{changed_sources, format_affected, AffectedProject};
+use crate::attributes::{add_suggested_attributes, find_attribute_problems, AttributeProblem};
+use crate::attributes::SUGGESTED_ATTRIBUTES;
+use crate::bundle::{find_missing_from_bundle, read_bundle, write_bundle};
+use crate::bundle::{BundleManifest, BundledFile};
+use crate::backups::{backup_file_name, plan_backup_repairs, BackupRepair};
+use crate::diff::{as_text, diff_lines, summarize_difference, DiffLine};
+use crate::fleet::{find_projects_under, format_fleet_table, read_fleet_manifest};
+use crate::fleet::FleetProjectStatus;
+use crate::encryption::{decrypt_contents, diagnose_encrypted_contents, encrypt_contents, Key};
+use crate::escrow::*;
 use crate::fs::*;
 use crate::git::*;
+use crate::inspect::*;
+use crate::interaction::{ask, Interaction, Response};
+use crate::key_metadata::{describe_key_age_problem, read_key_metadata, update_key_metadata};
+use crate::key_metadata::{KeyMetadata, KEY_METADATA_FILE_NAME};
+use crate::merge::MergeFormat;
+use crate::plan::PlannedChange;
+use crate::sanity::SanityChecks;
+use crate::self_test::run_self_test;
+use crate::schema::validate_configuration_schema;
+use crate::state::{read_apply_state, remove_apply_state, state_file_path, STATE_FILE_NAME};
+use crate::staging::{promote_stage, read_stage_manifest};
+use crate::signing::{sign_configuration, verify_configuration_signature};
+use crate::summary::{append_report, build_summary, write_summary};
+use crate::templates::*;
+use crate::transform::Transform;
 use crate::ui::*;
-use indicatif::ProgressBar;
+use indicatif::{ProgressBar, ProgressStyle};
 use chrono::prelude::*;
 
-use console::style;
-use log::{debug, info};
+use console::{style, Color};
+use log::{debug, info, log_enabled, Level};
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use structopt::StructOpt;
 
 use thiserror::Error;
 
-#[derive(Debug, Serialize, Deserialize, Eq, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
 pub struct ConfigurationFile {
     pub project_name: String,
     pub branch: String,
     pub pinned_hash: String,
     pub files_to_copy: Vec<File>,
+
+    /// Settings for the checks that catch empty or corrupted secrets. When absent, the defaults
+    /// in `SanityChecks` are used.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sanity_checks: Option<SanityChecks>,
+
+    /// The largest encrypted file, in bytes, that `apply` will decrypt. Zero or absent means no limit.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_file_size: Option<u64>,
+
+    /// Whether `apply` makes the decrypted files read-only, to discourage editing them by hand
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub read_only: bool,
+
+    /// The project's default for `apply --on-conflict`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub on_conflict: Option<OnConflict>,
+
+    /// What `apply --placeholder-on-missing` writes for missing optional secrets. Empty if unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub placeholder: Option<String>,
+
+    /// Where `apply` writes a summary of the secrets it applied, relative to the project root
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub summary_file: Option<String>,
+
+    /// Whether `update` signs `.configure` and `apply` and `validate` insist on a signature from
+    /// one of the signers in the secrets repository's `allowed_signers.json`
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub require_signed_updates: bool,
+
+    /// The oldest the pinned commit can be, in days, before `validate --strict` and
+    /// `status --check-freshness` fail
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_age_days: Option<u64>,
+
+    /// How many commits the pin can be behind the latest secrets on its branch before
+    /// `validate --strict` and `status --check-freshness` fail
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_commits_behind: Option<u32>,
+
+    /// The directory in the secrets repository that sources starting with `./` are relative to.
+    /// Defaults to `project_name`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_root: Option<String>,
+
+    /// How old the project's key can get, in days, before `apply` and `update` warn that it's due
+    /// for rotation. Keys whose age isn't known are never warned about.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_key_age_days: Option<u64>,
+
+    /// The URLs of remotes other than `origin`, by name, for branches named like
+    /// `<remote>/<branch>`. They're added to the secrets repository when they're first needed.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub remotes: BTreeMap<String, String>,
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
 }
 
 impl ConfigurationFile {
+    /// Whether this project hasn't been set up yet: there's no `.configure`, or it's blank or `{}`
     pub fn is_empty(&self) -> bool {
         self == &ConfigurationFile::default()
     }
 
-    fn needs_project_name(&self) -> bool {
-        self.project_name == ""
+    /// Fails with a pointer to `configure init` if the project hasn't been set up yet
+    pub fn ensure_initialized(&self) -> Result<(), ConfigureError> {
+        match self.is_empty() {
+            true => Err(ConfigureError::ConfigurationUninitialized),
+            false => Ok(()),
+        }
+    }
+
+    /// Which of the required fields are blank, as they can be in files written by older versions
+    pub fn completeness(&self) -> CompletenessReport {
+        let fields = [
+            (RequiredField::ProjectName, &self.project_name),
+            (RequiredField::Branch, &self.branch),
+            (RequiredField::PinnedHash, &self.pinned_hash),
+        ];
+
+        CompletenessReport {
+            uninitialized: self.is_empty(),
+            missing: fields
+                .iter()
+                .filter(|(_, value)| value.trim().is_empty())
+                .map(|(field, _)| *field)
+                .collect(),
+        }
+    }
+
+    /// Layers `other` on top of this configuration, for building one from a base and an overlay:
+    ///
+    /// - `project_name`, `branch`, and `pinned_hash` are replaced unless they're blank in `other`,
+    ///   so an overlay can leave them out – an empty string never overrides
+    /// - The optional settings are replaced whenever `other` sets them, even to an empty value
+    /// - `read_only` and `require_signed_updates` are on if either sets them, since `false` can't
+    ///   be told apart from unset
+    /// - Each file in `other` replaces the files here with the same destination and variant, in
+    ///   the position of the first of them, and the rest are added at the end. If `other` lists the
+    ///   same destination and variant more than once, its last entry wins.
+    pub fn merge(&mut self, other: ConfigurationFile) {
+        merge_text(&mut self.project_name, other.project_name);
+        merge_text(&mut self.branch, other.branch);
+        merge_text(&mut self.pinned_hash, other.pinned_hash);
+
+        for file in other.files_to_copy {
+            let same = |existing: &File| {
+                existing.destination == file.destination && existing.variant == file.variant
+            };
+
+            let position = self.files_to_copy.iter().position(same);
+            self.files_to_copy.retain(|existing| !same(existing));
+
+            let index = position.unwrap_or(self.files_to_copy.len());
+            self.files_to_copy.insert(index, file);
+        }
+
+        self.sanity_checks = other.sanity_checks.or(self.sanity_checks.take());
+        self.max_file_size = other.max_file_size.or(self.max_file_size);
+        self.read_only |= other.read_only;
+        self.on_conflict = other.on_conflict.or(self.on_conflict);
+        self.placeholder = other.placeholder.or(self.placeholder.take());
+        self.summary_file = other.summary_file.or(self.summary_file.take());
+        self.require_signed_updates |= other.require_signed_updates;
+        self.max_age_days = other.max_age_days.or(self.max_age_days);
+        self.max_commits_behind = other.max_commits_behind.or(self.max_commits_behind);
+        self.source_root = other.source_root.or(self.source_root.take());
+        self.max_key_age_days = other.max_key_age_days.or(self.max_key_age_days);
+        self.remotes.extend(other.remotes);
+    }
+
+    /// Where `file`'s source is in the secrets repository, relative to its root
+    pub fn source_path(&self, file: &File) -> String {
+        self.resolve_source(&file.source)
+    }
+
+    /// Resolves a source as written in `.configure`. Sources starting with `./` are relative to
+    /// `source_root` (or the project's name), and anything else to the secrets repository's root.
+    pub fn resolve_source(&self, source: &str) -> String {
+        let root = self.source_root_directory();
+
+        match source.strip_prefix("./") {
+            Some(rest) if !root.is_empty() => format!("{}/{}", root, rest),
+            Some(rest) => rest.to_string(),
+            None => source.to_string(),
+        }
+    }
+
+    /// The shortest way to write a source in `.configure` – `./…` if it's under `source_root`
+    pub fn shorthand_source(&self, source: &str) -> String {
+        let root = self.source_root_directory();
+        let source = self.resolve_source(source);
+
+        match source.strip_prefix(&root).and_then(|rest| rest.strip_prefix('/')) {
+            Some(rest) if !root.is_empty() && !rest.is_empty() => format!("./{}", rest),
+            _ => source,
+        }
+    }
+
+    fn source_root_directory(&self) -> String {
+        match &self.source_root {
+            Some(source_root) => source_root.trim_end_matches('/').to_string(),
+            None if self.project_name.trim().is_empty() => String::new(),
+            None => sanitized_key_name(&self.project_name),
+        }
+    }
+}
+
+fn merge_text(value: &mut String, other: String) {
+    if !other.trim().is_empty() {
+        *value = other;
+    }
+}
+
+/// The longest `project_name` that's allowed
+pub const MAX_PROJECT_NAME_LENGTH: usize = 64;
+
+/// What's wrong with `name` as a `project_name`, if anything. It becomes a key in `keys.json` and
+/// a directory in the secrets repository, so it's limited to ASCII letters, digits, `-`, `_` and
+/// `.`, starting with a letter or digit.
+pub fn project_name_problem(name: &str) -> Option<String> {
+    if name.trim() != name {
+        return Some("has leading or trailing whitespace".to_string());
+    }
+
+    if let Some(c) = name.chars().find(|c| !is_project_name_char(*c)) {
+        return Some(format!(
+            "contains {:?}, but only letters, digits, `-`, `_` and `.` are allowed",
+            c
+        ));
+    }
+
+    if !name.starts_with(|c: char| c.is_ascii_alphanumeric()) {
+        return Some("has to start with a letter or digit".to_string());
+    }
+
+    if name.len() > MAX_PROJECT_NAME_LENGTH {
+        return Some(format!(
+            "is longer than {} characters",
+            MAX_PROJECT_NAME_LENGTH
+        ));
+    }
+
+    None
+}
+
+fn is_project_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.')
+}
+
+/// `name` with everything `project_name_problem` objects to fixed, for use wherever the name
+/// becomes a key in `keys.json` or part of a path. Runs of other characters become a single `-`,
+/// so `apps/reader (beta)` becomes `apps-reader-beta`. Names that are already fine are unchanged.
+pub fn sanitized_key_name(name: &str) -> String {
+    let mut sanitized = String::new();
+
+    for c in name.trim().chars() {
+        if is_project_name_char(c) {
+            sanitized.push(c);
+        } else if !sanitized.ends_with('-') {
+            sanitized.push('-');
+        }
+    }
+
+    let sanitized: String = sanitized
+        .trim_start_matches(|c: char| !c.is_ascii_alphanumeric())
+        .chars()
+        .take(MAX_PROJECT_NAME_LENGTH)
+        .collect();
+    let sanitized = sanitized.trim_end_matches('-');
+
+    match sanitized {
+        "" => "project".to_string(),
+        sanitized => sanitized.to_string(),
+    }
+}
+
+/// A field every `.configure` file needs before secrets can be applied or updated
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum RequiredField {
+    ProjectName,
+    Branch,
+    PinnedHash,
+}
+
+impl std::fmt::Display for RequiredField {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            RequiredField::ProjectName => "project_name",
+            RequiredField::Branch => "branch",
+            RequiredField::PinnedHash => "pinned_hash",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+/// The required fields a `.configure` file is missing
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct CompletenessReport {
+    pub missing: Vec<RequiredField>,
+
+    /// Whether the file is empty, rather than just missing some fields
+    pub uninitialized: bool,
+}
+
+impl CompletenessReport {
+    pub fn is_complete(&self) -> bool {
+        self.missing.is_empty()
     }
 
-    fn needs_branch(&self) -> bool {
-        self.branch == ""
+    pub fn is_missing(&self, field: RequiredField) -> bool {
+        self.missing.contains(&field)
     }
 
-    fn needs_pinned_hash(&self) -> bool {
-        self.pinned_hash == ""
+    /// Fails with a message naming the missing fields, for commands that can't work without them
+    pub fn ensure_complete(&self) -> Result<(), ConfigureError> {
+        if self.is_complete() {
+            return Ok(());
+        }
+
+        if self.uninitialized {
+            return Err(ConfigureError::ConfigurationUninitialized);
+        }
+
+        let missing: Vec<String> = self.missing.iter().map(ToString::to_string).collect();
+        Err(ConfigureError::ConfigurationIncomplete(missing.join(", ")))
     }
 }
 
@@ -44,19 +348,99 @@ impl Default for ConfigurationFile {
             branch: "".to_string(),
             pinned_hash: "".to_string(),
             files_to_copy,
+            sanity_checks: None,
+            max_file_size: None,
+            read_only: false,
+            on_conflict: None,
+            placeholder: None,
+            summary_file: None,
+            require_signed_updates: false,
+            max_age_days: None,
+            max_commits_behind: None,
+            source_root: None,
+            max_key_age_days: None,
+            remotes: BTreeMap::new(),
         }
     }
 }
 
+/// Why a `.configure` file couldn't be loaded
+#[derive(Error, Debug)]
+pub enum ConfigurationParseError {
+    #[error("Unable to read {path}: {source}")]
+    Unreadable {
+        path: String,
+        source: std::io::Error,
+    },
+
+    #[error("{path} is not valid JSON: {message} at line {line}, column {column}\n{snippet}")]
+    InvalidJson {
+        path: String,
+        message: String,
+        line: usize,
+        column: usize,
+        snippet: String,
+    },
+
+    #[error("{path} is valid JSON, but {field} is wrong: {message} at line {line}, column {column}\n{snippet}")]
+    WrongShape {
+        path: String,
+        /// Where in the document the problem is, like `files_to_copy[3]`
+        field: String,
+        message: String,
+        line: usize,
+        column: usize,
+        snippet: String,
+    },
+
+    #[error("{path} has an unknown field {field} at line {line}, column {column}\n{snippet}")]
+    UnknownField {
+        path: String,
+        /// The unknown field, like `files_to_copy[3].destinaton`
+        field: String,
+        line: usize,
+        column: usize,
+        snippet: String,
+    },
+}
+
 #[derive(Error, Debug)]
 pub enum ConfigureError {
+    #[error(transparent)]
+    ConfigurationInvalid(#[from] ConfigurationParseError),
+
+    #[error("Every other file was applied, but these were in use and weren't replaced: {0}. Close the programs using them, then run `configure apply` again")]
+    FilesLocked(String),
+
+    #[error("Your .configure is missing: {0} – run `configure init` to fill it in")]
+    ConfigurationIncomplete(String),
+
+    #[error("This project hasn't been set up yet – run `configure init` to create its .configure")]
+    ConfigurationUninitialized,
 
     #[error("Unable to initialize underlying encryption")]
     EncryptionUnavailable,
 
+    #[error("The key uses the {0:?} algorithm, but there's no cipher registered for it")]
+    UnknownCipher(String),
+
     #[error("Unable to decrypt file")]
     DataDecryptionError(#[from] std::io::Error),
 
+    #[error("The secret for {destination} isn't valid {encoding}: {problem}")]
+    InvalidEncoding {
+        destination: String,
+        encoding: Encoding,
+        problem: String,
+    },
+
+    #[error("Unable to {transform} the secret for {destination}: {problem}")]
+    TransformFailed {
+        destination: String,
+        transform: Transform,
+        problem: String,
+    },
+
     #[error("Invalid git status")]
     GitStatusParsingError(#[from] std::num::ParseIntError),
 
@@ -66,6 +450,18 @@ pub enum ConfigureError {
     #[error("No secrets repository could be found on this machine")]
     SecretsNotPresent,
 
+    #[error("Unable to determine your home directory – make sure `HOME` is set")]
+    HomeDirectoryUnknown,
+
+    #[error("{0:?} isn't a git repository. Move it aside, and clone the secrets repository there instead")]
+    SecretsRepoNotARepository(PathBuf),
+
+    #[error("The secrets repository at {0:?} has no commits. It was probably created by hand – remove it, and clone the real secrets repository there instead")]
+    SecretsRepoHasNoCommits(PathBuf),
+
+    #[error("The secrets repository at {0:?} has no `{1}` remote to get secrets from. Add it with `git remote add {1} <url>`, or add its URL to `remotes` in `.configure`")]
+    SecretsRepoHasNoRemote(PathBuf, String),
+
     #[error("An encrypted file is missing – unable to apply secrets to project. Run `configure update` to fix this")]
     EncryptedFileMissing,
 
@@ -76,321 +472,5385 @@ pub enum ConfigureError {
     KeysFileIsNotValidJSON,
 
     #[error("That project key is not defined in keys.json")]
-    MissingProjectKey
-}
+    MissingProjectKey,
 
-#[derive(Debug, Serialize, Deserialize, Eq, PartialEq)]
-pub struct File {
-    #[serde(rename = "file")]
-    pub source: String,
-    pub destination: String,
-}
+    #[error("The key for {0} in keys.json isn't a valid encryption key")]
+    InvalidProjectKey(String),
 
-impl File {
-    pub fn get_encrypted_destination(&self) -> String {
-        self.destination.clone() + &".enc".to_owned()
-    }
+    #[error("{0:?} isn't valid key metadata: {1}")]
+    KeyMetadataInvalid(PathBuf, String),
 
-    pub fn get_decrypted_destination(&self) -> String {
-        self.destination.clone()
-    }
+    #[error("Unable to reach the secrets server: {0}")]
+    NetworkError(String),
 
-    pub fn get_backup_destination(&self) -> String {
-        let path = std::path::Path::new(&self.destination);
+    #[error("The secrets server didn't accept your credentials – check your SSH key or access token, and that you have access to the secrets repository: {0}")]
+    AuthenticationFailed(String),
 
-        let directory = match path.parent() {
-            Some(parent) => parent,
-            None => std::path::Path::new("/"),
-        };
+    #[error("Unable to fetch the latest secrets: {0}")]
+    FetchFailed(String),
 
+    #[error("The secrets server rejected the push: {0}")]
+    PushRejected(String),
 
-        let file_stem = path.file_stem().unwrap().to_str().unwrap_or("");
-        let datetime = Local::now().format("%Y-%m-%d-%H-%M-%S").to_string();
-        let extension = path.extension().unwrap_or(std::ffi::OsStr::new("")).to_str().unwrap_or("");
+    #[error("The secrets branch {branch:?} does not exist. Available branches: {}", .alternatives.join(", "))]
+    BranchNotFound {
+        branch: String,
+        alternatives: Vec<String>,
+    },
 
-        let filename = format!("{:}-{:}.{:}.bak", file_stem, datetime, extension);
+    #[error("Unable to read the secrets repository: {0}")]
+    GitError(#[from] git2::Error),
 
-        return directory
-            .join(filename)
-            .to_str()
-            .unwrap()
-            .to_string();
+    #[error("It isn't safe to write to the project right now: {0}. Pass `--force` to do it anyway")]
+    ProjectRepoBusy(String),
 
-    }
-}
+    #[error("There's no file at {0:?} in the project to import")]
+    ImportSourceMissing(String),
 
-pub fn apply_configuration(configuration: ConfigurationFile) {
-    // Decrypt the project's configuration files
-    decrypt_files_for_configuration(&configuration).expect("Unable to decrypt and copy files");
+    #[error("The secrets repository already has a different file at {0:?}. Pass `--force` to replace it")]
+    ImportWouldOverwrite(String),
 
-    debug!("All Files Copied!");
+    #[error("Sanity checks failed – pass `--no-sanity-checks` to skip them:\n{0}")]
+    SanityCheckFailed(String),
 
-    info!("Done")
-}
+    #[error("{path} is {size} bytes, which is larger than the {limit} byte limit")]
+    FileTooLarge { path: String, size: u64, limit: u64 },
 
-pub fn update_configuration(mut configuration: ConfigurationFile) {
-    let starting_branch =
-        get_current_secrets_branch().expect("Unable to determine current secrets branch");
-    let starting_ref =
-        get_secrets_current_hash().expect("Unable to determine current secrets commit hash");
+    #[error("Unable to read archive: {0}")]
+    ArchiveError(String),
 
-    heading("Configure Update");
+    #[error("The archive is missing files listed in `.configure`: {0}")]
+    ArchiveIncomplete(String),
 
-    //
-    // Step 1 – Fetch the latest secrets from the server
-    //          We need them in order to update the pinned hash
-    //
-    let bar = ProgressBar::new_spinner();
-    bar.enable_steady_tick(125);
-    bar.set_message("Fetching Latest Secrets");
+    #[error("{0:?} isn't a valid secrets bundle: {1}")]
+    BundleInvalid(PathBuf, String),
 
-    fetch_secrets_latest_remote_data().expect("Unable to fetch latest secrets");
+    #[error("The bundle is missing secrets that `files_to_copy` needs: {0}")]
+    BundleIncomplete(String),
 
-    bar.finish_and_clear();
+    #[error("{0:?} doesn't hold a valid key. Create one with `configure create-key --stdout`")]
+    TransferKeyInvalid(PathBuf),
 
-    //
-    // Step 2 – Check if the user wants to use a different secrets branch
-    //
-    configuration = prompt_for_branch(configuration, true);
+    #[error("The bundle was exported with a transfer key – pass it with `--transfer-key`")]
+    TransferKeyRequired,
 
-    //
-    // Step 3 – Check if the currente configuration branch is in sync with the server or not.or
-    // If not, check with the user whether they'd like to continue
-    //
-    let status = get_secrets_repo_status().expect("Unable to get secrets repo status");
+    #[error("The bundle was exported with a different transfer key (its fingerprint is {0})")]
+    TransferKeyMismatch(String),
 
-    let should_continue = match status.sync_state {
-        RepoSyncState::Ahead => {
-            warn(&format!(
-                "Your local secrets repo has {:?} change(s) that the server does not",
-                status.distance
-            ));
-            confirm("Would you like to continue?")
-        }
-        RepoSyncState::Behind => {
-            warn(&format!(
-                "The server has {:?} change(s) that your local secrets repo does not",
-                status.distance
-            ));
-            confirm("Would you like to continue?")
-        }
-        RepoSyncState::Synced => true,
-    };
+    #[error("Unable to use template: {0}")]
+    TemplateError(String),
 
-    if !should_continue {
-        return;
-    }
+    #[error("Unable to find the project root: {0}")]
+    ProjectRootNotFound(String),
 
-    //
-    // Step 4 – Check if the project's secrets are out of date compared to the server.
-    //          If they out of date, we'll prompt the user to pull the latest remote
-    //          changes into the local secrets repo before continuing.
-    //
-    let distance =
-        configure_file_distance_behind_secrets_repo(&configuration, &configuration.branch);
-    if distance > 0 {
-        let message = format!(
-            "This project is {:?} commit(s) behind the latest secrets. Would you like to use the latest secrets?",
-            distance
-        );
+    #[error("The configuration has {0} problem(s)")]
+    ConfigurationHasProblems(usize),
 
-        // Prompt to update to most recent secrets data in the branch
-        if confirm(&message) {
-            let latest_commit_hash = get_latest_hash_for_remote_branch(&configuration.branch)
-                .expect("Unable to fetch latest commit hash");
+    #[error("There's no entry for {0:?} in `.configure`")]
+    UnknownDestination(String),
 
-            debug!(
-                "Moving the repo to {:?} at {:?}",
-                &configuration.branch, latest_commit_hash
-            );
+    #[error("{0} already exists and differs from the incoming secrets. Pass `--on-conflict` to choose what to do with it")]
+    DestinationExists(String),
 
-            check_out_branch_at_revision(&configuration.branch, &latest_commit_hash)
-                .expect("Unable to check out branch at revision");
-            configuration.pinned_hash = latest_commit_hash;
-        }
-    }
+    #[error("{0} would be written outside {1:?}. Destinations must be relative paths that stay inside it")]
+    DestinationOutsideRoot(String, PathBuf),
 
-    //
-    // Step 5 – Write out encrypted files as needed
-    //
-    save_configuration(&configuration).expect("Unable to save updated configuration");
+    #[error("{0} would be outside the secrets repository. Pass a path relative to its root, like `MyApp/secrets.json`")]
+    SourceOutsideSecretsRepo(String),
 
-    //
-    // Step 6 – Write out encrypted files as needed
-    //
-    write_encrypted_files_for_configuration(&configuration)
-        .expect("Unable to copy encrypted files");
+    #[error("The destination {0} {1}")]
+    DestinationNotAllowed(String, String),
 
-    //
-    // Step 7 – Roll everything back to how it was before we started
-    //
-    crate::git::check_out_branch_at_revision(&starting_branch, &starting_ref)
-        .expect("Unable to roll back to branch");
+    #[error("Unable to merge the secret into {destination}: {problem}")]
+    MergeFailed { destination: String, problem: String },
 
-    //
-    // Step 8 – Apply these changes to the current repo
-    //
-    apply_configuration(configuration);
-}
+    #[error("The secrets repository is on {current}, but this needs it to be on {expected}. Check out {expected} and try again")]
+    NotOnBranch { expected: String, current: String },
 
-pub fn validate_configuration(configuration: ConfigurationFile) {
-    println!("{:?}", configuration);
-}
+    #[error("The key bundle can't be used: {0}")]
+    KeyBundleInvalid(String),
 
-pub fn setup_configuration(mut configuration: ConfigurationFile) {
-    heading("Configure Setup");
-    println!("Let's get configuration set up for this project.");
-    newline();
+    #[error("keys.json already has a different key for {0}. Pass `--force` to replace it")]
+    KeyWouldOverwrite(String),
 
-    // Help the user set the `project_name` field
-    configuration = prompt_for_project_name_if_needed(configuration);
+    #[error("Would create a key for {0}")]
+    KeyWouldBeCreated(String),
 
-    // Help the user set the `branch` field
-    configuration = prompt_for_branch(configuration, true);
+    #[error("The pinned secrets are staler than `.configure` allows")]
+    PinIsStale,
 
-    // Set the latest automatically hash based on the selected branch
-    configuration = set_latest_hash_if_needed(configuration);
+    #[error("Which projects should be checked? Pass their paths, `--manifest <file>`, or `--all <root>` to find every project under a directory")]
+    NoFleetProjects,
 
-    // Help the user add files
-    configuration = prompt_to_add_files(configuration);
+    #[error("Unable to look for projects under {0:?}: {1}")]
+    ProjectsRootUnreadable(PathBuf, std::io::Error),
 
-    info!("Writing changes to .configure");
+    #[error("{0} encrypted file(s) changed without the pin changing. Run `configure reencrypt` to regenerate them from the pinned secrets")]
+    SourceHashMismatch(usize),
 
+    #[error("The key for {0} in keys.json changed while configure was running – someone else may have just created it. Run the command again to see it")]
+    KeyChangedSinceRead(String),
 
-    save_configuration(&configuration).expect("Unable to save configure file");
+    #[error("{0:?} is locked by another configure. If none is running, delete it")]
+    FileLocked(PathBuf),
 
-    // Create a key in `keys.json` for the project if one doesn't already exist
-    if read_encryption_key(&configuration).unwrap() == None {
-        generate_encryption_key(&configuration).expect("Unable to automatically generate an encryption key for this project");
-    }
-}
+    #[error("The secrets repository is read-only, so configure can't {0}")]
+    SecretsReadOnly(String),
 
-fn prompt_for_project_name_if_needed(mut configuration: ConfigurationFile) -> ConfigurationFile {
-    // If there's already a project name, don't bother updating it
-    if !configuration.needs_project_name() {
-        return configuration;
-    }
+    #[error("Gave up after waiting {seconds}s for another configure to finish with {path:?}. If none is running, delete it")]
+    LockTimedOut { path: PathBuf, seconds: u64 },
 
-    let project_name = prompt("What is the name of your project?");
-    configuration.project_name = project_name.clone();
-    println!("Project Name set to: {:?}", project_name);
+    #[error("Which projects are still active? Pass them with `--project <name>`, or `--active-file <file>`")]
+    NoActiveProjects,
 
-    configuration
-}
+    #[error("keys.json has {0} key(s) for projects that aren't active. Pass `--delete` to remove them")]
+    OrphanedKeys(usize),
 
-fn prompt_for_branch(mut configuration: ConfigurationFile, force: bool) -> ConfigurationFile {
-    // If there's already a branch set, don't bother updating it
-    if !configuration.needs_branch() && !force {
-        return configuration;
-    }
+    #[error("{0} has changed since it was staged, so nothing was promoted. Run `configure update --stage-dir` again")]
+    StagedFileTampered(String),
 
-    let secrets_repo_path = find_secrets_repo();
-    let current_branch =
-        get_current_secrets_branch().expect("Unable to determine current secrets branch");
-    let branches = get_secrets_branches().expect("Unable to fetch secrets branches");
+    #[error("The project's {0} has changed since it was staged, so nothing was promoted. Run `configure update --stage-dir` again")]
+    StagedFileOutdated(String),
 
-    println!(
-        "We've found your secrets repository at {:?}",
-        secrets_repo_path
-    );
-    newline();
-    println!("Which branch would you like to use?");
-    println!("Current Branch: {}", style(&current_branch).green());
+    #[error("Couldn't read the staging manifest at {0:?}: {1}. Was it made with `configure update --stage-dir`?")]
+    StageManifestInvalid(PathBuf, String),
 
-    let selected_branch =
-        select(branches, &current_branch).expect("Unable to read selected branch");
+    #[error("The staging manifest lists {0:?}, which isn't one of the project's encrypted files, so nothing was promoted")]
+    StagedPathNotAllowed(String),
 
-    configuration.branch = selected_branch.clone();
-    println!("Secrets repo branch set to: {:?}", selected_branch);
+    #[error("Which project is the key for? Pass `--project`, or run this in a project with a `.configure`")]
+    ProjectNameRequired,
 
-    configuration
-}
+    #[error("{0:?} can't be a project name: it {1}")]
+    ProjectNameInvalid(String, String),
 
-fn set_latest_hash_if_needed(mut configuration: ConfigurationFile) -> ConfigurationFile {
-    if !configuration.needs_pinned_hash() {
-        return configuration;
-    }
+    #[error("{0} file(s) differ from the project's secrets")]
+    FilesDiffer(usize),
 
-    let latest_hash = get_secrets_latest_hash(&configuration.branch)
-        .expect("Unable to fetch the latest secrets hash");
-    configuration.pinned_hash = latest_hash;
+    #[error("{0} file(s) were edited or removed since the last apply, and applying would overwrite them")]
+    DriftDetected(usize),
 
-    configuration
+    #[error("The self-test failed at the `{0}` stage")]
+    SelfTestFailed(String),
+
+    #[error("The secrets repository doesn't have the commit {}. Run `configure update` to fetch it", display_hash(.0))]
+    CommitNotFound(String),
+
+    #[error("{path} isn't in the secrets repository at {}", display_hash(.commit))]
+    SourceMissingAtCommit { path: String, commit: String },
+
+    #[error("{} isn't a commit on the secrets branch {branch}", display_hash(.commit))]
+    CommitNotOnBranch { commit: String, branch: String },
+
+    #[error("{0} is missing, but this project requires signed updates. Run `configure update` to sign .configure")]
+    SignatureMissing(String),
+
+    #[error("The signature on .configure isn't valid: {0}")]
+    SignatureInvalid(String),
+
+    #[error(".configure was signed by {0}, which isn't in the secrets repository's allowed_signers.json")]
+    SignerNotAllowed(String),
+
+    #[error("The secrets repository's allowed_signers.json can't be used: {0}")]
+    AllowedSignersInvalid(String),
+
+    #[error("Unable to sign .configure: {0}")]
+    SigningFailed(String),
+
+    #[error("The answer file can't be used: {0}")]
+    AnswersInvalid(String),
+
+    #[error("The answer file has no answer for {0:?}")]
+    AnswerMissing(String),
+
+    #[error("Unable to read your answer: {0}")]
+    PromptFailed(std::io::Error),
+
+    #[error("A question needed {expected} as its answer, but got {response}")]
+    UnexpectedResponse { expected: String, response: String },
+
+    #[error("No files in `.configure` belong to the variant {0:?}")]
+    UnknownVariant(String),
+
+    #[error("{0:?} isn't a target configure knows. Use `<os>` or `<os>/<arch>`, like `ios/aarch64`, where the OS is one of {}", KNOWN_PLATFORMS.join(", "))]
+    UnknownTarget(String),
+
+    #[error("The variants {variants} all write to {destination}")]
+    VariantsShareDestination {
+        destination: String,
+        variants: String,
+    },
 }
 
-fn prompt_to_add_files(mut configuration: ConfigurationFile) -> ConfigurationFile {
-    let mut files = configuration.files_to_copy;
+/// The exit code for `create-key --dry-run` when the project has no key yet
+pub const MISSING_KEY_EXIT_CODE: i32 = 2;
 
-    let mut message = "Would you like to add files?";
+/// The exit code for a command that did some of what it was asked, but not all of it
+pub const PARTIAL_SUCCESS_EXIT_CODE: i32 = 3;
 
-    if !files.is_empty() {
-        message = "Would you like to add additional files?";
-    }
+/// The exit code when the secrets server couldn't be reached, so trying again later may work
+pub const NETWORK_ERROR_EXIT_CODE: i32 = 4;
 
-    while confirm(message) {
-        match prompt_to_add_file() {
-            Some(file) => files.push(file),
-            None => continue,
+/// The exit code when the secrets server didn't accept the user's credentials
+pub const AUTHENTICATION_FAILED_EXIT_CODE: i32 = 5;
+
+/// The exit code when fetching the secrets failed for any other reason
+pub const FETCH_FAILED_EXIT_CODE: i32 = 6;
+
+impl ConfigureError {
+    /// The exit code the error should give, so scripts can tell a partial success from a failure
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ConfigureError::KeyWouldBeCreated(_) => MISSING_KEY_EXIT_CODE,
+            ConfigureError::FilesLocked(_) => PARTIAL_SUCCESS_EXIT_CODE,
+            ConfigureError::NetworkError(_) => NETWORK_ERROR_EXIT_CODE,
+            ConfigureError::AuthenticationFailed(_) => AUTHENTICATION_FAILED_EXIT_CODE,
+            ConfigureError::FetchFailed(_) => FETCH_FAILED_EXIT_CODE,
+            _ => 1,
         }
     }
+}
 
-    configuration.files_to_copy = files;
+#[derive(Debug, Clone, Default, Serialize, Deserialize, Eq, PartialEq)]
+pub struct File {
+    #[serde(rename = "file")]
+    pub source: String,
+    pub destination: String,
 
-    configuration
+    /// The format the plaintext is expected to be in (`json` or `plist`), checked when it's
+    /// encrypted and decrypted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format: Option<String>,
+
+    /// Who to ask about this secret – a team name or GitHub handle
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+
+    /// What this secret is for. It's only shown to people, never used.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+
+    /// The build variant (like a flavor or environment) this entry belongs to. Entries without
+    /// one are applied for every variant.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub variant: Option<String>,
+
+    /// Whether the project can do without this secret. A missing optional secret is skipped
+    /// instead of being an error.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub optional: bool,
+
+    /// What `apply --placeholder-on-missing` writes when this optional secret is missing.
+    /// Overrides the project's `placeholder`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub placeholder: Option<String>,
+
+    /// The operating systems (as in `std::env::consts::OS`, like `macos` or `linux`) this entry
+    /// is applied on. Empty means every platform.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub platforms: Vec<String>,
+
+    /// How the secret is encoded inside its encrypted file. It's decoded after decrypting, so the
+    /// destination gets the raw bytes, and encoded again when it's encrypted.
+    #[serde(default, skip_serializing_if = "Encoding::is_plain")]
+    pub encoding: Encoding,
+
+    /// What the secret, kept as JSON, is converted to as it's applied, like a plist. It's still
+    /// encrypted as JSON.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transform: Option<Transform>,
+
+    /// Whether the destination is in the project (the default) or, for `user`, in the home
+    /// directory of whoever applies it – then the destination starts with `~/`
+    #[serde(default, skip_serializing_if = "Scope::is_project")]
+    pub scope: Scope,
+
+    /// Merge the secret into the file already at the destination instead of replacing it, for
+    /// files with other settings in them like `~/.netrc`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub merge: Option<MergeFormat>,
 }
 
-fn prompt_to_add_file() -> Option<File> {
-    let relative_source_file_path =
-        prompt("Enter the source file path (relative to the secrets root):");
+/// Where the encrypted files for `user` scope secrets are kept in the project. `~/.netrc` is
+/// encrypted to `.configure-user/.netrc.enc`.
+pub const USER_SCOPE_ENCRYPTED_DIRECTORY: &str = ".configure-user";
 
-    let secrets_root = match find_secrets_repo() {
-        Ok(repo_path) => repo_path,
-        Err(_) => return None,
-    };
+/// Where a secret's destination is
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// In the project, relative to its root
+    #[default]
+    Project,
 
-    let full_source_file_path = secrets_root.join(&relative_source_file_path);
+    /// In the home directory of whoever applies it, for machine-wide settings like `~/.netrc`
+    User,
+}
 
-    if !full_source_file_path.exists() {
-        println!("Source File does not exist: {:?}", full_source_file_path);
-        return None;
+impl Scope {
+    pub fn is_project(&self) -> bool {
+        self == &Scope::Project
     }
+}
 
-    let relative_destination_file_path =
-        prompt("Enter the destination file path (relative to the project root):");
+/// How a secret is wrapped inside its encrypted file, for secrets that were stored encoded
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    /// The encrypted file holds the secret as it is
+    #[default]
+    None,
 
-    let project_root = find_project_root();
-    let full_destination_file_path = project_root.join(&relative_destination_file_path);
+    Base64,
+    Hex,
+}
 
-    debug!("Destination: {:?}", full_destination_file_path);
+impl Encoding {
+    pub fn is_plain(&self) -> bool {
+        self == &Encoding::None
+    }
 
-    Some(File {
-        source: relative_source_file_path,
-        destination: relative_destination_file_path,
-    })
-}
+    pub fn encode(&self, contents: &[u8]) -> Vec<u8> {
+        match self {
+            Encoding::None => contents.to_vec(),
+            Encoding::Base64 => base64::encode(contents).into_bytes(),
+            Encoding::Hex => contents
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect::<String>()
+                .into_bytes(),
+        }
+    }
 
-fn configure_file_distance_behind_secrets_repo(
-    configuration: &ConfigurationFile,
-    branch_name: &str,
-) -> i32 {
-    debug!("Checking if configure file is behind secrets repo");
+    /// Decodes `contents`, ignoring any whitespace they're wrapped in
+    pub fn decode(&self, contents: &[u8]) -> Result<Vec<u8>, String> {
+        let text: Vec<u8> = contents
+            .iter()
+            .copied()
+            .filter(|byte| !byte.is_ascii_whitespace())
+            .collect();
+
+        match self {
+            Encoding::None => Ok(contents.to_vec()),
+            Encoding::Base64 => base64::decode(&text).map_err(|err| err.to_string()),
+            Encoding::Hex => {
+                if !text.len().is_multiple_of(2) {
+                    return Err("it has an odd number of digits".to_string());
+                }
 
-    let current_branch =
-        get_current_secrets_branch().expect("Unable to get current secrets branch");
-    debug!("Current branch is: {:?}", current_branch);
+                text.chunks(2)
+                    .map(|pair| {
+                        std::str::from_utf8(pair)
+                            .ok()
+                            .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                            .ok_or_else(|| {
+                                format!("{:?} isn't a hex byte", String::from_utf8_lossy(pair))
+                            })
+                    })
+                    .collect()
+            }
+        }
+    }
+}
 
-    let current_hash =
-        get_secrets_current_hash().expect("Unable to get current secrets hash");
-    debug!("Current hash is: {:?}", current_hash);
+impl std::fmt::Display for Encoding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Encoding::None => write!(f, "none"),
+            Encoding::Base64 => write!(f, "base64"),
+            Encoding::Hex => write!(f, "hex"),
+        }
+    }
+}
 
-    check_out_branch(branch_name).expect("Unable to switch branches");
+/// The operating systems `platforms` can name, as Rust calls them in `std::env::consts::OS`
+pub const KNOWN_PLATFORMS: &[&str] = &[
+    "linux", "macos", "windows", "ios", "android", "freebsd", "dragonfly", "netbsd", "openbsd",
+    "solaris",
+];
 
-    let latest_hash = get_secrets_current_hash().unwrap();
-    let distance = secrets_repo_distance_between(&configuration.pinned_hash, &latest_hash).unwrap();
+/// The CPU architectures `--target` can name, as Rust calls them in `std::env::consts::ARCH`
+pub const KNOWN_ARCHITECTURES: &[&str] = &[
+    "x86", "x86_64", "arm", "aarch64", "mips", "mips64", "powerpc", "powerpc64", "riscv64",
+    "s390x", "sparc64",
+];
 
-    // Put things back how we found them
-    crate::git::check_out_branch_at_revision(&current_branch, &current_hash)
-        .expect("Unable to roll back to branch");
+/// The operating system named by `target` – `<os>` or `<os>/<arch>` – which `platforms` filters
+/// are evaluated against, or the host's if there's no target
+fn target_platform(target: Option<&str>) -> Result<String, ConfigureError> {
+    let target = match target {
+        Some(target) => target,
+        None => return Ok(std::env::consts::OS.to_string()),
+    };
 
-    distance
+    let (os, arch) = match target.split_once('/') {
+        Some((os, arch)) => (os, Some(arch)),
+        None => (target, None),
+    };
+
+    let is_known = |known: &[&str], name: &str| known.iter().any(|k| k.eq_ignore_ascii_case(name));
+    let arch_is_known = arch.is_none_or(|arch| is_known(KNOWN_ARCHITECTURES, arch));
+    if !is_known(KNOWN_PLATFORMS, os) || !arch_is_known {
+        return Err(ConfigureError::UnknownTarget(target.to_string()));
+    }
+
+    Ok(os.to_ascii_lowercase())
+}
+
+impl File {
+    /// Whether this entry is applied on the operating system `os`
+    pub fn applies_to_platform(&self, os: &str) -> bool {
+        self.platforms.is_empty()
+            || self
+                .platforms
+                .iter()
+                .any(|platform| platform.eq_ignore_ascii_case(os))
+    }
+
+    /// Whether this entry is applied on any operating system at all, i.e. its `platforms` aren't
+    /// all misspelt
+    pub fn applies_to_any_platform(&self) -> bool {
+        KNOWN_PLATFORMS
+            .iter()
+            .any(|platform| self.applies_to_platform(platform))
+    }
+
+    /// Encodes the secret as it's stored in the encrypted file
+    pub fn encode_contents<'a>(&self, contents: &'a [u8]) -> std::borrow::Cow<'a, [u8]> {
+        match self.encoding.is_plain() {
+            true => std::borrow::Cow::Borrowed(contents),
+            false => std::borrow::Cow::Owned(self.encoding.encode(contents)),
+        }
+    }
+
+    /// Decodes the decrypted contents of the encrypted file into the secret itself
+    pub fn decode_contents(&self, decrypted: Vec<u8>) -> Result<Vec<u8>, ConfigureError> {
+        if self.encoding.is_plain() {
+            return Ok(decrypted);
+        }
+
+        self.encoding.decode(&decrypted).map_err(|problem| {
+            ConfigureError::InvalidEncoding {
+                destination: self.destination.clone(),
+                encoding: self.encoding,
+                problem,
+            }
+        })
+    }
+
+    /// Why the destination can't be written to, if it can't. Only `user` scope secrets can go in
+    /// the home directory, so a `~/` destination can't end up there by accident, and they can't
+    /// get out of it.
+    pub fn destination_problem(&self) -> Option<String> {
+        use std::path::Component;
+
+        let destination = match (self.scope, self.home_relative_destination()) {
+            (Scope::Project, _) if self.destination.starts_with('~') => {
+                return Some(
+                    "starts with ~, but only `\"scope\": \"user\"` secrets can go in the home directory"
+                        .to_string(),
+                )
+            }
+            (Scope::Project, _) => return None,
+            (Scope::User, None) => {
+                return Some("has `\"scope\": \"user\"`, so it has to start with ~/".to_string())
+            }
+            (Scope::User, Some(destination)) => destination,
+        };
+
+        let stays_within = std::path::Path::new(destination)
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+
+        match stays_within && !destination.is_empty() {
+            true => None,
+            false => Some("would be written outside the home directory".to_string()),
+        }
+    }
+
+    /// Merges the secret into `existing`, what's at the destination now, if it has a `merge`
+    pub fn merge_contents(
+        &self,
+        existing: Option<&[u8]>,
+        secret: Vec<u8>,
+    ) -> Result<Vec<u8>, ConfigureError> {
+        let (format, existing) = match (self.merge, existing) {
+            (Some(format), Some(existing)) => (format, existing),
+            _ => return Ok(secret),
+        };
+
+        let problem = |problem: &str| ConfigureError::MergeFailed {
+            destination: self.destination.clone(),
+            problem: problem.to_string(),
+        };
+        let existing = std::str::from_utf8(existing).map_err(|_| problem("it isn't text"))?;
+        let secret = std::str::from_utf8(&secret).map_err(|_| problem("the secret isn't text"))?;
+
+        Ok(format.merge(existing, secret).into_bytes())
+    }
+
+    /// Converts the secret into what's written to the destination, if it has a `transform`
+    pub fn transform_contents(&self, secret: Vec<u8>) -> Result<Vec<u8>, ConfigureError> {
+        let transform = match self.transform {
+            Some(transform) => transform,
+            None => return Ok(secret),
+        };
+
+        transform
+            .apply(&secret)
+            .map_err(|problem| ConfigureError::TransformFailed {
+                destination: self.destination.clone(),
+                transform,
+                problem: problem.to_string(),
+            })
+    }
+
+    /// The destination relative to the home directory, for a `user` scope secret
+    fn home_relative_destination(&self) -> Option<&str> {
+        match self.scope {
+            Scope::User => self.destination.strip_prefix("~/"),
+            Scope::Project => None,
+        }
+    }
+
+    /// What this entry's records in `.configure-state` are kept under
+    pub fn state_key(&self) -> String {
+        crate::state::state_key(&self.destination, self.variant.as_deref())
+    }
+
+    pub fn get_encrypted_destination(&self) -> String {
+        match self.home_relative_destination() {
+            Some(destination) => format!("{}/{}.enc", USER_SCOPE_ENCRYPTED_DIRECTORY, destination),
+            None => self.destination.clone() + ".enc",
+        }
+    }
+
+    /// Where the secret is decrypted to, relative to the project root – or, for a `user` scope
+    /// secret, the absolute path in the home directory
+    pub fn get_decrypted_destination(&self) -> Result<String, ConfigureError> {
+        match self.home_relative_destination() {
+            Some(destination) => Ok(home_directory()?
+                .join(destination)
+                .to_string_lossy()
+                .to_string()),
+            None => Ok(self.destination.clone()),
+        }
+    }
+
+    /// Where incoming content is written when the user wants to merge it by hand
+    pub fn get_new_destination(&self) -> Result<String, ConfigureError> {
+        Ok(self.get_decrypted_destination()? + ".new")
+    }
+
+    pub fn get_backup_destination(&self) -> Result<String, ConfigureError> {
+        let decrypted_destination = self.get_decrypted_destination()?;
+        let path = std::path::Path::new(&decrypted_destination);
+
+        let directory = match path.parent() {
+            Some(parent) => parent,
+            None => std::path::Path::new("/"),
+        };
+
+        let file_name = path.file_name().unwrap().to_str().unwrap_or("");
+        let filename = backup_file_name(file_name, &Local::now().naive_local());
+
+        Ok(directory
+            .join(filename)
+            .to_str()
+            .unwrap()
+            .to_string())
+    }
+}
+
+/// How to resolve a destination that was edited locally since the last apply and that
+/// also differs from the incoming secrets
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ConflictResolution {
+    /// Leave the local file untouched
+    KeepLocal,
+
+    /// Back up the local file, then replace it with the incoming content
+    TakeIncoming,
+
+    /// Leave the local file untouched and write the incoming content beside it for manual merging
+    WriteNew,
+}
+
+impl FromStr for ConflictResolution {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keep-local" => Ok(ConflictResolution::KeepLocal),
+            "take-incoming" => Ok(ConflictResolution::TakeIncoming),
+            "write-new" => Ok(ConflictResolution::WriteNew),
+            _ => Err(format!(
+                "Unknown conflict resolution {:?} – expected `keep-local`, `take-incoming`, or `write-new`",
+                s
+            )),
+        }
+    }
+}
+
+/// How to print a summary meant for either people or scripts
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum OutputFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(format!("Unknown output format {:?} – expected `text` or `json`", s)),
+        }
+    }
+}
+
+/// What to do when a destination already exists with different content, and configure has no
+/// record of writing it
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OnConflict {
+    /// Leave the existing file untouched
+    Keep,
+
+    /// Replace the existing file without keeping a copy
+    Overwrite,
+
+    /// Back up the existing file, then replace it
+    BackupOverwrite,
+
+    /// Show how the existing file differs, and ask whether to keep it or back it up and replace
+    /// it. Without anyone to ask, it's backed up and replaced. Either way, the choice is
+    /// remembered, so it's only asked once.
+    #[default]
+    Ask,
+
+    /// Stop without replacing the file
+    Fail,
+}
+
+impl FromStr for OnConflict {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keep" => Ok(OnConflict::Keep),
+            "overwrite" => Ok(OnConflict::Overwrite),
+            "backup-overwrite" => Ok(OnConflict::BackupOverwrite),
+            "fail" => Ok(OnConflict::Fail),
+            "ask" => Ok(OnConflict::Ask),
+            _ => Err(format!(
+                "Unknown conflict policy {:?} – expected `keep`, `overwrite`, `backup-overwrite`, `fail`, or `ask`",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, StructOpt)]
+pub struct KeysExportOptions {
+    /// The project whose key to export, as it appears in `keys.json`
+    pub project: String,
+
+    /// The recipient's public key, from `configure keys create-identity`
+    #[structopt(long)]
+    pub recipient: String,
+
+    /// Where to write the bundle. It's printed if this is omitted.
+    #[structopt(long, parse(from_os_str))]
+    pub output: Option<std::path::PathBuf>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct KeysImportOptions {
+    /// The bundle to import, from `configure keys export`
+    #[structopt(parse(from_os_str))]
+    pub bundle: std::path::PathBuf,
+
+    /// The identity file the bundle was exported for, from `configure keys create-identity`
+    #[structopt(long, parse(from_os_str))]
+    pub identity: std::path::PathBuf,
+
+    /// Replace a different key that's already in `keys.json` for the project without asking
+    #[structopt(long)]
+    pub force: bool,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct KeysCreateIdentityOptions {
+    /// Where to write the identity. Keep it private – anyone with it can open bundles sent to you.
+    #[structopt(long, parse(from_os_str))]
+    pub output: std::path::PathBuf,
+}
+
+#[derive(Debug, Default, StructOpt)]
+pub struct ValidateOptions {
+    /// Also require every entry in `files_to_copy` to have an `owner`, and the pin to be within
+    /// the project's `max_age_days` and `max_commits_behind`
+    #[structopt(long)]
+    pub strict: bool,
+
+    /// First check `.configure` against its published JSON Schema, to catch typos like unknown fields
+    #[structopt(long)]
+    pub schema: bool,
+
+    /// Fetch the latest secrets and check the pinned commit has been pushed to the remote branch,
+    /// so it can be found in a fresh clone. Skipped, with a note, when the fetch fails.
+    #[structopt(long)]
+    pub check_remote: bool,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct ExplainOptions {
+    /// The destination of the file to explain, relative to the project root
+    pub destination: String,
+}
+
+#[derive(Debug, Default, StructOpt)]
+pub struct FilterOptions {
+    /// The destination of the secret being filtered, relative to the project root. Its encrypted
+    /// destination works too.
+    pub destination: String,
+}
+
+#[derive(Debug, Default, StructOpt)]
+pub struct CreateKeyOptions {
+    /// The project to create the key for, as it appears in `keys.json`. Defaults to the
+    /// `project_name` in `.configure`.
+    #[structopt(long)]
+    pub project: Option<String>,
+
+    /// Print the new key instead of storing it in `keys.json`
+    #[structopt(long, conflicts_with = "project")]
+    pub stdout: bool,
+
+    /// Replace the project's existing key. Anything encrypted with the old key can no longer be
+    /// decrypted.
+    #[structopt(long)]
+    pub force: bool,
+
+    /// Commit the change to keys.json in the secrets repository, on its own, so it's easy to
+    /// merge with other new keys
+    #[structopt(long, conflicts_with = "stdout")]
+    pub commit: bool,
+
+    /// Only report whether a key would be created, without writing anything. Exits with 2 if the
+    /// project has no key yet.
+    #[structopt(long, conflicts_with_all = &["stdout", "force", "commit"])]
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Default, StructOpt)]
+pub struct StatusOptions {
+    /// Summarize several projects instead, showing how far each one's pin is behind the latest
+    /// secrets
+    #[structopt(long)]
+    pub fleet: bool,
+
+    /// The projects to summarize with `--fleet`
+    #[structopt(parse(from_os_str), requires = "fleet")]
+    pub projects: Vec<PathBuf>,
+
+    /// A file listing the projects to summarize with `--fleet`, one path per line. Relative paths
+    /// are relative to the file.
+    #[structopt(long, parse(from_os_str), requires = "fleet")]
+    pub manifest: Option<PathBuf>,
+
+    /// Summarize every project under this directory (or the current one) instead, like
+    /// `--fleet` – each directory with a `.configure` in it
+    #[structopt(long, value_name = "root", parse(from_os_str), max_values = 1)]
+    pub all: Option<Vec<PathBuf>>,
+
+    /// How to print the `--fleet` or `--all` summary: `text` or `json`
+    #[structopt(long, default_value = "text")]
+    pub output: OutputFormat,
+
+    /// Fail if the pin is older than the project's `max_age_days`, or further behind the latest
+    /// secrets than its `max_commits_behind`
+    #[structopt(long, conflicts_with_all = &["fleet", "all"])]
+    pub check_freshness: bool,
+}
+
+#[derive(Debug, Default, StructOpt)]
+pub struct DiffOptions {
+    /// Only print the destinations that would change, one per line
+    #[structopt(long)]
+    pub name_only: bool,
+
+    /// Compare the secrets at the pinned hash with the ones at this commit instead, without
+    /// looking at the project's files – to see what updating to it would change
+    #[structopt(long, value_name = "hash")]
+    pub against: Option<String>,
+}
+
+#[derive(Debug, Default, StructOpt)]
+pub struct ReencryptOptions {
+    /// Only re-encrypt the file with this destination, relative to the project root
+    #[structopt(long)]
+    pub only: Option<String>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct ExportBundleOptions {
+    /// Where to write the bundle, a tar archive
+    #[structopt(parse(from_os_str))]
+    pub path: PathBuf,
+
+    /// Re-encrypt the files with the key in this file (from `configure create-key --stdout`), so
+    /// the bundle can be imported without the project's key
+    #[structopt(long, parse(from_os_str))]
+    pub transfer_key: Option<PathBuf>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct ImportBundleOptions {
+    /// The bundle to import, from `configure export-bundle`
+    #[structopt(parse(from_os_str))]
+    pub path: PathBuf,
+
+    /// The file holding the transfer key the bundle was exported with, if it was
+    #[structopt(long, parse(from_os_str))]
+    pub transfer_key: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, StructOpt)]
+pub struct PruneBranchesOptions {
+    /// Delete the branches, rather than just listing what would be deleted
+    #[structopt(long)]
+    pub yes: bool,
+}
+
+#[derive(Debug, Default, StructOpt)]
+pub struct PruneKeysOptions {
+    /// A project that's still active, as it appears in `keys.json`. Can be given more than once
+    #[structopt(long = "project", value_name = "name")]
+    pub projects: Vec<String>,
+
+    /// A file listing the projects that are still active, one name per line
+    #[structopt(long, parse(from_os_str))]
+    pub active_file: Option<PathBuf>,
+
+    /// Remove the keys, rather than just listing them
+    #[structopt(long)]
+    pub delete: bool,
+}
+
+#[derive(Debug, Default, StructOpt)]
+pub struct RepairBackupsOptions {
+    /// Rename the backups, rather than just listing what would be renamed
+    #[structopt(long)]
+    pub yes: bool,
+}
+
+#[derive(Debug, Default, StructOpt)]
+pub struct InitOptions {
+    /// Start with the usual files for a kind of project: `ios`, `android`, or `react-native`
+    #[structopt(long, conflicts_with = "template-file")]
+    pub template: Option<String>,
+
+    /// Start with the files listed in a custom template, in the same format as the built-in ones
+    #[structopt(long, parse(from_os_str))]
+    pub template_file: Option<std::path::PathBuf>,
+
+    /// Don't create a key for the project in `keys.json`, for when keys are provisioned some other
+    /// way. `configure validate` reports the key as missing until it's there.
+    #[structopt(long)]
+    pub no_key: bool,
+}
+
+impl InitOptions {
+    /// The template the user asked for, if any
+    pub fn load_template(&self) -> Result<Option<Template>, ConfigureError> {
+        if let Some(path) = &self.template_file {
+            return read_template_file(path).map(Some);
+        }
+
+        match &self.template {
+            Some(name) => built_in_template(name).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+#[derive(Debug, Default, StructOpt)]
+pub struct ApplyOptions {
+    /// How to resolve files that were edited locally since the last apply and also differ from
+    /// the incoming secrets: `keep-local`, `take-incoming`, or `write-new`.
+    ///
+    /// When omitted, you'll be asked about each conflict. In non-interactive mode the default is `write-new`.
+    #[structopt(long)]
+    pub resolve_conflicts: Option<ConflictResolution>,
+
+    /// What to do when a destination already exists with different content that configure didn't
+    /// write: `keep`, `overwrite`, `backup-overwrite`, `fail`, or `ask`. Overrides `on_conflict`
+    /// in `.configure`. The default is `ask`, which backs the file up and replaces it when there's
+    /// nobody to ask.
+    #[structopt(long)]
+    pub on_conflict: Option<OnConflict>,
+
+    /// Place already-decrypted files from a tar archive instead of decrypting the project's
+    /// `.enc` files. The archive's layout must mirror the destinations in `.configure`.
+    #[structopt(long, parse(from_os_str))]
+    pub from_archive: Option<std::path::PathBuf>,
+
+    /// Write every file to a temporary location first, then move them all into place (making
+    /// backups as needed) in one final pass. This keeps the window where some files are new and
+    /// some are old as small as possible, which helps on slow network filesystems.
+    #[structopt(long)]
+    pub defer_backups: bool,
+
+    /// Don't check decrypted files for signs of corruption, like being empty
+    #[structopt(long)]
+    pub no_sanity_checks: bool,
+
+    /// Refuse to decrypt any encrypted file larger than this many bytes. Overrides
+    /// `max_file_size` in `.configure`. Zero means no limit.
+    #[structopt(long)]
+    pub max_file_size: Option<u64>,
+
+    /// Make the decrypted files read-only once they're written, as if `read_only` were set in `.configure`
+    #[structopt(long)]
+    pub read_only: bool,
+
+    /// If setting a decrypted file's permissions fails, warn and carry on rather than failing the
+    /// apply. For filesystems that don't support permissions, like some network or container mounts.
+    #[structopt(long)]
+    pub ignore_mode_errors: bool,
+
+    /// Evaluate `platforms` for this target, `<os>` or `<os>/<arch>` like `ios/aarch64`, instead of
+    /// the platform configure is running on. For cross-compiling.
+    #[structopt(long)]
+    pub target: Option<String>,
+
+    /// Apply the files for this variant, as well as the files that don't belong to a variant
+    #[structopt(long = "env", conflicts_with = "all-variants")]
+    pub variant: Option<String>,
+
+    /// Apply the files for every variant in a single pass
+    #[structopt(long)]
+    pub all_variants: bool,
+
+    /// Only apply the files whose destinations, relative to the project root, match this. It can
+    /// be a glob, like `config/*.json` or `ios/**`. Can be repeated.
+    #[structopt(long, number_of_values = 1)]
+    pub only: Vec<String>,
+
+    /// Don't apply the files whose destinations match this, even if they match `--only`. It can
+    /// be a glob too. Can be repeated.
+    #[structopt(long, number_of_values = 1)]
+    pub exclude: Vec<String>,
+
+    /// Write a placeholder for each optional secret that isn't available, so builds that expect
+    /// the file still work. Existing files are never replaced with a placeholder.
+    #[structopt(long)]
+    pub placeholder_on_missing: bool,
+
+    /// Only apply the files whose encrypted file changed since this machine last applied it.
+    /// Without a record of a previous apply, every file is applied.
+    #[structopt(long, conflicts_with = "output-root")]
+    pub since_last: bool,
+
+    /// Write a summary of what was applied (the pinned hash and a digest of each decrypted file)
+    /// to this path, relative to the project root. Overrides `summary_file` in `.configure`.
+    #[structopt(long)]
+    pub summary_file: Option<String>,
+
+    /// Don't list each file – just print how many there were of each outcome, along with any
+    /// warnings or errors
+    #[structopt(long)]
+    pub summary_only: bool,
+
+    /// Print the secrets repo, keys file, branch, pinned hash, and project root this apply would use
+    /// (as `CONFIGURE_*=…` lines) and stop, without changing anything
+    #[structopt(long)]
+    pub dump_env: bool,
+
+    /// Add a plain-text report of the apply (the pinned hash, what happened to each file, and
+    /// whether it succeeded) to the end of this file, creating it if needed
+    #[structopt(long, parse(from_os_str))]
+    pub report: Option<std::path::PathBuf>,
+
+    /// Before applying, check each encrypted file is the one expected at the pinned hash – the
+    /// one the last apply at that pin used, and the committed one
+    #[structopt(long)]
+    pub source_hash_check: bool,
+
+    /// If another apply is running in this project, wait up to this many seconds for it to
+    /// finish, rather than failing straight away
+    #[structopt(long, value_name = "secs")]
+    pub retry_on_lock: Option<u64>,
+
+    /// List the decrypted files that were edited or removed since the last apply, and stop
+    /// without changing anything. Fails if there are any.
+    #[structopt(long)]
+    pub report_drift: bool,
+
+    /// Write the decrypted files into this directory instead of the project, for assembling an
+    /// app somewhere else. `.configure` and the encrypted files are still read from the project.
+    /// Existing files there are overwritten without backups, and the apply isn't recorded.
+    #[structopt(long, value_name = "path", parse(from_os_str))]
+    pub output_root: Option<PathBuf>,
+
+    /// Write each decrypted file where its destination resolves to, but inside this directory,
+    /// like `DESTDIR` – so `/etc/app.json` goes to `<dir>/etc/app.json`, and a destination
+    /// relative to the project goes under `<dir>` followed by the project's absolute path. As with
+    /// `--output-root`, files there are overwritten without backups and the apply isn't recorded.
+    #[structopt(long, value_name = "dir", parse(from_os_str), conflicts_with = "output-root")]
+    pub prefix: Option<PathBuf>,
+
+    /// Flush each file to disk once it's in place, along with the directory it's in, so it
+    /// survives a crash or power loss straight afterwards. It's slower, so it's off by default.
+    #[structopt(long)]
+    pub fsync: bool,
+
+    /// List what applying would do to each file, and stop without changing anything
+    #[structopt(long, conflicts_with_all = &["from-archive", "output-root", "prefix"])]
+    pub dry_run: bool,
+
+    /// Like `--dry-run`, but fail if applying would change any file – for checking a checkout's
+    /// secrets are up to date
+    #[structopt(long, conflicts_with_all = &["from-archive", "output-root", "prefix"])]
+    pub check: bool,
+
+    /// How to print the `--dry-run` or `--check` plan: `text` or `json`
+    #[structopt(long, default_value = "text")]
+    pub output: OutputFormat,
+
+    /// Print a JSON array of where each file that would be applied is decrypted from and to, as
+    /// `source_abs` and `destination_abs` absolute paths, and stop without decrypting anything –
+    /// for build systems declaring apply's inputs and outputs
+    #[structopt(long, conflicts_with_all = &["from-archive", "dry-run", "check"])]
+    pub print_json_paths: bool,
+}
+
+#[derive(Debug, Default, StructOpt)]
+pub struct UpdateOptions {
+    /// Write to the project even if it has a rebase, merge, or cherry-pick in progress, or staged
+    /// changes to files configure manages
+    #[structopt(long)]
+    pub force: bool,
+
+    /// Don't check secrets for signs of corruption, like being empty, before encrypting them
+    #[structopt(long)]
+    pub no_sanity_checks: bool,
+
+    /// Write the encrypted files to this directory for review, rather than into the project. Run
+    /// `configure promote` to copy them into the project
+    #[structopt(long, parse(from_os_str), value_name = "dir")]
+    pub stage_dir: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, StructOpt)]
+pub struct RenameOptions {
+    /// The project's new name
+    pub new_name: String,
+
+    /// Change the sources in `.configure` that are in the directory named after the project to
+    /// use the new name's directory
+    #[structopt(long)]
+    pub update_sources: bool,
+
+    /// Rename the project's directory in the secrets repository as well, and commit that there.
+    /// Implies `--update-sources`.
+    #[structopt(long)]
+    pub also_move_secrets_dir: bool,
+}
+
+#[derive(Debug, Default, StructOpt)]
+pub struct AffectedOptions {
+    /// The secrets commit to compare `HEAD` with – usually the one before the rotation
+    #[structopt(long)]
+    pub since: String,
+
+    /// The secrets repository to look at. Defaults to the one the current directory is in.
+    #[structopt(long, parse(from_os_str), value_name = "path")]
+    pub secrets_repo: Option<PathBuf>,
+
+    /// The projects to check
+    #[structopt(parse(from_os_str))]
+    pub projects: Vec<PathBuf>,
+
+    /// A file listing the projects to check, one path per line. Relative paths are relative to
+    /// the file.
+    #[structopt(long, parse(from_os_str))]
+    pub manifest: Option<PathBuf>,
+
+    /// Check every project under this directory (or the current one) – each directory with a
+    /// `.configure` in it
+    #[structopt(long, value_name = "root", parse(from_os_str), max_values = 1)]
+    pub all: Option<Vec<PathBuf>>,
+
+    /// How to print which projects are affected: `text` or `json`
+    #[structopt(long, default_value = "text")]
+    pub output: OutputFormat,
+}
+
+#[derive(Debug, Default, StructOpt)]
+pub struct PromoteOptions {
+    /// The directory passed to `configure update --stage-dir`
+    #[structopt(long, parse(from_os_str), value_name = "dir")]
+    pub stage_dir: PathBuf,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct ImportOptions {
+    /// The plaintext file to import, relative to the project root
+    pub project_path: String,
+
+    /// Where to store the file in the secrets repository, relative to its root
+    #[structopt(long = "as")]
+    pub secrets_path: String,
+
+    /// Replace a different file that's already in the secrets repository at that path
+    #[structopt(long)]
+    pub force: bool,
+}
+
+/// Decides what to do about each three-way conflict during a single apply
+pub struct ConflictResolver {
+    session_choice: Option<ConflictResolution>,
+}
+
+impl ConflictResolver {
+    pub fn new(policy: Option<ConflictResolution>) -> ConflictResolver {
+        let session_choice = match policy {
+            Some(policy) => Some(policy),
+            None if !is_interactive() => Some(ConflictResolution::WriteNew),
+            None => None,
+        };
+
+        ConflictResolver { session_choice }
+    }
+
+    pub fn resolve(&mut self, destination: &str) -> Result<ConflictResolution, ConfigureError> {
+        if let Some(choice) = self.session_choice {
+            return Ok(choice);
+        }
+
+        warn(&format!(
+            "{} has been edited since secrets were last applied, and the incoming secrets are different",
+            destination
+        ));
+
+        let destination = destination.to_string();
+        let choice = match ask(Interaction::ResolveConflict { destination })?.choice()? {
+            0 => ConflictResolution::KeepLocal,
+            1 => ConflictResolution::TakeIncoming,
+            _ => ConflictResolution::WriteNew,
+        };
+
+        if ask(Interaction::ApplyToAllConflicts)?.confirmed()? {
+            self.session_choice = Some(choice);
+        }
+
+        Ok(choice)
+    }
+}
+
+/// Asks what to do about a file at `destination` that configure has no record of writing and
+/// that differs from the incoming secrets – usually one placed by hand before the project used
+/// configure. Returns `Keep` or `BackupOverwrite`, which is also the answer without anyone to ask.
+pub fn ask_about_unrecorded_file(
+    destination: &str,
+    local: &[u8],
+    incoming: &[u8],
+) -> Result<OnConflict, ConfigureError> {
+    if !is_interactive() {
+        return Ok(OnConflict::BackupOverwrite);
+    }
+
+    warn(&format!(
+        "{} already exists, and isn't the same as the incoming secrets",
+        destination
+    ));
+    for line in summarize_difference(local, incoming) {
+        print(&line);
+    }
+
+    let destination = destination.to_string();
+    match ask(Interaction::ResolveUnrecordedFile { destination })?.choice()? {
+        1 => Ok(OnConflict::Keep),
+        _ => Ok(OnConflict::BackupOverwrite),
+    }
+}
+
+/// What happened to a single destination during apply
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum FileOutcome {
+    /// The destination didn't exist and was created
+    Created,
+
+    /// The destination was replaced. If it had content that hadn't been applied by configure,
+    /// a backup was made first
+    Updated { backup: Option<String> },
+
+    /// The destination already had the incoming content
+    Unchanged,
+
+    /// The destination had different content that configure didn't write, and was left alone
+    Kept,
+
+    /// The destination is for an optional secret that isn't available, and was left alone
+    Skipped,
+
+    /// The destination is for an optional secret that isn't available, and a placeholder was written
+    Placeholder,
+
+    /// The destination was edited locally and the incoming secrets differ
+    Conflict {
+        resolution: ConflictResolution,
+        backup: Option<String>,
+    },
+
+    /// Another program had the destination open, so it couldn't be replaced. Holds the program,
+    /// if it could be found out.
+    Locked { holder: Option<String> },
+}
+
+impl FileOutcome {
+    /// What the outcome is counted as in the apply summary
+    fn summary_label(&self) -> &'static str {
+        match self {
+            FileOutcome::Created => "created",
+            FileOutcome::Updated { .. } => "updated",
+            FileOutcome::Unchanged => "unchanged",
+            FileOutcome::Kept => "kept",
+            FileOutcome::Skipped => "skipped",
+            FileOutcome::Placeholder => "placeholder(s)",
+            FileOutcome::Conflict { .. } => "conflict(s)",
+            FileOutcome::Locked { .. } => "locked",
+        }
+    }
+
+    /// A sentence describing what happened to the destination
+    pub fn describe(&self, destination: &str) -> String {
+        match self {
+            FileOutcome::Created => format!("Created {}", destination),
+            FileOutcome::Updated { backup: None } => format!("Updated {}", destination),
+            FileOutcome::Updated {
+                backup: Some(backup),
+            } => format!("Updated {} (previous version backed up to {})", destination, backup),
+            FileOutcome::Unchanged => format!("{} is already up to date", destination),
+            FileOutcome::Skipped => format!("Skipped {}, which isn't available", destination),
+            FileOutcome::Placeholder => {
+                format!("Wrote a placeholder to {}, which isn't available", destination)
+            }
+            FileOutcome::Kept => {
+                format!("Kept {}, which differs from the incoming secrets", destination)
+            }
+            FileOutcome::Conflict {
+                resolution: ConflictResolution::KeepLocal,
+                ..
+            } => format!("Conflict in {}: kept local file", destination),
+            FileOutcome::Conflict {
+                resolution: ConflictResolution::TakeIncoming,
+                backup,
+            } => format!(
+                "Conflict in {}: used incoming file (local file backed up to {})",
+                destination,
+                backup.as_deref().unwrap_or("nowhere")
+            ),
+            FileOutcome::Conflict {
+                resolution: ConflictResolution::WriteNew,
+                ..
+            } => format!(
+                "Conflict in {}: kept local file and wrote incoming file to {}.new",
+                destination, destination
+            ),
+            FileOutcome::Locked { holder } => format!(
+                "Couldn't replace {}, which is in use by {}",
+                destination,
+                holder.as_deref().unwrap_or("another program")
+            ),
+        }
+    }
+
+    /// Whether the outcome is something the user may need to act on
+    fn needs_attention(&self) -> bool {
+        matches!(
+            self,
+            FileOutcome::Kept
+                | FileOutcome::Placeholder
+                | FileOutcome::Conflict { .. }
+                | FileOutcome::Locked { .. }
+        )
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ApplyReport {
+    pub files: Vec<(String, FileOutcome)>,
+
+    /// How many files `--only` and `--exclude` left out of the apply
+    pub filtered_out: usize,
+}
+
+impl ApplyReport {
+    pub fn push(&mut self, destination: &str, outcome: FileOutcome) {
+        self.files.push((destination.to_string(), outcome));
+    }
+
+    /// Prints the outcomes under a heading for each variant, in the order the files are listed
+    pub fn print_by_variant(&self, files: &[File]) {
+        let variant_of = |destination: &str| {
+            files
+                .iter()
+                .find(|file| file.destination == destination)
+                .and_then(|file| file.variant.clone())
+                .unwrap_or_else(|| "All variants".to_string())
+        };
+
+        let mut variants: Vec<String> = Vec::new();
+        for (destination, _) in &self.files {
+            let variant = variant_of(destination);
+            if !variants.contains(&variant) {
+                variants.push(variant);
+            }
+        }
+
+        for variant in variants {
+            heading(&variant);
+            ApplyReport {
+                files: self
+                    .files
+                    .iter()
+                    .filter(|(destination, _)| variant_of(destination) == variant)
+                    .cloned()
+                    .collect(),
+                ..Default::default()
+            }
+            .print();
+        }
+    }
+
+    /// A line counting the outcomes, like `Applied 4 file(s): 1 created, 3 unchanged`
+    pub fn summary(&self) -> String {
+        let mut counts: Vec<(&str, usize)> = Vec::new();
+
+        for (_, outcome) in &self.files {
+            let label = outcome.summary_label();
+            match counts.iter_mut().find(|(existing, _)| *existing == label) {
+                Some((_, count)) => *count += 1,
+                None => counts.push((label, 1)),
+            }
+        }
+
+        let counts: Vec<String> = counts
+            .iter()
+            .map(|(label, count)| format!("{} {}", count, label))
+            .collect();
+
+        let summary = format!("Applied {} file(s): {}", self.files.len(), counts.join(", "));
+        match self.filtered_out {
+            0 => summary,
+            filtered_out => format!(
+                "{} – {} other file(s) left out by --only or --exclude",
+                summary, filtered_out
+            ),
+        }
+    }
+
+    pub fn print(&self) {
+        self.print_outcomes(false)
+    }
+
+    /// The destinations that other programs had open, so they weren't replaced
+    pub fn locked_destinations(&self) -> Vec<String> {
+        self.files
+            .iter()
+            .filter(|(_, outcome)| matches!(outcome, FileOutcome::Locked { .. }))
+            .map(|(destination, _)| destination.clone())
+            .collect()
+    }
+
+    /// Fails with `FilesLocked` if other programs had any of the destinations open
+    pub fn ensure_nothing_locked(&self) -> Result<(), ConfigureError> {
+        let locked = self.locked_destinations();
+        match locked.is_empty() {
+            true => Ok(()),
+            false => Err(ConfigureError::FilesLocked(locked.join(", "))),
+        }
+    }
+
+    /// Prints only the outcomes the user may need to act on, like conflicts
+    pub fn print_warnings(&self) {
+        self.print_outcomes(true)
+    }
+
+    fn print_outcomes(&self, warnings_only: bool) {
+        for (destination, outcome) in &self.files {
+            if warnings_only && !outcome.needs_attention() {
+                continue;
+            }
+
+            let description = outcome.describe(destination);
+
+            match outcome {
+                FileOutcome::Unchanged => debug!("{}", description),
+                _ if outcome.needs_attention() => warn(&description),
+                _ => info!("{}", description),
+            }
+        }
+    }
+}
+
+/// Narrows the configuration down to the files for the operating system `os`
+fn select_platform_files(mut configuration: ConfigurationFile, os: &str) -> ConfigurationFile {
+    let count = configuration.files_to_copy.len();
+
+    // Each file skipped is only listed with --verbose, since there can be hundreds of them
+    configuration.files_to_copy.retain(|file| {
+        let applies = file.applies_to_platform(os);
+
+        if !applies {
+            debug!(
+                "Skipping {}, which is only for {}",
+                file.destination,
+                file.platforms.join(", ")
+            );
+        }
+
+        applies
+    });
+
+    let skipped = count - configuration.files_to_copy.len();
+    if skipped > 0 {
+        info!("Skipping {} file(s) for other platforms", skipped);
+    }
+
+    configuration
+}
+
+/// Narrows the configuration down to the files matching `--only` (if any), then takes out those
+/// matching `--exclude`. A pattern that matches nothing is an error for `--only`, but only a
+/// warning for `--exclude`, since leaving out a file that isn't there does no harm.
+fn select_requested_files(
+    configuration: ConfigurationFile,
+    options: &ApplyOptions,
+) -> Result<ConfigurationFile, ConfigureError> {
+    check_requested_files(&configuration, options)?;
+    Ok(retain_requested_files(configuration, options))
+}
+
+/// Makes sure every `--only` pattern matches one of the configuration's destinations, so a typo
+/// doesn't quietly apply nothing
+fn check_requested_files(
+    configuration: &ConfigurationFile,
+    options: &ApplyOptions,
+) -> Result<(), ConfigureError> {
+    let is_known = |pattern: &String| {
+        configuration
+            .files_to_copy
+            .iter()
+            .any(|file| destination_matches(pattern, &file.destination))
+    };
+
+    if let Some(unknown) = options.only.iter().find(|pattern| !is_known(pattern)) {
+        return Err(ConfigureError::UnknownDestination(unknown.clone()));
+    }
+
+    for unknown in options.exclude.iter().filter(|pattern| !is_known(pattern)) {
+        warn(&format!("Not excluding {}, which isn't in .configure", unknown));
+    }
+
+    Ok(())
+}
+
+fn retain_requested_files(
+    mut configuration: ConfigurationFile,
+    options: &ApplyOptions,
+) -> ConfigurationFile {
+    let matches_any = |patterns: &[String], destination: &str| {
+        patterns
+            .iter()
+            .any(|pattern| destination_matches(pattern, destination))
+    };
+
+    configuration.files_to_copy.retain(|file| {
+        (options.only.is_empty() || matches_any(&options.only, &file.destination))
+            && !matches_any(&options.exclude, &file.destination)
+    });
+
+    configuration
+}
+
+/// Whether `destination` matches `pattern`, a glob in which `*` matches anything but `/`, `**`
+/// matches anything at all, and `?` matches one character other than `/`
+fn destination_matches(pattern: &str, destination: &str) -> bool {
+    fn matches(pattern: &[char], text: &[char]) -> bool {
+        match pattern {
+            [] => text.is_empty(),
+            ['*', '*', rest @ ..] => {
+                // `**/` can also match no directories at all
+                let rest_without_slash = rest.strip_prefix(&['/']).unwrap_or(rest);
+                matches(rest_without_slash, text)
+                    || (0..=text.len()).any(|skip| matches(rest, &text[skip..]))
+            }
+            ['*', rest @ ..] => (0..=text.len())
+                .take_while(|&skip| !text[..skip].contains(&'/'))
+                .any(|skip| matches(rest, &text[skip..])),
+            ['?', rest @ ..] => {
+                matches!(text.first(), Some(c) if *c != '/') && matches(rest, &text[1..])
+            }
+            [c, rest @ ..] => text.first() == Some(c) && matches(rest, &text[1..]),
+        }
+    }
+
+    let pattern: Vec<char> = pattern.chars().collect();
+    let destination: Vec<char> = destination.chars().collect();
+    matches(&pattern, &destination)
+}
+
+/// Narrows the configuration down to the files whose encrypted file isn't the one
+/// `.configure-state` says was last applied, along with any that have never been applied. If
+/// nothing was ever applied, every file is kept.
+fn select_files_changed_since_last_apply(
+    mut configuration: ConfigurationFile,
+    project_root: &Path,
+) -> ConfigurationFile {
+    let state = read_apply_state(project_root);
+    if state.files.is_empty() {
+        info!("There's no record of a previous apply, so every file will be applied");
+        return configuration;
+    }
+
+    configuration.files_to_copy.retain(|file| {
+        let applied_blob = state
+            .files
+            .get(&file.state_key())
+            .and_then(|applied| applied.encrypted_blob.as_deref());
+        let current_blob = blob_hash_of_file(&project_root.join(file.get_encrypted_destination()));
+
+        match (applied_blob, current_blob) {
+            (Some(applied_blob), Ok(current_blob)) => applied_blob != current_blob,
+            _ => true,
+        }
+    });
+
+    if configuration.files_to_copy.is_empty() {
+        info!("No encrypted file has changed since the last apply");
+    }
+
+    configuration
+}
+
+/// Narrows the configuration down to the files for the variant being applied. Files that don't
+/// belong to a variant are always included.
+fn select_variant_files(
+    mut configuration: ConfigurationFile,
+    options: &ApplyOptions,
+) -> Result<ConfigurationFile, ConfigureError> {
+    if options.all_variants {
+        // Every variant is written in the same pass, so they can't share a destination
+        for (index, file) in configuration.files_to_copy.iter().enumerate() {
+            let sharing: Vec<&File> = configuration.files_to_copy[index..]
+                .iter()
+                .filter(|other| other.destination == file.destination)
+                .collect();
+
+            if sharing.len() > 1 {
+                return Err(ConfigureError::VariantsShareDestination {
+                    destination: file.destination.clone(),
+                    variants: sharing
+                        .iter()
+                        .map(|file| file.variant.as_deref().unwrap_or("(none)"))
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                });
+            }
+        }
+
+        return Ok(configuration);
+    }
+
+    if let Some(variant) = &options.variant {
+        if !configuration
+            .files_to_copy
+            .iter()
+            .any(|file| file.variant.as_ref() == Some(variant))
+        {
+            return Err(ConfigureError::UnknownVariant(variant.clone()));
+        }
+    }
+
+    configuration
+        .files_to_copy
+        .retain(|file| file.variant.is_none() || file.variant == options.variant);
+
+    Ok(configuration)
+}
+
+/// Prints what `apply` would resolve each of its inputs to, as `CONFIGURE_*=…` lines that can be
+/// sourced into a shell. Anything that can't be resolved is left empty. Nothing is created –
+/// not even a missing `.configure` or `keys.json`.
+pub fn dump_apply_environment(options: &ApplyOptions) -> Result<(), ConfigureError> {
+    let project_root = find_project_root().ok();
+    let secrets_repo = find_secrets_repo().ok();
+    let configuration = read_configuration_if_present()?.unwrap_or_default();
+
+    let display =
+        |path: Option<PathBuf>| path.map(|path| path.display().to_string()).unwrap_or_default();
+
+    let environment = [
+        ("CONFIGURE_PROJECT_ROOT", display(project_root.clone())),
+        ("CONFIGURE_FILE", display(project_root.map(|root| root.join(".configure")))),
+        ("CONFIGURE_SECRETS_REPO", display(secrets_repo.clone())),
+        ("CONFIGURE_KEYS_FILE", display(secrets_repo.map(|repo| repo.join("keys.json")))),
+        ("CONFIGURE_PROJECT_NAME", configuration.project_name),
+        ("CONFIGURE_BRANCH", configuration.branch),
+        ("CONFIGURE_PINNED_HASH", configuration.pinned_hash),
+        ("CONFIGURE_VARIANT", options.variant.clone().unwrap_or_default()),
+    ];
+
+    for (name, value) in &environment {
+        print(&environment_line(name, value));
+    }
+
+    Ok(())
+}
+
+/// A `NAME=value` line, with the value quoted for the shell if it needs to be
+fn environment_line(name: &str, value: &str) -> String {
+    let is_plain = |c: char| c.is_ascii_alphanumeric() || "/._-+:@%,".contains(c);
+
+    if value.chars().all(is_plain) {
+        format!("{}={}", name, value)
+    } else {
+        format!("{}='{}'", name, value.replace('\'', "'\\''"))
+    }
+}
+
+/// Fails with `DestinationNotAllowed` if any destination is somewhere configure mustn't write to
+fn check_destinations_allowed(configuration: &ConfigurationFile) -> Result<(), ConfigureError> {
+    for file in &configuration.files_to_copy {
+        if let Some(problem) = file.destination_problem() {
+            return Err(ConfigureError::DestinationNotAllowed(
+                file.destination.clone(),
+                problem,
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+pub fn apply_configuration(
+    configuration: ConfigurationFile,
+    options: &ApplyOptions,
+) -> Result<(), ConfigureError> {
+    configuration.completeness().ensure_complete()?;
+
+    if configuration.require_signed_updates {
+        verify_configuration_signature(&configuration, &find_project_root()?)?;
+    }
+
+    warn_if_key_is_old(&configuration);
+    check_destinations_allowed(&configuration)?;
+
+    // `--only` is checked against every file, so naming one for another platform isn't a typo
+    check_requested_files(&configuration, options)?;
+    let os = target_platform(options.target.as_deref())?;
+    let configuration = select_platform_files(configuration, &os);
+    let configuration = select_variant_files(configuration, options)?;
+
+    // The summary describes every file this apply is for, not just the ones it wrote
+    let selected_configuration = configuration.clone();
+    let configuration = retain_requested_files(configuration, options);
+    let filtered_out =
+        selected_configuration.files_to_copy.len() - configuration.files_to_copy.len();
+
+    // Held until the apply is done, so jobs sharing a checkout take turns
+    let _lock = lock_project_for_apply(&find_project_root()?, options.retry_on_lock)?;
+
+    let configuration = if options.since_last {
+        select_files_changed_since_last_apply(configuration, &find_project_root()?)
+    } else {
+        configuration
+    };
+
+    if options.print_json_paths {
+        let paths = resolve_apply_paths(&configuration, options, &find_project_root()?)?;
+        print(&serde_json::to_string_pretty(&paths).unwrap());
+        return Ok(());
+    }
+
+    if options.source_hash_check {
+        let mismatches = find_source_hash_mismatches(&configuration, &find_project_root()?);
+        if !mismatches.is_empty() {
+            heading("These encrypted files didn't come from `configure update`");
+            print_findings(&mismatches);
+            return Err(ConfigureError::SourceHashMismatch(mismatches.len()));
+        }
+
+        info!("Every encrypted file is the one expected at the pinned hash");
+    }
+
+    if options.dry_run || options.check {
+        let plan = plan_for_configuration(&configuration)?;
+        match options.output {
+            OutputFormat::Text => plan.describe().iter().for_each(|line| print(line)),
+            OutputFormat::Json => print(&serde_json::to_string_pretty(&plan).unwrap()),
+        }
+
+        if options.check && plan.has_changes() {
+            return Err(ConfigureError::FilesDiffer(plan.changes().count()));
+        }
+
+        return Ok(());
+    }
+
+    // Decrypt the project's configuration files
+    let result = match &options.from_archive {
+        Some(archive_path) => {
+            extract_archive_for_configuration(&configuration, options, archive_path)
+        }
+        None => decrypt_files_for_configuration(&configuration, options),
+    };
+    let result = result.map(|report| ApplyReport {
+        filtered_out,
+        ..report
+    });
+
+    // The report is a record of the apply, so failing to write it doesn't change its outcome
+    if let Some(report_path) = &options.report {
+        if let Err(err) = append_report(report_path, &configuration, result.as_ref()) {
+            warn(&format!("Unable to write the report to {:?}: {}", report_path, err));
+        }
+    }
+
+    let report = result?;
+
+    debug!("All Files Copied!");
+
+    let project_root = find_project_root()?;
+    let destination_root = options
+        .output_root
+        .as_deref()
+        .or(options.prefix.as_deref())
+        .unwrap_or(&project_root);
+
+    // Under a prefix, each file is somewhere inside it that depends on its destination
+    let placed = |configuration: &ConfigurationFile| match options.prefix {
+        Some(_) => prefixed_configuration(configuration, &project_root),
+        None => Ok(configuration.clone()),
+    };
+
+    if options.read_only || configuration.read_only {
+        set_files_read_only(
+            &placed(&configuration)?,
+            destination_root,
+            true,
+            options.ignore_mode_errors,
+        )?;
+    }
+
+    let summary_file = options
+        .summary_file
+        .as_ref()
+        .or(configuration.summary_file.as_ref());
+
+    if let Some(summary_file) = summary_file {
+        let summary = build_summary(&placed(&selected_configuration)?, destination_root)?;
+        write_summary(&project_root.join(summary_file), &summary)?;
+    }
+
+    if options.summary_only {
+        report.print_warnings();
+    } else if options.all_variants {
+        report.print_by_variant(&configuration.files_to_copy);
+    } else {
+        report.print();
+    }
+
+    info!("{}", report.summary());
+
+    report.ensure_nothing_locked()?;
+
+    info!("Done");
+
+    Ok(())
+}
+
+pub fn update_configuration(
+    mut configuration: ConfigurationFile,
+    options: &UpdateOptions,
+) -> Result<(), ConfigureError> {
+    configuration.ensure_initialized()?;
+
+    // Updating needs somewhere to get secrets from, so say so before anything else fails
+    let remote = resolve_secrets_remote(&configuration.branch, &configuration.remotes)?.remote;
+    ensure_secrets_remote(&find_secrets_repo()?, &remote)?;
+
+    configuration.completeness().ensure_complete()?;
+    check_destinations_allowed(&configuration)?;
+    heading("Configure Update");
+
+    //
+    // Step 0 – Make sure we won't mix our changes into something the developer is in the
+    //          middle of in the project repo
+    //
+    if !options.force && !check_project_repo_is_safe_to_write(&configuration)? {
+        return Ok(());
+    }
+
+    // A read-only secrets checkout is never fetched or moved – secrets are read from its history
+    let read_only = secrets_read_only();
+
+    //
+    // Step 1 – Fetch the latest secrets from the server
+    //          We need them in order to update the pinned hash
+    //
+    if read_only {
+        warn("The secrets repository is read-only, so the latest secrets weren't fetched");
+    } else {
+        fetch_latest_secrets_with_progress(&configuration)?;
+    }
+
+    //
+    // Step 2 – Check if the user wants to use a different secrets branch
+    //
+    let previous_branch = configuration.branch.clone();
+    configuration = prompt_for_branch(configuration, true)?;
+    validate_branch(&configuration.branch)?;
+    let branch_changed = configuration.branch != previous_branch;
+
+    //
+    // Step 3 – Check if the currente configuration branch is in sync with the server or not.or
+    // If not, check with the user whether they'd like to continue
+    //
+    let status = get_secrets_repo_status(&configuration.branch)?;
+
+    let should_continue = match status.sync_state {
+        RepoSyncState::Ahead => {
+            warn(&format!(
+                "Your local secrets repo has {} change(s) that the server does not",
+                status.ahead
+            ));
+            let distance = status.ahead;
+            ask(Interaction::ConfirmContinueAhead { distance })?.confirmed()?
+        }
+        RepoSyncState::Behind => {
+            warn(&format!(
+                "The server has {} change(s) that your local secrets repo does not",
+                status.behind
+            ));
+            let distance = status.behind;
+            ask(Interaction::ConfirmContinueBehind { distance })?.confirmed()?
+        }
+        RepoSyncState::Diverged { ahead, behind } => {
+            warn(&format!(
+                "Your local secrets repo has diverged from the server: it has {} change(s) that the server does not, and the server has {} that it does not. Pull or rebase it to bring them together",
+                ahead, behind
+            ));
+            ask(Interaction::ConfirmContinueDiverged { ahead, behind })?.confirmed()?
+        }
+        RepoSyncState::Synced => true,
+    };
+
+    if !should_continue {
+        return Ok(());
+    }
+
+    //
+    // Step 4 – Choose the secrets commit to pin.
+    //          If the project's secrets are out of date compared to the server, we'll prompt the
+    //          user to use the latest ones. If they switched branches, the old pin may not even be
+    //          on the new branch, so they have to choose a commit on it before anything is saved.
+    //
+    let latest_commit_hash = get_latest_hash_for_remote_branch(&configuration.branch)?;
+
+    let use_latest = !branch_changed
+        && match configure_file_distance_behind_secrets_repo(&configuration, &configuration.branch)? {
+            CommitDistance::Same => false,
+            CommitDistance::BehindBy(distance) => ask(Interaction::ConfirmUseLatest {
+                distance,
+                latest: latest_commit_hash.clone(),
+                subject: current_commit_subject(&latest_commit_hash),
+            })?
+            .confirmed()?,
+            CommitDistance::AheadBy(distance) => {
+                warn(&format!(
+                    "This project is pinned {} commit(s) past the tip of {} in your secrets repo",
+                    distance, configuration.branch
+                ));
+                false
+            }
+            CommitDistance::Unrelated => {
+                warn(&format!(
+                    "This project's pinned secrets aren't part of the history of {}",
+                    configuration.branch
+                ));
+                false
+            }
+        };
+
+    let pinned_hash = choose_pinned_hash(
+        &configuration.pinned_hash,
+        branch_changed,
+        use_latest,
+        &latest_commit_hash,
+        || prompt_for_pin(&configuration.branch, &latest_commit_hash),
+    )?;
+    configuration.pinned_hash = resolve_commit_on_branch(&pinned_hash, &configuration.branch)?;
+
+    //
+    // Step 5 – Write out encrypted files as needed
+    //
+    save_configuration(&configuration)?;
+
+    if configuration.require_signed_updates {
+        sign_configuration(&configuration, &find_project_root()?)?;
+        info!("Signed .configure");
+    }
+
+    //
+    // Step 6 – Write out encrypted files as needed
+    //          They're read from the secrets repo's history at the pin, so its checkout is never
+    //          moved and there's nothing to roll back afterwards
+    //
+    check_encrypted_file_attributes(&configuration)?;
+    write_encrypted_files_for_configuration(
+        &configuration,
+        !options.no_sanity_checks,
+        options.stage_dir.as_deref(),
+    )?;
+
+    //
+    // Step 7 – Apply these changes to the current repo, unless they're waiting for review
+    //
+    // Applying warns about an old key itself, so it's only done here when nothing is applied
+    if let Some(stage_dir) = &options.stage_dir {
+        warn_if_key_is_old(&configuration);
+        return report_staged_files(stage_dir);
+    }
+
+    apply_configuration(configuration, &ApplyOptions::default())
+}
+
+/// Lists what `configure update --stage-dir` staged, and what to do next
+fn report_staged_files(stage_dir: &Path) -> Result<(), ConfigureError> {
+    let manifest = read_stage_manifest(stage_dir)?;
+
+    heading("Staged Encrypted Files");
+    for entry in &manifest.files {
+        let change = match (&entry.old_digest, entry.changed) {
+            (_, false) => "unchanged",
+            (None, true) => "new",
+            (Some(_), true) => "changed",
+        };
+        print(&format!("{} ({})", entry.path, change));
+    }
+
+    info!(
+        "Review the files in {:?}, then run `configure promote --stage-dir {}` and `configure apply`",
+        stage_dir,
+        stage_dir.display()
+    );
+
+    Ok(())
+}
+
+/// Copies the encrypted files staged by `configure update --stage-dir` into the project, after
+/// checking that neither they nor the project's copies have changed since
+pub fn promote_configuration(
+    configuration: ConfigurationFile,
+    options: &PromoteOptions,
+) -> Result<(), ConfigureError> {
+    let project_root = find_project_root()?;
+    let allowed: HashSet<String> = configuration
+        .files_to_copy
+        .iter()
+        .map(|file| file.get_encrypted_destination())
+        .collect();
+    let promoted = promote_stage(&options.stage_dir, &project_root, &allowed)?;
+
+    if promoted.is_empty() {
+        info!("None of the staged files change the project");
+    }
+
+    for path in &promoted {
+        info!("Promoted {}", path);
+    }
+
+    Ok(())
+}
+
+/// Fetches the latest secrets, showing git's progress
+fn fetch_latest_secrets_with_progress(
+    configuration: &ConfigurationFile,
+) -> Result<(), ConfigureError> {
+    let bar = ProgressBar::new_spinner();
+    bar.enable_steady_tick(125);
+    bar.set_message("Fetching Latest Secrets");
+    set_progress_bar(Some(bar.clone()));
+
+    // Once git reports progress, switch from the spinner to a bar for the current phase
+    let mut showing_progress = false;
+    let branch = &configuration.branch;
+    let fetched = fetch_secrets_latest_remote_data(branch, &configuration.remotes, |progress| {
+        if !showing_progress {
+            bar.set_style(
+                ProgressStyle::default_bar().template("{spinner} {msg} [{bar:30}] {pos}/{len}"),
+            );
+            showing_progress = true;
+        }
+
+        bar.set_length(progress.total);
+        bar.set_position(progress.current);
+        bar.set_message(&match &progress.transferred {
+            Some(transferred) => format!("{} ({})", progress.phase, transferred),
+            None => progress.phase.clone(),
+        });
+    });
+
+    bar.finish_and_clear();
+    set_progress_bar(None);
+    fetched
+}
+
+/// The encrypted files that git would change on checkout because of the project's
+/// `.gitattributes`. Projects that aren't in a git repository don't have any.
+fn find_encrypted_file_attribute_problems<'a>(
+    configuration: &'a ConfigurationFile,
+    project_root: &Path,
+) -> Result<Vec<(&'a File, AttributeProblem)>, ConfigureError> {
+    let repo = match git2::Repository::discover(project_root) {
+        Ok(repo) => repo,
+        Err(_) => return Ok(Vec::new()),
+    };
+
+    // Attributes are looked up by path from the repository root, which needn't be the project root
+    let workdir = repo.workdir().map(Path::to_path_buf).unwrap_or_default();
+    let mut problems = Vec::new();
+
+    for file in &configuration.files_to_copy {
+        let path = project_root.join(file.get_encrypted_destination());
+        let path = path.strip_prefix(&workdir).unwrap_or(&path).to_string_lossy().to_string();
+
+        for problem in find_attribute_problems(&repo, &[path])? {
+            problems.push((file, problem));
+        }
+    }
+
+    Ok(problems)
+}
+
+/// Warns loudly if git would change any of the encrypted files on checkout, making them
+/// undecryptable for everyone else, and offers to add `.gitattributes` rules that prevent it
+fn check_encrypted_file_attributes(configuration: &ConfigurationFile) -> Result<(), ConfigureError> {
+    let project_root = find_project_root()?;
+    let problems = find_encrypted_file_attribute_problems(configuration, &project_root)?;
+
+    if problems.is_empty() {
+        return Ok(());
+    }
+
+    for (file, problem) in &problems {
+        warn(&format!(
+            "Warning: {} {}",
+            file.get_encrypted_destination(),
+            problem.problem
+        ));
+    }
+
+    warn(&format!(
+        "Nobody else will be able to decrypt files git changes on checkout. Add `{}` to .gitattributes to leave them alone",
+        SUGGESTED_ATTRIBUTES
+    ));
+
+    if is_interactive() && ask(Interaction::AddGitattributes)?.confirmed()? {
+        add_suggested_attributes(&project_root)?;
+        info!("Added `{}` to .gitattributes", SUGGESTED_ATTRIBUTES);
+    }
+
+    Ok(())
+}
+
+/// Checks that the project repo isn't partway through a rebase/merge/cherry-pick, and that none of
+/// the files update writes have staged changes.
+///
+/// If there's a problem, interactive users are asked whether to continue (returning their answer),
+/// and non-interactive runs fail.
+fn check_project_repo_is_safe_to_write(
+    configuration: &ConfigurationFile,
+) -> Result<bool, ConfigureError> {
+    let mut paths = vec![".configure".to_string()];
+    paths.extend(
+        configuration
+            .files_to_copy
+            .iter()
+            .map(|file| file.get_encrypted_destination()),
+    );
+
+    // If the project isn't a git repo, there's nothing to check
+    let status = match get_project_repo_status(&paths) {
+        Some(status) => status,
+        None => return Ok(true),
+    };
+
+    if status.is_clean() {
+        return Ok(true);
+    }
+
+    let mut problems = Vec::new();
+
+    if let Some(operation) = &status.operation_in_progress {
+        problems.push(format!("the project has a {} in progress", operation));
+    }
+
+    if !status.staged_paths.is_empty() {
+        problems.push(format!(
+            "the project has staged changes to {}",
+            status.staged_paths.join(", ")
+        ));
+    }
+
+    let problem = problems.join(", and ");
+
+    if !is_interactive() {
+        return Err(ConfigureError::ProjectRepoBusy(problem));
+    }
+
+    warn(&format!("Warning: {}", problem));
+    ask(Interaction::ContinueBusyProject { problem })?.confirmed()
+}
+
+pub fn import_configuration(
+    mut configuration: ConfigurationFile,
+    options: &ImportOptions,
+) -> Result<(), ConfigureError> {
+    configuration.ensure_initialized()?;
+
+    let file = File {
+        source: options.secrets_path.clone(),
+        destination: options.project_path.clone(),
+        ..Default::default()
+    };
+
+    import_file_into_secrets(&configuration, &file, options.force)?;
+
+    // Replace any existing entry for this destination, so importing again doesn't duplicate it
+    configuration
+        .files_to_copy
+        .retain(|existing| existing.destination != file.destination);
+    configuration.files_to_copy.push(file);
+
+    save_configuration(&configuration)?;
+
+    info!(
+        "Imported {} into the secrets repository as {}",
+        options.project_path, options.secrets_path
+    );
+    newline();
+    print("To finish up:");
+    print(&format!(
+        "  1. Commit {} in the secrets repository and push it",
+        options.secrets_path
+    ));
+    print("  2. Run `configure update` to pin the project to that commit");
+    print(&format!(
+        "  3. Commit `.configure` and {}.enc in this project",
+        options.project_path
+    ));
+
+    Ok(())
+}
+
+static PROJECT_KEY_MISMATCH_REPORTED: AtomicBool = AtomicBool::new(false);
+static UNUSUAL_PROJECT_NAME_REPORTED: AtomicBool = AtomicBool::new(false);
+
+/// Warns that `project_name` doesn't follow the rules for project names, and suggests the
+/// sanitized form. It keeps working, so this is only a warning. Only happens once per run.
+pub fn report_unusual_project_name(project_name: &str, problem: &str) {
+    if UNUSUAL_PROJECT_NAME_REPORTED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+
+    warn(&format!(
+        "`project_name` {:?} {}. It still works, but consider renaming it (and its entry in keys.json) to {:?}",
+        project_name,
+        problem,
+        sanitized_key_name(project_name)
+    ));
+}
+
+/// Tells the user that `project_name` only matched an entry in `keys.json` when ignoring case and
+/// separators, and offers to rename one of them so they match exactly. Only happens once per run.
+pub fn report_project_key_mismatch(project_name: &str, key_name: &str) -> Result<(), ConfigureError> {
+    if PROJECT_KEY_MISMATCH_REPORTED.swap(true, Ordering::SeqCst) {
+        return Ok(());
+    }
+
+    warn(&format!(
+        "keys.json has no entry for {:?}, so using the one for {:?}. Rename one of them so they match exactly",
+        project_name, key_name
+    ));
+
+    if !is_interactive() {
+        return Ok(());
+    }
+
+    let choice = ask(Interaction::FixProjectKeyMismatch {
+        project_name: project_name.to_string(),
+        key_name: key_name.to_string(),
+    })?
+    .choice()?;
+
+    let result = match choice {
+        0 => read_configuration().and_then(|mut configuration| {
+            configuration.project_name = key_name.to_string();
+            save_configuration(&configuration)
+        }),
+        1 => rename_project_key(key_name, project_name),
+        _ => Ok(()),
+    };
+
+    if let Err(err) = result {
+        warn(&format!("Unable to rename it: {}", err));
+    }
+
+    Ok(())
+}
+
+/// Seals a project's key from `keys.json` for a recipient
+pub fn export_project_key(options: &KeysExportOptions) -> Result<(), ConfigureError> {
+    let keys = read_keys()?;
+    let key = keys
+        .get(&options.project)
+        .and_then(|key| key.as_str())
+        .ok_or(ConfigureError::MissingProjectKey)?;
+
+    let bundle = create_bundle(&options.project, key, &options.recipient)?;
+    let serialized = serde_json::to_string_pretty(&bundle).unwrap();
+
+    match &options.output {
+        Some(path) => {
+            std::fs::write(path, serialized)?;
+            info!(
+                "Wrote the key for {} (fingerprint {}) to {:?}",
+                bundle.project, bundle.fingerprint, path
+            );
+        }
+        None => print(&serialized),
+    }
+
+    Ok(())
+}
+
+/// Opens a bundle with the recipient's identity and installs the key into `keys.json`
+pub fn import_project_key(options: &KeysImportOptions) -> Result<(), ConfigureError> {
+    let invalid = |message: String| ConfigureError::KeyBundleInvalid(message);
+    let read_json = |path: &std::path::Path| {
+        std::fs::read_to_string(path)
+            .map_err(|err| invalid(format!("unable to read {:?}: {}", path, err)))
+    };
+
+    let bundle: KeyBundle = serde_json::from_str(&read_json(&options.bundle)?)
+        .map_err(|err| invalid(format!("it isn't a key bundle: {}", err)))?;
+    let identity: Identity = serde_json::from_str(&read_json(&options.identity)?)
+        .map_err(|err| invalid(format!("the identity is invalid: {}", err)))?;
+
+    let key = open_bundle(&bundle, &identity)?;
+
+    info!(
+        "The bundle has the key for {} with fingerprint {}",
+        bundle.project, bundle.fingerprint
+    );
+
+    let existing = read_keys()?
+        .get(&bundle.project)
+        .and_then(|key| key.as_str())
+        .map(String::from);
+
+    if let Some(existing) = &existing {
+        if *existing == key {
+            info!("keys.json already has this key for {}", bundle.project);
+            return Ok(());
+        }
+
+        warn(&format!(
+            "keys.json already has a different key for {} (fingerprint {})",
+            bundle.project,
+            key_fingerprint(existing)
+        ));
+
+        let confirmed = options.force
+            || (is_interactive()
+                && ask(Interaction::ReplaceKey {
+                    project: bundle.project.clone(),
+                })?
+                .confirmed()?);
+
+        if !confirmed {
+            return Err(ConfigureError::KeyWouldOverwrite(bundle.project));
+        }
+    }
+
+    save_project_key(&bundle.project, &key, existing.as_deref())?;
+
+    // The key wasn't created here, so there's no telling how old it is
+    let metadata_path = find_key_metadata_file()?;
+    if metadata_path.exists() {
+        update_key_metadata(&metadata_path, |metadata| {
+            metadata.remove(&bundle.project);
+        })?;
+    }
+    info!("Installed the key for {} into keys.json", bundle.project);
+
+    Ok(())
+}
+
+/// Creates a key pair for receiving bundles, and prints the public key to share with the sender
+pub fn create_recipient_identity(options: &KeysCreateIdentityOptions) -> Result<(), ConfigureError> {
+    let identity = Identity::generate();
+    write_private_file(
+        &options.output,
+        &serde_json::to_string_pretty(&identity).unwrap(),
+    )?;
+
+    info!("Wrote your identity to {:?} – keep it private", options.output);
+    print(&identity.public_key);
+
+    Ok(())
+}
+
+/// Removes what configure keeps about this checkout of the project. Secrets and their backups are
+/// left alone.
+pub fn clean_configuration() -> Result<(), ConfigureError> {
+    let project_root = find_project_root()?;
+
+    if remove_apply_state(&project_root)? {
+        info!("Removed {}, so configure no longer knows what was applied", STATE_FILE_NAME);
+    } else {
+        info!("There's nothing to clean up");
+    }
+
+    Ok(())
+}
+
+/// Toggles whether the decrypted files in the project are read-only
+pub fn lock_configuration(
+    configuration: ConfigurationFile,
+    read_only: bool,
+) -> Result<(), ConfigureError> {
+    let missing = set_files_read_only(&configuration, &find_project_root()?, read_only, false)?;
+
+    for destination in &missing {
+        warn(&format!(
+            "{} doesn't exist yet – run `configure apply` first",
+            destination
+        ));
+    }
+
+    let count = configuration.files_to_copy.len() - missing.len();
+
+    if read_only {
+        info!("Locked {} file(s). Run `configure unlock` to edit them", count);
+    } else {
+        info!("Unlocked {} file(s)", count);
+    }
+
+    Ok(())
+}
+
+/// Lists the projects in `keys.json` and how old their keys are. The keys themselves aren't shown.
+pub fn list_keys_configuration() -> Result<(), ConfigureError> {
+    for line in describe_key_ages(Utc::now())? {
+        print(&line);
+    }
+
+    Ok(())
+}
+
+/// A line for each project in `keys.json`, saying how old its key is at `now`
+fn describe_key_ages(now: DateTime<Utc>) -> Result<Vec<String>, ConfigureError> {
+    let keys = read_keys()?;
+    let metadata = read_key_metadata(&find_key_metadata_file()?)?;
+
+    let width = keys.keys().map(|name| name.chars().count()).max().unwrap_or(0);
+    Ok(keys
+        .keys()
+        .map(|name| {
+            let age = match metadata.get(name).and_then(|metadata| metadata.age_days(now)) {
+                Some(1) => "1 day old".to_string(),
+                Some(days) => format!("{} days old", days),
+                None => "age unknown".to_string(),
+            };
+            format!("{:width$}  {}", name, age, width = width)
+        })
+        .collect())
+}
+
+/// Warns if the project's key is older than its `max_key_age_days`
+fn warn_if_key_is_old(configuration: &ConfigurationFile) {
+    if let Some(problem) = key_age_problem(configuration, Utc::now()) {
+        warn(&problem);
+    }
+}
+
+/// Why the project's key needs rotating at `now`, if it's older than its `max_key_age_days`.
+/// Anything that gets in the way of finding out is ignored, since this is only advice.
+fn key_age_problem(configuration: &ConfigurationFile, now: DateTime<Utc>) -> Option<String> {
+    let max_age_days = configuration.max_key_age_days?;
+
+    let key_name = match read_keys()
+        .ok()
+        .and_then(|keys| match_project_key(&keys, &configuration.project_name))
+    {
+        Some(ProjectKeyMatch::Exact) => configuration.project_name.clone(),
+        Some(ProjectKeyMatch::Sanitized(key_name)) | Some(ProjectKeyMatch::Fuzzy(key_name)) => {
+            key_name
+        }
+        None => return None,
+    };
+
+    let age_days = find_key_metadata_file()
+        .and_then(|path| read_key_metadata(&path))
+        .ok()
+        .and_then(|metadata| metadata.get(&key_name)?.age_days(now))?;
+
+    describe_key_age_problem(&key_name, age_days, max_age_days)
+}
+
+/// Creates an encryption key for a project and stores it in `keys.json`, or prints it. With
+/// `--dry-run`, only reports whether it would, failing with `KeyWouldBeCreated` if so.
+pub fn create_project_key(options: &CreateKeyOptions) -> Result<(), ConfigureError> {
+    if options.stdout {
+        print(&crate::encryption::generate_key().to_base64());
+        return Ok(());
+    }
+
+    let project_name = match &options.project {
+        Some(project) => project.clone(),
+        None => match read_configuration_if_present()? {
+            Some(configuration) if !configuration.project_name.trim().is_empty() => {
+                configuration.project_name
+            }
+            _ if is_interactive() => ask(Interaction::ProjectName)?.text()?.trim().to_string(),
+            _ => return Err(ConfigureError::ProjectNameRequired),
+        },
+    };
+
+    // A dry run mustn't create keys.json, as reading it otherwise would
+    let keys = match options.dry_run && !keys_file_path()?.exists() {
+        true => serde_json::Map::new(),
+        false => read_keys()?,
+    };
+    let existing = match match_project_key(&keys, &project_name) {
+        Some(ProjectKeyMatch::Exact) => Some(project_name.clone()),
+        Some(ProjectKeyMatch::Sanitized(key_name)) | Some(ProjectKeyMatch::Fuzzy(key_name)) => {
+            Some(key_name)
+        }
+        None => None,
+    };
+
+    if options.dry_run {
+        return match existing {
+            Some(existing) => {
+                print(&format!("A key already exists for {}", existing));
+                Ok(())
+            }
+            None => Err(ConfigureError::KeyWouldBeCreated(sanitized_key_name(&project_name))),
+        };
+    }
+
+    if let Some(existing) = &existing {
+        if !options.force {
+            return Err(ConfigureError::KeyWouldOverwrite(existing.clone()));
+        }
+
+        warn(&format!("Replacing the existing key for {}", existing));
+    }
+
+    // Replace a near-match in place, rather than adding a second key for the same project. New
+    // keys get a name that's safe to use anywhere.
+    let key_name = existing.unwrap_or_else(|| {
+        let key_name = sanitized_key_name(&project_name);
+        if key_name != project_name {
+            warn(&format!(
+                "{:?} isn't a valid project name, so storing the key as {:?}",
+                project_name, key_name
+            ));
+        }
+        key_name
+    });
+    let previous = keys.get(&key_name).and_then(|key| key.as_str());
+    let key = crate::encryption::generate_key();
+    save_project_key(&key_name, &key.to_base64(), previous)?;
+    update_key_metadata(&find_key_metadata_file()?, |metadata| {
+        metadata.insert(key_name.clone(), KeyMetadata::created(Utc::now()));
+    })?;
+
+    info!("Stored a new key for {} in {:?}", key_name, find_keys_file()?);
+
+    if options.commit {
+        let verb = if previous.is_some() { "Replace" } else { "Add" };
+        let message = format!("{} the key for {}", verb, key_name);
+        let hash = commit_secrets_files(&[KEYS_FILE_NAME, KEY_METADATA_FILE_NAME], &message)?;
+        info!("Committed keys.json to the secrets repository as {}", display_hash(&hash));
+    }
+
+    Ok(())
+}
+
+/// Renames the project: `project_name` in `.configure`, its key in keys.json (which is copied, so
+/// other checkouts keep working until the old one is removed) and, if asked, its sources and its
+/// directory in the secrets repository. Whatever's left to do by hand is listed at the end.
+pub fn rename_project_configuration(
+    mut configuration: ConfigurationFile,
+    options: &RenameOptions,
+) -> Result<(), ConfigureError> {
+    configuration.ensure_initialized()?;
+
+    let old_name = configuration.project_name.clone();
+    let new_name = options.new_name.clone();
+    if let Some(problem) = project_name_problem(&new_name) {
+        return Err(ConfigureError::ProjectNameInvalid(new_name, problem));
+    }
+
+    let old_directory = sanitized_key_name(&old_name);
+    let new_directory = sanitized_key_name(&new_name);
+    let update_sources = options.update_sources || options.also_move_secrets_dir;
+    let mut follow_ups = Vec::new();
+
+    //
+    // Step 1 – Copy the key, before anything's changed, since another project may have the name
+    //
+    let old_key = match match_project_key(&read_keys()?, &old_name) {
+        Some(ProjectKeyMatch::Exact) => Some(old_name.clone()),
+        Some(ProjectKeyMatch::Sanitized(key_name)) | Some(ProjectKeyMatch::Fuzzy(key_name)) => {
+            Some(key_name)
+        }
+        None => None,
+    };
+
+    // The old key is only removed once everything else is done, so a failure part way never
+    // leaves `.configure` naming a project that has no key
+    let mut key_to_remove = None;
+
+    match old_key.filter(|old_key| *old_key != new_name) {
+        Some(old_key) => {
+            copy_project_key(&old_key, &new_name)?;
+            info!("Copied the key for {} in keys.json to {}", old_key, new_name);
+
+            let remove = is_interactive()
+                && ask(Interaction::RemoveOldKey {
+                    key_name: old_key.clone(),
+                })?
+                .confirmed()?;
+
+            if remove {
+                key_to_remove = Some(old_key);
+            } else {
+                follow_ups.push(format!(
+                    "Once no checkout uses the name {:?} any more, remove its key with `configure prune-keys`",
+                    old_key
+                ));
+            }
+            follow_ups.push("Commit keys.json in the secrets repository".to_string());
+        }
+        None => warn(&format!(
+            "keys.json has no key for {:?}, so there was none to copy",
+            old_name
+        )),
+    }
+
+    //
+    // Step 2 – Point the sources at the new directory, or keep `./` sources in the old one
+    //
+    if update_sources && old_directory != new_directory {
+        let changed = move_sources(&mut configuration, &old_name, &new_directory);
+        info!("Changed {} source(s) to be in {}/", changed, new_directory);
+    } else if old_directory != new_directory {
+        // `./` sources are relative to the directory named after the project, unless there's a
+        // `source_root`, so setting one keeps them where they are
+        let relative = configuration.files_to_copy.iter().any(|f| f.source.starts_with("./"));
+        if configuration.source_root.is_none() && relative {
+            configuration.source_root = Some(old_directory.clone());
+            info!(
+                "Set `source_root` to {:?}, so sources starting with ./ stay there",
+                old_directory
+            );
+        }
+
+        let remaining = configuration
+            .files_to_copy
+            .iter()
+            .filter(|file| old_directory_prefix(&file.source, &old_name).is_some())
+            .count();
+        if remaining > 0 || relative {
+            follow_ups.push(format!(
+                "The secrets are still in {}/ in the secrets repository. To move them, rename it to {}/ there and commit that, then change the sources in .configure – or run this again with `--also-move-secrets-dir` to do all of that",
+                old_directory, new_directory
+            ));
+        }
+    }
+
+    //
+    // Step 3 – Save the new name, which has a key now
+    //
+    configuration.project_name = new_name.clone();
+    save_configuration(&configuration)?;
+    info!("Renamed the project from {:?} to {:?}", old_name, new_name);
+
+    //
+    // Step 4 – Move the directory in the secrets repository
+    //
+    if options.also_move_secrets_dir && old_directory != new_directory {
+        if find_secrets_repo()?.join(&old_directory).is_dir() {
+            let message = format!("Rename {} to {}", old_directory, new_directory);
+            let hash = move_secrets_directory(&old_directory, &new_directory, &message)?;
+            info!(
+                "Moved {}/ to {}/ in the secrets repository, and committed it as {}",
+                old_directory,
+                new_directory,
+                display_hash(&hash)
+            );
+            follow_ups.push(format!(
+                "Push the secrets repository, then run `configure update` to pin {}",
+                display_hash(&hash)
+            ));
+        } else {
+            warn(&format!(
+                "The secrets repository has no {}/ directory to move",
+                old_directory
+            ));
+        }
+    } else if update_sources && old_directory != new_directory {
+        follow_ups.push(format!(
+            "Rename {}/ to {}/ in the secrets repository and commit it, then run `configure update`",
+            old_directory, new_directory
+        ));
+    }
+
+    //
+    // Step 5 – Remove the old key, now nothing in this checkout uses it
+    //
+    if let Some(old_key) = key_to_remove {
+        rename_project_key(&old_key, &new_name)?;
+        info!("Removed the key for {} from keys.json", old_key);
+    }
+
+    if !follow_ups.is_empty() {
+        heading("Still to do");
+        for follow_up in follow_ups {
+            print(&format!("  {}", follow_up));
+        }
+    }
+
+    Ok(())
+}
+
+/// The part of `source` naming the directory for the project `old_name`, if it's in it
+fn old_directory_prefix(source: &str, old_name: &str) -> Option<usize> {
+    [old_name.to_string(), sanitized_key_name(old_name)]
+        .iter()
+        .find(|directory| {
+            source.starts_with(directory.as_str()) && source[directory.len()..].starts_with('/')
+        })
+        .map(|directory| directory.len())
+}
+
+/// Changes sources in the directory for the project `old_name`, and a `source_root` naming it, to
+/// be in `new_directory` instead. Returns how many sources changed.
+fn move_sources(
+    configuration: &mut ConfigurationFile,
+    old_name: &str,
+    new_directory: &str,
+) -> usize {
+    let mut changed = 0;
+    for file in &mut configuration.files_to_copy {
+        if let Some(length) = old_directory_prefix(&file.source, old_name) {
+            file.source = format!("{}{}", new_directory, &file.source[length..]);
+            changed += 1;
+        }
+    }
+
+    let source_root = configuration.source_root.as_deref().map(|r| r.trim_end_matches('/'));
+    if source_root == Some(old_name) || source_root == Some(&sanitized_key_name(old_name)) {
+        configuration.source_root = Some(new_directory.to_string());
+    }
+
+    changed
+}
+
+/// The directories in the secrets repository with this project's sources that are named after
+/// other projects in `keys` with the same key as this one – left behind by a rename that copied
+/// the key – with how many sources are in each. Directories shared with projects that have their
+/// own keys aren't included.
+fn sources_under_other_projects(
+    configuration: &ConfigurationFile,
+    keys: &serde_json::Map<String, serde_json::Value>,
+) -> BTreeMap<String, usize> {
+    let own_key_name = match match_project_key(keys, &configuration.project_name) {
+        Some(ProjectKeyMatch::Exact) => configuration.project_name.clone(),
+        Some(ProjectKeyMatch::Sanitized(key_name)) | Some(ProjectKeyMatch::Fuzzy(key_name)) => {
+            key_name
+        }
+        None => return BTreeMap::new(),
+    };
+    let own_key = &keys[&own_key_name];
+    let own_directories = [
+        configuration.project_name.clone(),
+        sanitized_key_name(&configuration.project_name),
+    ];
+
+    let mut directories = BTreeMap::new();
+    for file in &configuration.files_to_copy {
+        let directory = match file.source.split_once('/') {
+            Some((directory, _)) => directory,
+            None => continue,
+        };
+
+        let other_project = keys.iter().any(|(key_name, key)| {
+            key == own_key && (key_name == directory || sanitized_key_name(key_name) == directory)
+        });
+        if other_project && !own_directories.iter().any(|own| own == directory) {
+            *directories.entry(directory.to_string()).or_insert(0) += 1;
+        }
+    }
+
+    directories
+}
+
+/// Shows how applying would change the project's decrypted files. Fails with `FilesDiffer` if
+/// anything would change, so scripts can use it as a check.
+pub fn diff_configuration(
+    configuration: ConfigurationFile,
+    options: &DiffOptions,
+) -> Result<(), ConfigureError> {
+    configuration.ensure_initialized()?;
+
+    if let Some(against) = &options.against {
+        return diff_against_commit(&configuration, options, against);
+    }
+
+    let plan = plan_for_configuration(&configuration)?;
+    let changed: Vec<&PlannedChange> = plan.changes().collect();
+
+    for change in &changed {
+        if options.name_only {
+            print(&change.destination);
+            continue;
+        }
+
+        heading(&change.destination);
+        print_diff(change);
+        newline();
+    }
+
+    if changed.is_empty() {
+        info!("All files match the project's secrets");
+        return Ok(());
+    }
+
+    Err(ConfigureError::FilesDiffer(changed.len()))
+}
+
+/// Shows how each secret differs between the pinned hash and `against`, as it would be decrypted
+/// into the project. Fails with `FilesDiffer` if any of them do.
+fn diff_against_commit(
+    configuration: &ConfigurationFile,
+    options: &DiffOptions,
+    against: &str,
+) -> Result<(), ConfigureError> {
+    ensure_commit_exists(&configuration.pinned_hash)?;
+    ensure_commit_exists(against)?;
+
+    let read_at = |reference: &str, file: &File| -> Result<Option<Vec<u8>>, ConfigureError> {
+        match read_file_at_ref(reference, &configuration.source_path(file))? {
+            Some(contents) => Ok(Some(file.transform_contents(contents)?)),
+            None => Ok(None),
+        }
+    };
+
+    let mut changed = 0;
+    for file in &configuration.files_to_copy {
+        let pinned = read_at(&configuration.pinned_hash, file)?;
+        let other = read_at(against, file)?;
+        if pinned == other {
+            continue;
+        }
+
+        changed += 1;
+        if options.name_only {
+            print(&file.destination);
+            continue;
+        }
+
+        heading(&file.destination);
+        match (&pinned, &other) {
+            (Some(pinned), Some(other)) => print_contents_diff(pinned, other),
+            (None, _) => print(&format!("Only in the secrets at {}", display_hash(against))),
+            (_, None) => print(&format!("Not in the secrets at {}", display_hash(against))),
+        }
+        newline();
+    }
+
+    if changed == 0 {
+        info!(
+            "The secrets at {} are the same as the pinned ones",
+            display_hash(against)
+        );
+        return Ok(());
+    }
+
+    Err(ConfigureError::FilesDiffer(changed))
+}
+
+fn print_diff(change: &PlannedChange) {
+    match (&change.local, &change.incoming) {
+        (Some(local), Some(incoming)) => print_contents_diff(local, incoming),
+        _ => print("Doesn't exist yet"),
+    }
+}
+
+fn print_contents_diff(local: &[u8], incoming: &[u8]) {
+    match (as_text(local), as_text(incoming)) {
+        (Some(local), Some(incoming)) => {
+            for line in diff_lines(local, incoming) {
+                match line {
+                    DiffLine::Unchanged(_) => {}
+                    DiffLine::Removed(line) => print_in(Color::Red, &format!("- {}", line)),
+                    DiffLine::Added(line) => print_in(Color::Green, &format!("+ {}", line)),
+                }
+            }
+        }
+        _ => print("Binary file differs"),
+    }
+}
+
+/// What `reencrypt` did with one encrypted file
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ReencryptOutcome {
+    /// The encrypted file was missing, and was written
+    Restored,
+
+    /// The encrypted file couldn't be decrypted, or didn't match the secret, and was rewritten
+    Repaired,
+
+    /// The encrypted file already had the secret's contents, and was left alone
+    Unchanged,
+}
+
+/// Cleans up the secrets checkout's stale branches: remote-tracking branches that are gone from the
+/// remote, and local branches whose upstream is gone. Local branches with commits that aren't on the
+/// remote are only reported, and the current branch is never touched. Unless `options.yes` is set,
+/// this only lists what it would remove.
+pub fn prune_branches_configuration(options: &PruneBranchesOptions) -> Result<(), ConfigureError> {
+    let stale_remote_branches = find_stale_remote_branches()?;
+    let plan = plan_secrets_branch_pruning(&stale_remote_branches)?;
+
+    for branch in &plan.unpushed {
+        warn(&format!(
+            "Kept {}, which has commits that aren't on the remote (its upstream {} is gone)",
+            branch.name, branch.upstream
+        ));
+    }
+
+    if stale_remote_branches.is_empty() && plan.delete.is_empty() {
+        info!("There are no stale branches to prune");
+        return Ok(());
+    }
+
+    if !options.yes {
+        for remote_branch in &stale_remote_branches {
+            info!("Would prune {}", remote_branch);
+        }
+
+        for branch in &plan.delete {
+            info!("Would delete {} (its upstream {} is gone)", branch.name, branch.upstream);
+        }
+
+        info!("Run `configure prune-branches --yes` to remove them");
+        return Ok(());
+    }
+
+    ensure_secrets_writable("prune its branches")?;
+    prune_stale_remote_branches()?;
+    for remote_branch in &stale_remote_branches {
+        info!("Pruned {}", remote_branch);
+    }
+
+    for branch in &plan.delete {
+        delete_secrets_branch(&branch.name)?;
+        info!("Deleted {} (was {})", branch.name, display_hash(&branch.hash));
+    }
+
+    Ok(())
+}
+
+/// Finds the keys in `keys.json` that no active project uses. Unless `options.delete` is set, this
+/// only lists them, and fails if there are any.
+pub fn prune_keys_configuration(options: &PruneKeysOptions) -> Result<(), ConfigureError> {
+    let mut active_projects = options.projects.clone();
+    if let Some(active_file) = &options.active_file {
+        active_projects.extend(read_project_names(active_file)?);
+    }
+
+    // Without a list of active projects, every key would look orphaned
+    if active_projects.is_empty() {
+        return Err(ConfigureError::NoActiveProjects);
+    }
+
+    let orphans = find_orphaned_keys(&read_keys()?, &active_projects);
+
+    if orphans.is_empty() {
+        info!("Every key in keys.json belongs to an active project");
+        return Ok(());
+    }
+
+    if !options.delete {
+        for orphan in &orphans {
+            info!("{} isn't an active project", orphan);
+        }
+
+        return Err(ConfigureError::OrphanedKeys(orphans.len()));
+    }
+
+    for removed in remove_orphaned_keys(&orphans, &active_projects)? {
+        info!("Removed the key for {}", removed);
+    }
+
+    Ok(())
+}
+
+/// Renames backups of the project's secrets that have old-style names to the current scheme, and
+/// reports any that can't be renamed. Never deletes anything.
+pub fn repair_backups_configuration(
+    configuration: ConfigurationFile,
+    options: &RepairBackupsOptions,
+) -> Result<(), ConfigureError> {
+    let project_root = find_project_root()?;
+    let repairs = plan_backup_repairs(&project_root, &configuration.files_to_copy)?;
+
+    if repairs.is_empty() {
+        info!("All backups already have the right names");
+        return Ok(());
+    }
+
+    let relative = |path: &PathBuf| {
+        let path = path.strip_prefix(&project_root).unwrap_or(path);
+        path.display().to_string()
+    };
+    let mut renamed = 0;
+
+    for repair in &repairs {
+        match repair {
+            // Something may have taken the name since the repairs were planned, and renaming
+            // would replace it
+            BackupRepair::Rename { from, to } if options.yes && to.exists() => warn(&format!(
+                "Couldn't rename {}, because {} already exists",
+                relative(from),
+                relative(to)
+            )),
+            BackupRepair::Rename { from, to } if options.yes => {
+                std::fs::rename(from, to)?;
+                renamed += 1;
+                info!("Renamed {} to {}", relative(from), relative(to));
+            }
+            BackupRepair::Rename { from, to } => {
+                info!("Would rename {} to {}", relative(from), relative(to));
+            }
+            BackupRepair::Clash { from, to } => warn(&format!(
+                "Couldn't rename {}, because {} already exists",
+                relative(from),
+                relative(to)
+            )),
+            BackupRepair::Unclassified(path) => warn(&format!(
+                "{} looks like a backup, but its name doesn't say when it was made",
+                relative(path)
+            )),
+        }
+    }
+
+    let can_rename = repairs
+        .iter()
+        .any(|repair| matches!(repair, BackupRepair::Rename { .. }));
+    if can_rename && !options.yes {
+        info!("Run `configure repair-backups --yes` to rename them");
+    } else if options.yes {
+        info!("Renamed {} backups", renamed);
+    }
+
+    Ok(())
+}
+
+/// Writes the project's encrypted files, and a manifest of where their secrets belong, to a
+/// bundle that `import_bundle_configuration` can put into another secrets repository
+pub fn export_bundle_configuration(
+    configuration: ConfigurationFile,
+    options: &ExportBundleOptions,
+) -> Result<(), ConfigureError> {
+    configuration.completeness().ensure_complete()?;
+
+    let project_root = find_project_root()?;
+
+    // Re-encrypting needs the project's key to decrypt the files first
+    let keys = match &options.transfer_key {
+        Some(path) => Some((require_encryption_key(&configuration)?, read_transfer_key(path)?)),
+        None => None,
+    };
+
+    let mut files = Vec::new();
+    let mut missing = Vec::new();
+
+    for file in &configuration.files_to_copy {
+        let path = file.get_encrypted_destination();
+        let mut contents = match std::fs::read(project_root.join(&path)) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                if file.optional {
+                    info!("Skipped {}, which isn't in the project", path);
+                } else {
+                    missing.push(path);
+                }
+                continue;
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        if let Some((project_key, transfer_key)) = &keys {
+            contents = encrypt_contents(&decrypt_contents(&contents, project_key)?, transfer_key);
+        }
+
+        let bundled = BundledFile {
+            source: configuration.source_path(file),
+            path,
+            encoding: file.encoding,
+            digest: String::new(),
+        };
+        files.push((bundled, contents));
+    }
+
+    if !missing.is_empty() {
+        return Err(ConfigureError::BundleIncomplete(missing.join(", ")));
+    }
+
+    let manifest = BundleManifest {
+        project_name: configuration.project_name.clone(),
+        branch: configuration.branch.clone(),
+        pinned_hash: configuration.pinned_hash.clone(),
+        transfer_key: keys.map(|(_, transfer_key)| key_fingerprint(&transfer_key.to_base64())),
+        files: Vec::new(),
+    };
+    let manifest = write_bundle(&options.path, manifest, files)?;
+
+    info!(
+        "Exported {} secret(s) at {} to {:?}",
+        manifest.files.len(),
+        display_hash(&manifest.pinned_hash),
+        options.path
+    );
+    Ok(())
+}
+
+/// Decrypts the secrets in a bundle from `export_bundle_configuration` into the secrets
+/// repository, where they belong. `configuration` is the project's, if there is one here, which
+/// the bundle has to have every required secret for.
+pub fn import_bundle_configuration(
+    configuration: Option<ConfigurationFile>,
+    options: &ImportBundleOptions,
+) -> Result<(), ConfigureError> {
+    ensure_secrets_writable("import a bundle")?;
+
+    let (manifest, files) = read_bundle(&options.path)?;
+
+    if let Some(configuration) = &configuration {
+        let missing = find_missing_from_bundle(&manifest, configuration);
+        if !missing.is_empty() {
+            return Err(ConfigureError::BundleIncomplete(missing.join(", ")));
+        }
+    }
+
+    let key = match (&manifest.transfer_key, &options.transfer_key) {
+        (Some(fingerprint), Some(path)) => {
+            let key = read_transfer_key(path)?;
+            if &key_fingerprint(&key.to_base64()) != fingerprint {
+                return Err(ConfigureError::TransferKeyMismatch(fingerprint.clone()));
+            }
+            key
+        }
+        (Some(_), None) => return Err(ConfigureError::TransferKeyRequired),
+        (None, _) => require_encryption_key(&ConfigurationFile {
+            project_name: manifest.project_name.clone(),
+            ..Default::default()
+        })?,
+    };
+
+    let secrets_root = find_secrets_repo()?;
+
+    for (bundled, contents) in &files {
+        let file = File {
+            destination: bundled.path.clone(),
+            encoding: bundled.encoding,
+            ..Default::default()
+        };
+        let secret = file.decode_contents(decrypt_contents(contents, &key)?)?;
+
+        let path = secrets_root.join(&bundled.source);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(&path, secret)?;
+        debug!("Wrote {:?}", path);
+    }
+
+    info!(
+        "Imported {} secret(s) for {} into the secrets repository. They were exported at {} on {} – commit them to use them with `configure update`",
+        files.len(),
+        manifest.project_name,
+        display_hash(&manifest.pinned_hash),
+        manifest.branch
+    );
+    Ok(())
+}
+
+/// Reads a transfer key, which is kept in a file of its own like the output of `create-key
+/// --stdout`
+fn read_transfer_key(path: &Path) -> Result<Key, ConfigureError> {
+    std::fs::read_to_string(path)
+        .ok()
+        .and_then(|contents| Key::from_base64(contents.trim()))
+        .ok_or_else(|| ConfigureError::TransferKeyInvalid(path.to_path_buf()))
+}
+
+/// Regenerates the project's encrypted files from the secrets at the pinned hash, without
+/// changing the pin, `.configure`, or the secrets checkout
+pub fn reencrypt_configuration(
+    configuration: ConfigurationFile,
+    options: &ReencryptOptions,
+) -> Result<(), ConfigureError> {
+    configuration.completeness().ensure_complete()?;
+    ensure_commit_exists(&configuration.pinned_hash)?;
+
+    let files: Vec<&File> = match &options.only {
+        Some(destination) => {
+            let file = configuration
+                .files_to_copy
+                .iter()
+                .find(|file| &file.destination == destination)
+                .ok_or_else(|| ConfigureError::UnknownDestination(destination.clone()))?;
+            vec![file]
+        }
+        None => configuration.files_to_copy.iter().collect(),
+    };
+
+    let project_root = find_project_root()?;
+    let encryption_key = require_encryption_key(&configuration)?;
+    let mut changed = 0;
+
+    for file in files {
+        let source = configuration.source_path(file);
+        let contents = match read_file_at_ref(&configuration.pinned_hash, &source)? {
+            Some(contents) => contents,
+            None if file.optional => {
+                info!("Skipped {}, which isn't in the secrets repository", file.destination);
+                continue;
+            }
+            None => {
+                return Err(ConfigureError::SourceMissingAtCommit {
+                    path: source,
+                    commit: configuration.pinned_hash.clone(),
+                })
+            }
+        };
+
+        match reencrypt_file(&project_root, file, &contents, &encryption_key)? {
+            ReencryptOutcome::Restored => info!("Restored {}", file.get_encrypted_destination()),
+            ReencryptOutcome::Repaired => info!("Repaired {}", file.get_encrypted_destination()),
+            ReencryptOutcome::Unchanged => {
+                debug!("{} is already up to date", file.get_encrypted_destination());
+                continue;
+            }
+        }
+
+        changed += 1;
+    }
+
+    info!(
+        "Re-encrypted {} file(s) at {}",
+        changed,
+        display_hash(&configuration.pinned_hash)
+    );
+
+    Ok(())
+}
+
+pub fn validate_configuration(
+    configuration: ConfigurationFile,
+    options: &ValidateOptions,
+) -> Result<(), ConfigureError> {
+    configuration.ensure_initialized()?;
+
+    let project_root = find_project_root()?;
+
+    // Look for sources as of the pinned hash, so validating never changes the secrets checkout.
+    // The pinned tree is listed once up front, rather than looking up each source in it.
+    let can_check_sources = find_secrets_repo().is_ok() && !configuration.pinned_hash.is_empty();
+    let pinned_files = match can_check_sources {
+        true => files_at_ref(&configuration.pinned_hash).unwrap_or_default(),
+        false => Default::default(),
+    };
+    let source_exists = |source: &str| pinned_files.contains(source);
+
+    if !can_check_sources {
+        warn("The secrets repository or pinned hash isn't available, so sources won't be checked");
+    }
+
+    let mut findings = validate(
+        &configuration,
+        &project_root,
+        if can_check_sources {
+            Some(&source_exists)
+        } else {
+            None
+        },
+        options.strict,
+    );
+
+    // A secrets repository that's there but can't be used is worth reporting, unlike one that
+    // just isn't on this machine
+    let secrets_repo_problem = match find_secrets_repo() {
+        // Remotes listed in `.configure` are added when they're needed, so they needn't be there
+        Ok(path) => {
+            let configured: Vec<String> = configuration.remotes.keys().cloned().collect();
+            let remote = split_remote_branch(&configuration.branch, &configured).remote;
+            if configuration.remotes.contains_key(&remote) {
+                None
+            } else {
+                ensure_secrets_remote(&path, &remote).err()
+            }
+        }
+        Err(ConfigureError::SecretsNotPresent) => None,
+        Err(err) => Some(err),
+    };
+
+    if let Some(problem) = secrets_repo_problem {
+        findings.push(ValidationFinding {
+            owner: PROJECT.to_string(),
+            kind: "Secrets repository problems",
+            message: problem.to_string(),
+        });
+    }
+
+    if let Ok(keys) = read_keys() {
+        let message = match match_project_key(&keys, &configuration.project_name) {
+            Some(ProjectKeyMatch::Fuzzy(key_name)) => Some(format!(
+                "`project_name` is {:?}, but keys.json calls it {:?}",
+                configuration.project_name, key_name
+            )),
+            None if !configuration.project_name.trim().is_empty() => Some(format!(
+                "keys.json has no key for {:?} – it has to be provisioned before secrets can be encrypted or applied",
+                configuration.project_name
+            )),
+            _ => None,
+        };
+
+        if let Some(message) = message {
+            findings.push(ValidationFinding {
+                owner: PROJECT.to_string(),
+                kind: "Key problems",
+                message,
+            });
+        }
+
+        for (directory, count) in sources_under_other_projects(&configuration, &keys) {
+            findings.push(ValidationFinding {
+                owner: PROJECT.to_string(),
+                kind: "Sources under another project",
+                message: format!(
+                    "{} source(s) are in {}/, which is named after another project in keys.json with the same key – if this project used to be called that, move them to {}/ in the secrets repository and change their sources, like `configure rename --also-move-secrets-dir` does",
+                    count, directory, sanitized_key_name(&configuration.project_name)
+                ),
+            });
+        }
+    }
+
+    for (file, problem) in find_encrypted_file_attribute_problems(&configuration, &project_root)? {
+        findings.push(ValidationFinding {
+            owner: owner_of(file).to_string(),
+            kind: "Encrypted files git may change",
+            message: format!(
+                "{} {} – add `{}` to .gitattributes",
+                file.get_encrypted_destination(),
+                problem.problem,
+                SUGGESTED_ATTRIBUTES
+            ),
+        });
+    }
+
+    // Only an exact match is used, since a near match is reported above
+    let encryption_key = read_keys().ok().and_then(|keys| {
+        keys.get(&configuration.project_name)
+            .and_then(|key| key.as_str())
+            .and_then(Key::from_base64)
+    });
+
+    for file in &configuration.files_to_copy {
+        let contents = match std::fs::read(project_root.join(file.get_encrypted_destination())) {
+            Ok(contents) => contents,
+            Err(_) => continue,
+        };
+
+        if let Some(problem) = diagnose_encrypted_contents(&contents, encryption_key.as_ref()) {
+            findings.push(ValidationFinding {
+                owner: owner_of(file).to_string(),
+                kind: "Damaged encrypted files",
+                message: format!(
+                    "{} {}. A git filter or line-ending conversion can do this – check .gitattributes",
+                    file.get_encrypted_destination(),
+                    problem
+                ),
+            });
+        }
+    }
+
+    if options.check_remote {
+        match check_pin_is_pushed(&configuration) {
+            Ok(Some(finding)) => findings.push(finding),
+            Ok(None) => {}
+            Err(err) => warn(&format!(
+                "Skipped checking the pinned commit is on the server: {}",
+                err
+            )),
+        }
+    }
+
+    if options.strict {
+        match check_pin_freshness(&configuration) {
+            Ok(check) if check.offline => {
+                for problem in check.problems {
+                    warn(&problem);
+                }
+            }
+            Ok(check) => findings.extend(check.problems.into_iter().map(|message| {
+                ValidationFinding {
+                    owner: PROJECT.to_string(),
+                    kind: "Stale pin",
+                    message,
+                }
+            })),
+            Err(err) => warn(&format!("Unable to check how fresh the pin is: {}", err)),
+        }
+    }
+
+    if configuration.require_signed_updates {
+        if let Err(err) = verify_configuration_signature(&configuration, &project_root) {
+            findings.push(ValidationFinding {
+                owner: PROJECT.to_string(),
+                kind: "Signature problems",
+                message: err.to_string(),
+            });
+        }
+    }
+
+    if findings.is_empty() {
+        info!("The configuration is valid");
+        return Ok(());
+    }
+
+    print_findings(&findings);
+    Err(ConfigureError::ConfigurationHasProblems(findings.len()))
+}
+
+/// Fetches the latest secrets and checks the pinned commit is on the remote copy of its branch,
+/// returning a finding if it's only local. Fails if the secrets can't be fetched.
+fn check_pin_is_pushed(
+    configuration: &ConfigurationFile,
+) -> Result<Option<ValidationFinding>, ConfigureError> {
+    configuration.completeness().ensure_complete()?;
+    fetch_secrets_latest_remote_data(&configuration.branch, &configuration.remotes, |_| {})?;
+
+    if commit_is_on_remote_branch(&configuration.pinned_hash, &configuration.branch)? {
+        return Ok(None);
+    }
+
+    Ok(Some(ValidationFinding {
+        owner: PROJECT.to_string(),
+        kind: "Unpushed pin",
+        message: format!(
+            "The pinned commit {} isn't on the server's {} branch, so it won't be in a fresh clone – push it, or pin one that's been pushed",
+            display_hash(&configuration.pinned_hash),
+            configuration.branch
+        ),
+    }))
+}
+
+/// How a pin measures up to the project's `max_age_days` and `max_commits_behind`
+struct FreshnessCheck {
+    /// The ways the pin is too stale, each saying how stale it is and what fixes it
+    problems: Vec<String>,
+
+    /// Whether the latest secrets couldn't be fetched. The pin is then only compared with what's
+    /// already in the secrets checkout, so problems should only be warnings.
+    offline: bool,
+}
+
+/// Checks the pin against the project's freshness limits. The age is measured from when the
+/// pinned commit was authored. The secrets repository is only fetched if there are limits.
+fn check_pin_freshness(
+    configuration: &ConfigurationFile,
+) -> Result<FreshnessCheck, ConfigureError> {
+    let mut check = FreshnessCheck {
+        problems: Vec::new(),
+        offline: false,
+    };
+
+    if configuration.max_age_days.is_none() && configuration.max_commits_behind.is_none() {
+        return Ok(check);
+    }
+
+    configuration.completeness().ensure_complete()?;
+
+    let remotes = &configuration.remotes;
+    if let Err(err) = fetch_secrets_latest_remote_data(&configuration.branch, remotes, |_| {}) {
+        warn(&format!(
+            "Unable to fetch the latest secrets, so the pin can only be checked against the local checkout: {}",
+            err
+        ));
+        check.offline = true;
+    }
+
+    let pinned_hash = &configuration.pinned_hash;
+    let age_days = (Utc::now().timestamp() - secrets_commit_time(pinned_hash)?.seconds()) / 86400;
+
+    let distance = match configuration.max_commits_behind {
+        Some(_) => {
+            let latest_hash = match get_latest_hash_for_remote_branch(&configuration.branch) {
+                Ok(hash) => hash,
+                Err(_) => get_branch_tip(&configuration.branch)?,
+            };
+
+            secrets_repo_distance_between(pinned_hash, &latest_hash)?
+        }
+        None => CommitDistance::Same,
+    };
+
+    check.problems = describe_pin_staleness(configuration, age_days.max(0) as u64, distance);
+    Ok(check)
+}
+
+/// Describes how the pin exceeds the project's freshness limits, if it does. `distance` is where
+/// the pin is in relation to the latest secrets on its branch. A pin that isn't behind them can
+/// only be checked if it's on the branch at all.
+fn describe_pin_staleness(
+    configuration: &ConfigurationFile,
+    age_days: u64,
+    distance: CommitDistance,
+) -> Vec<String> {
+    let mut problems = Vec::new();
+    let fix = "run `configure update` to move it to the latest secrets";
+
+    if let Some(max_age_days) = configuration.max_age_days {
+        if age_days > max_age_days {
+            problems.push(format!(
+                "The pinned commit is {} days old, but `max_age_days` is {} – {}",
+                age_days, max_age_days, fix
+            ));
+        }
+    }
+
+    if let Some(max_commits_behind) = configuration.max_commits_behind {
+        let branch = &configuration.branch;
+        match distance {
+            CommitDistance::BehindBy(commits_behind) if commits_behind > max_commits_behind => {
+                problems.push(format!(
+                    "The pin is {} commits behind {}, but `max_commits_behind` is {} – {}",
+                    commits_behind, branch, max_commits_behind, fix
+                ))
+            }
+            CommitDistance::AheadBy(count) => problems.push(format!(
+                "The pin is {} commit(s) past the latest secrets on {}, so how far behind it is can't be checked – push the pinned commit, or run `configure update` to pin one on {}",
+                count, branch, branch
+            )),
+            CommitDistance::Unrelated => problems.push(format!(
+                "The pinned commit isn't part of the history of {}, so how far behind it is can't be checked – run `configure update` to pin a commit on {}",
+                branch, branch
+            )),
+            _ => {}
+        }
+    }
+
+    problems
+}
+
+/// Past this many findings, they're grouped by kind instead of being listed in full by owner
+const MANY_FINDINGS: usize = 20;
+
+/// How many findings of each kind are listed when there are many, unless `--verbose` is passed
+const FINDINGS_SHOWN_PER_KIND: usize = 5;
+
+/// Prints findings grouped by owner – or, when there are too many to read through, by kind
+fn print_findings(findings: &[ValidationFinding]) {
+    if findings.len() > MANY_FINDINGS {
+        for (kind, messages) in findings_by_kind(findings, log_enabled!(Level::Debug)) {
+            heading(&kind);
+            for message in messages {
+                print(&format!("  {}", message));
+            }
+        }
+        return;
+    }
+
+    let groups = group_by_owner(
+        findings
+            .iter()
+            .map(|finding| (finding.owner.as_str(), &finding.message)),
+    );
+
+    for (owner, messages) in groups {
+        heading(&owner);
+        for message in messages {
+            print(&format!("  {}", message));
+        }
+    }
+}
+
+/// Groups findings by kind, in the order each kind is first found, with a heading counting them.
+/// Unless `all`, only the first few of each kind are listed.
+fn findings_by_kind(findings: &[ValidationFinding], all: bool) -> Vec<(String, Vec<String>)> {
+    let mut kinds: Vec<(&str, Vec<&str>)> = Vec::new();
+    for finding in findings {
+        match kinds.iter_mut().find(|(kind, _)| *kind == finding.kind) {
+            Some((_, messages)) => messages.push(&finding.message),
+            None => kinds.push((finding.kind, vec![&finding.message])),
+        }
+    }
+
+    kinds
+        .into_iter()
+        .map(|(kind, messages)| {
+            let heading = format!("{} ({})", kind, messages.len());
+            let shown = if all { messages.len() } else { FINDINGS_SHOWN_PER_KIND };
+
+            let mut lines: Vec<String> =
+                messages.iter().take(shown).map(|message| message.to_string()).collect();
+            if messages.len() > shown {
+                lines.push(format!(
+                    "…and {} more – pass --verbose to list them all",
+                    messages.len() - shown
+                ));
+            }
+
+            (heading, lines)
+        })
+        .collect()
+}
+
+/// Finds encrypted files that have changed even though the pin hasn't – so they didn't come from
+/// `configure update`, but were replaced by hand or left over from another pin. An encrypted file
+/// is checked against the one the last apply at the same pin used, and, when `.configure` is
+/// committed, against the committed one.
+fn find_source_hash_mismatches(
+    configuration: &ConfigurationFile,
+    project_root: &Path,
+) -> Vec<ValidationFinding> {
+    let state = read_apply_state(project_root);
+    let configure_file = project_root.join(".configure");
+    let pin_is_committed = match committed_blob_hash(&configure_file) {
+        Some(committed) => blob_hash_of_file(&configure_file).ok() == Some(committed),
+        None => false,
+    };
+
+    let committed = match pin_is_committed {
+        true => {
+            let destinations: Vec<String> = configuration
+                .files_to_copy
+                .iter()
+                .map(File::get_encrypted_destination)
+                .collect();
+            committed_blob_hashes(project_root, &destinations)
+        }
+        false => Default::default(),
+    };
+
+    let mut findings = Vec::new();
+
+    for file in &configuration.files_to_copy {
+        let encrypted = project_root.join(file.get_encrypted_destination());
+        let current = match blob_hash_of_file(&encrypted) {
+            Ok(current) => current,
+            Err(_) => continue,
+        };
+
+        let mut finding = |problem: String| {
+            findings.push(ValidationFinding {
+                owner: owner_of(file).to_string(),
+                kind: "Encrypted files changed by hand",
+                message: format!("{} {}", file.get_encrypted_destination(), problem),
+            })
+        };
+
+        let applied = state.files.get(&file.state_key()).filter(|applied| {
+            applied.pinned_hash.as_deref() == Some(configuration.pinned_hash.as_str())
+        });
+        if let Some(applied_blob) = applied.and_then(|applied| applied.encrypted_blob.as_deref()) {
+            if applied_blob != current {
+                finding(format!(
+                    "was {} when it was last applied at {}, but is now {}",
+                    display_hash(applied_blob),
+                    display_hash(&configuration.pinned_hash),
+                    display_hash(&current)
+                ));
+            }
+        }
+
+        if let Some(committed) = committed.get(&file.get_encrypted_destination()) {
+            if *committed != current {
+                finding(format!(
+                    "is {}, but {} is committed with the same pin",
+                    display_hash(&current),
+                    display_hash(committed)
+                ));
+            }
+        }
+    }
+
+    findings
+}
+
+/// Checks the raw `.configure` file against the published schema, before it's deserialized
+pub fn check_configuration_schema() -> Result<(), ConfigureError> {
+    let path = find_configure_file()?;
+    if !path.exists() {
+        return Ok(());
+    }
+    let contents = std::fs::read_to_string(&path)?;
+
+    // Invalid JSON is reported in more detail when the configuration is read
+    let document: serde_json::Value = match serde_json::from_str(&contents) {
+        Ok(document) => document,
+        Err(_) => return Ok(()),
+    };
+
+    let violations = validate_configuration_schema(&document);
+
+    if violations.is_empty() {
+        debug!("{:?} matches the schema", path);
+        return Ok(());
+    }
+
+    heading(&format!("{:?} doesn't match the schema", path));
+    for violation in &violations {
+        print(&format!("  {}", violation));
+    }
+
+    Err(ConfigureError::ConfigurationHasProblems(violations.len()))
+}
+
+/// Prints the entries in `files_to_copy`, grouped by owner
+pub fn list_configuration(configuration: ConfigurationFile) {
+    let groups = group_by_owner(
+        configuration
+            .files_to_copy
+            .iter()
+            .map(|file| (owner_of(file), file)),
+    );
+
+    for (owner, files) in groups {
+        heading(&owner);
+        for file in files {
+            let source = configuration.source_path(file);
+            let mut line = format!("  {} (from {})", file.destination, source);
+            if !file.platforms.is_empty() {
+                line += &format!(" [{} only]", file.platforms.join(", "));
+            }
+            if let Some(description) = &file.description {
+                line += &format!(" – {}", description);
+            }
+            print(&line);
+        }
+    }
+}
+
+/// Prints whether each entry in `files_to_copy` has been applied, grouped by owner
+pub fn status_configuration(
+    configuration: ConfigurationFile,
+    options: &StatusOptions,
+) -> Result<(), ConfigureError> {
+    configuration.completeness().ensure_complete()?;
+    let project_root = find_project_root()?;
+    let state = read_apply_state(&project_root);
+
+    let statuses = configuration
+        .files_to_copy
+        .iter()
+        .map(|file| {
+            let status = file_status(&project_root, &state, file)?;
+            Ok((owner_of(file), (file, status)))
+        })
+        .collect::<Result<Vec<_>, ConfigureError>>()?;
+    let groups = group_by_owner(statuses);
+
+    for (owner, files) in groups {
+        heading(&owner);
+        for (file, status) in files {
+            print(&format!("  {}: {}", file.destination, status));
+        }
+    }
+
+    if options.check_freshness {
+        let check = check_pin_freshness(&configuration)?;
+        for problem in &check.problems {
+            warn(problem);
+        }
+
+        if !check.problems.is_empty() && !check.offline {
+            return Err(ConfigureError::PinIsStale);
+        }
+    }
+
+    Ok(())
+}
+
+/// Runs `executable` through a whole setup against throwaway fixtures, listing how each stage went
+pub fn self_test_configuration(executable: &Path) -> Result<(), ConfigureError> {
+    heading("Configure Self-Test");
+
+    let results = run_self_test(executable)?;
+    for result in &results {
+        match &result.failure {
+            None => print_in(Color::Green, &format!("✓ {}", result.stage)),
+            Some(failure) => {
+                print_in(Color::Red, &format!("✗ {}", result.stage));
+                print(failure);
+            }
+        }
+    }
+
+    match results.iter().find(|result| result.failure.is_some()) {
+        Some(result) => Err(ConfigureError::SelfTestFailed(result.stage.to_string())),
+        None => Ok(()),
+    }
+}
+
+/// Lists how each decrypted file the apply would write compares with what was last applied to it,
+/// without changing anything. Fails with `DriftDetected` if any were edited or removed.
+pub fn report_drift_configuration(
+    configuration: ConfigurationFile,
+    options: &ApplyOptions,
+) -> Result<(), ConfigureError> {
+    configuration.ensure_initialized()?;
+
+    let configuration = select_requested_files(configuration, options)?;
+    let os = target_platform(options.target.as_deref())?;
+    let configuration = select_platform_files(configuration, &os);
+    let configuration = select_variant_files(configuration, options)?;
+
+    let project_root = find_project_root()?;
+    let state = read_apply_state(&project_root);
+
+    let drifts = configuration
+        .files_to_copy
+        .iter()
+        .map(|file| {
+            let drift = file_drift(&project_root, &state, file)?;
+            Ok((owner_of(file), (file, drift)))
+        })
+        .collect::<Result<Vec<_>, ConfigureError>>()?;
+    let groups = group_by_owner(drifts);
+
+    let mut drifted = 0;
+    for (owner, files) in groups {
+        heading(&owner);
+        for (file, drift) in files {
+            print(&format!("  {}: {}", file.destination, drift));
+            if drift.has_drifted() {
+                drifted += 1;
+            }
+        }
+    }
+
+    match drifted {
+        0 => Ok(()),
+        drifted => Err(ConfigureError::DriftDetected(drifted)),
+    }
+}
+
+/// Prints where each of several projects' pins stand against the latest secrets. A project that
+/// can't be read is listed with the reason, rather than stopping the whole summary.
+pub fn fleet_status_configuration(options: &StatusOptions) -> Result<(), ConfigureError> {
+    let projects = fleet_projects(&options.projects, &options.manifest, &options.all)?;
+
+    // The projects share the secrets repository, so it's only fetched once
+    if let Err(err) = fetch_secrets_remote(DEFAULT_REMOTE, |_| {}) {
+        warn(&format!(
+            "Unable to fetch the latest secrets, so projects may be further behind than shown: {}",
+            err
+        ));
+    }
+
+    let statuses: Vec<FleetProjectStatus> = projects
+        .iter()
+        .map(|project_root| {
+            let mut status = FleetProjectStatus {
+                project: project_root.display().to_string(),
+                ..Default::default()
+            };
+
+            if let Err(err) = read_fleet_project_status(project_root, &mut status) {
+                status.error = Some(err.to_string());
+            }
+
+            status
+        })
+        .collect();
+
+    match options.output {
+        OutputFormat::Text => {
+            for line in format_fleet_table(&statuses, Utc::now()) {
+                print(&line);
+            }
+        }
+        OutputFormat::Json => print(&serde_json::to_string_pretty(&statuses).unwrap()),
+    }
+
+    Ok(())
+}
+
+/// The projects given as paths, listed in `manifest`, and found under `all`'s root, in that order
+fn fleet_projects(
+    projects: &[PathBuf],
+    manifest: &Option<PathBuf>,
+    all: &Option<Vec<PathBuf>>,
+) -> Result<Vec<PathBuf>, ConfigureError> {
+    let mut projects = projects.to_vec();
+    if let Some(manifest) = manifest {
+        projects.extend(read_fleet_manifest(manifest)?);
+    }
+    if let Some(root) = all {
+        let root = root.first().cloned().unwrap_or_else(|| PathBuf::from("."));
+        match find_projects_under(&root) {
+            Ok(found) => projects.extend(found),
+            Err(err) => return Err(ConfigureError::ProjectsRootUnreadable(root, err)),
+        }
+    }
+
+    match projects.is_empty() {
+        true => Err(ConfigureError::NoFleetProjects),
+        false => Ok(projects),
+    }
+}
+
+/// Prints which of several projects use secrets that changed in the secrets repository since
+/// `--since`. It only reads the secrets repository's history and the projects' `.configure`, so
+/// it needs no keys and changes nothing.
+pub fn affected_configuration(options: &AffectedOptions) -> Result<(), ConfigureError> {
+    let secrets_repo = options.secrets_repo.clone().unwrap_or_else(|| PathBuf::from("."));
+    let changed = changed_paths_since(&secrets_repo, &options.since)?;
+    let projects = fleet_projects(&options.projects, &options.manifest, &options.all)?;
+
+    let projects: Vec<AffectedProject> = projects
+        .iter()
+        .map(|project_root| {
+            let mut project = AffectedProject {
+                project: project_root.display().to_string(),
+                ..Default::default()
+            };
+
+            match read_configuration_at(&project_root.join(".configure")) {
+                Ok(configuration) => {
+                    project.sources = changed_sources(&configuration, &changed);
+                    project.project_name = Some(configuration.project_name);
+                    project.branch = Some(configuration.branch);
+                    project.pinned_hash = Some(configuration.pinned_hash);
+                }
+                Err(err) => project.error = Some(err.to_string()),
+            }
+
+            project
+        })
+        .collect();
+
+    match options.output {
+        OutputFormat::Text => {
+            for line in format_affected(&projects) {
+                print(&line);
+            }
+        }
+        OutputFormat::Json => print(&serde_json::to_string_pretty(&projects).unwrap()),
+    }
+
+    Ok(())
+}
+
+/// Fills in as much of a project's fleet summary as possible
+fn read_fleet_project_status(
+    project_root: &Path,
+    status: &mut FleetProjectStatus,
+) -> Result<(), ConfigureError> {
+    let configuration = read_configuration_at(&project_root.join(".configure"))?;
+    configuration.completeness().ensure_complete()?;
+
+    let rfc3339 = |time: DateTime<Utc>| time.to_rfc3339();
+    let branch = &configuration.branch;
+    let pinned_hash = &configuration.pinned_hash;
+    status.branch = Some(branch.clone());
+    status.pinned_hash = Some(pinned_hash.clone());
+
+    status.last_applied = std::fs::metadata(state_file_path(project_root))
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .map(|modified| rfc3339(modified.into()));
+
+    status.pinned_at = secrets_commit_time(pinned_hash)
+        .ok()
+        .map(|time| rfc3339(Utc.timestamp(time.seconds(), 0)));
+
+    let latest_hash = match get_latest_hash_for_remote_branch(branch) {
+        Ok(hash) => hash,
+        Err(_) => get_branch_tip(branch)?,
+    };
+
+    match secrets_repo_distance_between(pinned_hash, &latest_hash)? {
+        CommitDistance::Same => status.commits_behind = Some(0),
+        CommitDistance::BehindBy(count) => status.commits_behind = Some(count),
+        CommitDistance::AheadBy(count) => {
+            status.error = Some(format!(
+                "pinned {} commit(s) past the latest secrets on {}",
+                count, branch
+            ))
+        }
+        CommitDistance::Unrelated => {
+            status.error = Some(format!("pinned to a commit that isn't on {}", branch))
+        }
+    }
+
+    Ok(())
+}
+
+/// Which way a git filter transforms a secret
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum FilterDirection {
+    /// Encrypted contents in, plaintext out
+    Smudge,
+
+    /// Plaintext in, encrypted contents out
+    Clean,
+}
+
+/// Transforms one secret from stdin to stdout, for use as a git filter. Nothing else is written to
+/// stdout, since git takes all of it as the file's contents.
+pub fn filter_configuration(
+    configuration: ConfigurationFile,
+    options: &FilterOptions,
+    direction: FilterDirection,
+) -> Result<(), ConfigureError> {
+    use std::io::{Read, Write};
+
+    let file = configuration
+        .files_to_copy
+        .iter()
+        .find(|file| {
+            file.destination == options.destination
+                || file.get_encrypted_destination() == options.destination
+        })
+        .ok_or_else(|| ConfigureError::UnknownDestination(options.destination.clone()))?;
+    let encryption_key = require_encryption_key(&configuration)?;
+
+    let mut input = Vec::new();
+    std::io::stdin().read_to_end(&mut input)?;
+
+    let output = match direction {
+        FilterDirection::Smudge => smudge_contents(file, &input, &encryption_key)?,
+        FilterDirection::Clean => {
+            let existing = find_project_root()
+                .ok()
+                .and_then(|root| std::fs::read(root.join(file.get_encrypted_destination())).ok());
+            clean_contents(file, &input, existing.as_deref(), &encryption_key)
+        }
+    };
+
+    let mut stdout = std::io::stdout();
+    stdout.write_all(&output)?;
+    stdout.flush()?;
+
+    Ok(())
+}
+
+/// Prints everything `.configure` says about one destination
+pub fn explain_configuration(
+    configuration: ConfigurationFile,
+    options: &ExplainOptions,
+) -> Result<(), ConfigureError> {
+    let file = configuration
+        .files_to_copy
+        .iter()
+        .find(|file| file.destination == options.destination)
+        .ok_or_else(|| ConfigureError::UnknownDestination(options.destination.clone()))?;
+
+    let project_root = find_project_root()?;
+    let state = read_apply_state(&project_root);
+
+    heading(&file.destination);
+    let source = configuration.source_path(file);
+    if source == file.source {
+        print(&format!("  Source:    {}", source));
+    } else {
+        print(&format!("  Source:    {} ({})", file.source, source));
+    }
+    print(&format!("  Encrypted: {}", file.get_encrypted_destination()));
+    print(&format!("  Owner:     {}", file.owner.as_deref().unwrap_or("nobody")));
+    if let Some(description) = &file.description {
+        print(&format!("  About:     {}", description));
+    }
+    if let Some(format) = &file.format {
+        print(&format!("  Format:    {}", format));
+    }
+    print(&format!("  Status:    {}", file_status(&project_root, &state, file)?));
+    if let Some(pinned_hash) = state
+        .files
+        .get(&file.state_key())
+        .and_then(|applied| applied.pinned_hash.as_deref())
+    {
+        print(&format!("  Applied:   at {}", display_hash(pinned_hash)));
+    }
+
+    Ok(())
+}
+
+pub fn setup_configuration(
+    mut configuration: ConfigurationFile,
+    template: Option<Template>,
+    create_key: bool,
+) -> Result<(), ConfigureError> {
+    heading("Configure Setup");
+    print("Let's get configuration set up for this project.");
+    newline();
+
+    let completeness = configuration.completeness();
+
+    // Help the user set the `project_name` field
+    if completeness.is_missing(RequiredField::ProjectName) {
+        configuration = prompt_for_project_name(configuration)?;
+    }
+
+    // Help the user set the `branch` field
+    configuration = prompt_for_branch(configuration, true)?;
+
+    // Set the latest automatically hash based on the selected branch
+    if completeness.is_missing(RequiredField::PinnedHash) {
+        configuration = set_latest_hash(configuration)?;
+    }
+
+    // Start with the template's files, if there is one
+    if let Some(template) = template {
+        configuration = add_files_from_template(configuration, &template)?;
+    }
+
+    // Help the user add files
+    configuration = prompt_to_add_files(configuration)?;
+
+    // Without these, the file would be no more use than none at all
+    let completeness = configuration.completeness();
+    if completeness.is_missing(RequiredField::ProjectName)
+        || completeness.is_missing(RequiredField::Branch)
+    {
+        warn("Not writing .configure, since it still needs a project name and a branch");
+        return Ok(());
+    }
+
+    info!("Writing changes to .configure");
+    save_configuration(&configuration)?;
+
+    // Create a key in `keys.json` for the project if one doesn't already exist
+    if !create_key {
+        info!(
+            "Not creating a key – keys.json needs one for {:?} before secrets can be encrypted",
+            configuration.project_name
+        );
+    } else if read_encryption_key(&configuration)?.is_none() {
+        generate_encryption_key(&configuration)?;
+    }
+
+    Ok(())
+}
+
+fn prompt_for_project_name(
+    mut configuration: ConfigurationFile,
+) -> Result<ConfigurationFile, ConfigureError> {
+    let mut project_name = ask(Interaction::ProjectName)?.text()?.trim().to_string();
+
+    if let Some(problem) = project_name_problem(&project_name) {
+        let sanitized = sanitized_key_name(&project_name);
+        warn(&format!(
+            "{:?} {}, so using {:?} instead",
+            project_name, problem, sanitized
+        ));
+        project_name = sanitized;
+    }
+
+    configuration.project_name = project_name.clone();
+    print(&format!("Project Name set to: {:?}", project_name));
+
+    Ok(configuration)
+}
+
+fn prompt_for_branch(
+    mut configuration: ConfigurationFile,
+    force: bool,
+) -> Result<ConfigurationFile, ConfigureError> {
+    // If there's already a valid branch set, don't bother updating it
+    if !configuration.completeness().is_missing(RequiredField::Branch) && !force {
+        if branch_exists(&configuration.branch) {
+            return Ok(configuration);
+        }
+
+        warn(&format!(
+            "The secrets branch {:?} doesn't exist – please choose another one",
+            configuration.branch
+        ));
+    }
+
+    let secrets_repo_path = find_secrets_repo()?;
+    let current_branch = get_current_secrets_branch()?;
+    let branches = get_secrets_branches()?;
+
+    print(&format!(
+        "We've found your secrets repository at {}",
+        secrets_repo_path.display()
+    ));
+    newline();
+    print("Which branch would you like to use?");
+    print(&format!("Current Branch: {}", style(&current_branch).green()));
+
+    // A branch that's already set and exists can be kept as it is
+    let keep = Some(configuration.branch.clone())
+        .filter(|branch| !branch.is_empty() && branches.contains(branch));
+
+    let choice = match ask(Interaction::ChooseBranch {
+        options: branches.clone(),
+        current: current_branch,
+        keep,
+    })? {
+        Response::Cancelled => {
+            print(&format!("Keeping the secrets repo branch {:?}", configuration.branch));
+            return Ok(configuration);
+        }
+        response => response.choice_of(branches.len())?,
+    };
+    let selected_branch = branches[choice].clone();
+
+    configuration.branch = selected_branch.clone();
+    print(&format!("Secrets repo branch set to: {:?}", selected_branch));
+
+    Ok(configuration)
+}
+
+/// How the user wants to pin the secrets after switching branches in `update`
+#[derive(Debug, Eq, PartialEq)]
+enum PinChoice {
+    Latest,
+    Specific(String),
+}
+
+/// The commit `update` pins. The current pin is only kept when the branch didn't change – after a
+/// switch it may not be on the new branch, so `choose_pin` is asked for a commit that is.
+fn choose_pinned_hash(
+    current: &str,
+    branch_changed: bool,
+    use_latest: bool,
+    latest: &str,
+    choose_pin: impl FnOnce() -> Result<PinChoice, ConfigureError>,
+) -> Result<String, ConfigureError> {
+    if use_latest {
+        return Ok(latest.to_string());
+    }
+
+    if !branch_changed {
+        return Ok(current.to_string());
+    }
+
+    match choose_pin()? {
+        PinChoice::Latest => Ok(latest.to_string()),
+        PinChoice::Specific(hash) => Ok(hash),
+    }
+}
+
+fn prompt_for_pin(branch: &str, latest: &str) -> Result<PinChoice, ConfigureError> {
+    if !is_interactive() {
+        return Ok(PinChoice::Latest);
+    }
+
+    let choice = ask(Interaction::ChoosePin {
+        branch: branch.to_string(),
+        latest: latest.to_string(),
+    })?
+    .choice()?;
+
+    match choice {
+        0 => Ok(PinChoice::Latest),
+        _ => Ok(PinChoice::Specific(ask(Interaction::PinHash)?.text()?.trim().to_string())),
+    }
+}
+
+fn set_latest_hash(
+    mut configuration: ConfigurationFile,
+) -> Result<ConfigurationFile, ConfigureError> {
+    configuration.pinned_hash = get_secrets_latest_hash(&configuration.branch)?;
+
+    Ok(configuration)
+}
+
+fn add_files_from_template(
+    mut configuration: ConfigurationFile,
+    template: &Template,
+) -> Result<ConfigurationFile, ConfigureError> {
+    let secrets_root = find_secrets_repo()?;
+
+    // Don't duplicate entries that are already configured
+    let files: Vec<File> = template
+        .files_for_project(&sanitized_key_name(&configuration.project_name))
+        .into_iter()
+        .filter(|file| {
+            !configuration
+                .files_to_copy
+                .iter()
+                .any(|existing| existing.destination == file.destination)
+        })
+        .collect();
+
+    if files.is_empty() {
+        return Ok(configuration);
+    }
+
+    let labels: Vec<String> = files
+        .iter()
+        .map(|file| {
+            let label = format!("{} → {}", file.source, file.destination);
+
+            if secrets_root.join(configuration.source_path(file)).exists() {
+                label
+            } else {
+                format!("{} (not in the secrets repository yet)", label)
+            }
+        })
+        .collect();
+
+    let selected = if is_interactive() {
+        ask(Interaction::ChooseTemplateFiles {
+            files: labels.clone(),
+        })?
+        .choices()?
+    } else {
+        (0..files.len()).collect()
+    };
+
+    for (index, file) in files.into_iter().enumerate() {
+        if selected.contains(&index) {
+            print(&format!("Adding {}", labels[index]));
+            configuration.files_to_copy.push(file);
+        }
+    }
+
+    Ok(configuration)
+}
+
+fn prompt_to_add_files(
+    mut configuration: ConfigurationFile,
+) -> Result<ConfigurationFile, ConfigureError> {
+    let mut files = std::mem::take(&mut configuration.files_to_copy);
+
+    let has_files = !files.is_empty();
+
+    while ask(Interaction::AddFile { has_files })?.confirmed()? {
+        match prompt_to_add_file(&configuration)? {
+            Some(file) => files.push(file),
+            None => continue,
+        }
+    }
+
+    configuration.files_to_copy = files;
+
+    Ok(configuration)
+}
+
+fn prompt_to_add_file(configuration: &ConfigurationFile) -> Result<Option<File>, ConfigureError> {
+    let relative_source_file_path = ask(Interaction::FileSource)?.text()?;
+
+    let secrets_root = match find_secrets_repo() {
+        Ok(repo_path) => repo_path,
+        Err(_) => return Ok(None),
+    };
+
+    let full_source_file_path =
+        secrets_root.join(configuration.resolve_source(&relative_source_file_path));
+
+    if !full_source_file_path.exists() {
+        print(&format!("Source File does not exist: {:?}", full_source_file_path));
+        return Ok(None);
+    }
+
+    let relative_destination_file_path = ask(Interaction::FileDestination)?.text()?;
+
+    let project_root = match find_project_root() {
+        Ok(project_root) => project_root,
+        Err(_) => return Ok(None),
+    };
+    let full_destination_file_path = project_root.join(&relative_destination_file_path);
+
+    debug!("Destination: {:?}", full_destination_file_path);
+
+    Ok(Some(File {
+        source: configuration.shorthand_source(&relative_source_file_path),
+        destination: relative_destination_file_path,
+        ..Default::default()
+    }))
+}
+
+fn configure_file_distance_behind_secrets_repo(
+    configuration: &ConfigurationFile,
+    branch_name: &str,
+) -> Result<CommitDistance, ConfigureError> {
+    debug!("Checking if configure file is behind secrets repo");
+
+    let start = std::time::Instant::now();
+
+    // Reading the branch tip directly means we don't need to switch branches (and back again)
+    let latest_hash = get_branch_tip(branch_name)?;
+    debug!("Latest hash on {:?} is: {:?}", branch_name, latest_hash);
+
+    let distance = secrets_repo_distance_between(&configuration.pinned_hash, &latest_hash)?;
+
+    debug!("Computed distance behind secrets repo in {:?}", start.elapsed());
+
+    Ok(distance)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn configuration(files: &[(&str, Option<&str>)]) -> ConfigurationFile {
+        ConfigurationFile {
+            files_to_copy: files
+                .iter()
+                .map(|(destination, variant)| File {
+                    source: destination.to_string(),
+                    destination: destination.to_string(),
+                    variant: variant.map(String::from),
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn destinations(configuration: ConfigurationFile) -> Vec<String> {
+        configuration
+            .files_to_copy
+            .into_iter()
+            .map(|file| file.destination)
+            .collect()
+    }
+
+    /// A `.configure` as older versions left it when `init` was interrupted partway
+    fn parse_partial(contents: &str) -> ConfigurationFile {
+        crate::fs::parse_configuration(".configure", contents, false).unwrap()
+    }
+
+    #[test]
+    fn test_platform_files_are_filtered_by_os() {
+        let file = |destination: &str, platforms: &[&str]| File {
+            source: destination.to_string(),
+            destination: destination.to_string(),
+            platforms: platforms.iter().map(|p| p.to_string()).collect(),
+            ..Default::default()
+        };
+        let configuration = ConfigurationFile {
+            files_to_copy: vec![
+                file("everywhere.json", &[]),
+                file("mac.json", &["macos"]),
+                file("unix.json", &["macOS", "linux"]),
+            ],
+            ..Default::default()
+        };
+
+        let destinations = |os: &str| -> Vec<String> {
+            select_platform_files(configuration.clone(), os)
+                .files_to_copy
+                .into_iter()
+                .map(|file| file.destination)
+                .collect()
+        };
+
+        assert_eq!(
+            destinations("macos"),
+            vec!["everywhere.json", "mac.json", "unix.json"]
+        );
+        assert_eq!(destinations("linux"), vec!["everywhere.json", "unix.json"]);
+        assert_eq!(destinations("windows"), vec!["everywhere.json"]);
+    }
+
+    #[test]
+    fn test_targets_name_a_known_platform() {
+        assert_eq!(target_platform(None).unwrap(), std::env::consts::OS);
+        assert_eq!(target_platform(Some("ios")).unwrap(), "ios");
+        assert_eq!(target_platform(Some("iOS/aarch64")).unwrap(), "ios");
+
+        for typo in &["iso", "ios/arm65", "ios/", "/aarch64"] {
+            assert!(matches!(
+                target_platform(Some(typo)),
+                Err(ConfigureError::UnknownTarget(target)) if target == *typo
+            ));
+        }
+    }
+
+    #[test]
+    fn test_environment_lines_are_quoted_for_the_shell() {
+        assert_eq!(
+            environment_line("CONFIGURE_BRANCH", "release/1.0"),
+            "CONFIGURE_BRANCH=release/1.0"
+        );
+        assert_eq!(environment_line("CONFIGURE_VARIANT", ""), "CONFIGURE_VARIANT=");
+        assert_eq!(
+            environment_line("CONFIGURE_PROJECT_ROOT", "/Users/me/My Project"),
+            "CONFIGURE_PROJECT_ROOT='/Users/me/My Project'"
+        );
+        assert_eq!(
+            environment_line("CONFIGURE_PROJECT_NAME", "Jane's App"),
+            "CONFIGURE_PROJECT_NAME='Jane'\\''s App'"
+        );
+    }
+
+    #[test]
+    fn test_merge_overrides_fields_set_in_the_overlay() {
+        let mut base = ConfigurationFile {
+            project_name: "WordPress".to_string(),
+            branch: "trunk".to_string(),
+            pinned_hash: "abc123".to_string(),
+            max_file_size: Some(1024),
+            placeholder: Some("{}".to_string()),
+            read_only: true,
+            ..Default::default()
+        };
+
+        base.merge(ConfigurationFile {
+            project_name: "".to_string(),
+            branch: " ".to_string(),
+            pinned_hash: "def456".to_string(),
+            placeholder: Some("".to_string()),
+            on_conflict: Some(OnConflict::Keep),
+            ..Default::default()
+        });
+
+        assert_eq!(
+            base,
+            ConfigurationFile {
+                project_name: "WordPress".to_string(),
+                branch: "trunk".to_string(),
+                pinned_hash: "def456".to_string(),
+                max_file_size: Some(1024),
+                placeholder: Some("".to_string()),
+                read_only: true,
+                on_conflict: Some(OnConflict::Keep),
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_shorthand_sources_are_relative_to_the_source_root() {
+        let mut configuration = ConfigurationFile {
+            project_name: "WordPress".to_string(),
+            ..Default::default()
+        };
+
+        assert_eq!(configuration.resolve_source("./a.json"), "WordPress/a.json");
+        assert_eq!(configuration.resolve_source("Shared/a.json"), "Shared/a.json");
+        assert_eq!(configuration.shorthand_source("WordPress/iOS/a.json"), "./iOS/a.json");
+        assert_eq!(configuration.shorthand_source("./a.json"), "./a.json");
+        assert_eq!(configuration.shorthand_source("WordPressKit/a.json"), "WordPressKit/a.json");
+
+        configuration.source_root = Some("apps/wordpress/".to_string());
+        assert_eq!(configuration.resolve_source("./a.json"), "apps/wordpress/a.json");
+        assert_eq!(configuration.shorthand_source("WordPress/a.json"), "WordPress/a.json");
+        assert_eq!(configuration.shorthand_source("apps/wordpress/a.json"), "./a.json");
+
+        configuration.source_root = Some("".to_string());
+        assert_eq!(configuration.resolve_source("./a.json"), "a.json");
+        assert_eq!(configuration.shorthand_source("a.json"), "a.json");
+    }
+
+    #[test]
+    fn test_unusual_project_names_are_reported_and_sanitized() {
+        let cases = [
+            ("WordPress-iOS", None, "WordPress-iOS"),
+            ("apps/reader (beta)", Some("contains '/'"), "apps-reader-beta"),
+            ("Day One", Some("contains ' '"), "Day-One"),
+            ("Café Crème", Some("contains 'é'"), "Caf-Cr-me"),
+            ("  Simplenote\n", Some("has leading or trailing whitespace"), "Simplenote"),
+            (".hidden", Some("has to start with a letter or digit"), "hidden"),
+            ("日本", Some("contains '日'"), "project"),
+        ];
+
+        for (name, problem, sanitized) in &cases {
+            let found = project_name_problem(name);
+            assert_eq!(
+                found.as_deref().map(|found| &found[..problem.map_or(0, str::len)]),
+                *problem,
+                "{:?}",
+                name
+            );
+            assert_eq!(sanitized_key_name(name), *sanitized, "{:?}", name);
+            assert_eq!(project_name_problem(&sanitized_key_name(name)), None);
+        }
+
+        let long = "a".repeat(MAX_PROJECT_NAME_LENGTH + 1);
+        assert_eq!(
+            project_name_problem(&long).unwrap(),
+            "is longer than 64 characters"
+        );
+        assert_eq!(sanitized_key_name(&long).len(), MAX_PROJECT_NAME_LENGTH);
+
+        // The source root follows the sanitized name, so it's always a plain directory
+        let configuration = ConfigurationFile {
+            project_name: "apps/reader (beta)".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(configuration.resolve_source("./a.json"), "apps-reader-beta/a.json");
+    }
+
+    #[test]
+    fn test_pin_staleness_is_only_reported_past_the_limits() {
+        let configuration = ConfigurationFile {
+            branch: "trunk".to_string(),
+            max_age_days: Some(30),
+            max_commits_behind: Some(50),
+            ..Default::default()
+        };
+
+        let behind = CommitDistance::BehindBy;
+        assert_eq!(describe_pin_staleness(&configuration, 30, behind(50)), Vec::<String>::new());
+        assert_eq!(
+            describe_pin_staleness(&configuration, 45, behind(51)),
+            vec![
+                "The pinned commit is 45 days old, but `max_age_days` is 30 – run `configure update` to move it to the latest secrets",
+                "The pin is 51 commits behind trunk, but `max_commits_behind` is 50 – run `configure update` to move it to the latest secrets",
+            ]
+        );
+
+        // A pin that's off the branch can't be measured, so it's a problem too
+        let ahead = describe_pin_staleness(&configuration, 0, CommitDistance::AheadBy(2));
+        assert_eq!(ahead.len(), 1);
+        assert!(ahead[0].starts_with("The pin is 2 commit(s) past the latest secrets on trunk"));
+        let unrelated = describe_pin_staleness(&configuration, 0, CommitDistance::Unrelated);
+        assert_eq!(unrelated.len(), 1);
+        assert!(unrelated[0].starts_with("The pinned commit isn't part of the history of trunk"));
+
+        let unlimited = ConfigurationFile::default();
+        for distance in [behind(1000), CommitDistance::Unrelated] {
+            assert_eq!(describe_pin_staleness(&unlimited, 1000, distance), Vec::<String>::new());
+        }
+    }
+
+    #[test]
+    fn test_merge_unions_files_by_destination_and_variant() {
+        let mut base = configuration(&[
+            ("a.json", None),
+            ("b.json", Some("debug")),
+            ("b.json", Some("release")),
+            ("c.json", None),
+            ("a.json", None),
+        ]);
+
+        let mut overlay = configuration(&[
+            ("d.json", None),
+            ("a.json", None),
+            ("b.json", Some("release")),
+            ("d.json", None),
+        ]);
+        overlay.files_to_copy[1].source = "overlay/a.json".to_string();
+        overlay.files_to_copy[3].source = "overlay/d.json".to_string();
+
+        base.merge(overlay);
+
+        let files: Vec<(&str, Option<&str>, &str)> = base
+            .files_to_copy
+            .iter()
+            .map(|file| {
+                (
+                    file.destination.as_str(),
+                    file.variant.as_deref(),
+                    file.source.as_str(),
+                )
+            })
+            .collect();
+
+        assert_eq!(
+            files,
+            vec![
+                ("a.json", None, "overlay/a.json"),
+                ("b.json", Some("debug"), "b.json"),
+                ("b.json", Some("release"), "b.json"),
+                ("c.json", None, "c.json"),
+                ("d.json", None, "overlay/d.json"),
+            ]
+        );
+    }
+
+    fn rename_sandbox() -> crate::fixtures::Sandbox {
+        let key = crate::encryption::generate_key().to_base64();
+        let sandbox = crate::fixtures::Sandbox::new(&serde_json::json!({ "OldApp": key }).to_string());
+        crate::fixtures::commit_file(&sandbox.secrets, "OldApp/a.json", "{}", "Add a secret");
+
+        let configuration = ConfigurationFile {
+            project_name: "OldApp".to_string(),
+            branch: "main".to_string(),
+            pinned_hash: "abc123".to_string(),
+            files_to_copy: vec![File {
+                source: "OldApp/a.json".to_string(),
+                destination: "a.json".to_string(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        save_configuration(&configuration).unwrap();
+
+        // Agree to remove the old key
+        crate::interaction::set_interaction_handler(Some(Box::new(|interaction| {
+            match interaction {
+                Interaction::RemoveOldKey { .. } => Response::Confirmed(true),
+                other => panic!("Unexpected question {:?}", other),
+            }
+        })));
+
+        sandbox
+    }
+
+    #[test]
+    fn test_rename_moves_the_project_and_removes_the_old_key_last() {
+        let sandbox = rename_sandbox();
+        let options = RenameOptions {
+            new_name: "NewApp".to_string(),
+            also_move_secrets_dir: true,
+            ..Default::default()
+        };
+
+        rename_project_configuration(read_configuration().unwrap(), &options).unwrap();
+
+        let configuration = read_configuration().unwrap();
+        assert_eq!(configuration.project_name, "NewApp");
+        assert_eq!(configuration.files_to_copy[0].source, "NewApp/a.json");
+        assert!(sandbox.secrets_path("NewApp/a.json").exists());
+
+        let keys = read_keys().unwrap();
+        assert!(keys.contains_key("NewApp"));
+        assert!(!keys.contains_key("OldApp"));
+    }
+
+    #[test]
+    fn test_rename_keeps_the_old_key_if_moving_the_secrets_fails() {
+        let sandbox = rename_sandbox();
+        std::fs::create_dir_all(sandbox.secrets_path("NewApp")).unwrap();
+        let options = RenameOptions {
+            new_name: "NewApp".to_string(),
+            also_move_secrets_dir: true,
+            ..Default::default()
+        };
+
+        assert!(rename_project_configuration(read_configuration().unwrap(), &options).is_err());
+
+        // `.configure` has the new name, and both names still have a key
+        let configuration = read_configuration_at(&sandbox.project.path().join(".configure"));
+        assert_eq!(configuration.unwrap().project_name, "NewApp");
+        let keys = read_keys().unwrap();
+        assert_eq!(keys["OldApp"], keys["NewApp"]);
+    }
+
+    #[test]
+    fn test_completeness_lists_blank_required_fields() {
+        let configuration = parse_partial(
+            r#"{ "project_name": "WordPress", "branch": "", "pinned_hash": "",
+                "files_to_copy": [] }"#,
+        );
+        let completeness = configuration.completeness();
+
+        assert_eq!(
+            completeness.missing,
+            vec![RequiredField::Branch, RequiredField::PinnedHash]
+        );
+        assert!(!completeness.is_missing(RequiredField::ProjectName));
+        assert_eq!(
+            completeness.ensure_complete().unwrap_err().to_string(),
+            "Your .configure is missing: branch, pinned_hash – run `configure init` to fill it in"
+        );
+    }
+
+    #[test]
+    fn test_many_findings_are_summarized_by_kind() {
+        let finding = |kind: &'static str, index: usize| ValidationFinding {
+            owner: UNOWNED.to_string(),
+            kind,
+            message: format!("{}.json: {}", index, kind),
+        };
+        let mut findings: Vec<ValidationFinding> =
+            (0..30).map(|index| finding("Missing sources", index)).collect();
+        findings.insert(3, finding("Duplicate entries", 30));
+
+        let groups = findings_by_kind(&findings, false);
+        let headings: Vec<&str> = groups.iter().map(|(heading, _)| heading.as_str()).collect();
+        assert_eq!(headings, vec!["Missing sources (30)", "Duplicate entries (1)"]);
+        assert_eq!(groups[0].1.len(), FINDINGS_SHOWN_PER_KIND + 1);
+        assert_eq!(groups[0].1[0], "0.json: Missing sources");
+        assert_eq!(
+            groups[0].1.last().unwrap(),
+            "…and 25 more – pass --verbose to list them all"
+        );
+        assert_eq!(groups[1].1, vec!["30.json: Duplicate entries"]);
+
+        assert_eq!(findings_by_kind(&findings, true)[0].1.len(), 30);
+    }
+
+    fn key_age_sandbox() -> crate::fixtures::Sandbox {
+        let key = crate::encryption::generate_key().to_base64();
+        let keys = serde_json::json!({ "WordPress": key, "Simplenote": key });
+        let sandbox = crate::fixtures::Sandbox::new(&keys.to_string());
+
+        let created = Utc.ymd(2026, 1, 1).and_hms(0, 0, 0);
+        let metadata = serde_json::json!({ "WordPress": KeyMetadata::created(created) });
+        std::fs::write(sandbox.secrets_path(KEY_METADATA_FILE_NAME), metadata.to_string()).unwrap();
+
+        sandbox
+    }
+
+    #[test]
+    fn test_key_ages_are_listed_without_the_keys() {
+        let _sandbox = key_age_sandbox();
+        let now = Utc.ymd(2026, 1, 31).and_hms(0, 0, 0);
+
+        assert_eq!(
+            describe_key_ages(now).unwrap(),
+            vec!["WordPress   30 days old", "Simplenote  age unknown"]
+        );
+    }
+
+    #[test]
+    fn test_an_old_key_is_only_a_problem_past_the_maximum_age() {
+        let _sandbox = key_age_sandbox();
+        let now = Utc.ymd(2026, 1, 31).and_hms(0, 0, 0);
+        let configuration = |project_name: &str, max_key_age_days: Option<u64>| ConfigurationFile {
+            project_name: project_name.to_string(),
+            max_key_age_days,
+            ..Default::default()
+        };
+
+        let problem = key_age_problem(&configuration("WordPress", Some(29)), now).unwrap();
+        assert!(problem.starts_with("The key for WordPress is 30 days old"));
+
+        assert_eq!(key_age_problem(&configuration("WordPress", Some(30)), now), None);
+        assert_eq!(key_age_problem(&configuration("WordPress", None), now), None);
+
+        // A key of unknown age can't be too old
+        assert_eq!(key_age_problem(&configuration("Simplenote", Some(1)), now), None);
+    }
+
+    #[test]
+    fn test_a_missing_key_has_its_own_exit_code() {
+        let missing = ConfigureError::KeyWouldBeCreated("WordPress".to_string());
+        assert_eq!(missing.exit_code(), MISSING_KEY_EXIT_CODE);
+
+        // Scripts can tell whether to retry, fix credentials, or look at the repository
+        let failure = || "fatal: failed".to_string();
+        assert_eq!(ConfigureError::NetworkError(failure()).exit_code(), NETWORK_ERROR_EXIT_CODE);
+        assert_eq!(
+            ConfigureError::AuthenticationFailed(failure()).exit_code(),
+            AUTHENTICATION_FAILED_EXIT_CODE
+        );
+        assert_eq!(ConfigureError::FetchFailed(failure()).exit_code(), FETCH_FAILED_EXIT_CODE);
+        assert_eq!(missing.to_string(), "Would create a key for WordPress");
+        assert_eq!(ConfigureError::ProjectNameRequired.exit_code(), 1);
+    }
+
+    #[test]
+    fn test_a_dry_run_reports_whether_a_key_would_be_created_without_writing_anything() {
+        let key = crate::encryption::generate_key().to_base64();
+        let keys = serde_json::json!({ "WordPress": key }).to_string();
+        let sandbox = crate::fixtures::Sandbox::new(&keys);
+        let keys_file = sandbox.secrets_path(KEYS_FILE_NAME);
+        let dry_run = |project: &str| {
+            create_project_key(&CreateKeyOptions {
+                project: Some(project.to_string()),
+                dry_run: true,
+                ..Default::default()
+            })
+        };
+
+        dry_run("WordPress").unwrap();
+        assert!(matches!(
+            dry_run("Simplenote"),
+            Err(ConfigureError::KeyWouldBeCreated(name)) if name == "Simplenote"
+        ));
+        assert_eq!(std::fs::read_to_string(&keys_file).unwrap(), keys);
+
+        std::fs::remove_file(&keys_file).unwrap();
+        assert!(matches!(
+            dry_run("WordPress"),
+            Err(ConfigureError::KeyWouldBeCreated(_))
+        ));
+        assert!(!keys_file.exists());
+    }
+
+    #[test]
+    fn test_create_key_adds_new_keys_and_only_replaces_matching_ones_with_force() {
+        let key = || crate::encryption::generate_key().to_base64();
+        let keys = serde_json::json!({ "apps-reader-beta": key(), "Simplenote-iOS": key() });
+        let _sandbox = crate::fixtures::Sandbox::new(&keys.to_string());
+        let create = |project: &str, force: bool| {
+            create_project_key(&CreateKeyOptions {
+                project: Some(project.to_string()),
+                force,
+                ..Default::default()
+            })
+        };
+
+        // A new project's key is stored under a name that's safe to use anywhere
+        create("Day One", false).unwrap();
+        let mut current = read_keys().unwrap();
+        assert!(current.contains_key("Day-One"));
+        assert!(!current.contains_key("Day One"));
+        assert!(read_key_metadata(&find_key_metadata_file().unwrap())
+            .unwrap()
+            .contains_key("Day-One"));
+
+        // Existing keys are only replaced with `--force`, whether they match exactly, by their
+        // sanitized name, or only differ in case and separators
+        for (project, key_name) in [
+            ("Day-One", "Day-One"),
+            ("apps/reader (beta)", "apps-reader-beta"),
+            ("simplenote_ios", "Simplenote-iOS"),
+        ] {
+            assert!(matches!(
+                create(project, false),
+                Err(ConfigureError::KeyWouldOverwrite(name)) if name == key_name
+            ));
+            assert_eq!(read_keys().unwrap(), current);
+
+            create(project, true).unwrap();
+            let replaced = read_keys().unwrap();
+            assert_ne!(replaced[key_name], current[key_name], "{}", project);
+            assert_eq!(replaced.len(), current.len(), "{}", project);
+            current = replaced;
+        }
+    }
+
+    /// Answers `init`'s questions for a project called Demo on `main`, with no files
+    fn answer_init_questions() {
+        crate::interaction::set_interaction_handler(Some(Box::new(|interaction| {
+            match interaction {
+                Interaction::ProjectName => Response::Text("Demo".to_string()),
+                Interaction::ChooseBranch { options, .. } => {
+                    Response::Choice(options.iter().position(|b| b == "main").unwrap())
+                }
+                Interaction::AddFile { .. } => Response::Confirmed(false),
+                other => panic!("Unexpected question {:?}", other),
+            }
+        })));
+    }
+
+    #[test]
+    fn test_init_creates_a_usable_key_for_a_project_without_one() {
+        let sandbox = crate::fixtures::Sandbox::new("{}");
+        answer_init_questions();
+
+        setup_configuration(ConfigurationFile::default(), None, true).unwrap();
+
+        let configuration = read_configuration().unwrap();
+        assert!(read_encryption_key(&configuration).unwrap().is_some());
+        let metadata = read_key_metadata(&sandbox.secrets_path(KEY_METADATA_FILE_NAME)).unwrap();
+        assert!(metadata.contains_key("Demo"));
+    }
+
+    #[test]
+    fn test_the_branch_prompt_shows_where_the_secrets_repository_is() {
+        let sandbox = crate::fixtures::Sandbox::new("{}");
+        answer_init_questions();
+
+        let mut chosen = None;
+        let lines = crate::fixtures::capture_output("We've found", || {
+            chosen = Some(prompt_for_branch(ConfigurationFile::default(), true));
+        });
+
+        assert_eq!(chosen.unwrap().unwrap().branch, "main");
+        let secrets = sandbox.home.path().join(".mobile-secrets");
+        let expected = format!("We've found your secrets repository at {}", secrets.display());
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0].1, expected);
+    }
+
+    #[test]
+    fn test_init_reports_an_invalid_key_instead_of_replacing_it() {
+        let _sandbox = crate::fixtures::Sandbox::new(r#"{ "Demo": "Foo!" }"#);
+        answer_init_questions();
+
+        assert!(matches!(
+            setup_configuration(ConfigurationFile::default(), None, true),
+            Err(ConfigureError::InvalidProjectKey(_))
+        ));
+    }
+
+    #[test]
+    fn test_commands_refuse_an_uninitialized_project() {
+        for contents in &["", "  \n\t", "{}", " { }\n"] {
+            let configuration =
+                crate::fs::parse_configuration(".configure", contents, true).unwrap();
+            assert!(configuration.is_empty());
+
+            let refused = |result: Result<(), ConfigureError>| {
+                matches!(result, Err(ConfigureError::ConfigurationUninitialized))
+            };
+            let empty = || configuration.clone();
+            assert!(refused(apply_configuration(empty(), &ApplyOptions::default())));
+            assert!(refused(report_drift_configuration(empty(), &ApplyOptions::default())));
+            assert!(refused(update_configuration(empty(), &UpdateOptions::default())));
+            assert!(refused(status_configuration(empty(), &StatusOptions::default())));
+            assert!(refused(diff_configuration(empty(), &DiffOptions::default())));
+            assert!(refused(reencrypt_configuration(empty(), &ReencryptOptions::default())));
+            assert!(refused(validate_configuration(empty(), &ValidateOptions::default())));
+        }
+
+        // A file that's merely missing fields is incomplete, not uninitialized
+        let configuration = ConfigurationFile {
+            project_name: "WordPress".to_string(),
+            ..Default::default()
+        };
+        assert!(configuration.ensure_initialized().is_ok());
+        assert!(matches!(
+            configuration.completeness().ensure_complete(),
+            Err(ConfigureError::ConfigurationIncomplete(_))
+        ));
+    }
+
+    #[test]
+    fn test_completeness_treats_whitespace_as_blank() {
+        let configuration = parse_partial(
+            r#"{ "project_name": " ", "branch": "trunk", "pinned_hash": "abc",
+                "files_to_copy": [] }"#,
+        );
+        assert_eq!(
+            configuration.completeness().missing,
+            vec![RequiredField::ProjectName]
+        );
+
+        let configuration = parse_partial(
+            r#"{ "project_name": "app", "branch": "trunk", "pinned_hash": "abc",
+                "files_to_copy": [] }"#,
+        );
+        assert!(configuration.completeness().is_complete());
+        assert!(configuration.completeness().ensure_complete().is_ok());
+    }
+
+    #[test]
+    fn test_apply_report_summary_counts_outcomes() {
+        let mut report = ApplyReport::default();
+        report.push("a.json", FileOutcome::Unchanged);
+        report.push("b.json", FileOutcome::Created);
+        report.push("c.json", FileOutcome::Unchanged);
+        report.push("d.json", FileOutcome::Updated { backup: None });
+        report.push(
+            "e.json",
+            FileOutcome::Conflict {
+                resolution: ConflictResolution::WriteNew,
+                backup: None,
+            },
+        );
+
+        assert_eq!(
+            report.summary(),
+            "Applied 5 file(s): 2 unchanged, 1 created, 1 updated, 1 conflict(s)"
+        );
+
+        report.filtered_out = 2;
+        assert!(report
+            .summary()
+            .ends_with("1 conflict(s) – 2 other file(s) left out by --only or --exclude"));
+        assert!(!FileOutcome::Unchanged.needs_attention());
+        assert!(FileOutcome::Kept.needs_attention());
+    }
+
+    #[test]
+    fn test_declining_latest_after_switching_branches_asks_for_a_pin() {
+        let mut asked = false;
+        let pinned_hash = choose_pinned_hash("old-pin", true, false, "latest", || {
+            asked = true;
+            Ok(PinChoice::Specific("chosen".to_string()))
+        })
+        .unwrap();
+
+        assert!(asked);
+        assert_eq!(pinned_hash, "chosen");
+
+        let pinned_hash =
+            choose_pinned_hash("old-pin", true, false, "latest", || Ok(PinChoice::Latest)).unwrap();
+        assert_eq!(pinned_hash, "latest");
+    }
+
+    #[test]
+    fn test_pin_is_kept_only_on_the_same_branch() {
+        let never_asked = || -> Result<PinChoice, ConfigureError> { panic!("Shouldn't ask for a pin") };
+
+        assert_eq!(
+            choose_pinned_hash("old-pin", false, false, "latest", never_asked).unwrap(),
+            "old-pin"
+        );
+        assert_eq!(
+            choose_pinned_hash("old-pin", false, true, "latest", never_asked).unwrap(),
+            "latest"
+        );
+    }
+
+    #[test]
+    fn test_variant_files_are_selected_with_shared_files() {
+        let configuration = configuration(&[
+            ("shared.json", None),
+            ("debug.json", Some("debug")),
+            ("release.json", Some("release")),
+        ]);
+
+        let options = ApplyOptions {
+            variant: Some("release".to_string()),
+            ..Default::default()
+        };
+
+        let selected = select_variant_files(configuration, &options).unwrap();
+        assert_eq!(destinations(selected), vec!["shared.json", "release.json"]);
+    }
+
+    #[test]
+    fn test_excluded_files_are_taken_out_after_only() {
+        let configuration =
+            configuration(&[("a.json", None), ("b.json", None), ("c.json", None)]);
+
+        let select = |only: &[&str], exclude: &[&str]| {
+            let options = ApplyOptions {
+                only: only.iter().map(|d| d.to_string()).collect(),
+                exclude: exclude.iter().map(|d| d.to_string()).collect(),
+                ..Default::default()
+            };
+            select_requested_files(configuration.clone(), &options).map(destinations)
+        };
+
+        assert_eq!(select(&[], &[]).unwrap(), vec!["a.json", "b.json", "c.json"]);
+        assert_eq!(select(&[], &["b.json"]).unwrap(), vec!["a.json", "c.json"]);
+        assert_eq!(
+            select(&["a.json", "b.json"], &["b.json"]).unwrap(),
+            vec!["a.json"]
+        );
+
+        // Excluding a file that isn't there is only a warning
+        assert_eq!(
+            select(&[], &["missing.json"]).unwrap(),
+            vec!["a.json", "b.json", "c.json"]
+        );
+
+        assert!(matches!(
+            select(&["missing.json"], &[]),
+            Err(ConfigureError::UnknownDestination(destination)) if destination == "missing.json"
+        ));
+
+        assert_eq!(select(&["*.json"], &["c.*"]).unwrap(), vec!["a.json", "b.json"]);
+        assert!(matches!(
+            select(&["*.plist"], &[]),
+            Err(ConfigureError::UnknownDestination(pattern)) if pattern == "*.plist"
+        ));
+    }
+
+    #[test]
+    fn test_destination_globs() {
+        assert!(destination_matches("config/secrets.json", "config/secrets.json"));
+        assert!(destination_matches("config/*.json", "config/secrets.json"));
+        assert!(!destination_matches("*.json", "config/secrets.json"));
+        assert!(destination_matches("**/*.json", "config/secrets.json"));
+        assert!(destination_matches("**/*.json", "secrets.json"));
+        assert!(destination_matches("ios/**", "ios/App/Secrets.plist"));
+        assert!(destination_matches("config/secret?.json", "config/secrets.json"));
+        assert!(!destination_matches("config/secret?.json", "config/secret.json"));
+        assert!(!destination_matches("config/*.json", "config/secrets.json.enc"));
+    }
+
+    #[test]
+    fn test_unknown_variant_is_an_error() {
+        let configuration = configuration(&[("debug.json", Some("debug"))]);
+
+        let options = ApplyOptions {
+            variant: Some("beta".to_string()),
+            ..Default::default()
+        };
+
+        assert!(matches!(
+            select_variant_files(configuration, &options),
+            Err(ConfigureError::UnknownVariant(_))
+        ));
+    }
+
+    #[test]
+    fn test_all_variants_must_have_distinct_destinations() {
+        let options = ApplyOptions {
+            all_variants: true,
+            ..Default::default()
+        };
+
+        let distinct = configuration(&[
+            ("debug.json", Some("debug")),
+            ("release.json", Some("release")),
+        ]);
+        let selected = select_variant_files(distinct, &options).unwrap();
+        assert_eq!(destinations(selected), vec!["debug.json", "release.json"]);
+
+        let shared = configuration(&[
+            ("secrets.json", Some("debug")),
+            ("secrets.json", Some("release")),
+        ]);
+        match select_variant_files(shared, &options) {
+            Err(ConfigureError::VariantsShareDestination {
+                destination,
+                variants,
+            }) => {
+                assert_eq!(destination, "secrets.json");
+                assert_eq!(variants, "debug, release");
+            }
+            other => panic!("Expected a shared destination error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_only_files_whose_encrypted_file_changed_are_applied_since_the_last_apply() {
+        let dir = tempfile::tempdir().unwrap();
+        let configuration =
+            configuration(&[("a.json", None), ("b.json", None), ("c.json", None)]);
+        for name in &["a.json.enc", "b.json.enc", "c.json.enc"] {
+            std::fs::write(dir.path().join(name), "encrypted").unwrap();
+        }
+
+        let select = || {
+            destinations(select_files_changed_since_last_apply(
+                configuration.clone(),
+                dir.path(),
+            ))
+        };
+        assert_eq!(select(), vec!["a.json", "b.json", "c.json"]);
+
+        let mut state = crate::state::ApplyState::default();
+        for destination in &["a.json", "b.json"] {
+            state.record(destination, String::new(), 0);
+            state.record_origin(
+                destination,
+                crate::state::FileOrigin {
+                    encrypted_blob: blob_hash_of_file(&dir.path().join("a.json.enc")).ok(),
+                    ..Default::default()
+                },
+            );
+        }
+        crate::state::save_apply_state(dir.path(), &state).unwrap();
+        std::fs::write(dir.path().join("b.json.enc"), "re-encrypted").unwrap();
+
+        // c.json was never applied, so there's nothing to compare it with
+        assert_eq!(select(), vec!["b.json", "c.json"]);
+    }
+
+    #[test]
+    fn test_encrypted_files_changed_without_the_pin_are_mismatches() {
+        let (dir, repo) = crate::fixtures::empty_repo();
+        let configuration = ConfigurationFile {
+            pinned_hash: "abc123".to_string(),
+            ..configuration(&[("a.json", None)])
+        };
+        crate::fixtures::commit_file(&repo, ".configure", "{}", "Pin the secrets");
+        crate::fixtures::commit_file(&repo, "a.json.enc", "encrypted", "Add the secrets");
+
+        let messages = || -> Vec<String> {
+            find_source_hash_mismatches(&configuration, dir.path())
+                .into_iter()
+                .map(|finding| finding.message)
+                .collect()
+        };
+        assert!(messages().is_empty());
+
+        // What the last apply at the same pin used
+        let mut state = crate::state::ApplyState::default();
+        state.record("a.json", String::new(), 0);
+        state.record_origin(
+            "a.json",
+            crate::state::FileOrigin {
+                encrypted_blob: blob_hash_of_file(&dir.path().join("a.json.enc")).ok(),
+                pinned_hash: "abc123".to_string(),
+                ..Default::default()
+            },
+        );
+        crate::state::save_apply_state(dir.path(), &state).unwrap();
+
+        std::fs::write(dir.path().join("a.json.enc"), "swapped").unwrap();
+        let messages = messages();
+        assert_eq!(messages.len(), 2);
+        assert!(messages[0].contains("when it was last applied at abc123"));
+        assert!(messages[1].contains("is committed with the same pin"));
+
+        // Once the pin changes, the encrypted files are expected to have changed
+        std::fs::write(dir.path().join(".configure"), "{ \"updated\": true }").unwrap();
+        let moved = ConfigurationFile {
+            pinned_hash: "def456".to_string(),
+            ..configuration.clone()
+        };
+        assert!(find_source_hash_mismatches(&moved, dir.path()).is_empty());
+    }
+
+    #[test]
+    fn test_renamed_projects_sources_move_to_the_new_directory() {
+        let mut moved = ConfigurationFile {
+            project_name: "Simplenote".to_string(),
+            source_root: Some("Simplenote/".to_string()),
+            ..configuration(&[
+                ("Simplenote/a.json", None),
+                ("Simplenote-Android/b.json", None),
+                ("shared/c.json", None),
+            ])
+        };
+        let keys = serde_json::json!({ "Simplenote": "key", "Notes App": "key" });
+        let keys = keys.as_object().unwrap();
+        assert!(sources_under_other_projects(&moved, keys).is_empty());
+
+        assert_eq!(move_sources(&mut moved, "Simplenote", "Notes-App"), 1);
+        let sources: Vec<&str> = moved.files_to_copy.iter().map(|f| &*f.source).collect();
+        assert_eq!(
+            sources,
+            vec!["Notes-App/a.json", "Simplenote-Android/b.json", "shared/c.json"]
+        );
+        assert_eq!(moved.source_root.as_deref(), Some("Notes-App"));
+
+        // Renamed without moving, the sources are left under the old name's key
+        let mut renamed = ConfigurationFile {
+            project_name: "Notes App".to_string(),
+            ..configuration(&[("Simplenote/a.json", None), ("Simplenote/b.json", None)])
+        };
+        let found = sources_under_other_projects(&renamed, keys);
+        assert_eq!(found.into_iter().collect::<Vec<_>>(), vec![("Simplenote".to_string(), 2)]);
+
+        move_sources(&mut renamed, "Simplenote", "Notes-App");
+        assert!(sources_under_other_projects(&renamed, keys).is_empty());
+
+        // Sources in a directory shared with a project that has its own key are deliberate
+        let sharing = ConfigurationFile {
+            project_name: "Notes App".to_string(),
+            ..configuration(&[("Simplenote/a.json", None)])
+        };
+        let keys = serde_json::json!({ "Simplenote": "key", "Notes App": "other key" });
+        assert!(sources_under_other_projects(&sharing, keys.as_object().unwrap()).is_empty());
+    }
+
+    #[test]
+    fn test_only_user_scope_secrets_go_in_the_home_directory() {
+        let file = |destination: &str, scope: Scope| File {
+            destination: destination.to_string(),
+            scope,
+            ..Default::default()
+        };
+
+        let netrc = file("~/.netrc", Scope::User);
+        assert_eq!(netrc.destination_problem(), None);
+        assert_eq!(netrc.get_encrypted_destination(), ".configure-user/.netrc.enc");
+        let home = dirs::home_dir().unwrap();
+        assert_eq!(
+            PathBuf::from(netrc.get_decrypted_destination().unwrap()),
+            home.join(".netrc")
+        );
+        assert_eq!(
+            PathBuf::from(netrc.get_new_destination().unwrap()),
+            home.join(".netrc.new")
+        );
+
+        let project = file("config/secrets.json", Scope::Project);
+        assert_eq!(project.destination_problem(), None);
+        assert_eq!(project.get_decrypted_destination().unwrap(), "config/secrets.json");
+
+        for (destination, scope) in &[
+            ("~/.netrc", Scope::Project),
+            (".netrc", Scope::User),
+            ("~/../other/.netrc", Scope::User),
+            ("~//etc/hosts", Scope::User),
+            ("~/", Scope::User),
+        ] {
+            assert!(file(destination, *scope).destination_problem().is_some(), "{}", destination);
+        }
+    }
+
+    #[test]
+    fn test_diff_against_a_commit_shows_changed_added_removed_and_binary_secrets() {
+        let sandbox = crate::fixtures::Sandbox::new("{}");
+        let secrets = &sandbox.secrets;
+        crate::fixtures::commit_file(secrets, "App/changed.json", "diff-test: old\n", "Add");
+        crate::fixtures::commit_file(secrets, "App/same.json", "diff-test: same\n", "Add");
+        crate::fixtures::commit_file(secrets, "App/removed.json", "diff-test: gone\n", "Add");
+        let pinned = crate::fixtures::commit_file(secrets, "App/icon.bin", [0xff, 0, 1], "Add");
+
+        crate::fixtures::commit_file(secrets, "App/changed.json", "diff-test: new\n", "Change");
+        crate::fixtures::commit_file(secrets, "App/added.json", "diff-test: added\n", "Add");
+        crate::fixtures::remove_file(secrets, "App/removed.json", "Remove");
+        let against = crate::fixtures::commit_file(secrets, "App/icon.bin", [0xff, 0, 2], "Change");
+        let against = against.to_string();
+
+        let names = ["changed.json", "same.json", "added.json", "removed.json", "icon.bin"];
+        let configuration = ConfigurationFile {
+            project_name: "App".to_string(),
+            pinned_hash: pinned.to_string(),
+            files_to_copy: names
+                .iter()
+                .map(|name| File {
+                    source: format!("App/{}", name),
+                    destination: format!("diff-test/{}", name),
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        };
+
+        let diff = |name_only: bool| {
+            let options = DiffOptions {
+                name_only,
+                against: Some(against.clone()),
+            };
+            let mut result = None;
+            let lines = crate::fixtures::capture_output("", || {
+                result = Some(diff_against_commit(&configuration, &options, &against));
+            });
+            let lines: Vec<String> = lines.into_iter().map(|(_, line)| line).collect();
+            (result.unwrap(), lines)
+        };
+
+        let (result, lines) = diff(true);
+        assert!(matches!(result, Err(ConfigureError::FilesDiffer(4))));
+        let destinations: Vec<&String> =
+            lines.iter().filter(|line| line.starts_with("diff-test/")).collect();
+        assert_eq!(
+            destinations,
+            vec![
+                "diff-test/changed.json",
+                "diff-test/added.json",
+                "diff-test/removed.json",
+                "diff-test/icon.bin"
+            ]
+        );
+        assert!(!lines.iter().any(|line| line.starts_with("- diff-test")));
+
+        let (result, lines) = diff(false);
+        assert!(matches!(result, Err(ConfigureError::FilesDiffer(4))));
+        for expected in [
+            "diff-test/changed.json".to_string(),
+            "- diff-test: old".to_string(),
+            "+ diff-test: new".to_string(),
+            format!("Only in the secrets at {}", display_hash(&against)),
+            format!("Not in the secrets at {}", display_hash(&against)),
+            "Binary file differs".to_string(),
+        ] {
+            assert!(lines.contains(&expected), "{} in {:?}", expected, lines);
+        }
+        assert!(!lines.iter().any(|line| line.contains("diff-test/same.json")));
+    }
 }
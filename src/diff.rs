@@ -0,0 +1,153 @@
+//! A line-by-line diff between two versions of a secret, for `configure diff`, and a shorter
+//! summary of how they differ for when there's only room for a line or two
+
+/// `bytes` as text, or `None` if they look binary – they aren't UTF-8, or, like git decides, they
+/// have a NUL byte in them
+pub fn as_text(bytes: &[u8]) -> Option<&str> {
+    match bytes.contains(&0) {
+        true => None,
+        false => std::str::from_utf8(bytes).ok(),
+    }
+}
+
+/// One line of a diff
+#[derive(Debug, Eq, PartialEq)]
+pub enum DiffLine<'a> {
+    Unchanged(&'a str),
+    Removed(&'a str),
+    Added(&'a str),
+}
+
+/// The lines that turn `old` into `new`, based on their longest common subsequence. Secrets are
+/// small, so the quadratic table isn't a concern.
+pub fn diff_lines<'a>(old: &'a str, new: &'a str) -> Vec<DiffLine<'a>> {
+    let old: Vec<&str> = old.lines().collect();
+    let new: Vec<&str> = new.lines().collect();
+
+    // common[i][j] is the length of the longest common subsequence of old[i..] and new[j..]
+    let mut common = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            common[i][j] = if old[i] == new[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+
+    let mut lines = Vec::new();
+    let (mut i, mut j) = (0, 0);
+
+    while i < old.len() && j < new.len() {
+        if old[i] == new[j] {
+            lines.push(DiffLine::Unchanged(old[i]));
+            i += 1;
+            j += 1;
+        } else if common[i + 1][j] >= common[i][j + 1] {
+            lines.push(DiffLine::Removed(old[i]));
+            i += 1;
+        } else {
+            lines.push(DiffLine::Added(new[j]));
+            j += 1;
+        }
+    }
+
+    lines.extend(old[i..].iter().map(|line| DiffLine::Removed(line)));
+    lines.extend(new[j..].iter().map(|line| DiffLine::Added(line)));
+    lines
+}
+
+/// A couple of lines saying how `local` differs from `incoming`: their sizes, and for text, the
+/// first line that differs in each
+pub fn summarize_difference(local: &[u8], incoming: &[u8]) -> Vec<String> {
+    let mut summary = vec![format!(
+        "Yours is {} bytes, the incoming file is {} bytes",
+        local.len(),
+        incoming.len()
+    )];
+
+    if let (Ok(local), Ok(incoming)) = (std::str::from_utf8(local), std::str::from_utf8(incoming)) {
+        let mut local_lines = local.lines();
+        let mut incoming_lines = incoming.lines();
+        let mut number = 1;
+
+        loop {
+            match (local_lines.next(), incoming_lines.next()) {
+                (None, None) => break,
+                (local_line, incoming_line) if local_line != incoming_line => {
+                    summary.push(format!("They first differ on line {}:", number));
+                    summary.push(format!("  yours:    {}", local_line.unwrap_or("(no line)")));
+                    summary.push(format!("  incoming: {}", incoming_line.unwrap_or("(no line)")));
+                    break;
+                }
+                _ => number += 1,
+            }
+        }
+    }
+
+    summary
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_files_with_nul_bytes_or_invalid_utf8_are_binary() {
+        assert_eq!(as_text(b"{ \"key\": 1 }\n"), Some("{ \"key\": 1 }\n"));
+        assert_eq!(as_text(b""), Some(""));
+        assert_eq!(as_text(b"\x00\x01"), None);
+        assert_eq!(as_text(&[0xff, 0xfe]), None);
+    }
+
+    #[test]
+    fn test_diff_lines_finds_edits() {
+        let old = "{\n  \"key\": \"old\",\n  \"url\": \"a\"\n}";
+        let new = "{\n  \"key\": \"new\",\n  \"url\": \"a\",\n  \"extra\": 1\n}";
+
+        assert_eq!(
+            diff_lines(old, new),
+            vec![
+                DiffLine::Unchanged("{"),
+                DiffLine::Removed("  \"key\": \"old\","),
+                DiffLine::Removed("  \"url\": \"a\""),
+                DiffLine::Added("  \"key\": \"new\","),
+                DiffLine::Added("  \"url\": \"a\","),
+                DiffLine::Added("  \"extra\": 1"),
+                DiffLine::Unchanged("}"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_differences_are_summarized_by_size_and_first_differing_line() {
+        assert_eq!(
+            summarize_difference(b"a\nb\nc\n", b"a\nB\n"),
+            vec![
+                "Yours is 6 bytes, the incoming file is 4 bytes",
+                "They first differ on line 2:",
+                "  yours:    b",
+                "  incoming: B",
+            ]
+        );
+        assert_eq!(
+            summarize_difference(b"a", b"a\nb")[1..],
+            ["They first differ on line 2:", "  yours:    (no line)", "  incoming: b"]
+        );
+        assert_eq!(
+            summarize_difference(&[0xff, 0xfe], b"a"),
+            vec!["Yours is 2 bytes, the incoming file is 1 bytes"]
+        );
+    }
+
+    #[test]
+    fn test_diff_lines_handles_empty_sides() {
+        assert_eq!(diff_lines("", "a"), vec![DiffLine::Added("a")]);
+        assert_eq!(diff_lines("a", ""), vec![DiffLine::Removed("a")]);
+        assert_eq!(
+            diff_lines("a\nb", "a\nb"),
+            vec![DiffLine::Unchanged("a"), DiffLine::Unchanged("b")]
+        );
+    }
+}
@@ -2,56 +2,230 @@ use crate::ConfigureError;
 use log::debug;
 use sodiumoxide::base64::Variant;
 use sodiumoxide::base64::{decode, encode};
-use sodiumoxide::crypto::secretbox;
+use sodiumoxide::crypto::{box_, sealedbox, secretbox};
+use std::fmt;
 use std::fs::{read, write};
-use std::io::{Error, ErrorKind};
+use std::io::{Error, ErrorKind, Write};
 use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use zeroize::Zeroize;
+
+/// The algorithm keys use unless their metadata says otherwise: libsodium's `secretbox`
+pub const DEFAULT_ALGORITHM: &str = "xsalsa20poly1305";
+
+/// A way of encrypting secrets with a project's key. configure uses `SecretboxCipher` unless a
+/// key's metadata names another algorithm, which has to have been added with `register_cipher`.
+pub trait Cipher: Send + Sync {
+    fn encrypt(&self, content: &[u8], key: &[u8]) -> Vec<u8>;
+
+    fn decrypt(&self, content: &[u8], key: &[u8]) -> Result<Vec<u8>, Error>;
+
+    /// Writes `content`, encrypted, to `output`. Ciphers that can avoid putting the whole
+    /// encrypted file together in memory first should.
+    fn encrypt_to_writer(
+        &self,
+        content: &[u8],
+        key: &[u8],
+        output: &mut dyn Write,
+    ) -> Result<(), Error> {
+        output.write_all(&self.encrypt(content, key))
+    }
+
+    /// The size of the smallest encrypted file, so a file shorter than this can be spotted as
+    /// corrupt without a key
+    fn minimum_length(&self) -> usize {
+        0
+    }
+}
+
+/// The cipher configure has always used: XSalsa20 and Poly1305, with the nonce stored first
+pub struct SecretboxCipher;
+
+impl SecretboxCipher {
+    fn secretbox_key(key: &[u8]) -> secretbox::Key {
+        secretbox::Key::from_slice(key).expect("Keys are always the right length")
+    }
+}
+
+impl Cipher for SecretboxCipher {
+    fn encrypt(&self, content: &[u8], key: &[u8]) -> Vec<u8> {
+        encrypt_bytes(content, SecretboxCipher::secretbox_key(key))
+    }
+
+    fn decrypt(&self, content: &[u8], key: &[u8]) -> Result<Vec<u8>, Error> {
+        let invalid = || Error::new(ErrorKind::InvalidData, "Unable to decrypt file");
+
+        if content.len() < secretbox::NONCEBYTES {
+            return Err(invalid());
+        }
+
+        decrypt_bytes(content, SecretboxCipher::secretbox_key(key)).map_err(|_| invalid())
+    }
+
+    fn encrypt_to_writer(
+        &self,
+        content: &[u8],
+        key: &[u8],
+        output: &mut dyn Write,
+    ) -> Result<(), Error> {
+        let nonce = secretbox::gen_nonce();
+        output.write_all(&nonce[..])?;
+        output.write_all(&secretbox::seal(content, &nonce, &SecretboxCipher::secretbox_key(key)))
+    }
+
+    fn minimum_length(&self) -> usize {
+        secretbox::NONCEBYTES + secretbox::MACBYTES
+    }
+}
+
+/// Ciphers added with `register_cipher`, by algorithm name
+static REGISTERED_CIPHERS: RwLock<Vec<(String, Arc<dyn Cipher>)>> = RwLock::new(Vec::new());
+
+/// Makes `cipher` available to keys whose metadata names `algorithm`, replacing any cipher
+/// already registered under that name. The default algorithm can't be replaced.
+pub fn register_cipher(algorithm: &str, cipher: Arc<dyn Cipher>) {
+    let mut ciphers = REGISTERED_CIPHERS.write().unwrap();
+    ciphers.retain(|(name, _)| name != algorithm);
+    ciphers.push((algorithm.to_string(), cipher));
+}
+
+/// The cipher for `algorithm`, if there is one
+pub fn cipher_for(algorithm: &str) -> Option<Arc<dyn Cipher>> {
+    if algorithm == DEFAULT_ALGORITHM {
+        return Some(Arc::new(SecretboxCipher));
+    }
+
+    REGISTERED_CIPHERS
+        .read()
+        .unwrap()
+        .iter()
+        .find(|(name, _)| name == algorithm)
+        .map(|(_, cipher)| cipher.clone())
+}
+
+/// A project's encryption key. It can't be printed or logged by accident – `Debug` and `Display`
+/// show `Key(***)` – and its bytes are wiped from memory when it's dropped. `to_base64` is the
+/// only way to see it, for storing it in `keys.json`.
+///
+/// A key also knows which algorithm it's for, and everything encrypted with it uses that cipher.
+#[derive(Clone)]
+pub struct Key {
+    bytes: Vec<u8>,
+    algorithm: String,
+    cipher: Arc<dyn Cipher>,
+}
+
+impl Key {
+    fn new(bytes: Vec<u8>) -> Key {
+        Key {
+            bytes,
+            algorithm: DEFAULT_ALGORITHM.to_string(),
+            cipher: Arc::new(SecretboxCipher),
+        }
+    }
+
+    /// Reads a key in the base64 form `keys.json` keeps it in, or `None` if it isn't one
+    pub fn from_base64(encoded: &str) -> Option<Key> {
+        let key = Key::new(decode(encoded.trim(), Variant::Original).ok()?);
+
+        match key.bytes.len() {
+            secretbox::KEYBYTES => Some(key),
+            _ => None,
+        }
+    }
+
+    pub fn to_base64(&self) -> String {
+        encode(&self.bytes, Variant::Original)
+    }
+
+    /// The same key, for use with the cipher registered for `algorithm`
+    pub fn with_algorithm(mut self, algorithm: &str) -> Result<Key, ConfigureError> {
+        self.cipher = cipher_for(algorithm)
+            .ok_or_else(|| ConfigureError::UnknownCipher(algorithm.to_string()))?;
+        self.algorithm = algorithm.to_string();
+        Ok(self)
+    }
+}
+
+impl PartialEq for Key {
+    fn eq(&self, other: &Key) -> bool {
+        self.bytes == other.bytes && self.algorithm == other.algorithm
+    }
+}
+
+impl Eq for Key {}
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Key(***)")
+    }
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Key(***)")
+    }
+}
+
+impl Drop for Key {
+    fn drop(&mut self) {
+        self.bytes.zeroize();
+    }
+}
 
 pub fn init() -> Result<(), ConfigureError> {
-    return match sodiumoxide::init() {
+    match sodiumoxide::init() {
         Ok(()) => Ok(()),
         Err(()) => Err(ConfigureError::EncryptionUnavailable),
     }
 }
 
-pub fn generate_key() -> String {
+pub fn generate_key() -> Key {
     debug!("Generating an encryption key");
-    let key_bytes = secretbox::gen_key();
-    encode_key(key_bytes)
+    Key::new(secretbox::gen_key().0.to_vec())
 }
 
 pub fn encrypt_file(
     input_path: &PathBuf,
     output_path: &PathBuf,
-    secret: &str,
+    key: &Key,
 ) -> Result<(), std::io::Error> {
     let content = read(input_path)?;
-    let ciphertext = encrypt_bytes(content, decode_key(secret));
-    write(&output_path, &ciphertext)?;
+    let ciphertext = encrypt_contents(&content, key);
+    write(output_path, &ciphertext)?;
 
     Ok(())
 }
 
-pub fn decrypt_file(
-    input_path: &PathBuf,
-    output_path: &PathBuf,
-    secret: &str,
-) -> Result<(), std::io::Error> {
+pub fn decrypt_file_contents(input_path: &PathBuf, key: &Key) -> Result<Vec<u8>, std::io::Error> {
     let content = read(input_path)?;
+    decrypt_contents(&content, key)
+}
 
-    match decrypt_bytes(content, decode_key(secret)) {
-        Ok(decrypted_bytes) => Ok(write(&output_path, decrypted_bytes)?),
-        Err(_err) => Err(Error::new(ErrorKind::InvalidData, "Unable to decrypt file")),
-    }
+/// Encrypts contents that are already in memory, in the same format as `encrypt_file`
+pub fn encrypt_contents(content: &[u8], key: &Key) -> Vec<u8> {
+    key.cipher.encrypt(content, &key.bytes)
 }
 
-fn encrypt_bytes(input: Vec<u8>, key: sodiumoxide::crypto::secretbox::Key) -> Vec<u8> {
+/// Encrypts `content` to `output` in the same format as `encrypt_contents`, without putting the
+/// whole encrypted file together in memory first
+pub fn encrypt_to_writer(content: &[u8], key: &Key, output: &mut impl Write) -> Result<(), Error> {
+    key.cipher.encrypt_to_writer(content, &key.bytes, output)
+}
+
+/// Decrypts contents that are already in memory, failing (rather than panicking) if they're too
+/// short to have been encrypted by configure
+pub fn decrypt_contents(content: &[u8], key: &Key) -> Result<Vec<u8>, std::io::Error> {
+    key.cipher.decrypt(content, &key.bytes)
+}
+
+fn encrypt_bytes(input: &[u8], key: sodiumoxide::crypto::secretbox::Key) -> Vec<u8> {
     let nonce = secretbox::gen_nonce();
-    let secret_bytes = secretbox::seal(&input, &nonce, &key);
+    let secret_bytes = secretbox::seal(input, &nonce, &key);
     [&nonce[..], &secret_bytes].concat()
 }
 
-fn decrypt_bytes(input: Vec<u8>, key: sodiumoxide::crypto::secretbox::Key) -> Result<Vec<u8>, ()> {
+fn decrypt_bytes(input: &[u8], key: sodiumoxide::crypto::secretbox::Key) -> Result<Vec<u8>, ()> {
     // Encoded Format byte layout:
     // |======================================|=====================================|
     // | 0                                 23 | 24                                ∞ |
@@ -69,22 +243,73 @@ fn decrypt_bytes(input: Vec<u8>, key: sodiumoxide::crypto::secretbox::Key) -> Re
     // Read the encrypted data bytes
     let data_bytes = &input[NONCE_SIZE..];
 
-    Ok(secretbox::open(&data_bytes, &nonce, &key)?)
+    secretbox::open(data_bytes, &nonce, &key)
+}
+
+/// What's wrong with an encrypted file, if it can't be what configure wrote. Without a key, only
+/// problems that don't need one are found.
+pub fn diagnose_encrypted_contents(content: &[u8], key: Option<&Key>) -> Option<String> {
+    // git-crypt's encrypted files start with this header
+    if content.starts_with(b"\0GITCRYPT\0") {
+        return Some("is encrypted by git-crypt, whose filter isn't working here".to_string());
+    }
+
+    let minimum_length = match key {
+        Some(key) => key.cipher.minimum_length(),
+        None => SecretboxCipher.minimum_length(),
+    };
+
+    if content.len() < minimum_length {
+        return Some(format!(
+            "is only {} byte(s), which is too short to be an encrypted secret",
+            content.len()
+        ));
+    }
+
+    match key {
+        Some(key) if decrypt_contents(content, key).is_err() => Some(
+            "can't be decrypted with the project's key, so it's been corrupted or encrypted with a \
+             different key"
+                .to_string(),
+        ),
+        _ => None,
+    }
 }
 
-fn encode_key(key: sodiumoxide::crypto::secretbox::Key) -> String {
-    encode(&key, Variant::Original)
+/// Generates a key pair for receiving sealed data, returning the (public, secret) keys in base64
+pub fn generate_recipient_keys() -> (String, String) {
+    debug!("Generating a recipient key pair");
+    let (public_key, secret_key) = box_::gen_keypair();
+    (
+        encode(public_key, Variant::Original),
+        encode(secret_key, Variant::Original),
+    )
 }
 
-fn decode_key(key: &str) -> sodiumoxide::crypto::secretbox::Key {
-    let decoded_key_bytes = decode(key, Variant::Original).expect("Unable to decode key");
+/// Encrypts `input` so that only the holder of the secret key for `public_key` can read it
+pub fn seal_for_recipient(input: &[u8], public_key: &str) -> Result<Vec<u8>, Error> {
+    Ok(sealedbox::seal(input, &decode_recipient_public_key(public_key)?))
+}
 
-    let mut key_bytes: [u8; 32] = Default::default();
-    key_bytes.copy_from_slice(&decoded_key_bytes);
+/// Decrypts data sealed with `seal_for_recipient` for this key pair
+pub fn open_as_recipient(input: &[u8], public_key: &str, secret_key: &str) -> Result<Vec<u8>, Error> {
+    let secret_key = decode(secret_key, Variant::Original)
+        .ok()
+        .and_then(|bytes| box_::SecretKey::from_slice(&bytes))
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Invalid secret key"))?;
 
-    sodiumoxide::crypto::secretbox::Key(key_bytes)
+    sealedbox::open(input, &decode_recipient_public_key(public_key)?, &secret_key)
+        .map_err(|_| Error::new(ErrorKind::InvalidData, "Unable to decrypt – is this the right identity?"))
 }
 
+fn decode_recipient_public_key(public_key: &str) -> Result<box_::PublicKey, Error> {
+    decode(public_key.trim(), Variant::Original)
+        .ok()
+        .and_then(|bytes| box_::PublicKey::from_slice(&bytes))
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Invalid public key"))
+}
+
+
 #[cfg(test)]
 mod tests {
     // Note this useful idiom: importing names from outer (for mod tests) scope.
@@ -94,4 +319,100 @@ mod tests {
     fn test_init_does_not_fail() {
         assert!(init().is_ok());
     }
+
+    #[test]
+    fn test_keys_are_redacted_and_round_trip_through_base64() {
+        init().unwrap();
+        let key = generate_key();
+
+        assert_eq!(format!("{:?}", key), "Key(***)");
+        assert_eq!(format!("{}", key), "Key(***)");
+        assert_eq!(format!("{:?}", Some(&key)), "Some(Key(***))");
+
+        assert_eq!(Key::from_base64(&key.to_base64()), Some(key));
+        assert_eq!(Key::from_base64("Foo!"), None);
+        assert_eq!(Key::from_base64("c2hvcnQ="), None);
+    }
+
+    #[test]
+    fn test_diagnose_encrypted_contents_spots_corruption() {
+        init().unwrap();
+        let key = generate_key();
+        let encrypted = encrypt_contents(b"{\"secret\": 1}", &key);
+
+        assert_eq!(diagnose_encrypted_contents(&encrypted, Some(&key)), None);
+        assert_eq!(diagnose_encrypted_contents(&encrypted, None), None);
+
+        // Like a checkout that converted a line ending in the ciphertext
+        let mut converted = encrypted.clone();
+        converted.insert(30, b'\r');
+        assert!(diagnose_encrypted_contents(&converted, Some(&key))
+            .unwrap()
+            .starts_with("can't be decrypted"));
+        assert_eq!(diagnose_encrypted_contents(&converted, None), None);
+
+        assert_eq!(
+            diagnose_encrypted_contents(b"\0GITCRYPT\0\x01\x02", None).unwrap(),
+            "is encrypted by git-crypt, whose filter isn't working here"
+        );
+        assert_eq!(
+            diagnose_encrypted_contents(b"{}", Some(&key)).unwrap(),
+            "is only 2 byte(s), which is too short to be an encrypted secret"
+        );
+    }
+
+    /// Stands in for another algorithm, so it's easy to see which cipher was used
+    struct ReversingCipher;
+
+    impl Cipher for ReversingCipher {
+        fn encrypt(&self, content: &[u8], _key: &[u8]) -> Vec<u8> {
+            content.iter().rev().cloned().collect()
+        }
+
+        fn decrypt(&self, content: &[u8], _key: &[u8]) -> Result<Vec<u8>, Error> {
+            Ok(content.iter().rev().cloned().collect())
+        }
+    }
+
+    #[test]
+    fn test_keys_use_the_cipher_for_their_algorithm() {
+        init().unwrap();
+        register_cipher("test-reversing", Arc::new(ReversingCipher));
+
+        let default_key = generate_key();
+        assert_eq!(default_key.algorithm, DEFAULT_ALGORITHM);
+
+        let key = default_key.clone().with_algorithm("test-reversing").unwrap();
+        assert_eq!(encrypt_contents(b"secret", &key), b"terces");
+        assert_eq!(decrypt_contents(b"terces", &key).unwrap(), b"secret");
+        assert_ne!(key, default_key);
+
+        let mut written = Vec::new();
+        encrypt_to_writer(b"secret", &key, &mut written).unwrap();
+        assert_eq!(written, b"terces");
+
+        // Short files are only suspicious for ciphers that say so
+        assert_eq!(diagnose_encrypted_contents(b"{}", Some(&key)), None);
+
+        let encrypted = encrypt_contents(b"secret", &default_key);
+        assert_eq!(decrypt_contents(&encrypted, &default_key).unwrap(), b"secret");
+        assert_ne!(decrypt_contents(&encrypted, &key).unwrap(), b"secret");
+
+        assert!(matches!(
+            default_key.with_algorithm("rot13"),
+            Err(ConfigureError::UnknownCipher(algorithm)) if algorithm == "rot13"
+        ));
+    }
+
+    #[test]
+    fn test_sealed_data_can_only_be_opened_by_the_recipient() {
+        init().unwrap();
+        let (public_key, secret_key) = generate_recipient_keys();
+        let (other_public_key, other_secret_key) = generate_recipient_keys();
+
+        let sealed = seal_for_recipient(b"secret", &public_key).unwrap();
+
+        assert_eq!(open_as_recipient(&sealed, &public_key, &secret_key).unwrap(), b"secret");
+        assert!(open_as_recipient(&sealed, &other_public_key, &other_secret_key).is_err());
+    }
 }
@@ -0,0 +1,133 @@
+//! Handing a project's encryption key to someone else without it ever being readable in transit.
+//!
+//! The recipient creates an identity (a key pair) and shares its public key. The sender seals the
+//! project's key for that public key in a bundle, which only the recipient's identity can open.
+
+use crate::encryption::{generate_recipient_keys, open_as_recipient, seal_for_recipient};
+use crate::ConfigureError;
+use ring::digest::{digest, SHA256};
+use serde::{Deserialize, Serialize};
+use sodiumoxide::base64::{decode, encode, Variant};
+
+const BUNDLE_VERSION: u32 = 1;
+
+/// A project's encryption key, sealed for a single recipient
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq)]
+pub struct KeyBundle {
+    pub version: u32,
+
+    /// The name of the project the key is for, as it appears in `keys.json`
+    pub project: String,
+
+    /// The fingerprint of the key, so the recipient can check they got the one they expected
+    pub fingerprint: String,
+
+    /// The key, sealed for the recipient and base64-encoded
+    pub sealed_key: String,
+}
+
+/// A key pair for receiving bundles. The secret key must never leave the recipient's machine.
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq)]
+pub struct Identity {
+    pub public_key: String,
+    pub secret_key: String,
+}
+
+impl Identity {
+    pub fn generate() -> Identity {
+        let (public_key, secret_key) = generate_recipient_keys();
+        Identity {
+            public_key,
+            secret_key,
+        }
+    }
+}
+
+/// A short, safe-to-share identifier for an encryption key
+pub fn key_fingerprint(key: &str) -> String {
+    let hash = digest(&SHA256, key.as_bytes());
+    format!("SHA256:{}", encode(&hash.as_ref()[..16], Variant::Original))
+}
+
+/// Seals `key` so that only the holder of the identity for `recipient` (a public key) can open it
+pub fn create_bundle(
+    project: &str,
+    key: &str,
+    recipient: &str,
+) -> Result<KeyBundle, ConfigureError> {
+    let sealed_key = seal_for_recipient(key.as_bytes(), recipient)
+        .map_err(|err| ConfigureError::KeyBundleInvalid(err.to_string()))?;
+
+    Ok(KeyBundle {
+        version: BUNDLE_VERSION,
+        project: project.to_string(),
+        fingerprint: key_fingerprint(key),
+        sealed_key: encode(&sealed_key, Variant::Original),
+    })
+}
+
+/// Opens a bundle with the recipient's identity, checking the key inside matches its fingerprint
+pub fn open_bundle(bundle: &KeyBundle, identity: &Identity) -> Result<String, ConfigureError> {
+    let invalid = |message: &str| ConfigureError::KeyBundleInvalid(message.to_string());
+
+    if bundle.version != BUNDLE_VERSION {
+        return Err(invalid(&format!(
+            "it's version {}, but only version {} is supported",
+            bundle.version, BUNDLE_VERSION
+        )));
+    }
+
+    let sealed_key = decode(&bundle.sealed_key, Variant::Original)
+        .map_err(|_| invalid("the sealed key isn't valid base64"))?;
+
+    let key = open_as_recipient(&sealed_key, &identity.public_key, &identity.secret_key)
+        .map_err(|err| invalid(&err.to_string()))?;
+
+    let key = String::from_utf8(key).map_err(|_| invalid("the key isn't valid text"))?;
+
+    if key_fingerprint(&key) != bundle.fingerprint {
+        return Err(invalid("the key doesn't match its fingerprint"));
+    }
+
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bundle_round_trips_for_the_recipient() {
+        crate::encryption::init().unwrap();
+        let identity = Identity::generate();
+
+        let bundle = create_bundle("WordPress-iOS", "the-key", &identity.public_key).unwrap();
+
+        assert_eq!(bundle.project, "WordPress-iOS");
+        assert_eq!(bundle.fingerprint, key_fingerprint("the-key"));
+        assert_eq!(open_bundle(&bundle, &identity).unwrap(), "the-key");
+    }
+
+    #[test]
+    fn test_bundle_cannot_be_opened_by_someone_else() {
+        crate::encryption::init().unwrap();
+        let recipient = Identity::generate();
+        let someone_else = Identity::generate();
+
+        let bundle = create_bundle("WordPress-iOS", "the-key", &recipient.public_key).unwrap();
+
+        assert!(open_bundle(&bundle, &someone_else).is_err());
+    }
+
+    #[test]
+    fn test_bundle_with_wrong_fingerprint_is_rejected() {
+        crate::encryption::init().unwrap();
+        let identity = Identity::generate();
+
+        let mut bundle = create_bundle("WordPress-iOS", "the-key", &identity.public_key).unwrap();
+        bundle.fingerprint = key_fingerprint("another-key");
+
+        let message = open_bundle(&bundle, &identity).unwrap_err().to_string();
+        assert!(message.contains("fingerprint"), "{}", message);
+    }
+}
@@ -0,0 +1,171 @@
+//! Throwaway git repositories for tests
+
+use crate::ui::{set_output_handler, Stream};
+use git2::{Index, Oid, Repository, Signature};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use tempfile::TempDir;
+
+/// Creates an empty repository in a temporary directory that's removed when the `TempDir` is dropped
+pub fn empty_repo() -> (TempDir, Repository) {
+    let dir = tempfile::tempdir().unwrap();
+    let repo = Repository::init(dir.path()).unwrap();
+    (dir, repo)
+}
+
+/// Creates a repository with a single commit on `main`
+pub fn secrets_repo() -> (TempDir, Repository) {
+    let (dir, repo) = empty_repo();
+    repo.set_head("refs/heads/main").unwrap();
+    commit_file(&repo, "keys.json", "{}", "Initial commit");
+    (dir, repo)
+}
+
+/// Writes `contents` to `path` in the repo's working tree and commits it on the current branch
+pub fn commit_file(
+    repo: &Repository,
+    path: &str,
+    contents: impl AsRef<[u8]>,
+    message: &str,
+) -> Oid {
+    let workdir = repo.workdir().unwrap();
+    let full_path = workdir.join(path);
+    std::fs::create_dir_all(full_path.parent().unwrap()).unwrap();
+    std::fs::write(&full_path, contents).unwrap();
+
+    let mut index = repo.index().unwrap();
+    index.add_path(Path::new(path)).unwrap();
+    commit_index(repo, &mut index, message)
+}
+
+/// Deletes `path` from the repo's working tree and commits its removal on the current branch
+pub fn remove_file(repo: &Repository, path: &str, message: &str) -> Oid {
+    std::fs::remove_file(repo.workdir().unwrap().join(path)).unwrap();
+
+    let mut index = repo.index().unwrap();
+    index.remove_path(Path::new(path)).unwrap();
+    commit_index(repo, &mut index, message)
+}
+
+fn commit_index(repo: &Repository, index: &mut Index, message: &str) -> Oid {
+    index.write().unwrap();
+
+    let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+    let signature = Signature::now("Configure Tests", "tests@example.com").unwrap();
+
+    let parent = repo.head().ok().and_then(|head| head.peel_to_commit().ok());
+    let parents = parent.iter().collect::<Vec<_>>();
+
+    repo.commit(Some("HEAD"), &signature, &signature, message, &tree, &parents)
+        .unwrap()
+}
+
+/// Creates a local branch pointing at `commit`
+pub fn create_branch(repo: &Repository, name: &str, commit: Oid) {
+    let commit = repo.find_commit(commit).unwrap();
+    repo.branch(name, &commit, false).unwrap();
+}
+
+/// Creates a remote-tracking branch (as if it had been fetched from `origin`) pointing at `commit`
+pub fn create_remote_branch(repo: &Repository, name: &str, commit: Oid) {
+    repo.remote("origin", "https://example.com/secrets.git")
+        .or_else(|_| repo.find_remote("origin"))
+        .unwrap();
+    repo.reference(
+        &format!("refs/remotes/origin/{}", name),
+        commit,
+        true,
+        "Fake fetch",
+    )
+    .unwrap();
+}
+
+/// Held by whichever test is pointing configure at a sandbox, since the home directory and current
+/// directory are shared by the whole process
+static SANDBOX: Mutex<()> = Mutex::new(());
+
+/// A throwaway home directory with a secrets repository in it, and a project to run commands in.
+/// While it's alive, `HOME` and the current directory point at them, as they do for a developer.
+/// They're put back, and any interaction handler removed, when it's dropped.
+pub struct Sandbox {
+    pub home: TempDir,
+    pub project: TempDir,
+    pub secrets: Repository,
+    previous_home: Option<OsString>,
+    previous_directory: PathBuf,
+    _lock: MutexGuard<'static, ()>,
+}
+
+impl Sandbox {
+    /// Creates the sandbox, with `keys` as the secrets repository's committed `keys.json`
+    pub fn new(keys: &str) -> Sandbox {
+        let lock = SANDBOX.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        let home = tempfile::tempdir().unwrap();
+        let secrets = Repository::init(home.path().join(".mobile-secrets")).unwrap();
+        secrets.set_head("refs/heads/main").unwrap();
+        let mut config = secrets.config().unwrap();
+        config.set_str("user.name", "Configure Tests").unwrap();
+        config.set_str("user.email", "tests@example.com").unwrap();
+        commit_file(&secrets, "keys.json", keys, "Initial commit");
+
+        let project = tempfile::tempdir().unwrap();
+        Repository::init(project.path()).unwrap();
+
+        let previous_home = std::env::var_os("HOME");
+        let previous_directory = std::env::current_dir().unwrap();
+        std::env::set_var("HOME", home.path());
+        std::env::set_current_dir(project.path()).unwrap();
+
+        Sandbox {
+            home,
+            project,
+            secrets,
+            previous_home,
+            previous_directory,
+            _lock: lock,
+        }
+    }
+
+    /// The path of `path` in the secrets repository's working tree
+    pub fn secrets_path(&self, path: &str) -> PathBuf {
+        self.home.path().join(".mobile-secrets").join(path)
+    }
+}
+
+impl Drop for Sandbox {
+    fn drop(&mut self) {
+        crate::interaction::set_interaction_handler(None);
+
+        match &self.previous_home {
+            Some(home) => std::env::set_var("HOME", home),
+            None => std::env::remove_var("HOME"),
+        }
+        let _ = std::env::set_current_dir(&self.previous_directory);
+    }
+}
+
+/// The output handler is shared by the whole process, so tests that use it take turns
+static HANDLER_LOCK: Mutex<()> = Mutex::new(());
+
+/// Runs `write`, returning the lines it wrote that start with `prefix` (other tests may be
+/// writing at the same time)
+pub fn capture_output(prefix: &str, write: impl FnOnce()) -> Vec<(Stream, String)> {
+    let _lock = HANDLER_LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let lines = Arc::new(Mutex::new(Vec::new()));
+
+    let captured = lines.clone();
+    set_output_handler(Some(Box::new(move |stream, line| {
+        captured.lock().unwrap().push((stream, line.to_string()))
+    })));
+    write();
+    set_output_handler(None);
+
+    let lines = lines.lock().unwrap();
+    lines
+        .iter()
+        .filter(|(_, line)| line.starts_with(prefix))
+        .cloned()
+        .collect()
+}
@@ -0,0 +1,238 @@
+//! `configure status --fleet`, which shows where several projects' pins stand against the latest
+//! secrets – for checking a set of apps before a release.
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+
+/// One project's row in the fleet summary. Anything that couldn't be found out is `None`, and
+/// `error` says why.
+#[derive(Debug, Default, Serialize, Eq, PartialEq)]
+pub struct FleetProjectStatus {
+    /// The path to the project, as it was given
+    pub project: String,
+
+    pub branch: Option<String>,
+    pub pinned_hash: Option<String>,
+
+    /// When the pinned commit was made, in RFC 3339 format
+    pub pinned_at: Option<String>,
+
+    /// How many commits the pin is behind the latest secrets on its branch
+    pub commits_behind: Option<u32>,
+
+    /// When secrets were last applied in the project, in RFC 3339 format
+    pub last_applied: Option<String>,
+
+    pub error: Option<String>,
+}
+
+/// Reads a list of projects, one path per line. Blank lines and lines starting with `#` are
+/// skipped, and relative paths are relative to the list.
+pub fn read_fleet_manifest(path: &Path) -> Result<Vec<PathBuf>, std::io::Error> {
+    let directory = path.parent().unwrap_or_else(|| Path::new(""));
+
+    Ok(std::fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| directory.join(line))
+        .collect())
+}
+
+/// Directories that are never searched for projects: hidden ones like `.git`, and dependencies
+const SKIPPED_DIRECTORIES: &[&str] = &["node_modules", "Pods", "Carthage"];
+
+/// Finds every project under `root` – every directory with a `.configure` in it, including `root`
+/// itself – sorted by path. Hidden directories, dependency directories and symlinks aren't
+/// followed, and directories that can't be read are skipped. Fails if `root` isn't a directory.
+pub fn find_projects_under(root: &Path) -> Result<Vec<PathBuf>, std::io::Error> {
+    if !root.is_dir() {
+        return Err(std::io::Error::new(std::io::ErrorKind::NotFound, "not a directory"));
+    }
+
+    let mut projects = Vec::new();
+    let mut directories = vec![root.to_path_buf()];
+
+    while let Some(directory) = directories.pop() {
+        if directory.join(".configure").is_file() {
+            projects.push(directory.clone());
+        }
+
+        let entries = match std::fs::read_dir(&directory) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let is_directory = entry.file_type().is_ok_and(|file_type| file_type.is_dir());
+
+            let skipped = name.starts_with('.') || SKIPPED_DIRECTORIES.contains(&name.as_str());
+            if is_directory && !skipped {
+                directories.push(entry.path());
+            }
+        }
+    }
+
+    projects.sort();
+    Ok(projects)
+}
+
+/// Lays the summary out as a table, with ages relative to `now`
+pub fn format_fleet_table(statuses: &[FleetProjectStatus], now: DateTime<Utc>) -> Vec<String> {
+    let age = |time: &Option<String>| {
+        time.as_deref()
+            .and_then(|time| DateTime::parse_from_rfc3339(time).ok())
+            .map(|time| describe_age(now.signed_duration_since(time).num_seconds()))
+    };
+    let unknown = || "?".to_string();
+
+    let mut rows = vec![[
+        "PROJECT".to_string(),
+        "BRANCH".to_string(),
+        "PINNED".to_string(),
+        "BEHIND".to_string(),
+        "LAST APPLY".to_string(),
+    ]];
+
+    for status in statuses {
+        let pinned = match (&status.pinned_hash, age(&status.pinned_at)) {
+            (Some(hash), Some(age)) => format!("{} ({} old)", crate::ui::display_hash(hash), age),
+            (Some(hash), None) => crate::ui::display_hash(hash).to_string(),
+            (None, _) => unknown(),
+        };
+
+        rows.push([
+            status.project.clone(),
+            status.branch.clone().unwrap_or_else(unknown),
+            pinned,
+            status
+                .commits_behind
+                .map(|behind| behind.to_string())
+                .unwrap_or_else(unknown),
+            age(&status.last_applied)
+                .map(|age| format!("{} ago", age))
+                .unwrap_or_else(|| "never".to_string()),
+        ]);
+    }
+
+    let mut widths = [0; 5];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let mut lines: Vec<String> = rows
+        .iter()
+        .map(|row| {
+            let cells: Vec<String> = row
+                .iter()
+                .zip(&widths)
+                .map(|(cell, width)| format!("{:width$}", cell, width = width))
+                .collect();
+            cells.join("  ").trim_end().to_string()
+        })
+        .collect();
+
+    // Problems go after the table, so they don't stretch its columns
+    for status in statuses {
+        if let Some(error) = &status.error {
+            lines.push(format!("{}: {}", status.project, error));
+        }
+    }
+
+    lines
+}
+
+/// A rough, human-friendly length of time, like `3 days`
+fn describe_age(seconds: i64) -> String {
+    let (count, unit) = match seconds.max(0) {
+        seconds if seconds < 60 * 60 => (seconds / 60, "minute"),
+        seconds if seconds < 60 * 60 * 24 => (seconds / (60 * 60), "hour"),
+        seconds => (seconds / (60 * 60 * 24), "day"),
+    };
+
+    match count {
+        1 => format!("1 {}", unit),
+        _ => format!("{} {}s", count, unit),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_fleet_table_lines_up_projects_and_lists_errors() {
+        let now = Utc.ymd(2026, 10, 14).and_hms(12, 0, 0);
+        let statuses = vec![
+            FleetProjectStatus {
+                project: "WordPress-iOS".to_string(),
+                branch: Some("trunk".to_string()),
+                pinned_hash: Some("0123456789abcdef".to_string()),
+                pinned_at: Some("2026-10-11T12:00:00+00:00".to_string()),
+                commits_behind: Some(4),
+                last_applied: Some("2026-10-14T09:30:00+00:00".to_string()),
+                error: None,
+            },
+            FleetProjectStatus {
+                project: "Simplenote".to_string(),
+                error: Some("there's no .configure".to_string()),
+                ..Default::default()
+            },
+        ];
+
+        assert_eq!(
+            format_fleet_table(&statuses, now),
+            vec![
+                "PROJECT        BRANCH  PINNED                 BEHIND  LAST APPLY",
+                "WordPress-iOS  trunk   01234567 (3 days old)  4       2 hours ago",
+                "Simplenote     ?       ?                      ?       never",
+                "Simplenote: there's no .configure",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_projects_are_found_throughout_the_tree() {
+        let dir = tempfile::tempdir().unwrap();
+        for project in &[
+            "",
+            "apps/reader",
+            "apps/editor/module",
+            ".git/modules/old",
+            "apps/reader/node_modules/dependency",
+        ] {
+            let directory = dir.path().join(project);
+            std::fs::create_dir_all(&directory).unwrap();
+            std::fs::write(directory.join(".configure"), "{}").unwrap();
+        }
+        std::fs::create_dir_all(dir.path().join("apps/empty")).unwrap();
+
+        assert_eq!(
+            find_projects_under(dir.path()).unwrap(),
+            vec![
+                dir.path().to_path_buf(),
+                dir.path().join("apps/editor/module"),
+                dir.path().join("apps/reader"),
+            ]
+        );
+        assert!(find_projects_under(&dir.path().join("missing")).is_err());
+        assert!(find_projects_under(&dir.path().join("apps/reader/.configure")).is_err());
+    }
+
+    #[test]
+    fn test_manifest_paths_are_relative_to_it() {
+        let dir = tempfile::tempdir().unwrap();
+        let manifest = dir.path().join("fleet.txt");
+        std::fs::write(&manifest, "# Release apps\nWordPress-iOS\n\n  /src/Simplenote  \n").unwrap();
+
+        assert_eq!(
+            read_fleet_manifest(&manifest).unwrap(),
+            vec![dir.path().join("WordPress-iOS"), PathBuf::from("/src/Simplenote")]
+        );
+    }
+}
@@ -1,38 +1,61 @@
-use crate::encryption::{decrypt_file, encrypt_file};
+use crate::archive::{list_archive_entries, read_archive_entry};
+use crate::configure::{
+    project_name_problem, report_project_key_mismatch, report_unusual_project_name, sanitized_key_name, ApplyOptions, ApplyReport, ConflictResolution, ConflictResolver, FileOutcome, OnConflict,
+    ask_about_unrecorded_file,
+    ReencryptOutcome,
+};
+use crate::encryption::{
+    decrypt_contents, decrypt_file_contents, encrypt_contents, encrypt_file, encrypt_to_writer, Key,
+};
+use crate::key_metadata::{read_key_metadata, update_key_metadata, KeyMetadata, KEY_METADATA_FILE_NAME};
+use crate::git::{
+    blob_hash_of_file, check_secrets_repo, ensure_secrets_writable, read_file_at_ref,
+};
+use crate::plan::{plan_apply, Plan, PlanAction};
+use crate::sanity::{check_contents, SanityChecks, SanityFinding};
+use crate::state::{modified_time, read_apply_state, save_apply_state, ApplyState, FileOrigin};
+use crate::configure::ConfigurationParseError;
 use crate::ConfigurationFile;
 use crate::ConfigureError;
-use log::{debug, info};
+use log::{debug, info, warn};
 use ring::digest::{Context, SHA256};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
 use std::env;
-use std::fs::{create_dir_all, remove_file, rename, File};
-use std::io::{BufReader, Error, Read, Write};
-use std::path::PathBuf;
-use serde_json::json;
+use std::fs::{create_dir_all, rename, File, Permissions};
+use std::io::{BufReader, BufWriter, Error, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::sync::Mutex;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use serde_json::{json, Value};
 
 /// Find the .configure file in the current project
-pub fn find_configure_file() -> PathBuf {
-    let project_root = find_project_root();
+pub fn find_configure_file() -> Result<PathBuf, ConfigureError> {
+    let project_root = find_project_root()?;
 
     let configure_file_path = project_root.join(".configure");
 
     debug!("Configure file found at: {:?}", configure_file_path);
 
-    if !configure_file_path.exists() {
-        info!(
-            "No configure file found at: {:?}. Creating one for you",
-            configure_file_path
-        );
+    Ok(configure_file_path)
+}
 
-        save_configuration(&ConfigurationFile::default())
-            .expect("There is no `configure.json` file in your project, and creating one failed");
-    }
+/// Where every project's key is kept, in the root of the secrets repository
+pub const KEYS_FILE_NAME: &str = "keys.json";
+
+/// The previous version of `keys.json`, kept beside it whenever it's changed
+pub const KEYS_BACKUP_FILE_NAME: &str = "keys.json.bak";
 
-    configure_file_path
+/// Where `keys.json` is (or would be) in the secrets repository
+pub fn keys_file_path() -> Result<PathBuf, ConfigureError> {
+    Ok(find_secrets_repo()?.join(KEYS_FILE_NAME))
 }
 
 pub fn find_keys_file() -> Result<PathBuf, ConfigureError> {
-    let secrets_root = find_secrets_repo();
-    let keys_file_path = secrets_root?.join("keys.json");
+    let keys_file_path = keys_file_path()?;
 
     debug!("Keys file found at: {:?}", keys_file_path);
 
@@ -49,25 +72,76 @@ pub fn find_keys_file() -> Result<PathBuf, ConfigureError> {
     Ok(keys_file_path)
 }
 
-pub fn find_project_root() -> PathBuf {
+/// Where `keys-metadata.json` is (or would be) in the secrets repository
+pub fn find_key_metadata_file() -> Result<PathBuf, ConfigureError> {
+    Ok(find_secrets_repo()?.join(KEY_METADATA_FILE_NAME))
+}
+
+/// The file or directory whose presence marks the project root, if the user chose one. When it's
+/// `None`, the project root is the root of the git repository.
+static PROJECT_ROOT_MARKER: Mutex<Option<String>> = Mutex::new(None);
+
+pub fn set_project_root_marker(marker: Option<String>) {
+    *PROJECT_ROOT_MARKER.lock().unwrap() = marker;
+}
+
+/// Find the directory that destinations in `.configure` are relative to
+pub fn find_project_root() -> Result<PathBuf, ConfigureError> {
     let path = env::current_dir().expect("Unable to determine current directory");
 
-    let repo = git2::Repository::discover(&path)
-        .expect("Unable to find the root of the respository – are you sure you're running this inside a git repo?");
+    if let Some(marker) = PROJECT_ROOT_MARKER.lock().unwrap().as_deref() {
+        let root = find_directory_containing(&path, marker).ok_or_else(|| {
+            ConfigureError::ProjectRootNotFound(format!(
+                "there's no {:?} in {:?} or any of its parents",
+                marker, path
+            ))
+        })?;
+
+        debug!("Discovered project root marker {:?} in {:?}", marker, root);
+
+        return Ok(root);
+    }
+
+    let repo = git2::Repository::discover(&path).map_err(|_| {
+        ConfigureError::ProjectRootNotFound(format!(
+            "{:?} isn't inside a git repository",
+            path
+        ))
+    })?;
 
     debug!("Discovered Repository at {:?}", &path);
 
-    repo.workdir().unwrap().to_path_buf()
+    match repo.workdir() {
+        Some(workdir) => Ok(workdir.to_path_buf()),
+        None => Err(ConfigureError::ProjectRootNotFound(format!(
+            "the repository at {:?} has no working directory",
+            repo.path()
+        ))),
+    }
+}
+
+/// Walks up from `start` to find the nearest directory that contains `marker`
+fn find_directory_containing(start: &Path, marker: &str) -> Option<PathBuf> {
+    start
+        .ancestors()
+        .find(|directory| directory.join(marker).exists())
+        .map(Path::to_path_buf)
+}
+
+/// The user's home directory, where `user` scope secrets and the secrets repository live
+pub fn home_directory() -> Result<PathBuf, ConfigureError> {
+    dirs::home_dir().ok_or(ConfigureError::HomeDirectoryUnknown)
 }
 
 pub fn find_secrets_repo() -> Result<PathBuf, ConfigureError> {
     // TODO: Allow the user to set their own secrets path using an environment variable
 
-    let home_dir = dirs::home_dir().expect("Unable to determine user home directory");
+    let home_dir = home_directory()?;
 
     let root_secrets_path = home_dir.join(".mobile-secrets");
 
     if root_secrets_path.exists() && root_secrets_path.is_dir() {
+        check_secrets_repo(&root_secrets_path)?;
         return Ok(root_secrets_path);
     }
 
@@ -76,6 +150,7 @@ pub fn find_secrets_repo() -> Result<PathBuf, ConfigureError> {
     if projects_path.exists() {
         let projects_secrets_path = projects_path.join(".mobile-secrets");
         if projects_secrets_path.exists() && projects_secrets_path.is_dir() {
+            check_secrets_repo(&projects_secrets_path)?;
             return Ok(projects_secrets_path);
         }
     }
@@ -83,206 +158,4113 @@ pub fn find_secrets_repo() -> Result<PathBuf, ConfigureError> {
     Err(crate::configure::ConfigureError::SecretsNotPresent)
 }
 
-pub fn read_configuration() -> ConfigurationFile {
-    let configure_file_path = find_configure_file();
-    let mut file = File::open(&configure_file_path).expect("Unable to open configuration file");
+/// Whether `.configure` files with fields configure doesn't know about are rejected, rather than
+/// the fields being ignored
+static REJECT_UNKNOWN_FIELDS: AtomicBool = AtomicBool::new(false);
 
-    let mut file_contents = String::new();
-    file.read_to_string(&mut file_contents)
-        .expect("Unable to read configuration file");
+pub fn set_reject_unknown_fields(reject: bool) {
+    REJECT_UNKNOWN_FIELDS.store(reject, Ordering::SeqCst);
+}
 
-    let result: ConfigurationFile = serde_json::from_str(&file_contents)
-        .expect("Unable to parse configuration file – the JSON is probably invalid");
+/// Reads the project's `.configure` if there is one – unlike `read_configuration`, this never
+/// creates it. Outside a project, there's no configuration either.
+pub fn read_configuration_if_present() -> Result<Option<ConfigurationFile>, ConfigureError> {
+    let path = match find_project_root() {
+        Ok(project_root) => project_root.join(".configure"),
+        Err(_) => return Ok(None),
+    };
 
-    result
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    read_configuration().map(Some)
+}
+
+/// Reads the project's `.configure`. A project without one reads as empty, like one whose file is
+/// empty – it isn't created until `configure init` saves it, so an `init` that's abandoned part
+/// way doesn't leave one behind.
+pub fn read_configuration() -> Result<ConfigurationFile, ConfigureError> {
+    let configure_file_path = find_configure_file()?;
+
+    if !configure_file_path.exists() {
+        debug!("There's no configure file at {:?} yet", configure_file_path);
+        return Ok(ConfigurationFile::default());
+    }
+
+    read_configuration_at(&configure_file_path)
+}
+
+/// Reads the `.configure` file at `configure_file_path`, which needn't be in this project
+pub fn read_configuration_at(
+    configure_file_path: &Path,
+) -> Result<ConfigurationFile, ConfigureError> {
+    let file_contents = std::fs::read_to_string(configure_file_path).map_err(|source| {
+        ConfigurationParseError::Unreadable {
+            path: configure_file_path.display().to_string(),
+            source,
+        }
+    })?;
+
+    Ok(parse_configuration(
+        &configure_file_path.display().to_string(),
+        &file_contents,
+        REJECT_UNKNOWN_FIELDS.load(Ordering::SeqCst),
+    )?)
+}
+
+/// Parses the contents of a `.configure` file, describing exactly where any problem is.
+///
+/// A file that's blank or just `{}` is a project that hasn't been set up yet, and parses as an
+/// empty configuration, which commands other than `init` refuse to use.
+///
+/// Fields configure doesn't know about are ignored, so that older versions can read files written
+/// for newer ones – unless `reject_unknown_fields` is set, which catches typos like
+/// `file_to_copy`. `path` is only used in error messages.
+pub fn parse_configuration(
+    path: &str,
+    contents: &str,
+    reject_unknown_fields: bool,
+) -> Result<ConfigurationFile, ConfigurationParseError> {
+    if contents.trim().is_empty() {
+        return Ok(ConfigurationFile::default());
+    }
+
+    // Parse the JSON separately first, so broken JSON is told apart from JSON with the wrong fields
+    let value: serde_json::Value = match serde_json::from_str(contents) {
+        Ok(value) => value,
+        Err(err) => {
+            return Err(ConfigurationParseError::InvalidJson {
+                path: path.to_string(),
+                message: describe_json_error(&err),
+                line: err.line(),
+                column: err.column(),
+                snippet: annotated_snippet(contents, err.line(), err.column()),
+            })
+        }
+    };
+
+    if value.as_object().is_some_and(serde_json::Map::is_empty) {
+        return Ok(ConfigurationFile::default());
+    }
+
+    if reject_unknown_fields {
+        if let Some((field, name)) = find_unknown_field(&value) {
+            let (line, column) = locate_key(contents, &name).unwrap_or((0, 0));
+
+            return Err(ConfigurationParseError::UnknownField {
+                path: path.to_string(),
+                field,
+                line,
+                column,
+                snippet: annotated_snippet(contents, line, column),
+            });
+        }
+    }
+
+    serde_json::from_str(contents).map_err(|err| ConfigurationParseError::WrongShape {
+        path: path.to_string(),
+        field: locate_shape_error(&value),
+        message: describe_json_error(&err),
+        line: err.line(),
+        column: err.column(),
+        snippet: annotated_snippet(contents, err.line(), err.column()),
+    })
+}
+
+/// Finds the first field in a parsed `.configure` document that `ConfigurationFile` doesn't have,
+/// returning where it is (like `files_to_copy[3].destinaton`) and its name
+fn find_unknown_field(value: &serde_json::Value) -> Option<(String, String)> {
+    let unknown_in = |value: &serde_json::Value, fields: &[&str], prefix: &str| {
+        value.as_object().and_then(|object| {
+            object
+                .keys()
+                .find(|key| !fields.contains(&key.as_str()))
+                .map(|key| (format!("{}{}", prefix, key), key.clone()))
+        })
+    };
+
+    if let Some(unknown) = unknown_in(value, struct_fields::<ConfigurationFile>(), "") {
+        return Some(unknown);
+    }
+
+    if let Some(files) = value.get("files_to_copy").and_then(|files| files.as_array()) {
+        for (index, file) in files.iter().enumerate() {
+            let prefix = format!("files_to_copy[{}].", index);
+            if let Some(unknown) = unknown_in(file, struct_fields::<crate::File>(), &prefix) {
+                return Some(unknown);
+            }
+        }
+    }
+
+    value
+        .get("sanity_checks")
+        .and_then(|checks| unknown_in(checks, struct_fields::<SanityChecks>(), "sanity_checks."))
+}
+
+/// The names of the fields serde reads a struct from, renames included.
+///
+/// serde passes them to `Deserializer::deserialize_struct`, so this deserializer just captures
+/// them and gives up. That keeps the list in step with the struct without maintaining a copy.
+fn struct_fields<T: serde::de::DeserializeOwned>() -> &'static [&'static str] {
+    use serde::de::{Error, Visitor};
+
+    struct FieldNames<'a>(&'a mut &'static [&'static str]);
+
+    impl<'de, 'a> serde::Deserializer<'de> for FieldNames<'a> {
+        type Error = serde::de::value::Error;
+
+        fn deserialize_any<V: Visitor<'de>>(self, _: V) -> Result<V::Value, Self::Error> {
+            Err(Self::Error::custom("only structs have fields"))
+        }
+
+        fn deserialize_struct<V: Visitor<'de>>(
+            self,
+            _name: &'static str,
+            fields: &'static [&'static str],
+            _visitor: V,
+        ) -> Result<V::Value, Self::Error> {
+            *self.0 = fields;
+            Err(Self::Error::custom("the fields have been captured"))
+        }
+
+        serde::forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 u8 u16 u32 u64 f32 f64 char str string bytes byte_buf option unit
+            unit_struct newtype_struct seq tuple tuple_struct map enum identifier ignored_any
+        }
+    }
+
+    let mut fields: &'static [&'static str] = &[];
+    let _ = T::deserialize(FieldNames(&mut fields));
+    fields
+}
+
+/// The line and column of the first place `name` is used as a key
+fn locate_key(contents: &str, name: &str) -> Option<(usize, usize)> {
+    let quoted = format!("\"{}\"", name);
+
+    contents.lines().enumerate().find_map(|(index, line)| {
+        line.match_indices(&quoted)
+            .find(|(offset, _)| line[offset + quoted.len()..].trim_start().starts_with(':'))
+            .map(|(offset, _)| (index + 1, line[..offset].chars().count() + 1))
+    })
+}
+
+/// serde_json's messages end with the location, which we show separately
+fn describe_json_error(err: &serde_json::Error) -> String {
+    let message = err.to_string();
+    match message.rfind(" at line ") {
+        Some(index) => message[..index].to_string(),
+        None => message,
+    }
+}
+
+/// Works out which part of a parsed `.configure` document doesn't match `ConfigurationFile`
+fn locate_shape_error(value: &serde_json::Value) -> String {
+    if !value.is_object() {
+        return "the top level".to_string();
+    }
+
+    if let Some(files) = value.get("files_to_copy").and_then(|files| files.as_array()) {
+        for (index, file) in files.iter().enumerate() {
+            if serde_json::from_value::<crate::File>(file.clone()).is_err() {
+                return format!("files_to_copy[{}]", index);
+            }
+        }
+    }
+
+    for field in &["project_name", "branch", "pinned_hash", "files_to_copy"] {
+        match value.get(field) {
+            None => return format!("`{}` (it's missing)", field),
+            Some(field_value) if *field == "files_to_copy" && !field_value.is_array() => {
+                return format!("`{}`", field)
+            }
+            Some(field_value) if *field != "files_to_copy" && !field_value.is_string() => {
+                return format!("`{}`", field)
+            }
+            _ => {}
+        }
+    }
+
+    "the document".to_string()
+}
+
+/// Shows the lines around `line` with a caret under `column`, like a compiler would
+fn annotated_snippet(contents: &str, line: usize, column: usize) -> String {
+    let lines: Vec<&str> = contents.lines().collect();
+
+    if line == 0 || lines.is_empty() {
+        return String::new();
+    }
+
+    let line = line.min(lines.len());
+    let first = line.saturating_sub(2).max(1);
+    let width = line.to_string().len();
+
+    let mut snippet = String::new();
+
+    for number in first..=line {
+        snippet += &format!("{:>width$} | {}\n", number, lines[number - 1], width = width);
+    }
+
+    snippet += &format!(
+        "{:>width$} | {}^",
+        "",
+        " ".repeat(column.saturating_sub(1)),
+        width = width
+    );
+
+    snippet
 }
 
-pub fn save_configuration(configuration: &ConfigurationFile) -> Result<(), Error> {
-    let serialized = serde_json::to_string_pretty(&configuration)?;
+pub fn save_configuration(configuration: &ConfigurationFile) -> Result<(), ConfigureError> {
+    let configure_file = find_configure_file()?;
+    write_configuration(&configure_file, configuration)?;
+    Ok(())
+}
 
-    let configure_file = find_configure_file();
+fn write_configuration(path: &Path, configuration: &ConfigurationFile) -> Result<(), Error> {
+    let serialized = serialize_configuration(configuration);
 
-    debug!("Writing to: {:?}", configure_file);
+    debug!("Writing to: {:?}", path);
 
-    let mut file = File::create(configure_file)?;
+    let mut file = File::create(path)?;
     file.write_all(serialized.as_bytes())?;
     Ok(())
 }
 
-pub fn read_encryption_key(configuration: &ConfigurationFile) -> Result<Option<String>, ConfigureError> {
-    let keys_file_path = find_keys_file()?;
+/// Serializes `.configure` the same way every time, so saving it only changes what actually
+/// changed: keys are sorted, `files_to_copy` is sorted by destination (then variant and source),
+/// and there's a trailing newline
+pub fn serialize_configuration(configuration: &ConfigurationFile) -> String {
+    let mut sorted = configuration.clone();
+    sorted.files_to_copy.sort_by(|a, b| {
+        (&a.destination, &a.variant, &a.source).cmp(&(&b.destination, &b.variant, &b.source))
+    });
 
-    debug!("Reading keys from {:?}", keys_file_path);
+    let mut value = serde_json::to_value(&sorted).expect("Unable to serialize the configuration");
+    sort_keys(&mut value);
 
-    let file = match File::open(keys_file_path) {
-        Ok(file) => file,
-        Err(_) => return Err(ConfigureError::KeysFileCannotBeRead),
-    };
+    let mut serialized = serde_json::to_string_pretty(&value).unwrap();
+    serialized.push('\n');
+    serialized
+}
 
-    let json: serde_json::Value = match serde_json::from_reader(file) {
-        Ok(json) => json,
-        Err(_) => return Err(ConfigureError::KeysFileIsNotValidJSON),
-    };
+fn sort_keys(value: &mut Value) {
+    match value {
+        Value::Object(object) => {
+            let mut entries: Vec<(String, Value)> = std::mem::take(object).into_iter().collect();
+            entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+            for (key, mut value) in entries {
+                sort_keys(&mut value);
+                object.insert(key, value);
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(sort_keys),
+        _ => {}
+    }
+}
 
-    match json.get(&configuration.project_name) {
-        Some(key) => return Ok(Some(String::from(key.as_str().unwrap()))),
+pub fn read_encryption_key(configuration: &ConfigurationFile) -> Result<Option<Key>, ConfigureError> {
+    let keys = read_keys()?;
+
+    let key_name = match match_project_key(&keys, &configuration.project_name) {
+        Some(ProjectKeyMatch::Exact) => configuration.project_name.clone(),
+        Some(ProjectKeyMatch::Sanitized(key_name)) => key_name,
+        Some(ProjectKeyMatch::Fuzzy(key_name)) => {
+            report_project_key_mismatch(&configuration.project_name, &key_name)?;
+            key_name
+        }
         None => return Ok(None),
     };
+
+    if let Some(problem) = project_name_problem(&configuration.project_name) {
+        report_unusual_project_name(&configuration.project_name, &problem);
+    }
+
+    let key = keys[&key_name]
+        .as_str()
+        .and_then(Key::from_base64)
+        .ok_or_else(|| ConfigureError::InvalidProjectKey(key_name.clone()))?;
+
+    let metadata = read_key_metadata(&find_key_metadata_file()?)?;
+    match metadata.get(&key_name).and_then(|metadata| metadata.algorithm.as_deref()) {
+        Some(algorithm) => key.with_algorithm(algorithm).map(Some),
+        None => Ok(Some(key)),
+    }
 }
 
-pub fn generate_encryption_key(configuration: &ConfigurationFile) -> Result<(), ConfigureError> {
-    let keys_file_path = find_keys_file()?;
+/// Reads every project's key from `keys.json`
+pub fn read_keys() -> Result<serde_json::Map<String, serde_json::Value>, ConfigureError> {
+    read_keys_at(&find_keys_file()?)
+}
 
-    let file = match File::open(&keys_file_path) {
-        Ok(file) => file,
-        Err(_) => return Err(ConfigureError::KeysFileCannotBeRead),
-    };
+/// `keys.json` as it was last parsed, so a run that needs it more than once only parses it once
+struct ParsedKeys {
+    path: PathBuf,
+
+    /// The hash of the file's contents, so it's parsed again if it's changed at all – when the
+    /// secrets repository switches branches, say. Keys are all the same length, so a changed file
+    /// can have the same size, and even the same modification time.
+    digest: String,
+
+    keys: serde_json::Map<String, Value>,
+}
+
+static PARSED_KEYS: Mutex<Option<ParsedKeys>> = Mutex::new(None);
+
+fn read_keys_at(path: &Path) -> Result<serde_json::Map<String, Value>, ConfigureError> {
+    let contents = std::fs::read(path).map_err(|_| ConfigureError::KeysFileCannotBeRead)?;
+    let digest = hash_bytes(&contents);
+
+    let mut parsed = PARSED_KEYS.lock().unwrap();
+    if let Some(parsed) = parsed.as_ref() {
+        if parsed.path == path && parsed.digest == digest {
+            return Ok(parsed.keys.clone());
+        }
+    }
 
-    let mut json: serde_json::Value = match serde_json::from_reader(file) {
-        Ok(json) => json,
-        Err(_) => return Err(ConfigureError::KeysFileIsNotValidJSON),
+    debug!("Reading keys from {:?}", path);
+
+    let keys = match serde_json::from_slice(&contents) {
+        Ok(serde_json::Value::Object(keys)) => keys,
+        _ => return Err(ConfigureError::KeysFileIsNotValidJSON),
     };
 
-    json[&configuration.project_name] = json!("Foo!");
+    *parsed = Some(ParsedKeys {
+        path: path.to_path_buf(),
+        digest,
+        keys: keys.clone(),
+    });
+
+    Ok(keys)
+}
 
-    write_file_with_contents(&keys_file_path, &serde_json::to_string_pretty(&json).unwrap())?;
+/// How a project's name matched an entry in `keys.json`
+#[derive(Debug, Eq, PartialEq)]
+pub enum ProjectKeyMatch {
+    /// There's an entry with exactly the project's name
+    Exact,
 
-    Ok(())
+    /// There's an entry with the project's sanitized name, like `apps-reader-beta` for
+    /// `apps/reader (beta)`. Holds the entry's name.
+    Sanitized(String),
+
+    /// The only match is an entry whose name differs in case or separators, like `wordpress-ios`
+    /// for `WordPress-iOS`. Holds the entry's name.
+    Fuzzy(String),
 }
 
-pub fn decrypt_files_for_configuration(
-    configuration: &ConfigurationFile,
-) -> Result<(), ConfigureError> {
-    let project_root = find_project_root();
-    let encryption_key = match read_encryption_key(configuration) {
-        Ok(key)   => match key {
-            Some(value) => value,
-            None        => return Err(ConfigureError::MissingProjectKey),
-        },
-        Err(err)  => return Err(err),
-    };
+/// Finds the entry in `keys` for `project_name`.
+///
+/// An exact match always wins, so names from before project names were checked keep working.
+/// Then comes the sanitized name (see `sanitized_key_name`). Otherwise, names are compared
+/// ignoring case and the separators `-`, `_`, `.` and space – but that's only a match if exactly
+/// one entry matches, so an ambiguous name is never resolved to an arbitrary key.
+pub fn match_project_key(
+    keys: &serde_json::Map<String, serde_json::Value>,
+    project_name: &str,
+) -> Option<ProjectKeyMatch> {
+    if keys.contains_key(project_name) {
+        return Some(ProjectKeyMatch::Exact);
+    }
 
-    for file in &configuration.files_to_copy {
-        let source = project_root.join(&file.get_encrypted_destination());
-        let destination = project_root.join(&file.get_decrypted_destination());
+    let sanitized = sanitized_key_name(project_name);
+    if !project_name.trim().is_empty() && keys.contains_key(&sanitized) {
+        return Some(ProjectKeyMatch::Sanitized(sanitized));
+    }
 
-        create_parent_directory_for_path_if_not_exists(&destination)?;
+    let normalized = normalize_project_name(project_name);
+    if normalized.is_empty() {
+        return None;
+    }
 
-        // If the developer tries to run `configure_apply` while missing the encrypted originals, this script will crash saying "missing file"
-        // We can try to detect this scenario and fix things for the developer if the secrets are available locally, but it's tricky because
-        // we'd need to basically run `configure update` inside this method for just the one file. For now, we'll just error out.
-        if !source.exists() {
-            info!("Encrypted original file at {:?} not found", source);
-            return Err(ConfigureError::EncryptedFileMissing {});
+    let mut matches = keys
+        .keys()
+        .filter(|key_name| normalize_project_name(key_name) == normalized);
+
+    match (matches.next(), matches.next()) {
+        (Some(key_name), None) => Some(ProjectKeyMatch::Fuzzy(key_name.clone())),
+        _ => None,
+    }
+}
+
+fn normalize_project_name(name: &str) -> String {
+    name.chars()
+        .filter(|c| !matches!(c, '-' | '_' | '.' | ' '))
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+/// The entries in `keys` that none of `active_projects` use. A project uses the entry
+/// `match_project_key` finds for it, so an entry that only matches ignoring case is kept too.
+pub fn find_orphaned_keys(
+    keys: &serde_json::Map<String, serde_json::Value>,
+    active_projects: &[String],
+) -> Vec<String> {
+    let used: Vec<String> = active_projects
+        .iter()
+        .filter_map(|project| match match_project_key(keys, project)? {
+            ProjectKeyMatch::Exact => Some(project.clone()),
+            ProjectKeyMatch::Sanitized(key_name) | ProjectKeyMatch::Fuzzy(key_name) => Some(key_name),
+        })
+        .collect();
+
+    keys.keys()
+        .filter(|key_name| !used.contains(key_name))
+        .cloned()
+        .collect()
+}
+
+/// Removes the `orphans` entries from `keys.json`, returning the ones it removed. Which entries
+/// are orphaned is checked again against the file under its lock, so an entry that an active
+/// project has started using since is never removed.
+pub fn remove_orphaned_keys(
+    orphans: &[String],
+    active_projects: &[String],
+) -> Result<Vec<String>, ConfigureError> {
+    remove_orphaned_keys_in_file(&find_keys_file()?, orphans, active_projects)
+}
+
+fn remove_orphaned_keys_in_file(
+    path: &Path,
+    orphans: &[String],
+    active_projects: &[String],
+) -> Result<Vec<String>, ConfigureError> {
+    let mut removed = Vec::new();
+
+    update_keys_file(path, |keys| {
+        for key_name in find_orphaned_keys(keys, active_projects) {
+            if orphans.contains(&key_name) {
+                keys.remove(&key_name);
+                removed.push(key_name);
+            }
         }
+        Ok(())
+    })?;
 
-        // If the file already exists, make a backup of the old one in case we need it later
-        if destination.exists() {
-            let backup_destination = project_root.join(&file.get_backup_destination());
+    let metadata_path = path.with_file_name(KEY_METADATA_FILE_NAME);
+    if metadata_path.exists() {
+        update_key_metadata(&metadata_path, |metadata| {
+            for key_name in &removed {
+                metadata.remove(key_name);
+            }
+        })?;
+    }
 
-            debug!(
-                "{:?} already exists – making a backup at {:?}",
-                destination, backup_destination
-            );
-            rename(&destination, &backup_destination)?;
+    Ok(removed)
+}
 
-            // Encrypt the file and write the encrypted contents to the destination
-            debug!(
-                "Encrypting file at {:?} and storing contents at {:?}",
-                source, destination
-            );
-            decrypt_file(&source, &destination, &encryption_key)?;
+/// Reads a list of project names, one per line. Blank lines and lines starting with `#` are
+/// skipped.
+pub fn read_project_names(path: &Path) -> Result<Vec<String>, Error> {
+    Ok(std::fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+        .collect())
+}
+
+/// Adds a project's key to `keys.json`, replacing any key it already has.
+///
+/// `previous` is the key the project had when the caller looked (`None` if it didn't have one).
+/// If someone else has changed it since, nothing is written and this fails with
+/// `KeyChangedSinceRead`, rather than clobbering their key.
+pub fn save_project_key(
+    project_name: &str,
+    key: &str,
+    previous: Option<&str>,
+) -> Result<(), ConfigureError> {
+    save_key_in_file(&find_keys_file()?, project_name, key, previous)
+}
+
+fn save_key_in_file(
+    path: &Path,
+    project_name: &str,
+    key: &str,
+    previous: Option<&str>,
+) -> Result<(), ConfigureError> {
+    update_keys_file(path, |keys| {
+        let current = keys.get(project_name).and_then(|key| key.as_str());
+
+        if current != previous && current != Some(key) {
+            return Err(ConfigureError::KeyChangedSinceRead(project_name.to_string()));
+        }
+
+        keys.insert(project_name.to_string(), json!(key));
+        Ok(())
+    })
+}
+
+/// How long to wait for another configure to finish with `keys.json`
+const KEYS_LOCK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A lock left behind for this long belongs to a configure that crashed
+const LOCK_STALE_AFTER: Duration = Duration::from_secs(60);
+
+/// How often a configure holding a lock marks it as still in use, so however long it holds it –
+/// a slow apply, say – it's never taken for one that crashed
+const LOCK_REFRESH_INTERVAL: Duration = Duration::from_secs(15);
+
+/// How often to say that configure is still waiting for a lock
+const LOCK_WAIT_LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Where `apply` takes its lock in the project root, as `.configure-apply.lock`
+pub const APPLY_LOCK_NAME: &str = ".configure-apply";
+
+/// Holds the lock on a file until it's dropped
+pub struct FileLock {
+    path: PathBuf,
+
+    /// Keeps the lock's modification time recent while it's held. Dropping the sender stops it.
+    refresher: Option<(Sender<()>, JoinHandle<()>)>,
+}
 
-            // If the backup file is identical to the old file, remove the backup
-            let new_file_hash = hash_file(&destination);
-            let original_file_hash = hash_file(&backup_destination);
+impl FileLock {
+    /// Takes the lock on `path`, by creating `<path>.lock`. Waits for another process holding it.
+    pub fn acquire(path: &Path) -> Result<FileLock, ConfigureError> {
+        FileLock::acquire_within(path, KEYS_LOCK_TIMEOUT, |_| {})
+    }
 
-            debug!("Original File Hash: {:?}", original_file_hash);
-            debug!("New File hash: {:?}", new_file_hash);
+    /// Like `acquire`, but waits up to `timeout`, calling `waiting` with how long it's waited
+    /// every `LOCK_WAIT_LOG_INTERVAL`. A zero timeout fails at once if the lock is held.
+    fn acquire_within<F>(
+        path: &Path,
+        timeout: Duration,
+        mut waiting: F,
+    ) -> Result<FileLock, ConfigureError>
+    where
+        F: FnMut(Duration),
+    {
+        let mut lock_path = path.as_os_str().to_owned();
+        lock_path.push(".lock");
+        let lock_path = PathBuf::from(lock_path);
+        let started = Instant::now();
+        let mut next_log = LOCK_WAIT_LOG_INTERVAL;
 
-            if hash_file(&destination)? == hash_file(&backup_destination)? {
-                debug!("Removing backup file because it's the same as the original");
-                remove_file(&backup_destination)?;
-            } else {
-                debug!("Keeping backup file because it differs from the original");
+        loop {
+            match std::fs::OpenOptions::new().write(true).create_new(true).open(&lock_path) {
+                Ok(_) => return Ok(FileLock::hold(lock_path, LOCK_REFRESH_INTERVAL)),
+                Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {}
+                Err(err) => return Err(err.into()),
             }
 
-        } else {
-            // Encrypt the file and write the encrypted contents to the destination
-            debug!(
-                "Encrypting file at {:?} and storing contents at {:?}",
-                source, destination
-            );
-            decrypt_file(&source, &destination, &encryption_key)?;
+            let age = std::fs::metadata(&lock_path)
+                .and_then(|metadata| metadata.modified())
+                .ok()
+                .and_then(|modified| modified.elapsed().ok());
+
+            if age.is_some_and(|age| age > LOCK_STALE_AFTER) {
+                warn!("Removing the stale lock {:?}", lock_path);
+                let _ = std::fs::remove_file(&lock_path);
+                continue;
+            }
+
+            let waited = started.elapsed();
+            if waited >= timeout {
+                return Err(ConfigureError::FileLocked(lock_path));
+            }
+
+            if waited >= next_log {
+                waiting(waited);
+                next_log += LOCK_WAIT_LOG_INTERVAL;
+            }
+
+            std::thread::sleep(Duration::from_millis(50));
         }
     }
 
-    Ok(())
+    /// Holds the lock at `lock_path`, which has just been created, touching it every
+    /// `refresh_every` until it's dropped
+    fn hold(lock_path: PathBuf, refresh_every: Duration) -> FileLock {
+        let (stop, stopped) = channel::<()>();
+        let path = lock_path.clone();
+
+        let refresher = std::thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(refresh_every) {
+                let _ = std::fs::OpenOptions::new()
+                    .write(true)
+                    .open(&path)
+                    .and_then(|file| file.set_modified(std::time::SystemTime::now()));
+            }
+        });
+
+        FileLock {
+            path: lock_path,
+            refresher: Some((stop, refresher)),
+        }
+    }
 }
 
-pub fn write_encrypted_files_for_configuration(
-    configuration: &ConfigurationFile,
-) -> Result<(), ConfigureError> {
-    let project_root = find_project_root();
-    let secrets_root = find_secrets_repo().unwrap();
-    let encryption_key = match read_encryption_key(configuration) {
-        Ok(key)   => match key {
-            Some(value) => value,
-            None        => return Err(ConfigureError::MissingProjectKey),
-        },
-        Err(err)  => return Err(err),
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        // Stop touching the lock before removing it, so it isn't touched again afterwards
+        if let Some((stop, refresher)) = self.refresher.take() {
+            drop(stop);
+            let _ = refresher.join();
+        }
+
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Takes the project's apply lock, so two applies in the same checkout don't write its secrets at
+/// the same time. Without `retry_seconds`, this fails at once if another apply holds it.
+/// Otherwise it waits up to that long, saying now and then that it's still waiting.
+pub fn lock_project_for_apply(
+    project_root: &Path,
+    retry_seconds: Option<u64>,
+) -> Result<FileLock, ConfigureError> {
+    let timeout = Duration::from_secs(retry_seconds.unwrap_or(0));
+
+    FileLock::acquire_within(&project_root.join(APPLY_LOCK_NAME), timeout, |waited| {
+        info!(
+            "Waiting for another configure to finish applying secrets here ({}s so far)",
+            waited.as_secs()
+        )
+    })
+    .map_err(|err| match (err, retry_seconds) {
+        (ConfigureError::FileLocked(path), Some(seconds)) => {
+            ConfigureError::LockTimedOut { path, seconds }
+        }
+        (err, _) => err,
+    })
+}
+
+/// Changes `keys.json` at `path` while holding its lock, so simultaneous changes by other
+/// configure processes aren't lost. The file is read afresh under the lock and replaced
+/// atomically, so it's never seen half-written. Nothing is written if `change` fails, or if it
+/// doesn't change anything.
+///
+/// Keys are written sorted by name, so changes to the file diff cleanly, and the version being
+/// replaced is copied to `keys.json.bak` first (see `keys_backup_path`).
+fn update_keys_file<F>(path: &Path, change: F) -> Result<(), ConfigureError>
+where
+    F: FnOnce(&mut serde_json::Map<String, Value>) -> Result<(), ConfigureError>,
+{
+    ensure_secrets_writable("change keys.json")?;
+    let _lock = FileLock::acquire(path)?;
+
+    let contents = std::fs::read_to_string(path).map_err(|_| ConfigureError::KeysFileCannotBeRead)?;
+    let mut keys = match serde_json::from_str(&contents) {
+        Ok(Value::Object(keys)) => keys,
+        _ => return Err(ConfigureError::KeysFileIsNotValidJSON),
     };
 
-    for file in &configuration.files_to_copy {
-        let source = &secrets_root.join(&file.source);
-        let destination = project_root.join(&file.get_encrypted_destination());
+    change(&mut keys)?;
 
-        create_parent_directory_for_path_if_not_exists(&destination)?;
+    let sorted: BTreeMap<&String, &Value> = keys.iter().collect();
+    let serialized = format!("{}\n", serde_json::to_string_pretty(&sorted).unwrap());
+    if serialized == contents {
+        return Ok(());
+    }
 
-        // Encrypt the file and write the encrypted contents to the destination
-        debug!(
-            "Encrypting file at {:?} and storing contents at {:?}",
-            source, destination
-        );
+    std::fs::copy(path, keys_backup_path(path))?;
 
-        encrypt_file(&source, &destination, &encryption_key)?;
+    let temporary_path = temporary_path_for(path);
+    if let Err(err) = std::fs::write(&temporary_path, serialized) {
+        let _ = std::fs::remove_file(&temporary_path);
+        return Err(err.into());
     }
+    rename(&temporary_path, path)?;
+    *PARSED_KEYS.lock().unwrap() = None;
 
     Ok(())
 }
 
-/// Helper method to create an empty file
-fn write_file_with_contents(path: &PathBuf, contents: &str) -> Result<(), std::io::Error> {
-    let mut file = File::create(path)?;
-    file.write_all(contents.as_bytes())?;
-    Ok(())
+/// Where the version of `keys.json` at `path` that's being replaced is kept: in the git directory
+/// of the repository it's in, so the backup can't be committed by mistake, or beside it otherwise
+fn keys_backup_path(path: &Path) -> PathBuf {
+    let directory = path.parent().unwrap_or_else(|| Path::new(""));
+
+    match git2::Repository::open(directory) {
+        Ok(repo) => repo.path().join(KEYS_BACKUP_FILE_NAME),
+        Err(_) => path.with_file_name(KEYS_BACKUP_FILE_NAME),
+    }
 }
 
-/// Returns the SHA-256 hash of a file at the given path
-fn hash_file(path: &PathBuf) -> Result<String, Error> {
-    let input = File::open(path)?;
-    let mut reader = BufReader::new(input);
-    let mut context = Context::new(&SHA256);
-    let mut buffer = [0; 1024];
+/// Writes a file only the current user can read. Refuses to replace an existing file.
+pub fn write_private_file(path: &Path, contents: &str) -> Result<(), Error> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
 
-    loop {
-        let count = reader.read(&mut buffer)?;
-        if count == 0 {
-            break;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    options.open(path)?.write_all(contents.as_bytes())
+}
+
+/// Renames a project's entry in `keys.json`, keeping its key
+pub fn rename_project_key(from: &str, to: &str) -> Result<(), ConfigureError> {
+    update_keys_file(&find_keys_file()?, |keys| {
+        if let Some(key) = keys.remove(from) {
+            keys.insert(to.to_string(), key);
         }
-        context.update(&buffer[..count]);
+        Ok(())
+    })?;
+
+    let metadata_path = find_key_metadata_file()?;
+    if metadata_path.exists() {
+        update_key_metadata(&metadata_path, |metadata| {
+            if let Some(entry) = metadata.remove(from) {
+                metadata.insert(to.to_string(), entry);
+            }
+        })?;
     }
 
-    let digest = context.finish();
+    Ok(())
+}
 
-    Ok(base64::encode(digest.as_ref()))
+/// Copies the key for `from` in `keys.json`, and its metadata, to `to` – leaving `from` where it
+/// is, so checkouts still using the old name keep working. Returns whether there was a key to
+/// copy. Fails with `KeyWouldOverwrite` if `to` already has a different key.
+pub fn copy_project_key(from: &str, to: &str) -> Result<bool, ConfigureError> {
+    copy_key_in_file(&find_keys_file()?, from, to)
 }
 
-fn create_parent_directory_for_path_if_not_exists(path: &PathBuf) -> Result<(), Error> {
-    let parent = match path.parent() {
-        Some(parent) => parent,
-        None => return Ok(()), // if we're in the root of the filesystem, we have no work to do
-    };
+fn copy_key_in_file(path: &Path, from: &str, to: &str) -> Result<bool, ConfigureError> {
+    let mut copied = false;
 
-    Ok(create_dir_all(parent)?)
+    update_keys_file(path, |keys| {
+        let key = match keys.get(from) {
+            Some(key) => key.clone(),
+            None => return Ok(()),
+        };
+
+        match keys.get(to) {
+            Some(existing) if *existing != key => {
+                return Err(ConfigureError::KeyWouldOverwrite(to.to_string()))
+            }
+            _ => keys.insert(to.to_string(), key),
+        };
+
+        copied = true;
+        Ok(())
+    })?;
+
+    let metadata_path = path.with_file_name(KEY_METADATA_FILE_NAME);
+    if copied && metadata_path.exists() {
+        update_key_metadata(&metadata_path, |metadata| {
+            if let Some(entry) = metadata.get(from).cloned() {
+                metadata.entry(to.to_string()).or_insert(entry);
+            }
+        })?;
+    }
+
+    Ok(copied)
+}
+
+/// Like `read_encryption_key`, but treats a missing key as an error
+pub fn require_encryption_key(configuration: &ConfigurationFile) -> Result<Key, ConfigureError> {
+    match read_encryption_key(configuration)? {
+        Some(key) => Ok(key),
+        None => Err(ConfigureError::MissingProjectKey),
+    }
+}
+
+/// Stores a new key for the project in `keys.json`, under its sanitized name, recording when it
+/// was created
+pub fn generate_encryption_key(configuration: &ConfigurationFile) -> Result<(), ConfigureError> {
+    let key_name = sanitized_key_name(&configuration.project_name);
+    let key = crate::encryption::generate_key();
+    save_project_key(&key_name, &key.to_base64(), None)?;
+
+    update_key_metadata(&find_key_metadata_file()?, |metadata| {
+        metadata.insert(key_name, KeyMetadata::created(chrono::Utc::now()));
+    })
+}
+
+pub fn decrypt_files_for_configuration(
+    configuration: &ConfigurationFile,
+    options: &ApplyOptions,
+) -> Result<ApplyReport, ConfigureError> {
+    let project_root = find_project_root()?;
+
+    // The key is resolved once, and its cipher shared by every file
+    let resolving = Instant::now();
+    let encryption_key = require_encryption_key(configuration)?;
+    let resolving = resolving.elapsed();
+
+    let max_file_size = options.max_file_size.or(configuration.max_file_size);
+    let mut decrypting = Duration::ZERO;
+    let placing = Instant::now();
+
+    let result = place_files_for_configuration(configuration, options, &project_root, true, |file| {
+        let source = project_root.join(file.get_encrypted_destination());
+
+        // If the developer tries to run `configure_apply` while missing the encrypted originals, this script will crash saying "missing file"
+        // We can try to detect this scenario and fix things for the developer if the secrets are available locally, but it's tricky because
+        // we'd need to basically run `configure update` inside this method for just the one file. For now, we'll just error out.
+        if !source.exists() {
+            info!("Encrypted original file at {:?} not found", source);
+
+            if file.optional {
+                return Ok(None);
+            }
+
+            return Err(ConfigureError::EncryptedFileMissing {});
+        }
+
+        check_file_size(&source, max_file_size)?;
+
+        debug!("Decrypting file at {:?}", source);
+        let started = Instant::now();
+        let decrypted = decrypt_file_contents(&source, &encryption_key)?;
+        decrypting += started.elapsed();
+        Ok(Some(file.decode_contents(decrypted)?))
+    });
+
+    debug!(
+        "Resolving the key took {:?}, decrypting {:?}, and writing the files {:?}",
+        resolving,
+        decrypting,
+        placing.elapsed().saturating_sub(decrypting)
+    );
+
+    result
+}
+
+/// A project file's decrypted secret, alongside what's at its destination now
+#[derive(Debug)]
+pub struct FileComparison {
+    pub destination: String,
+    pub incoming: Vec<u8>,
+
+    /// The destination's contents, or `None` if it doesn't exist
+    pub local: Option<Vec<u8>>,
+}
+
+/// Works out what applying `configuration` would do, without writing anything
+pub fn plan_for_configuration(configuration: &ConfigurationFile) -> Result<Plan, ConfigureError> {
+    let project_root = find_project_root()?;
+    let state = read_apply_state(&project_root);
+
+    let mut local = BTreeMap::new();
+    let mut incoming = BTreeMap::new();
+    for comparison in compare_decrypted_files(configuration)? {
+        if let Some(contents) = comparison.local {
+            local.insert(comparison.destination.clone(), contents);
+        }
+        incoming.insert(comparison.destination, comparison.incoming);
+    }
+
+    // Optional secrets that aren't available aren't compared, but may still be there from before
+    for file in &configuration.files_to_copy {
+        if incoming.contains_key(&file.destination) {
+            continue;
+        }
+
+        match std::fs::read(project_root.join(file.get_decrypted_destination()?)) {
+            Ok(contents) => local.insert(file.destination.clone(), contents),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(err.into()),
+        };
+    }
+
+    Ok(plan_apply(configuration, &state, &local, &incoming))
+}
+
+/// Decrypts each of the project's encrypted files and pairs it with its destination's contents,
+/// without writing anything. Optional files without an encrypted copy are left out.
+pub fn compare_decrypted_files(
+    configuration: &ConfigurationFile,
+) -> Result<Vec<FileComparison>, ConfigureError> {
+    let project_root = find_project_root()?;
+    let state = read_apply_state(&project_root);
+    let mut encryption_key = None;
+    let mut comparisons = Vec::new();
+
+    for file in &configuration.files_to_copy {
+        let source = project_root.join(file.get_encrypted_destination());
+
+        if !source.exists() {
+            if file.optional {
+                continue;
+            }
+
+            return Err(ConfigureError::EncryptedFileMissing);
+        }
+
+        let destination = project_root.join(file.get_decrypted_destination()?);
+        let local = match std::fs::read(&destination) {
+            Ok(contents) => Some(contents),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+            Err(err) => return Err(err.into()),
+        };
+
+        // If the destination still has what the last apply decrypted from this same encrypted
+        // file, with the same settings, that's what decrypting it would give – and the key isn't
+        // needed
+        let key = file.state_key();
+        let applied_from_source = local.as_ref().is_some_and(|local| {
+            let encrypted_digest = state.encrypted_digest_for(&key);
+            state.digest_for(&key) == Some(hash_bytes(local).as_str())
+                && state.settings_for(&key) == Some(settings_digest(file).as_str())
+                && encrypted_digest.is_some()
+                && encrypted_digest == hash_file(&source).ok().as_deref()
+        });
+
+        let incoming = match &local {
+            Some(local) if applied_from_source => local.clone(),
+            _ => {
+                if encryption_key.is_none() {
+                    encryption_key = Some(require_encryption_key(configuration)?);
+                }
+                let decrypted = decrypt_file_contents(&source, encryption_key.as_ref().unwrap())?;
+                let secret = file.transform_contents(file.decode_contents(decrypted)?)?;
+                file.merge_contents(local.as_deref(), secret)?
+            }
+        };
+
+        comparisons.push(FileComparison {
+            destination: file.destination.clone(),
+            incoming,
+            local,
+        });
+    }
+
+    Ok(comparisons)
+}
+
+/// Places already-decrypted files from a tar archive whose layout mirrors the project's
+/// destinations, skipping decryption entirely.
+pub fn extract_archive_for_configuration(
+    configuration: &ConfigurationFile,
+    options: &ApplyOptions,
+    archive_path: &Path,
+) -> Result<ApplyReport, ConfigureError> {
+    let project_root = find_project_root()?;
+    let entries = list_archive_entries(archive_path)?;
+
+    let destinations: Vec<&str> = configuration
+        .files_to_copy
+        .iter()
+        .map(|file| file.destination.as_str())
+        .collect();
+
+    let missing: Vec<String> = configuration
+        .files_to_copy
+        .iter()
+        .filter(|file| !file.optional && !entries.contains(&file.destination))
+        .map(|file| file.destination.clone())
+        .collect();
+
+    if !missing.is_empty() {
+        return Err(ConfigureError::ArchiveIncomplete(missing.join(", ")));
+    }
+
+    for entry in &entries {
+        if !destinations.contains(&entry.as_str()) {
+            warn!("{:?} contains {:?}, which isn't in `.configure` – skipping it", archive_path, entry);
+        }
+    }
+
+    place_files_for_configuration(configuration, options, &project_root, false, |file| {
+        if !entries.contains(&file.destination) {
+            return Ok(None);
+        }
+
+        debug!("Reading {:?} from {:?}", file.destination, archive_path);
+        read_archive_entry(archive_path, &file.destination).map(Some)
+    })
+}
+
+/// Writes the plaintext contents for each of the configuration's files to its destination,
+/// keeping track of what was written so later applies can tell if it was edited.
+///
+/// With `--output-root`, destinations are in that directory rather than the project. It's
+/// expected to be thrown away, so whatever is there is overwritten without backups, and nothing is
+/// recorded in the project's state.
+///
+/// `read_contents` provides the plaintext for a given file, or `None` if it's an optional file
+/// that isn't available. `from_encrypted_files` says whether it decrypts the project's encrypted
+/// files, in which case they're recorded as where the content came from.
+fn place_files_for_configuration<F>(
+    configuration: &ConfigurationFile,
+    options: &ApplyOptions,
+    project_root: &Path,
+    from_encrypted_files: bool,
+    mut read_contents: F,
+) -> Result<ApplyReport, ConfigureError>
+where
+    F: FnMut(&crate::File) -> Result<Option<Vec<u8>>, ConfigureError>,
+{
+    let prefix = options.prefix.as_deref();
+    let output_root = options.output_root.as_deref().or(prefix);
+    let destination_root = output_root.unwrap_or(project_root);
+
+    let mut state = match output_root {
+        Some(output_root) => {
+            // Nothing can get out from under a prefix, since destinations are resolved first
+            if prefix.is_none() {
+                check_destinations_within(configuration, output_root)?;
+            }
+            ApplyState::default()
+        }
+        None => read_apply_state(project_root),
+    };
+    let mut resolver = ConflictResolver::new(options.resolve_conflicts);
+    let on_conflict = match output_root {
+        Some(_) => OnConflict::Overwrite,
+        None => options
+            .on_conflict
+            .or(configuration.on_conflict)
+            .unwrap_or_default(),
+    };
+    let mut report = ApplyReport::default();
+    // With `--defer-backups`, decrypted secrets wait beside their destinations until every file
+    // is ready, and they're removed if anything fails before they're moved into place
+    let mut staged_files = StagedFiles::default();
+    let mut incoming_hashes = Vec::new();
+
+    for file in &configuration.files_to_copy {
+        let key = file.state_key();
+        let placed = match prefix {
+            Some(_) => Cow::Owned(prefixed_file(file, project_root)?),
+            None => Cow::Borrowed(file),
+        };
+        let destination = destination_root.join(placed.get_decrypted_destination()?);
+
+        create_parent_directory_for_path_if_not_exists(&destination)?;
+
+        let contents = match read_contents(file)? {
+            Some(contents) => contents,
+            None => {
+                let outcome =
+                    place_placeholder(configuration, options, file, &destination, &mut state)?;
+                report.push(&file.destination, outcome);
+                continue;
+            }
+        };
+
+        // Archives already hold what's written to each destination
+        let contents = match from_encrypted_files {
+            true => file.transform_contents(contents)?,
+            false => contents,
+        };
+        let contents = match file.merge {
+            Some(_) => file.merge_contents(std::fs::read(&destination).ok().as_deref(), contents)?,
+            None => contents,
+        };
+        let incoming_hash = hash_bytes(&contents);
+        incoming_hashes.push((file, incoming_hash.clone()));
+
+        if !options.no_sanity_checks {
+            // `format` describes the secret as it's kept, which the transform has already parsed
+            let transformed = file.transform.map(|_| crate::File {
+                format: None,
+                ..file.clone()
+            });
+            let findings = check_contents(
+                transformed.as_ref().unwrap_or(file),
+                &contents,
+                state.size_for(&key),
+                &sanity_check_settings(configuration),
+            );
+            report_sanity_findings(configuration, findings)?;
+        }
+
+        // A file the developer chose to keep over these same secrets is theirs to look after
+        let local_hash = hash_file(&destination).ok();
+        if local_hash.as_deref().is_some_and(|local_hash| {
+            state.was_kept_over(&key, &incoming_hash, local_hash)
+        }) {
+            report.push(&file.destination, FileOutcome::Kept);
+            continue;
+        }
+
+        // What's merged into keeps everything else in the file, so it's never a conflict
+        let last_applied_hash = match file.merge {
+            Some(_) => local_hash.as_deref(),
+            None => state.last_applied_digest(&key, &file.destination, local_hash.as_deref()),
+        };
+        let file_on_conflict = match (on_conflict, &local_hash) {
+            (OnConflict::Ask, Some(local_hash))
+                if last_applied_hash.is_none() && *local_hash != incoming_hash =>
+            {
+                let local = std::fs::read(&destination)?;
+                ask_about_unrecorded_file(&file.destination, &local, &contents)?
+            }
+            _ => on_conflict,
+        };
+
+        let plan = plan_file(
+            destination_root,
+            &placed,
+            &incoming_hash,
+            last_applied_hash,
+            file_on_conflict,
+            &mut resolver,
+        )?;
+
+        // Remember the choice to keep the file, so it isn't asked about again
+        if on_conflict == OnConflict::Ask && file_on_conflict == OnConflict::Keep {
+            if let Some(local_hash) = local_hash {
+                let size = std::fs::metadata(&destination)?.len();
+                state.record_kept(&key, local_hash, size, &incoming_hash);
+            }
+        }
+
+        if options.defer_backups {
+            staged_files.0.push(stage_file(plan, &contents, incoming_hash, options.fsync)?);
+            continue;
+        }
+
+        let committed = commit_file(destination_root, &plan, |path| match options.fsync {
+            true => write_durably(path, &contents, plan.permissions.as_ref()),
+            false => write_with_permissions(path, &contents, plan.permissions.as_ref()),
+        });
+        if let Err(err) = committed {
+            if !is_locked_file_error(&err) {
+                return Err(err.into());
+            }
+
+            // Another program has it open, so carry on with the rest and say so at the end
+            let holder = describe_lock_holder(&destination);
+            report.push(&file.destination, FileOutcome::Locked { holder });
+            continue;
+        }
+
+        if plan.destination_has_incoming_contents() {
+            state.record(&key, incoming_hash, contents.len() as u64);
+        }
+
+        report.push(&file.destination, plan.outcome);
+    }
+
+    let result = commit_staged_files(
+        destination_root,
+        staged_files,
+        options.fsync,
+        &mut state,
+        &mut report,
+    );
+
+    if output_root.is_some() {
+        result?;
+        return Ok(report);
+    }
+
+    for (file, incoming_hash) in incoming_hashes {
+        let encrypted_file = project_root.join(file.get_encrypted_destination());
+        let encrypted_file = Some(encrypted_file).filter(|_| from_encrypted_files);
+        record_file_origin(
+            configuration,
+            project_root,
+            file,
+            &incoming_hash,
+            encrypted_file,
+            &mut state,
+        );
+    }
+
+    // Save the state even if committing failed partway, so that it reflects the files that made it
+    save_apply_state(project_root, &state)?;
+    result?;
+
+    Ok(report)
+}
+
+/// Makes sure none of the configuration's destinations would be written outside `root`, so a
+/// `.configure` can't be used to write anywhere else on the machine running the apply
+fn check_destinations_within(
+    configuration: &ConfigurationFile,
+    root: &Path,
+) -> Result<(), ConfigureError> {
+    use std::path::Component;
+
+    for file in &configuration.files_to_copy {
+        let destination = file.get_decrypted_destination()?;
+        let stays_within = Path::new(&destination)
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+
+        if !stays_within {
+            return Err(ConfigureError::DestinationOutsideRoot(
+                file.destination.clone(),
+                root.to_path_buf(),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// `file` as it's placed under `--prefix`: its destination is resolved against the project root if
+/// it's relative (or the home directory, for a `user` scope secret), then made relative to the
+/// root of the filesystem, so that it can be joined to the prefix. `..` can't climb out of the
+/// prefix.
+pub fn prefixed_file(
+    file: &crate::File,
+    project_root: &Path,
+) -> Result<crate::File, ConfigureError> {
+    use std::path::Component;
+
+    let mut destination = PathBuf::new();
+    for component in project_root.join(file.get_decrypted_destination()?).components() {
+        match component {
+            Component::Normal(part) => destination.push(part),
+            Component::ParentDir => {
+                destination.pop();
+            }
+            Component::RootDir | Component::Prefix(_) | Component::CurDir => {}
+        }
+    }
+
+    Ok(crate::File {
+        destination: destination.to_string_lossy().to_string(),
+        ..file.clone()
+    })
+}
+
+/// The configuration with each file as it's placed under `--prefix`
+pub fn prefixed_configuration(
+    configuration: &ConfigurationFile,
+    project_root: &Path,
+) -> Result<ConfigurationFile, ConfigureError> {
+    Ok(ConfigurationFile {
+        files_to_copy: configuration
+            .files_to_copy
+            .iter()
+            .map(|file| prefixed_file(file, project_root))
+            .collect::<Result<_, _>>()?,
+        ..configuration.clone()
+    })
+}
+
+/// Where apply reads one file's encrypted secret from, and where it's decrypted to
+#[derive(Debug, Clone, serde::Serialize, Eq, PartialEq)]
+pub struct ApplyPaths {
+    pub source_abs: PathBuf,
+    pub destination_abs: PathBuf,
+}
+
+/// Where apply would read and write each of `configuration`'s files, as absolute paths, taking
+/// `--output-root` and `--prefix` into account the same way placing the files does
+pub fn resolve_apply_paths(
+    configuration: &ConfigurationFile,
+    options: &ApplyOptions,
+    project_root: &Path,
+) -> Result<Vec<ApplyPaths>, ConfigureError> {
+    let absolute = |path: &Path| match path.is_absolute() {
+        true => path.to_path_buf(),
+        false => env::current_dir()
+            .expect("Unable to determine current directory")
+            .join(path),
+    };
+
+    let prefix = options.prefix.as_deref();
+    let destination_root = options.output_root.as_deref().or(prefix).unwrap_or(project_root);
+    let destination_root = absolute(destination_root);
+
+    configuration
+        .files_to_copy
+        .iter()
+        .map(|file| {
+            let placed = match prefix {
+                Some(_) => Cow::Owned(prefixed_file(file, project_root)?),
+                None => Cow::Borrowed(file),
+            };
+
+            Ok(ApplyPaths {
+                source_abs: absolute(&project_root.join(file.get_encrypted_destination())),
+                destination_abs: destination_root.join(placed.get_decrypted_destination()?),
+            })
+        })
+        .collect()
+}
+
+/// Notes where the content applied to `file` came from, so later commands can answer questions
+/// about it without decrypting or even hashing anything. Only done once the destination certainly
+/// has the incoming content – it might have been kept, or match the last apply but be edited.
+fn record_file_origin(
+    configuration: &ConfigurationFile,
+    project_root: &Path,
+    file: &crate::File,
+    incoming_hash: &str,
+    encrypted_file: Option<PathBuf>,
+    state: &mut ApplyState,
+) {
+    let destination = match file.get_decrypted_destination() {
+        Ok(destination) => project_root.join(destination),
+        Err(_) => return,
+    };
+
+    let key = file.state_key();
+    if state.digest_for(&key) != Some(incoming_hash)
+        || hash_file(&destination).ok().as_deref() != Some(incoming_hash)
+    {
+        return;
+    }
+
+    let origin = FileOrigin {
+        encrypted_digest: encrypted_file.as_ref().and_then(|path| hash_file(path).ok()),
+        encrypted_blob: encrypted_file.and_then(|path| blob_hash_of_file(&path).ok()),
+        settings: Some(settings_digest(file)),
+        pinned_hash: configuration.pinned_hash.clone(),
+        modified: std::fs::metadata(&destination)
+            .ok()
+            .and_then(|metadata| modified_time(&metadata)),
+    };
+
+    state.record_origin(&key, origin);
+}
+
+/// A digest of the settings that turn an entry's decrypted secret into what's written to its
+/// destination
+fn settings_digest(file: &crate::File) -> String {
+    let settings = json!({
+        "encoding": file.encoding,
+        "transform": file.transform,
+        "merge": file.merge,
+    });
+    hash_bytes(settings.to_string().as_bytes())
+}
+
+/// Handles an optional file whose secret isn't available. With `--placeholder-on-missing`, a
+/// placeholder is written so builds that expect the file still work – but never over an existing
+/// file, which might be a real secret.
+fn place_placeholder(
+    configuration: &ConfigurationFile,
+    options: &ApplyOptions,
+    file: &crate::File,
+    destination: &Path,
+    state: &mut crate::state::ApplyState,
+) -> Result<FileOutcome, ConfigureError> {
+    if !options.placeholder_on_missing || destination.exists() {
+        debug!("Skipping optional file {}, which isn't available", file.destination);
+        return Ok(FileOutcome::Skipped);
+    }
+
+    let placeholder = file
+        .placeholder
+        .as_ref()
+        .or(configuration.placeholder.as_ref())
+        .map(String::as_str)
+        .unwrap_or("");
+
+    warn!(
+        "Writing a placeholder to {}, because its secret isn't available",
+        file.destination
+    );
+
+    std::fs::write(destination, placeholder)?;
+
+    // Remember the placeholder, so the real secret can replace it later without a backup
+    state.record(
+        &file.state_key(),
+        hash_bytes(placeholder.as_bytes()),
+        placeholder.len() as u64,
+    );
+
+    Ok(FileOutcome::Placeholder)
+}
+
+/// What `apply` has decided to do with a single destination
+struct PlannedFile {
+    file: crate::File,
+
+    /// Where the file's destination is
+    destination: PathBuf,
+
+    /// Where the incoming content will be written, if anywhere
+    write_to: Option<PathBuf>,
+
+    /// The project-relative path the existing destination will be moved to before writing
+    backup: Option<String>,
+
+    /// The permissions the incoming content is written with, so that replacing a file never
+    /// makes it readable by more people than before
+    permissions: Option<Permissions>,
+
+    outcome: FileOutcome,
+}
+
+impl PlannedFile {
+    /// Whether the destination will hold the incoming content once the plan is carried out.
+    ///
+    /// We only remember content that's actually at the destination, so a conflict the user
+    /// deferred comes up again next time.
+    fn destination_has_incoming_contents(&self) -> bool {
+        !matches!(
+            self.outcome,
+            FileOutcome::Kept
+                | FileOutcome::Conflict {
+                resolution: ConflictResolution::KeepLocal,
+                ..
+            } | FileOutcome::Conflict {
+                resolution: ConflictResolution::WriteNew,
+                ..
+            }
+        )
+    }
+}
+
+/// Decides how to write incoming content to the destination for `file`, taking care not to lose
+/// anything the developer has at that path. Nothing is changed on disk.
+///
+/// `last_applied_hash` is the hash of what configure last wrote there (if known). When the file
+/// on disk matches neither that nor the incoming content, it was edited locally and the
+/// `resolver` decides what happens. When there's no record of writing it, `on_conflict` does.
+fn plan_file(
+    project_root: &Path,
+    file: &crate::File,
+    incoming_hash: &str,
+    last_applied_hash: Option<&str>,
+    on_conflict: OnConflict,
+    resolver: &mut ConflictResolver,
+) -> Result<PlannedFile, ConfigureError> {
+    let destination = project_root.join(file.get_decrypted_destination()?);
+    let permissions = permissions_for(&destination, file);
+    let resolved = destination.clone();
+
+    let plan = |write_to: Option<PathBuf>, backup: Option<String>, outcome: FileOutcome| PlannedFile {
+        file: file.clone(),
+        destination: resolved.clone(),
+        write_to,
+        backup,
+        permissions: permissions.clone(),
+        outcome,
+    };
+
+    let local_hash = match destination.exists() {
+        true => Some(hash_file(&destination)?),
+        false => None,
+    };
+
+    match PlanAction::for_digests(local_hash.as_deref(), incoming_hash, last_applied_hash) {
+        PlanAction::Create => Ok(plan(Some(destination), None, FileOutcome::Created)),
+
+        PlanAction::Unchanged => {
+            debug!("{:?} already has the incoming contents", destination);
+            Ok(plan(None, None, FileOutcome::Unchanged))
+        }
+
+        // The file on disk is what we wrote last time, so there's nothing to preserve
+        PlanAction::Update => Ok(plan(
+            Some(destination),
+            None,
+            FileOutcome::Updated { backup: None },
+        )),
+
+        // The file was edited after we last wrote it
+        PlanAction::Conflict => {
+            let resolution = resolver.resolve(&file.destination)?;
+            let outcome = |backup: Option<String>| FileOutcome::Conflict { resolution, backup };
+
+            Ok(match resolution {
+                ConflictResolution::KeepLocal => plan(None, None, outcome(None)),
+                ConflictResolution::TakeIncoming => {
+                    let backup = file.get_backup_destination()?;
+                    plan(Some(destination), Some(backup.clone()), outcome(Some(backup)))
+                }
+                ConflictResolution::WriteNew => plan(
+                    Some(project_root.join(file.get_new_destination()?)),
+                    None,
+                    outcome(None),
+                ),
+            })
+        }
+
+        // We don't know where this file came from, so the policy decides whether it's safe to replace
+        PlanAction::Replace => match on_conflict {
+            OnConflict::Keep => Ok(plan(None, None, FileOutcome::Kept)),
+            OnConflict::Overwrite => Ok(plan(
+                Some(destination),
+                None,
+                FileOutcome::Updated { backup: None },
+            )),
+            OnConflict::BackupOverwrite | OnConflict::Ask => {
+                let backup = file.get_backup_destination()?;
+                Ok(plan(
+                    Some(destination),
+                    Some(backup.clone()),
+                    FileOutcome::Updated {
+                        backup: Some(backup),
+                    },
+                ))
+            }
+            OnConflict::Fail => Err(ConfigureError::DestinationExists(file.destination.clone())),
+        },
+
+        // Only decided by the caller, which knows about kept files and missing secrets
+        PlanAction::Keep | PlanAction::Skip => unreachable!(),
+    }
+}
+
+/// Carries out a plan: moves the existing destination aside if a backup is needed, then uses
+/// `write` to put the incoming content in place.
+///
+/// If writing fails, the backup is moved back so the destination is left as it was.
+fn commit_file<W>(project_root: &Path, plan: &PlannedFile, mut write: W) -> Result<(), Error>
+where
+    W: FnMut(&Path) -> Result<(), Error>,
+{
+    let destination = &plan.destination;
+
+    if let Some(backup) = &plan.backup {
+        debug!(
+            "{:?} already exists – making a backup at {:?}",
+            destination, backup
+        );
+        retry_while_locked(|| rename(destination, project_root.join(backup)))?;
+    }
+
+    if let Some(write_to) = &plan.write_to {
+        debug!("Writing decrypted contents to {:?}", write_to);
+
+        // A locked file can't be overwritten in place, so unlock it while writing
+        let was_read_only = is_read_only(write_to);
+        if was_read_only {
+            set_read_only(write_to, false)?;
+        }
+
+        if let Err(err) = retry_while_locked(|| write(write_to)) {
+            if was_read_only {
+                let _ = set_read_only(write_to, true);
+            }
+            if let Some(backup) = &plan.backup {
+                rename(project_root.join(backup), destination)?;
+            }
+            return Err(err);
+        }
+
+        if was_read_only {
+            set_read_only(write_to, true)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// How many times to try replacing a destination that another program has open
+const LOCKED_FILE_ATTEMPTS: u32 = 5;
+
+/// How long to wait before trying a locked destination again the first time. The wait doubles
+/// after each attempt.
+const LOCKED_FILE_FIRST_DELAY: Duration = Duration::from_millis(100);
+
+/// Whether `err` means another program has the file open in a way that stops it being replaced –
+/// a sharing or lock violation on Windows, or `EBUSY` or `ETXTBSY` elsewhere
+pub fn is_locked_file_error(err: &Error) -> bool {
+    #[cfg(windows)]
+    const LOCKED_CODES: &[i32] = &[32, 33];
+    #[cfg(not(windows))]
+    const LOCKED_CODES: &[i32] = &[16, 26];
+
+    err.raw_os_error()
+        .is_some_and(|code| LOCKED_CODES.contains(&code))
+}
+
+fn retry_while_locked<T, F>(operation: F) -> Result<T, Error>
+where
+    F: FnMut() -> Result<T, Error>,
+{
+    retry_while_locked_with(LOCKED_FILE_FIRST_DELAY, operation)
+}
+
+/// Runs `operation`, trying again after `first_delay` (then twice as long, and so on) while it
+/// fails because the file is locked, up to `LOCKED_FILE_ATTEMPTS` times in all
+fn retry_while_locked_with<T, F>(first_delay: Duration, mut operation: F) -> Result<T, Error>
+where
+    F: FnMut() -> Result<T, Error>,
+{
+    let mut delay = first_delay;
+
+    for _ in 1..LOCKED_FILE_ATTEMPTS {
+        match operation() {
+            Err(err) if is_locked_file_error(&err) => {
+                debug!("The file is in use ({}) – trying again in {:?}", err, delay);
+                std::thread::sleep(delay);
+                delay *= 2;
+            }
+            result => return result,
+        }
+    }
+
+    operation()
+}
+
+/// Which program has the file at `path` open, like `Xcode (pid 123)`, as far as can be told
+#[cfg(target_os = "linux")]
+pub fn describe_lock_holder(path: &Path) -> Option<String> {
+    let path = path.canonicalize().ok()?;
+
+    for process in std::fs::read_dir("/proc").ok()?.flatten() {
+        let pid = process.file_name().to_string_lossy().to_string();
+        if !pid.chars().all(|c| c.is_ascii_digit()) {
+            continue;
+        }
+
+        let descriptors = match std::fs::read_dir(process.path().join("fd")) {
+            Ok(descriptors) => descriptors,
+            Err(_) => continue,
+        };
+        let has_open = descriptors
+            .flatten()
+            .any(|descriptor| std::fs::read_link(descriptor.path()).ok().as_ref() == Some(&path));
+
+        if has_open {
+            let name = std::fs::read_to_string(process.path().join("comm")).unwrap_or_default();
+            return Some(format!("{} (pid {})", name.trim(), pid));
+        }
+    }
+
+    None
+}
+
+/// Which program has the file at `path` open, like `Xcode (pid 123)`, as far as can be told
+#[cfg(target_os = "macos")]
+pub fn describe_lock_holder(path: &Path) -> Option<String> {
+    let output = std::process::Command::new("lsof")
+        .args(["-F", "pc", "--"])
+        .arg(path)
+        .output()
+        .ok()?;
+    let output = String::from_utf8_lossy(&output.stdout);
+
+    let pid = output.lines().find_map(|line| line.strip_prefix('p'))?;
+    let name = output.lines().find_map(|line| line.strip_prefix('c'))?;
+    Some(format!("{} (pid {})", name, pid))
+}
+
+/// Which program has the file at `path` open, which isn't known on this platform
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub fn describe_lock_holder(_path: &Path) -> Option<String> {
+    None
+}
+
+/// Makes every destination in the configuration read-only (or writable again), returning the
+/// destinations that don't exist yet and so were skipped.
+///
+/// With `ignore_errors`, a file whose permissions can't be changed is warned about and left as it is.
+pub fn set_files_read_only(
+    configuration: &ConfigurationFile,
+    root: &Path,
+    read_only: bool,
+    ignore_errors: bool,
+) -> Result<Vec<String>, ConfigureError> {
+    let mut missing = Vec::new();
+
+    for file in &configuration.files_to_copy {
+        let destination = root.join(file.get_decrypted_destination()?);
+
+        if !destination.exists() {
+            missing.push(file.destination.clone());
+            continue;
+        }
+
+        debug!("Setting {:?} read-only: {}", destination, read_only);
+        change_mode(&destination, ignore_errors, |path| set_read_only(path, read_only))?;
+    }
+
+    Ok(missing)
+}
+
+/// Changes the permissions of the file at `path` with `chmod`, downgrading a failure to a warning
+/// when `ignore_errors` is set – the file's contents are what matter on a filesystem without
+/// permissions
+fn change_mode<F>(path: &Path, ignore_errors: bool, chmod: F) -> Result<(), Error>
+where
+    F: FnOnce(&Path) -> Result<(), Error>,
+{
+    match chmod(path) {
+        Err(err) if ignore_errors => {
+            crate::ui::warn(&format!(
+                "Warning: Unable to set the permissions of {:?}, so they were left as they are: {}",
+                path, err
+            ));
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+fn is_read_only(path: &Path) -> bool {
+    match std::fs::metadata(path) {
+        Ok(metadata) => metadata.permissions().readonly(),
+        Err(_) => false,
+    }
+}
+
+/// Toggles whether the owner can write to the file at `path`. On Windows, this is the file's
+/// read-only attribute.
+#[cfg(unix)]
+fn set_read_only(path: &Path, read_only: bool) -> Result<(), Error> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mut permissions = std::fs::metadata(path)?.permissions();
+    let mode = permissions.mode();
+
+    // Remove all the write bits when locking, but only give the owner write access back when
+    // unlocking – a locked file shouldn't become writable by everyone
+    permissions.set_mode(if read_only { mode & !0o222 } else { mode | 0o200 });
+
+    std::fs::set_permissions(path, permissions)
+}
+
+#[cfg(not(unix))]
+fn set_read_only(path: &Path, read_only: bool) -> Result<(), Error> {
+    let mut permissions = std::fs::metadata(path)?.permissions();
+    #[allow(clippy::permissions_set_readonly_false)]
+    permissions.set_readonly(read_only);
+    std::fs::set_permissions(path, permissions)
+}
+
+/// A planned file whose incoming content has been written to a temporary location beside its
+/// destination, ready to be moved into place
+struct StagedFile {
+    plan: PlannedFile,
+    temporary_path: Option<PathBuf>,
+    incoming_hash: String,
+    size: u64,
+}
+
+/// Staged files waiting to be moved into place together. The decrypted copies of any still
+/// waiting when it's dropped are removed, so they're never left lying around in plain text.
+#[derive(Default)]
+struct StagedFiles(Vec<StagedFile>);
+
+impl Drop for StagedFiles {
+    fn drop(&mut self) {
+        for staged in &self.0 {
+            if let Some(temporary_path) = &staged.temporary_path {
+                let _ = std::fs::remove_file(temporary_path);
+            }
+        }
+    }
+}
+
+/// Writes the incoming contents beside their destination, ready to be moved into place. When
+/// `durable`, they're flushed to disk first, so the move can never leave an empty file behind.
+fn stage_file(
+    plan: PlannedFile,
+    contents: &[u8],
+    incoming_hash: String,
+    durable: bool,
+) -> Result<StagedFile, Error> {
+    let temporary_path = match &plan.write_to {
+        Some(write_to) => {
+            let temporary_path = temporary_path_for(write_to);
+            debug!("Staging decrypted contents at {:?}", temporary_path);
+            let mut staged = create_with_permissions(&temporary_path, plan.permissions.as_ref())?;
+            staged.write_all(contents)?;
+            if durable {
+                staged.sync_all()?;
+            }
+            Some(temporary_path)
+        }
+        None => None,
+    };
+
+    Ok(StagedFile {
+        plan,
+        temporary_path,
+        incoming_hash,
+        size: contents.len() as u64,
+    })
+}
+
+/// Moves every staged file into place in a single tight pass, after all the slow work (decrypting
+/// and writing to disk) is done.
+///
+/// If a file can't be committed, the files before it are left in their new state, and it and
+/// the files after it are left untouched (their temporary copies are cleaned up).
+fn commit_staged_files(
+    project_root: &Path,
+    mut staged_files: StagedFiles,
+    durable: bool,
+    state: &mut crate::state::ApplyState,
+    report: &mut ApplyReport,
+) -> Result<(), Error> {
+    while !staged_files.0.is_empty() {
+        let staged = staged_files.0.remove(0);
+
+        let result = commit_file(project_root, &staged.plan, |path| {
+            // The staged file was flushed before the move, so only the move itself needs to be
+            rename(staged.temporary_path.as_ref().unwrap(), path)?;
+            match durable {
+                true => sync_directory_of(path),
+                false => Ok(()),
+            }
+        });
+
+        if let Err(err) = result {
+            if let Some(temporary_path) = &staged.temporary_path {
+                let _ = std::fs::remove_file(temporary_path);
+            }
+
+            if is_locked_file_error(&err) {
+                let holder = describe_lock_holder(&staged.plan.destination);
+                report.push(&staged.plan.file.destination, FileOutcome::Locked { holder });
+                continue;
+            }
+
+            // The files after it are cleaned up when `staged_files` is dropped
+            return Err(err);
+        }
+
+        if staged.plan.destination_has_incoming_contents() {
+            state.record(
+                &staged.plan.file.state_key(),
+                staged.incoming_hash,
+                staged.size,
+            );
+        }
+
+        report.push(&staged.plan.file.destination, staged.plan.outcome);
+    }
+
+    Ok(())
+}
+
+/// Writes `contents` to `path` so that it survives a crash straight afterwards: they're written
+/// beside it and flushed to disk, then moved into place, and then the directory is flushed so the
+/// move is too. A crash part way through leaves the old file, never an empty or partial one.
+pub fn write_durably(
+    path: &Path,
+    contents: &[u8],
+    permissions: Option<&Permissions>,
+) -> Result<(), Error> {
+    let temporary_path = temporary_path_for(path);
+
+    let result = create_with_permissions(&temporary_path, permissions)
+        .and_then(|mut file| {
+            file.write_all(contents)?;
+            file.sync_all()
+        })
+        .and_then(|_| rename(&temporary_path, path));
+
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temporary_path);
+    }
+
+    result?;
+    sync_directory_of(path)
+}
+
+/// Writes `contents` to `path`, with `permissions` if given
+fn write_with_permissions(
+    path: &Path,
+    contents: &[u8],
+    permissions: Option<&Permissions>,
+) -> Result<(), Error> {
+    create_with_permissions(path, permissions)?.write_all(contents)
+}
+
+/// Creates the file at `path`, with `permissions` if given. They're set before anything is
+/// written, so the contents are never readable by anyone they shouldn't be.
+fn create_with_permissions(path: &Path, permissions: Option<&Permissions>) -> Result<File, Error> {
+    let file = File::create(path)?;
+    if let Some(permissions) = permissions {
+        file.set_permissions(permissions.clone())?;
+    }
+    Ok(file)
+}
+
+/// The permissions to write a file's incoming content to `destination` with. An existing file
+/// keeps its own, so replacing it doesn't change who can read it, and a new `user` scope secret
+/// – like a password in `~/.netrc` – can only be read by the user. `None` leaves it to the umask.
+fn permissions_for(destination: &Path, file: &crate::File) -> Option<Permissions> {
+    if let Ok(metadata) = std::fs::metadata(destination) {
+        return Some(metadata.permissions());
+    }
+
+    match file.scope {
+        #[cfg(unix)]
+        crate::configure::Scope::User => {
+            use std::os::unix::fs::PermissionsExt;
+            Some(Permissions::from_mode(0o600))
+        }
+        _ => None,
+    }
+}
+
+/// Flushes the directory `path` is in to disk, so a file that was just created or renamed there
+/// stays there after a crash. Windows has no way to do this, and doesn't need it.
+fn sync_directory_of(path: &Path) -> Result<(), Error> {
+    #[cfg(unix)]
+    {
+        let directory = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        File::open(directory)?.sync_all()?;
+    }
+
+    #[cfg(not(unix))]
+    let _ = path;
+
+    Ok(())
+}
+
+pub fn temporary_path_for(path: &Path) -> PathBuf {
+    let mut temporary_path = path.as_os_str().to_owned();
+    temporary_path.push(".configure-tmp");
+    PathBuf::from(temporary_path)
+}
+
+/// Encrypts the configuration's secrets into the project or, given `stage_dir`, into that
+/// directory for `configure promote` to copy into the project once they've been reviewed
+pub fn write_encrypted_files_for_configuration(
+    configuration: &ConfigurationFile,
+    run_sanity_checks: bool,
+    stage_dir: Option<&Path>,
+) -> Result<(), ConfigureError> {
+    let project_root = find_project_root()?;
+    let encryption_key = require_encryption_key(configuration)?;
+
+    // The secrets checkout is never moved to the pin, so its secrets are read from history – which
+    // leaves the developer's branch, and any commits on it, alone
+    let read_source = |file: &crate::File| -> Result<Option<Vec<u8>>, ConfigureError> {
+        let source = configuration.source_path(file);
+
+        match read_file_at_ref(&configuration.pinned_hash, &source)? {
+            None if !file.optional => Err(ConfigureError::SourceMissingAtCommit {
+                path: source,
+                commit: configuration.pinned_hash.clone(),
+            }),
+            contents => Ok(contents),
+        }
+    };
+
+    // Check every file before encrypting any of them, so a bad secret doesn't leave the project
+    // with a mix of old and new encrypted files
+    if run_sanity_checks {
+        let state = read_apply_state(&project_root);
+        let settings = sanity_check_settings(configuration);
+        let mut findings = Vec::new();
+
+        for file in &configuration.files_to_copy {
+            let contents = match read_source(file)? {
+                Some(contents) => contents,
+                None => continue,
+            };
+
+            findings.extend(check_contents(
+                file,
+                &contents,
+                state.size_for(&file.state_key()),
+                &settings,
+            ));
+        }
+
+        report_sanity_findings(configuration, findings)?;
+    }
+
+    let mut staged_files = Vec::new();
+
+    // Nothing's moved into place until every file is encrypted, so an update that fails part way
+    // leaves every encrypted file as it was – not some for the new pin and some for the old
+    let mut pending = PendingEncryptedFiles::default();
+
+    for file in &configuration.files_to_copy {
+        let source = configuration.source_path(file);
+        let destination = project_root.join(file.get_encrypted_destination());
+
+        let contents = match read_source(file)? {
+            Some(contents) => contents,
+            None => {
+                info!("Skipping optional file {:?}, which isn't in the secrets repository", source);
+                continue;
+            }
+        };
+
+        // Encrypt the file and write the encrypted contents to the destination
+        debug!(
+            "Encrypting file at {:?} and storing contents at {:?}",
+            source, destination
+        );
+
+        match stage_dir {
+            Some(_) => {
+                let encrypted = encrypt_contents(&file.encode_contents(&contents), &encryption_key);
+
+                // Encrypting uses a random nonce, so keep the project's bytes for a secret that
+                // hasn't changed, rather than staging a change for someone to review
+                let unchanged = std::fs::read(&destination).ok().filter(|existing| {
+                    decrypt_contents(existing, &encryption_key)
+                        .map_err(ConfigureError::from)
+                        .and_then(|decrypted| file.decode_contents(decrypted))
+                        .is_ok_and(|decrypted| decrypted == contents)
+                });
+
+                staged_files.push((
+                    file.get_encrypted_destination(),
+                    unchanged.unwrap_or(encrypted),
+                ));
+            }
+            None => {
+                create_parent_directory_for_path_if_not_exists(&destination)?;
+                let contents = file.encode_contents(&contents);
+                let temporary_path =
+                    write_encrypted_temporary_file(&destination, &contents, &encryption_key)?;
+                pending.0.push((temporary_path, destination));
+            }
+        }
+    }
+
+    pending.move_into_place()?;
+
+    if let Some(stage_dir) = stage_dir {
+        crate::staging::write_stage(stage_dir, &project_root, &staged_files)?;
+    }
+
+    Ok(())
+}
+
+/// Encrypted files written beside their destinations, as `(temporary path, destination)`, waiting
+/// to be moved into place together. Any still waiting when it's dropped are removed.
+#[derive(Default)]
+struct PendingEncryptedFiles(Vec<(PathBuf, PathBuf)>);
+
+impl PendingEncryptedFiles {
+    /// Moves every file into place. Each old file is copied aside first, so if one can't be
+    /// moved, the ones already moved are put back as they were.
+    fn move_into_place(mut self) -> Result<(), Error> {
+        let mut moved: Vec<(&PathBuf, Option<PathBuf>)> = Vec::new();
+
+        for (index, (temporary_path, destination)) in self.0.iter().enumerate() {
+            let result = set_aside(destination).and_then(|previous| {
+                match rename(temporary_path, destination) {
+                    Ok(()) => Ok(previous),
+                    Err(err) => {
+                        if let Some(previous) = &previous {
+                            let _ = std::fs::remove_file(previous);
+                        }
+                        Err(err)
+                    }
+                }
+            });
+
+            match result {
+                Ok(previous) => moved.push((destination, previous)),
+                Err(err) => {
+                    for (destination, previous) in moved.into_iter().rev() {
+                        let _ = match previous {
+                            Some(previous) => rename(&previous, destination),
+                            None => std::fs::remove_file(destination),
+                        };
+                    }
+
+                    // The rest are cleaned up on drop
+                    self.0.drain(..index);
+                    return Err(err);
+                }
+            }
+        }
+
+        for (_, previous) in moved {
+            if let Some(previous) = previous {
+                let _ = std::fs::remove_file(previous);
+            }
+        }
+
+        self.0.clear();
+        Ok(())
+    }
+}
+
+/// Copies `destination` aside, if it exists, so it can be put back – returning where it went
+fn set_aside(destination: &Path) -> Result<Option<PathBuf>, Error> {
+    if !destination.exists() {
+        return Ok(None);
+    }
+
+    let mut previous = destination.as_os_str().to_owned();
+    previous.push(".configure-old");
+    let previous = PathBuf::from(previous);
+
+    std::fs::copy(destination, &previous)?;
+    Ok(Some(previous))
+}
+
+impl Drop for PendingEncryptedFiles {
+    fn drop(&mut self) {
+        for (temporary_path, _) in &self.0 {
+            let _ = std::fs::remove_file(temporary_path);
+        }
+    }
+}
+
+/// Encrypts `contents` to a temporary file beside `destination`, flushed to disk, returning its
+/// path – so a crash part way through never leaves a truncated encrypted file. It's removed again
+/// if writing it fails.
+fn write_encrypted_temporary_file(
+    destination: &Path,
+    contents: &[u8],
+    encryption_key: &Key,
+) -> Result<PathBuf, Error> {
+    let temporary_path = temporary_path_for(destination);
+
+    let written = File::create(&temporary_path).and_then(|file| {
+        let mut writer = BufWriter::new(file);
+        encrypt_to_writer(contents, encryption_key, &mut writer)?;
+        writer.into_inner().map_err(|err| err.into_error())?.sync_all()
+    });
+
+    if let Err(err) = written {
+        let _ = std::fs::remove_file(&temporary_path);
+        return Err(err);
+    }
+
+    Ok(temporary_path)
+}
+
+/// Encrypts the secret at `source` to `destination`, encoding it first if `file` asks for it
+fn encrypt_secret(
+    file: &crate::File,
+    source: &PathBuf,
+    destination: &PathBuf,
+    encryption_key: &Key,
+) -> Result<(), ConfigureError> {
+    if file.encoding.is_plain() {
+        encrypt_file(source, destination, encryption_key)?;
+    } else {
+        let contents = std::fs::read(source)?;
+        let contents = file.encode_contents(&contents);
+        std::fs::write(destination, encrypt_contents(&contents, encryption_key))?;
+    }
+
+    Ok(())
+}
+
+/// Writes `contents` encrypted to `file`'s encrypted destination, unless it already decrypts to
+/// exactly `contents` – encrypting uses a random nonce, so rewriting it would be a needless change
+pub fn reencrypt_file(
+    project_root: &Path,
+    file: &crate::File,
+    contents: &[u8],
+    encryption_key: &Key,
+) -> Result<ReencryptOutcome, ConfigureError> {
+    let destination = project_root.join(file.get_encrypted_destination());
+
+    let outcome = match std::fs::read(&destination) {
+        Ok(existing) => match decrypt_contents(&existing, encryption_key)
+            .map_err(ConfigureError::from)
+            .and_then(|decrypted| file.decode_contents(decrypted))
+        {
+            Ok(decrypted) if decrypted == contents => return Ok(ReencryptOutcome::Unchanged),
+            _ => ReencryptOutcome::Repaired,
+        },
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => ReencryptOutcome::Restored,
+        Err(err) => return Err(err.into()),
+    };
+
+    create_parent_directory_for_path_if_not_exists(&destination)?;
+    std::fs::write(
+        &destination,
+        encrypt_contents(&file.encode_contents(contents), encryption_key),
+    )?;
+
+    Ok(outcome)
+}
+
+/// Decrypts an encrypted file's contents, as a git smudge filter does when checking it out
+pub fn smudge_contents(
+    file: &crate::File,
+    encrypted: &[u8],
+    encryption_key: &Key,
+) -> Result<Vec<u8>, ConfigureError> {
+    file.decode_contents(decrypt_contents(encrypted, encryption_key)?)
+}
+
+/// Encrypts a secret's contents, as a git clean filter does when staging it. If `existing` (the
+/// encrypted file as it is) already decrypts to `contents`, it's returned unchanged – encrypting
+/// uses a random nonce, so git would otherwise see a change every time.
+pub fn clean_contents(
+    file: &crate::File,
+    contents: &[u8],
+    existing: Option<&[u8]>,
+    encryption_key: &Key,
+) -> Vec<u8> {
+    if let Some(existing) = existing {
+        let decrypted = smudge_contents(file, existing, encryption_key);
+        if decrypted.is_ok_and(|decrypted| decrypted == contents) {
+            return existing.to_vec();
+        }
+    }
+
+    encrypt_contents(&file.encode_contents(contents), encryption_key)
+}
+
+/// Fails if the file at `path` is larger than `limit` bytes. A limit of zero (or none) means
+/// any size is allowed.
+fn check_file_size(path: &Path, limit: Option<u64>) -> Result<(), ConfigureError> {
+    let limit = match limit {
+        Some(limit) if limit > 0 => limit,
+        _ => return Ok(()),
+    };
+
+    let size = std::fs::metadata(path)?.len();
+
+    if size > limit {
+        return Err(ConfigureError::FileTooLarge {
+            path: path.display().to_string(),
+            size,
+            limit,
+        });
+    }
+
+    Ok(())
+}
+
+fn sanity_check_settings(configuration: &ConfigurationFile) -> SanityChecks {
+    configuration.sanity_checks.clone().unwrap_or_default()
+}
+
+/// Fails with every finding if the project treats findings as errors. Otherwise, shows each one
+/// as a warning.
+fn report_sanity_findings(
+    configuration: &ConfigurationFile,
+    findings: Vec<SanityFinding>,
+) -> Result<(), ConfigureError> {
+    if findings.is_empty() {
+        return Ok(());
+    }
+
+    let messages: Vec<String> = findings.iter().map(|finding| finding.to_string()).collect();
+
+    if sanity_check_settings(configuration).fail_on_findings {
+        return Err(ConfigureError::SanityCheckFailed(messages.join("\n")));
+    }
+
+    for message in messages {
+        crate::ui::warn(&format!("Warning: {}", message));
+    }
+
+    Ok(())
+}
+
+/// Copies a plaintext file from the project into the secrets repository, then writes its encrypted
+/// form back into the project.
+///
+/// Returns an error without changing anything if the secrets repository already has a different
+/// file at `file.source`, unless `force` is set.
+pub fn import_file_into_secrets(
+    configuration: &ConfigurationFile,
+    file: &crate::File,
+    force: bool,
+) -> Result<(), ConfigureError> {
+    use std::path::Component;
+
+    let source = configuration.source_path(file);
+    let stays_within = Path::new(&source)
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+    if !stays_within || source.is_empty() {
+        return Err(ConfigureError::SourceOutsideSecretsRepo(file.source.clone()));
+    }
+
+    ensure_secrets_writable("add files to it")?;
+
+    let project_root = find_project_root()?;
+    let secrets_root = find_secrets_repo()?;
+    let encryption_key = require_encryption_key(configuration)?;
+
+    // The file is added to the secrets repo's working tree, so make sure it lands on the right branch
+    if !configuration.branch.is_empty() {
+        crate::git::ensure_on_branch(&configuration.branch)?;
+    }
+
+    let plaintext = project_root.join(&file.destination);
+    let secrets_copy = secrets_root.join(&source);
+
+    if !plaintext.exists() {
+        return Err(ConfigureError::ImportSourceMissing(file.destination.clone()));
+    }
+
+    if secrets_copy.exists() && hash_file(&secrets_copy)? != hash_file(&plaintext)? {
+        print_file_diff(&secrets_copy, &plaintext);
+
+        if !force {
+            return Err(ConfigureError::ImportWouldOverwrite(source));
+        }
+    }
+
+    create_parent_directory_for_path_if_not_exists(&secrets_copy)?;
+    debug!("Copying {:?} to {:?}", plaintext, secrets_copy);
+    std::fs::copy(&plaintext, &secrets_copy)?;
+
+    let encrypted_destination = project_root.join(file.get_encrypted_destination());
+    debug!("Encrypting {:?} to {:?}", secrets_copy, encrypted_destination);
+    encrypt_secret(file, &secrets_copy, &encrypted_destination, &encryption_key)?;
+
+    Ok(())
+}
+
+/// Prints a unified diff between two files. This is informational only, so failures are ignored.
+fn print_file_diff(old: &Path, new: &Path) {
+    let _ = crate::git::run_git_attached(
+        std::process::Command::new("git")
+            .arg("--no-pager")
+            .arg("diff")
+            .arg("--no-index")
+            .arg("--")
+            .arg(old)
+            .arg(new),
+    );
+}
+
+/// Helper method to create an empty file
+fn write_file_with_contents(path: &PathBuf, contents: &str) -> Result<(), std::io::Error> {
+    let mut file = File::create(path)?;
+    file.write_all(contents.as_bytes())?;
+    Ok(())
+}
+
+/// Returns the SHA-256 hash of the given bytes
+pub fn hash_bytes(bytes: &[u8]) -> String {
+    let digest = ring::digest::digest(&SHA256, bytes);
+    base64::encode(digest.as_ref())
+}
+
+/// Returns the SHA-256 hash of a file at the given path
+pub fn hash_file(path: &PathBuf) -> Result<String, Error> {
+    let input = File::open(path)?;
+    let mut reader = BufReader::new(input);
+    let mut context = Context::new(&SHA256);
+    let mut buffer = [0; 1024];
+
+    loop {
+        let count = reader.read(&mut buffer)?;
+        if count == 0 {
+            break;
+        }
+        context.update(&buffer[..count]);
+    }
+
+    let digest = context.finish();
+
+    Ok(base64::encode(digest.as_ref()))
+}
+
+fn create_parent_directory_for_path_if_not_exists(path: &Path) -> Result<(), Error> {
+    let parent = match path.parent() {
+        Some(parent) => parent,
+        None => return Ok(()), // if we're in the root of the filesystem, we have no work to do
+    };
+
+    create_dir_all(parent)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Encoding;
+    use std::fs::{read, write};
+
+    fn file() -> crate::File {
+        crate::File {
+            source: "secrets.json".to_string(),
+            destination: "secrets.json".to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn apply(
+        project_root: &Path,
+        last_applied: Option<&str>,
+        resolution: ConflictResolution,
+    ) -> FileOutcome {
+        let mut resolver = ConflictResolver::new(Some(resolution));
+        let plan = plan_file(
+            project_root,
+            &file(),
+            &hash_bytes(b"incoming"),
+            last_applied,
+            OnConflict::default(),
+            &mut resolver,
+        )
+        .unwrap();
+
+        commit_file(project_root, &plan, |path| write(path, b"incoming")).unwrap();
+
+        plan.outcome
+    }
+
+    #[test]
+    fn test_serialized_configuration_ignores_input_order() {
+        let file = |destination: &str, variant: Option<&str>| crate::File {
+            source: destination.to_string(),
+            destination: destination.to_string(),
+            variant: variant.map(String::from),
+            ..Default::default()
+        };
+
+        let configuration = ConfigurationFile {
+            project_name: "WordPress".to_string(),
+            files_to_copy: vec![
+                file("b.json", None),
+                file("a.json", Some("release")),
+                file("a.json", Some("debug")),
+            ],
+            sanity_checks: Some(Default::default()),
+            ..Default::default()
+        };
+
+        let mut reordered = configuration.clone();
+        reordered.files_to_copy.reverse();
+
+        let serialized = serialize_configuration(&configuration);
+        assert_eq!(serialized, serialize_configuration(&reordered));
+        assert!(serialized.ends_with("}\n"));
+
+        // The same bytes come back after a round trip through `.configure`
+        let parsed = parse_configuration(".configure", &serialized, true).unwrap();
+        assert_eq!(serialize_configuration(&parsed), serialized);
+
+        let keys: Vec<String> = match serde_json::from_str(&serialized).unwrap() {
+            Value::Object(object) => object.keys().cloned().collect(),
+            _ => panic!("The configuration isn't an object"),
+        };
+        let mut sorted_keys = keys.clone();
+        sorted_keys.sort();
+        assert_eq!(keys, sorted_keys);
+
+        let destinations: Vec<(String, Option<String>)> = parsed
+            .files_to_copy
+            .into_iter()
+            .map(|file| (file.destination, file.variant))
+            .collect();
+        assert_eq!(
+            destinations,
+            vec![
+                ("a.json".to_string(), Some("debug".to_string())),
+                ("a.json".to_string(), Some("release".to_string())),
+                ("b.json".to_string(), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_apply_file_overwrites_previously_applied_content() {
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path().join("secrets.json"), "applied").unwrap();
+
+        let outcome = apply(
+            dir.path(),
+            Some(&hash_bytes(b"applied")),
+            ConflictResolution::KeepLocal,
+        );
+
+        assert_eq!(outcome, FileOutcome::Updated { backup: None });
+        assert_eq!(read(dir.path().join("secrets.json")).unwrap(), b"incoming");
+    }
+
+    #[test]
+    fn test_apply_file_keeps_local_edits() {
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path().join("secrets.json"), "edited").unwrap();
+
+        let outcome = apply(
+            dir.path(),
+            Some(&hash_bytes(b"applied")),
+            ConflictResolution::KeepLocal,
+        );
+
+        assert_eq!(
+            outcome,
+            FileOutcome::Conflict {
+                resolution: ConflictResolution::KeepLocal,
+                backup: None
+            }
+        );
+        assert_eq!(read(dir.path().join("secrets.json")).unwrap(), b"edited");
+    }
+
+    #[test]
+    fn test_apply_file_writes_new_file_beside_local_edits() {
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path().join("secrets.json"), "edited").unwrap();
+
+        apply(
+            dir.path(),
+            Some(&hash_bytes(b"applied")),
+            ConflictResolution::WriteNew,
+        );
+
+        assert_eq!(read(dir.path().join("secrets.json")).unwrap(), b"edited");
+        assert_eq!(read(dir.path().join("secrets.json.new")).unwrap(), b"incoming");
+    }
+
+    #[test]
+    fn test_apply_file_backs_up_local_edits_when_taking_incoming() {
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path().join("secrets.json"), "edited").unwrap();
+
+        let outcome = apply(
+            dir.path(),
+            Some(&hash_bytes(b"applied")),
+            ConflictResolution::TakeIncoming,
+        );
+
+        let backup = match outcome {
+            FileOutcome::Conflict {
+                backup: Some(backup),
+                ..
+            } => backup,
+            _ => panic!("Expected a backup to be made"),
+        };
+
+        assert_eq!(read(dir.path().join("secrets.json")).unwrap(), b"incoming");
+        assert_eq!(read(dir.path().join(backup)).unwrap(), b"edited");
+    }
+
+    /// Applies incoming content over an existing file that configure has no record of writing
+    fn apply_over_unknown_file(
+        project_root: &Path,
+        on_conflict: OnConflict,
+    ) -> Result<FileOutcome, ConfigureError> {
+        write(project_root.join("secrets.json"), "existing").unwrap();
+
+        let mut resolver = ConflictResolver::new(None);
+        let plan = plan_file(
+            project_root,
+            &file(),
+            &hash_bytes(b"incoming"),
+            None,
+            on_conflict,
+            &mut resolver,
+        )?;
+
+        commit_file(project_root, &plan, |path| write(path, b"incoming")).unwrap();
+
+        Ok(plan.outcome)
+    }
+
+    #[test]
+    fn test_on_conflict_keep_leaves_existing_file() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let outcome = apply_over_unknown_file(dir.path(), OnConflict::Keep).unwrap();
+
+        assert_eq!(outcome, FileOutcome::Kept);
+        assert_eq!(read(dir.path().join("secrets.json")).unwrap(), b"existing");
+    }
+
+    #[test]
+    fn test_on_conflict_overwrite_replaces_existing_file_without_backup() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let outcome = apply_over_unknown_file(dir.path(), OnConflict::Overwrite).unwrap();
+
+        assert_eq!(outcome, FileOutcome::Updated { backup: None });
+        assert_eq!(read(dir.path().join("secrets.json")).unwrap(), b"incoming");
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_on_conflict_backup_overwrite_backs_up_existing_file() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let outcome = apply_over_unknown_file(dir.path(), OnConflict::BackupOverwrite).unwrap();
+
+        let backup = match outcome {
+            FileOutcome::Updated {
+                backup: Some(backup),
+            } => backup,
+            _ => panic!("Expected a backup to be made"),
+        };
+
+        assert_eq!(read(dir.path().join("secrets.json")).unwrap(), b"incoming");
+        assert_eq!(read(dir.path().join(backup)).unwrap(), b"existing");
+    }
+
+    #[test]
+    fn test_on_conflict_fail_stops_without_touching_existing_file() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let result = apply_over_unknown_file(dir.path(), OnConflict::Fail);
+
+        assert!(matches!(result, Err(ConfigureError::DestinationExists(_))));
+        assert_eq!(read(dir.path().join("secrets.json")).unwrap(), b"existing");
+    }
+
+    #[test]
+    fn test_fsync_applies_files_through_a_flushed_temporary_file() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("config")).unwrap();
+        std::fs::write(dir.path().join("config/existing.json"), "old").unwrap();
+
+        let entry = |destination: &str| crate::File {
+            source: destination.to_string(),
+            destination: destination.to_string(),
+            ..Default::default()
+        };
+        let configuration = ConfigurationFile {
+            files_to_copy: vec![entry("config/new.json"), entry("config/existing.json")],
+            ..Default::default()
+        };
+
+        for defer_backups in &[false, true] {
+            let options = ApplyOptions {
+                fsync: true,
+                defer_backups: *defer_backups,
+                on_conflict: Some(OnConflict::Overwrite),
+                no_sanity_checks: true,
+                ..Default::default()
+            };
+            place_files_for_configuration(&configuration, &options, dir.path(), false, |_| {
+                Ok(Some(b"secret".to_vec()))
+            })
+            .unwrap();
+
+            let read = |name: &str| std::fs::read_to_string(dir.path().join(name)).unwrap();
+            assert_eq!(read("config/new.json"), "secret");
+            assert_eq!(read("config/existing.json"), "secret");
+            assert!(!temporary_path_for(&dir.path().join("config/new.json")).exists());
+        }
+
+        // A destination that can't be written to leaves nothing behind
+        let missing_directory = dir.path().join("missing/new.json");
+        assert!(write_durably(&missing_directory, b"secret", None).is_err());
+        assert!(!temporary_path_for(&missing_directory).exists());
+    }
+
+    #[test]
+    fn test_placeholders_are_only_written_for_missing_optional_files() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let entry = |destination: &str, optional: bool| crate::File {
+            source: destination.to_string(),
+            destination: destination.to_string(),
+            optional,
+            ..Default::default()
+        };
+
+        let configuration = ConfigurationFile {
+            files_to_copy: vec![
+                entry("required.json", false),
+                entry("optional.json", true),
+                entry("missing.json", true),
+                crate::File {
+                    placeholder: Some("{}".to_string()),
+                    ..entry("missing-with-template.json", true)
+                },
+            ],
+            placeholder: Some("# placeholder".to_string()),
+            ..Default::default()
+        };
+
+        let options = ApplyOptions {
+            placeholder_on_missing: true,
+            no_sanity_checks: true,
+            ..Default::default()
+        };
+
+        let report =
+            place_files_for_configuration(&configuration, &options, dir.path(), false, |file| {
+                Ok(match file.destination.as_str() {
+                    "required.json" | "optional.json" => Some(b"secret".to_vec()),
+                    _ => None,
+                })
+            })
+            .unwrap();
+
+        let read = |name: &str| std::fs::read_to_string(dir.path().join(name)).unwrap();
+        assert_eq!(read("required.json"), "secret");
+        assert_eq!(read("optional.json"), "secret");
+        assert_eq!(read("missing.json"), "# placeholder");
+        assert_eq!(read("missing-with-template.json"), "{}");
+
+        let outcomes: Vec<&FileOutcome> = report.files.iter().map(|(_, outcome)| outcome).collect();
+        assert_eq!(
+            outcomes,
+            vec![
+                &FileOutcome::Created,
+                &FileOutcome::Created,
+                &FileOutcome::Placeholder,
+                &FileOutcome::Placeholder,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_transformed_secrets_are_converted_as_theyre_applied() {
+        let dir = tempfile::tempdir().unwrap();
+        let entry = |destination: &str| crate::File {
+            source: "Secrets/config.json".to_string(),
+            destination: destination.to_string(),
+            format: Some("json".to_string()),
+            transform: Some(crate::Transform::Xcconfig),
+            ..Default::default()
+        };
+        let apply = |secret: &'static [u8]| {
+            let configuration = ConfigurationFile {
+                files_to_copy: vec![entry("Secrets.xcconfig")],
+                ..Default::default()
+            };
+            place_files_for_configuration(
+                &configuration,
+                &ApplyOptions::default(),
+                dir.path(),
+                true,
+                |_| Ok(Some(secret.to_vec())),
+            )
+        };
+
+        apply(br#"{ "API": { "KEY": "secret" } }"#).unwrap();
+        let written = std::fs::read_to_string(dir.path().join("Secrets.xcconfig")).unwrap();
+        assert_eq!(written, "API_KEY = secret\n");
+
+        match apply(br#"{ "API": { "KEY": null } }"#) {
+            Err(ConfigureError::TransformFailed { destination, problem, .. }) => {
+                assert_eq!(destination, "Secrets.xcconfig");
+                assert!(problem.starts_with("API.KEY is null"));
+            }
+            other => panic!("expected the transform to fail, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_first_apply_adopts_identical_files_and_remembers_kept_ones() {
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path().join("same.json"), "secret").unwrap();
+        write(dir.path().join("kept.json"), "mine").unwrap();
+
+        let entry = |destination: &str| crate::File {
+            source: destination.to_string(),
+            destination: destination.to_string(),
+            ..Default::default()
+        };
+        let configuration = ConfigurationFile {
+            files_to_copy: vec![entry("same.json"), entry("kept.json")],
+            ..Default::default()
+        };
+        let options = ApplyOptions {
+            no_sanity_checks: true,
+            ..Default::default()
+        };
+
+        // As if the developer had been asked about kept.json, and chose to keep it
+        let mut state = ApplyState::default();
+        state.record_kept("kept.json", hash_bytes(b"mine"), 4, &hash_bytes(b"secret"));
+        save_apply_state(dir.path(), &state).unwrap();
+
+        let place = |incoming: &'static [u8]| {
+            let report =
+                place_files_for_configuration(&configuration, &options, dir.path(), false, |_| {
+                    Ok(Some(incoming.to_vec()))
+                })
+                .unwrap();
+            report.files.into_iter().map(|(_, outcome)| outcome).collect::<Vec<_>>()
+        };
+
+        assert_eq!(place(b"secret"), vec![FileOutcome::Unchanged, FileOutcome::Kept]);
+        assert_eq!(read(dir.path().join("kept.json")).unwrap(), b"mine");
+        let state = read_apply_state(dir.path());
+        assert_eq!(state.applied_digest_for("same.json"), Some(hash_bytes(b"secret").as_str()));
+
+        // Once the secrets change, there's nobody to ask, so the kept file is backed up
+        let outcomes = place(b"new secret");
+        assert_eq!(outcomes[0], FileOutcome::Updated { backup: None });
+        let backup = match &outcomes[1] {
+            FileOutcome::Updated {
+                backup: Some(backup),
+            } => backup,
+            outcome => panic!("Expected a backup to be made, not {:?}", outcome),
+        };
+        assert_eq!(read(dir.path().join(backup)).unwrap(), b"mine");
+        assert_eq!(read(dir.path().join("kept.json")).unwrap(), b"new secret");
+    }
+
+    #[test]
+    fn test_output_root_receives_files_without_backups_or_state() {
+        let project = tempfile::tempdir().unwrap();
+        let output_root = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(output_root.path().join("config")).unwrap();
+        write(output_root.path().join("config/a.json"), "stale").unwrap();
+
+        let entry = |destination: &str| crate::File {
+            source: destination.to_string(),
+            destination: destination.to_string(),
+            ..Default::default()
+        };
+        let mut configuration = ConfigurationFile {
+            files_to_copy: vec![entry("config/a.json"), entry("nested/b.json")],
+            ..Default::default()
+        };
+        let options = ApplyOptions {
+            output_root: Some(output_root.path().to_path_buf()),
+            no_sanity_checks: true,
+            ..Default::default()
+        };
+
+        let place = |configuration: &ConfigurationFile| {
+            place_files_for_configuration(configuration, &options, project.path(), false, |_| {
+                Ok(Some(b"secret".to_vec()))
+            })
+        };
+
+        place(&configuration).unwrap();
+        assert_eq!(read(output_root.path().join("config/a.json")).unwrap(), b"secret");
+        assert_eq!(read(output_root.path().join("nested/b.json")).unwrap(), b"secret");
+        assert_eq!(std::fs::read_dir(output_root.path().join("config")).unwrap().count(), 1);
+        assert!(!project.path().join("config").exists());
+        assert!(!project.path().join(crate::state::STATE_FILE_NAME).exists());
+
+        configuration.files_to_copy.push(entry("../escaped.json"));
+        assert!(matches!(
+            place(&configuration),
+            Err(ConfigureError::DestinationOutsideRoot(destination, _))
+                if destination == "../escaped.json"
+        ));
+        assert!(!output_root.path().parent().unwrap().join("escaped.json").exists());
+    }
+
+    #[test]
+    fn test_apply_paths_are_resolved_like_files_are_placed() {
+        let project = tempfile::tempdir().unwrap();
+        let output_root = tempfile::tempdir().unwrap();
+        let configuration = ConfigurationFile {
+            files_to_copy: vec![crate::File {
+                source: "a.json".to_string(),
+                destination: "config/a.json".to_string(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        let paths = |options: &ApplyOptions| {
+            resolve_apply_paths(&configuration, options, project.path())
+                .unwrap()
+                .into_iter()
+                .map(|paths| (paths.source_abs, paths.destination_abs))
+                .collect::<Vec<_>>()
+        };
+        let source = project.path().join("config/a.json.enc");
+
+        assert_eq!(
+            paths(&ApplyOptions::default()),
+            vec![(source.clone(), project.path().join("config/a.json"))]
+        );
+
+        let options = ApplyOptions {
+            output_root: Some(output_root.path().to_path_buf()),
+            ..Default::default()
+        };
+        assert_eq!(
+            paths(&options),
+            vec![(source.clone(), output_root.path().join("config/a.json"))]
+        );
+
+        let options = ApplyOptions {
+            prefix: Some(output_root.path().to_path_buf()),
+            ..Default::default()
+        };
+        let placed = prefixed_file(&configuration.files_to_copy[0], project.path()).unwrap();
+        assert_eq!(
+            paths(&options),
+            vec![(source, output_root.path().join(placed.destination))]
+        );
+    }
+
+    #[test]
+    fn test_locked_destinations_are_retried_then_given_up_on() {
+        #[cfg(windows)]
+        let locked = || Error::from_raw_os_error(32);
+        #[cfg(not(windows))]
+        let locked = || Error::from_raw_os_error(16);
+
+        // Released on the third attempt
+        let mut attempts = 0;
+        let result = retry_while_locked_with(Duration::from_millis(1), || {
+            attempts += 1;
+            match attempts {
+                3 => Ok("written"),
+                _ => Err(locked()),
+            }
+        });
+        assert_eq!(result.unwrap(), "written");
+        assert_eq!(attempts, 3);
+
+        // Never released
+        let mut attempts = 0;
+        let result: Result<(), Error> = retry_while_locked_with(Duration::from_millis(1), || {
+            attempts += 1;
+            Err(locked())
+        });
+        assert!(is_locked_file_error(&result.unwrap_err()));
+        assert_eq!(attempts, LOCKED_FILE_ATTEMPTS);
+
+        // Other problems aren't worth waiting for
+        let mut attempts = 0;
+        let result: Result<(), Error> = retry_while_locked_with(Duration::from_millis(1), || {
+            attempts += 1;
+            Err(Error::from(std::io::ErrorKind::PermissionDenied))
+        });
+        assert!(!is_locked_file_error(&result.unwrap_err()));
+        assert_eq!(attempts, 1);
+
+        let mut report = crate::ApplyReport::default();
+        report.push("a.json", FileOutcome::Created);
+        report.push("b.json", FileOutcome::Locked { holder: None });
+        assert_eq!(report.locked_destinations(), vec!["b.json"]);
+        assert_eq!(
+            ConfigureError::FilesLocked("b.json".to_string()).exit_code(),
+            crate::configure::PARTIAL_SUCCESS_EXIT_CODE
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_apply_carries_on_past_a_locked_destination() {
+        let dir = tempfile::tempdir().unwrap();
+
+        // Linux won't let a running program's executable be written to
+        let program = dir.path().join("tool");
+        std::fs::copy("/bin/sleep", &program).unwrap();
+        let mut running = std::process::Command::new(&program).arg("30").spawn().unwrap();
+        let exe = PathBuf::from(format!("/proc/{}/exe", running.id()));
+        while std::fs::read_link(&exe).ok().as_deref() != Some(program.as_path()) {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        let entry = |destination: &str| crate::File {
+            source: destination.to_string(),
+            destination: destination.to_string(),
+            ..Default::default()
+        };
+        let configuration = ConfigurationFile {
+            files_to_copy: vec![entry("tool"), entry("a.json")],
+            ..Default::default()
+        };
+        let options = ApplyOptions {
+            on_conflict: Some(OnConflict::Overwrite),
+            no_sanity_checks: true,
+            ..Default::default()
+        };
+        let report =
+            place_files_for_configuration(&configuration, &options, dir.path(), false, |_| {
+                Ok(Some(b"secret".to_vec()))
+            });
+        running.kill().unwrap();
+        running.wait().unwrap();
+        let report = report.unwrap();
+
+        assert_eq!(report.files[0].0, "tool");
+        assert!(matches!(report.files[0].1, FileOutcome::Locked { .. }));
+        assert_eq!(report.files[1], ("a.json".to_string(), FileOutcome::Created));
+        assert_eq!(read(dir.path().join("a.json")).unwrap(), b"secret");
+        assert_ne!(read(&program).unwrap(), b"secret");
+
+        let locked = report.ensure_nothing_locked().unwrap_err();
+        assert_eq!(locked.to_string(), ConfigureError::FilesLocked("tool".to_string()).to_string());
+        assert_eq!(locked.exit_code(), crate::configure::PARTIAL_SUCCESS_EXIT_CODE);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_lock_holder_is_the_process_with_the_file_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("profile.mobileprovision");
+        let _open = File::create(&path).unwrap();
+
+        let holder = describe_lock_holder(&path).unwrap();
+        assert!(holder.ends_with(&format!("(pid {})", std::process::id())));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_replaced_files_keep_their_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let destination = dir.path().join("secrets.json");
+        let configuration = ConfigurationFile {
+            files_to_copy: vec![file()],
+            ..Default::default()
+        };
+
+        for (fsync, defer_backups) in [(false, false), (true, false), (false, true)] {
+            write(&destination, "edited").unwrap();
+            std::fs::set_permissions(&destination, Permissions::from_mode(0o600)).unwrap();
+
+            let options = ApplyOptions {
+                fsync,
+                defer_backups,
+                on_conflict: Some(OnConflict::BackupOverwrite),
+                resolve_conflicts: Some(ConflictResolution::TakeIncoming),
+                no_sanity_checks: true,
+                ..Default::default()
+            };
+            place_files_for_configuration(&configuration, &options, dir.path(), false, |_| {
+                Ok(Some(b"secret".to_vec()))
+            })
+            .unwrap();
+
+            let mode = std::fs::metadata(&destination).unwrap().permissions().mode();
+            assert_eq!(read(&destination).unwrap(), b"secret");
+            assert_eq!(mode & 0o777, 0o600);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_user_scope_secrets_are_only_readable_by_the_user() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let sandbox = crate::fixtures::Sandbox::new("{}");
+        let configuration = ConfigurationFile {
+            files_to_copy: vec![crate::File {
+                source: "netrc".to_string(),
+                destination: "~/.netrc".to_string(),
+                scope: crate::configure::Scope::User,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let options = ApplyOptions {
+            no_sanity_checks: true,
+            ..Default::default()
+        };
+
+        let project_root = sandbox.project.path();
+        place_files_for_configuration(&configuration, &options, project_root, false, |_| {
+            Ok(Some(b"machine example.com password hunter2".to_vec()))
+        })
+        .unwrap();
+
+        let netrc = sandbox.home.path().join(".netrc");
+        let mode = std::fs::metadata(netrc).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[test]
+    fn test_user_scope_secrets_are_placed_at_their_home_directory_path_under_a_prefix() {
+        let sandbox = crate::fixtures::Sandbox::new("{}");
+        let netrc = crate::File {
+            source: "netrc".to_string(),
+            destination: "~/.netrc".to_string(),
+            scope: crate::configure::Scope::User,
+            ..Default::default()
+        };
+
+        let placed = prefixed_file(&netrc, sandbox.project.path()).unwrap();
+        assert_eq!(
+            Path::new("/").join(placed.destination),
+            sandbox.home.path().join(".netrc")
+        );
+    }
+
+    #[test]
+    fn test_prefix_receives_files_at_their_resolved_destinations() {
+        let project = tempfile::tempdir().unwrap();
+        let prefix = tempfile::tempdir().unwrap();
+
+        let entry = |destination: &str| crate::File {
+            source: "a.json".to_string(),
+            destination: destination.to_string(),
+            ..Default::default()
+        };
+        let configuration = ConfigurationFile {
+            files_to_copy: vec![
+                entry("/etc/app/secrets.json"),
+                entry("config/a.json"),
+                entry("../shared/b.json"),
+            ],
+            ..Default::default()
+        };
+        let options = ApplyOptions {
+            prefix: Some(prefix.path().to_path_buf()),
+            no_sanity_checks: true,
+            ..Default::default()
+        };
+
+        place_files_for_configuration(&configuration, &options, project.path(), false, |_| {
+            Ok(Some(b"secret".to_vec()))
+        })
+        .unwrap();
+
+        let under_prefix = |path: &Path| {
+            prefix
+                .path()
+                .join(path.strip_prefix("/").unwrap_or(path))
+        };
+        assert_eq!(read(prefix.path().join("etc/app/secrets.json")).unwrap(), b"secret");
+        assert_eq!(
+            read(under_prefix(&project.path().join("config/a.json"))).unwrap(),
+            b"secret"
+        );
+        let shared = project.path().parent().unwrap().join("shared/b.json");
+        assert_eq!(read(under_prefix(&shared)).unwrap(), b"secret");
+
+        assert!(!project.path().join("config").exists());
+        assert!(!project.path().join(crate::state::STATE_FILE_NAME).exists());
+
+        // Climbing above the root still lands inside the prefix
+        let climbing = prefixed_file(&entry("/../../etc/app.json"), project.path()).unwrap();
+        assert_eq!(climbing.destination, "etc/app.json");
+    }
+
+    #[test]
+    fn test_placeholders_are_not_written_without_the_option_or_over_existing_files() {
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path().join("existing.json"), "real secret").unwrap();
+
+        let entry = |destination: &str| crate::File {
+            source: destination.to_string(),
+            destination: destination.to_string(),
+            optional: true,
+            ..Default::default()
+        };
+
+        let configuration = ConfigurationFile {
+            files_to_copy: vec![entry("missing.json"), entry("existing.json")],
+            ..Default::default()
+        };
+
+        let mut options = ApplyOptions::default();
+        place_files_for_configuration(&configuration, &options, dir.path(), false, |_| Ok(None))
+            .unwrap();
+        assert!(!dir.path().join("missing.json").exists());
+
+        options.placeholder_on_missing = true;
+        place_files_for_configuration(&configuration, &options, dir.path(), false, |_| Ok(None))
+            .unwrap();
+        assert!(dir.path().join("missing.json").exists());
+        assert_eq!(read(dir.path().join("existing.json")).unwrap(), b"real secret");
+    }
+
+    #[test]
+    fn test_apply_records_where_content_came_from() {
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path().join("secrets.json.enc"), "encrypted").unwrap();
+
+        let configuration = ConfigurationFile {
+            pinned_hash: "abc123".to_string(),
+            files_to_copy: vec![file()],
+            ..Default::default()
+        };
+        let options = ApplyOptions {
+            no_sanity_checks: true,
+            ..Default::default()
+        };
+        let apply = |contents: &'static [u8], from_encrypted_files: bool| {
+            place_files_for_configuration(
+                &configuration,
+                &options,
+                dir.path(),
+                from_encrypted_files,
+                |_| Ok(Some(contents.to_vec())),
+            )
+            .unwrap();
+            read_apply_state(dir.path())
+        };
+
+        let state = apply(b"secret", true);
+        let encrypted_digest = hash_file(&dir.path().join("secrets.json.enc")).unwrap();
+        let destination = dir.path().join("secrets.json");
+        assert_eq!(
+            state.encrypted_digest_for("secrets.json"),
+            Some(encrypted_digest.as_str())
+        );
+        assert_eq!(
+            state.files["secrets.json"].pinned_hash.as_deref(),
+            Some("abc123")
+        );
+        assert!(state.is_unmodified("secrets.json", &destination));
+
+        write(&destination, "edited").unwrap();
+        assert!(!state.is_unmodified("secrets.json", &destination));
+
+        // Content that didn't come from the encrypted file isn't attributed to it
+        write(&destination, "secret").unwrap();
+        let state = apply(b"from an archive", false);
+        assert_eq!(state.encrypted_digest_for("secrets.json"), None);
+    }
+
+    #[test]
+    fn test_each_variant_has_its_own_record_of_a_shared_destination() {
+        let dir = tempfile::tempdir().unwrap();
+        let configuration = |variant: &str| ConfigurationFile {
+            files_to_copy: vec![crate::File {
+                variant: Some(variant.to_string()),
+                ..file()
+            }],
+            ..Default::default()
+        };
+        let options = ApplyOptions {
+            on_conflict: Some(OnConflict::Fail),
+            no_sanity_checks: true,
+            ..Default::default()
+        };
+        let apply = |variant: &str, contents: &'static [u8]| {
+            let configuration = configuration(variant);
+            place_files_for_configuration(&configuration, &options, dir.path(), false, |_| {
+                Ok(Some(contents.to_vec()))
+            })
+            .unwrap()
+        };
+
+        apply("staging", b"staging secret");
+        let report = apply("prod", b"prod secret");
+
+        // What another variant wrote is configure's to replace, without a backup
+        assert_eq!(report.files[0].1, FileOutcome::Updated { backup: None });
+
+        let state = read_apply_state(dir.path());
+        let staging = hash_bytes(b"staging secret");
+        let prod = hash_bytes(b"prod secret");
+        assert_eq!(state.digest_for("staging:secrets.json"), Some(staging.as_str()));
+        assert_eq!(state.digest_for("prod:secrets.json"), Some(prod.as_str()));
+        assert_eq!(state.digest_for("secrets.json"), None);
+    }
+
+    #[test]
+    fn test_changed_settings_are_compared_by_decrypting_again() {
+        let sandbox = crate::fixtures::Sandbox::new("{}");
+        let project_root = sandbox.project.path();
+        write(project_root.join("secrets.json.enc"), "encrypted").unwrap();
+
+        let mut configuration = ConfigurationFile {
+            project_name: "App".to_string(),
+            files_to_copy: vec![file()],
+            ..Default::default()
+        };
+        let options = ApplyOptions {
+            no_sanity_checks: true,
+            ..Default::default()
+        };
+        place_files_for_configuration(&configuration, &options, project_root, true, |_| {
+            Ok(Some(b"{}".to_vec()))
+        })
+        .unwrap();
+
+        // Unchanged, what was applied is what decrypting would give, so no key is needed
+        let comparisons = compare_decrypted_files(&configuration).unwrap();
+        assert_eq!(comparisons[0].incoming, b"{}");
+
+        // With a transform, it would give something else – which takes the key to find out
+        configuration.files_to_copy[0].transform = Some(crate::transform::Transform::Properties);
+        assert!(matches!(
+            compare_decrypted_files(&configuration),
+            Err(ConfigureError::MissingProjectKey)
+        ));
+    }
+
+    #[test]
+    fn test_commit_staged_files_leaves_remaining_files_untouched_on_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut resolver = ConflictResolver::new(None);
+        let mut staged_files = StagedFiles::default();
+
+        for name in &["first", "second", "third"] {
+            write(dir.path().join(name), "original").unwrap();
+
+            let file = crate::File {
+                source: name.to_string(),
+                destination: name.to_string(),
+                ..Default::default()
+            };
+            let plan = plan_file(
+                dir.path(),
+                &file,
+                &hash_bytes(b"incoming"),
+                Some(&hash_bytes(b"original")),
+                OnConflict::default(),
+                &mut resolver,
+            )
+            .unwrap();
+
+            staged_files.0.push(stage_file(plan, b"incoming", hash_bytes(b"incoming"), false).unwrap());
+        }
+
+        // Simulate a failure partway through the commit phase
+        std::fs::remove_file(staged_files.0[1].temporary_path.as_ref().unwrap()).unwrap();
+
+        let mut state = crate::state::ApplyState::default();
+        let mut report = ApplyReport::default();
+        let result = commit_staged_files(dir.path(), staged_files, false, &mut state, &mut report);
+
+        assert!(result.is_err());
+        assert_eq!(read(dir.path().join("first")).unwrap(), b"incoming");
+        assert_eq!(read(dir.path().join("second")).unwrap(), b"original");
+        assert_eq!(read(dir.path().join("third")).unwrap(), b"original");
+        assert!(!dir.path().join("third.configure-tmp").exists());
+
+        assert!(state.digest_for("first").is_some());
+        assert!(state.digest_for("second").is_none());
+    }
+
+    #[test]
+    fn test_staged_files_are_removed_if_they_are_never_committed() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = crate::File {
+            source: "secrets.json".to_string(),
+            destination: "secrets.json".to_string(),
+            ..Default::default()
+        };
+        let plan = plan_file(
+            dir.path(),
+            &file,
+            &hash_bytes(b"incoming"),
+            None,
+            OnConflict::default(),
+            &mut ConflictResolver::new(None),
+        )
+        .unwrap();
+
+        let staged = stage_file(plan, b"incoming", hash_bytes(b"incoming"), false).unwrap();
+        let temporary_path = staged.temporary_path.clone().unwrap();
+        assert!(temporary_path.exists());
+
+        // Like an error part way through an apply, before the staged files are committed
+        drop(StagedFiles(vec![staged]));
+
+        assert!(!temporary_path.exists());
+        assert!(!dir.path().join("secrets.json").exists());
+    }
+
+    #[test]
+    fn test_check_file_size_rejects_files_over_the_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("secrets.json.enc");
+        write(&path, vec![0; 1024]).unwrap();
+
+        assert!(check_file_size(&path, None).is_ok());
+        assert!(check_file_size(&path, Some(0)).is_ok());
+        assert!(check_file_size(&path, Some(1024)).is_ok());
+
+        match check_file_size(&path, Some(1023)) {
+            Err(ConfigureError::FileTooLarge { size, limit, .. }) => {
+                assert_eq!(size, 1024);
+                assert_eq!(limit, 1023);
+            }
+            result => panic!("Unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_destinations_differ_when_missing_or_changed() {
+        let configuration = ConfigurationFile {
+            files_to_copy: vec![crate::File {
+                destination: "a.json".to_string(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let incoming = BTreeMap::from([("a.json".to_string(), b"{}".to_vec())]);
+        let differs = |local: Option<&[u8]>| {
+            let local = local.map(|local| ("a.json".to_string(), local.to_vec()));
+            let local = local.into_iter().collect();
+            plan_apply(&configuration, &ApplyState::default(), &local, &incoming).has_changes()
+        };
+
+        assert!(!differs(Some(b"{}")));
+        assert!(differs(Some(b"{ }")));
+        assert!(differs(None));
+    }
+
+    #[test]
+    fn test_large_encrypted_files_round_trip() {
+        crate::encryption::init().unwrap();
+        let key = crate::encryption::generate_key();
+        let dir = tempfile::tempdir().unwrap();
+        let destination = dir.path().join("large.bin.enc");
+        std::fs::write(&destination, "an older encrypted file").unwrap();
+
+        let contents: Vec<u8> = (0..32 * 1024 * 1024).map(|i: u32| (i % 251) as u8).collect();
+        let mut pending = PendingEncryptedFiles::default();
+        let temporary_path = write_encrypted_temporary_file(&destination, &contents, &key).unwrap();
+        pending.0.push((temporary_path, destination.clone()));
+        pending.move_into_place().unwrap();
+
+        assert!(decrypt_file_contents(&destination.to_path_buf(), &key).unwrap() == contents);
+        assert!(!temporary_path_for(&destination).exists());
+    }
+
+    #[test]
+    fn test_encrypted_files_only_replace_the_old_ones_once_all_are_written() {
+        crate::encryption::init().unwrap();
+        let key = crate::encryption::generate_key();
+        let dir = tempfile::tempdir().unwrap();
+        let destinations = [dir.path().join("a.json.enc"), dir.path().join("b.json.enc")];
+        for destination in &destinations {
+            std::fs::write(destination, "the old pin's encrypted file").unwrap();
+        }
+
+        let pending_files = || {
+            let mut pending = PendingEncryptedFiles::default();
+            for destination in &destinations {
+                let temporary_path =
+                    write_encrypted_temporary_file(destination, b"new", &key).unwrap();
+                pending.0.push((temporary_path, destination.clone()));
+            }
+            pending
+        };
+
+        // An update that fails after encrypting leaves every old file, and no temporary ones
+        drop(pending_files());
+        for destination in &destinations {
+            assert_eq!(std::fs::read(destination).unwrap(), b"the old pin's encrypted file");
+            assert!(!temporary_path_for(destination).exists());
+        }
+
+        // One that can't be moved into place puts back the ones that were
+        let pending = pending_files();
+        std::fs::remove_file(&pending.0[1].0).unwrap();
+        assert!(pending.move_into_place().is_err());
+        for destination in &destinations {
+            assert_eq!(std::fs::read(destination).unwrap(), b"the old pin's encrypted file");
+            assert!(!temporary_path_for(destination).exists());
+        }
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), destinations.len());
+
+        pending_files().move_into_place().unwrap();
+        for destination in &destinations {
+            assert_eq!(decrypt_file_contents(destination, &key).unwrap(), b"new");
+            assert!(!temporary_path_for(destination).exists());
+        }
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), destinations.len());
+    }
+
+    #[test]
+    fn test_filters_round_trip_secrets() {
+        crate::encryption::init().unwrap();
+        let key = crate::encryption::generate_key();
+
+        for encoding in &[crate::Encoding::None, crate::Encoding::Base64] {
+            let file = crate::File {
+                encoding: *encoding,
+                ..Default::default()
+            };
+
+            let encrypted = clean_contents(&file, b"{\"secret\": 1}", None, &key);
+            assert_eq!(smudge_contents(&file, &encrypted, &key).unwrap(), b"{\"secret\": 1}");
+
+            // An unchanged secret keeps its ciphertext, and a changed one gets a new one
+            let unchanged = clean_contents(&file, b"{\"secret\": 1}", Some(&encrypted), &key);
+            assert_eq!(unchanged, encrypted);
+            let changed = clean_contents(&file, b"{\"secret\": 2}", Some(&encrypted), &key);
+            assert_ne!(changed, encrypted);
+            assert_eq!(smudge_contents(&file, &changed, &key).unwrap(), b"{\"secret\": 2}");
+        }
+
+        let other_key = crate::encryption::generate_key();
+        let encrypted = clean_contents(&crate::File::default(), b"secret", None, &key);
+        assert!(smudge_contents(&crate::File::default(), &encrypted, &other_key).is_err());
+    }
+
+    #[test]
+    fn test_reencrypt_file_only_rewrites_missing_or_broken_files() {
+        crate::encryption::init().unwrap();
+        let key = crate::encryption::generate_key();
+        let dir = tempfile::tempdir().unwrap();
+        let file = crate::File {
+            source: "a.json".to_string(),
+            destination: "config/a.json".to_string(),
+            ..Default::default()
+        };
+        let encrypted = dir.path().join("config/a.json.enc");
+
+        let reencrypt = |contents: &[u8]| reencrypt_file(dir.path(), &file, contents, &key).unwrap();
+
+        assert_eq!(reencrypt(b"{}"), ReencryptOutcome::Restored);
+        assert_eq!(decrypt_file_contents(&encrypted, &key).unwrap(), b"{}");
+
+        let written = std::fs::read(&encrypted).unwrap();
+        assert_eq!(reencrypt(b"{}"), ReencryptOutcome::Unchanged);
+        assert_eq!(std::fs::read(&encrypted).unwrap(), written);
+
+        std::fs::write(&encrypted, "garbage").unwrap();
+        assert_eq!(reencrypt(b"{}"), ReencryptOutcome::Repaired);
+        assert_eq!(decrypt_file_contents(&encrypted, &key).unwrap(), b"{}");
+
+        assert_eq!(reencrypt(b"{ \"a\": 1 }"), ReencryptOutcome::Repaired);
+    }
+
+    #[test]
+    fn test_encoded_secrets_round_trip() {
+        crate::encryption::init().unwrap();
+        let key = crate::encryption::generate_key();
+        let dir = tempfile::tempdir().unwrap();
+        let secret = dir.path().join("secret.bin");
+        let encrypted = dir.path().join("secret.bin.enc");
+        std::fs::write(&secret, b"\x00\xffraw bytes").unwrap();
+
+        for (encoding, stored) in &[
+            (Encoding::Base64, "AP9yYXcgYnl0ZXM="),
+            (Encoding::Hex, "00ff726177206279746573"),
+        ] {
+            let file = crate::File {
+                destination: "secret.bin".to_string(),
+                encoding: *encoding,
+                ..Default::default()
+            };
+
+            encrypt_secret(&file, &secret, &encrypted, &key).unwrap();
+            let decrypted = decrypt_file_contents(&encrypted, &key).unwrap();
+            assert_eq!(decrypted, stored.as_bytes());
+            assert_eq!(file.decode_contents(decrypted).unwrap(), b"\x00\xffraw bytes");
+
+            // Encoded secrets are often wrapped across lines
+            let wrapped = format!("{}\n{}\n", &stored[..4], &stored[4..]).into_bytes();
+            assert_eq!(file.decode_contents(wrapped).unwrap(), b"\x00\xffraw bytes");
+        }
+    }
+
+    #[test]
+    fn test_simultaneous_key_updates_are_all_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(KEYS_FILE_NAME);
+        write(&path, "{}").unwrap();
+
+        let threads: Vec<_> = (0..8)
+            .map(|index| {
+                let path = path.clone();
+                std::thread::spawn(move || {
+                    save_key_in_file(&path, &format!("project-{}", index), "key", None).unwrap()
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let keys: serde_json::Map<String, Value> =
+            serde_json::from_slice(&read(&path).unwrap()).unwrap();
+        assert_eq!(keys.len(), 8);
+        assert!(!dir.path().join("keys.json.lock").exists());
+    }
+
+    #[test]
+    fn test_a_held_lock_is_kept_fresh_so_it_is_never_taken_over() {
+        let dir = tempfile::tempdir().unwrap();
+        let lock_path = dir.path().join("keys.json.lock");
+        File::create(&lock_path).unwrap();
+
+        // As if it had been held for a long time
+        let long_ago = std::time::SystemTime::now() - LOCK_STALE_AFTER * 2;
+        let lock = FileLock::hold(lock_path.clone(), Duration::from_millis(20));
+        File::options().write(true).open(&lock_path).unwrap().set_modified(long_ago).unwrap();
+        std::thread::sleep(Duration::from_millis(200));
+
+        assert!(matches!(
+            FileLock::acquire_within(&dir.path().join("keys.json"), Duration::ZERO, |_| {}),
+            Err(ConfigureError::FileLocked(_))
+        ));
+
+        drop(lock);
+        assert!(!lock_path.exists());
+    }
+
+    #[test]
+    fn test_apply_lock_waits_for_another_apply_to_finish() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let held = lock_project_for_apply(dir.path(), None).unwrap();
+        assert!(matches!(
+            lock_project_for_apply(dir.path(), None),
+            Err(ConfigureError::FileLocked(_))
+        ));
+        assert!(matches!(
+            lock_project_for_apply(dir.path(), Some(0)),
+            Err(ConfigureError::LockTimedOut { seconds: 0, .. })
+        ));
+
+        // The first apply finishes while the second is waiting
+        let release = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(300));
+            drop(held);
+        });
+
+        let started = Instant::now();
+        let lock = lock_project_for_apply(dir.path(), Some(10)).unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(250));
+        release.join().unwrap();
+
+        drop(lock);
+        assert!(!dir.path().join(".configure-apply.lock").exists());
+    }
+
+    #[test]
+    fn test_key_changed_by_someone_else_is_not_overwritten() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(KEYS_FILE_NAME);
+        write(&path, r#"{ "WordPress": "theirs" }"#).unwrap();
+
+        // We looked before they created it
+        assert!(matches!(
+            save_key_in_file(&path, "WordPress", "ours", None),
+            Err(ConfigureError::KeyChangedSinceRead(_))
+        ));
+        assert_eq!(read(&path).unwrap(), br#"{ "WordPress": "theirs" }"#.to_vec());
+
+        save_key_in_file(&path, "WordPress", "ours", Some("theirs")).unwrap();
+        let keys: Value = serde_json::from_slice(&read(&path).unwrap()).unwrap();
+        assert_eq!(keys["WordPress"], "ours");
+    }
+
+    #[test]
+    fn test_keys_are_parsed_again_once_they_change() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(KEYS_FILE_NAME);
+        write(&path, r#"{ "WordPress": "old" }"#).unwrap();
+        assert_eq!(read_keys_at(&path).unwrap()["WordPress"], "old");
+
+        // Like switching the secrets repository's branch
+        write(&path, r#"{ "WordPress": "newer" }"#).unwrap();
+        assert_eq!(read_keys_at(&path).unwrap()["WordPress"], "newer");
+
+        save_key_in_file(&path, "WordPress", "saved", Some("newer")).unwrap();
+        assert_eq!(read_keys_at(&path).unwrap()["WordPress"], "saved");
+
+        // A rewrite of the same length, with the same modification time
+        write(&path, r#"{ "WordPress": "first" }"#).unwrap();
+        let modified = std::fs::metadata(&path).unwrap().modified().unwrap();
+        assert_eq!(read_keys_at(&path).unwrap()["WordPress"], "first");
+        write(&path, r#"{ "WordPress": "again" }"#).unwrap();
+        File::options().write(true).open(&path).unwrap().set_modified(modified).unwrap();
+        assert_eq!(read_keys_at(&path).unwrap()["WordPress"], "again");
+
+        write(&path, "not json").unwrap();
+        assert!(matches!(
+            read_keys_at(&path),
+            Err(ConfigureError::KeysFileIsNotValidJSON)
+        ));
+    }
+
+    #[test]
+    fn test_keys_are_copied_to_a_new_name_without_overwriting_another() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(KEYS_FILE_NAME);
+        write(&path, r#"{ "OldApp": "a", "Taken": "b" }"#).unwrap();
+        let metadata_path = dir.path().join(KEY_METADATA_FILE_NAME);
+        write(&metadata_path, r#"{ "OldApp": { "created_at": "2024-10-14T12:00:00+00:00" } }"#)
+            .unwrap();
+
+        assert!(copy_key_in_file(&path, "OldApp", "NewApp").unwrap());
+        let keys: Value = serde_json::from_slice(&read(&path).unwrap()).unwrap();
+        assert_eq!(keys["OldApp"], "a");
+        assert_eq!(keys["NewApp"], "a");
+        let metadata = read_key_metadata(&metadata_path).unwrap();
+        assert_eq!(metadata["NewApp"], metadata["OldApp"]);
+
+        // Copying again is harmless, but another project's key is never replaced
+        assert!(copy_key_in_file(&path, "OldApp", "NewApp").unwrap());
+        assert!(matches!(
+            copy_key_in_file(&path, "OldApp", "Taken"),
+            Err(ConfigureError::KeyWouldOverwrite(name)) if name == "Taken"
+        ));
+        assert!(!copy_key_in_file(&path, "Missing", "Elsewhere").unwrap());
+    }
+
+    #[test]
+    fn test_only_keys_no_active_project_uses_are_pruned() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(KEYS_FILE_NAME);
+        write(
+            &path,
+            r#"{ "wordpress_ios": "a", "Simplenote": "b", "Retired": "c", "Gone": "d" }"#,
+        )
+        .unwrap();
+
+        let active = vec!["WordPress-iOS".to_string(), "Simplenote".to_string()];
+        let keys: serde_json::Map<String, Value> =
+            serde_json::from_slice(&read(&path).unwrap()).unwrap();
+        let orphans = find_orphaned_keys(&keys, &active);
+        assert_eq!(orphans, vec!["Retired", "Gone"]);
+
+        // Someone starts using one of the orphans before they're removed
+        let mut active = active;
+        active.push("Gone".to_string());
+        assert_eq!(
+            remove_orphaned_keys_in_file(&path, &orphans, &active).unwrap(),
+            vec!["Retired"]
+        );
+
+        let keys: serde_json::Map<String, Value> =
+            serde_json::from_slice(&read(&path).unwrap()).unwrap();
+        let remaining: Vec<&String> = keys.keys().collect();
+        assert_eq!(remaining, vec!["Gone", "Simplenote", "wordpress_ios"]);
+    }
+
+    #[test]
+    fn test_keys_file_is_backed_up_sorted_and_survives_a_failed_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(KEYS_FILE_NAME);
+        let original = r#"{ "WordPress": "a", "Simplenote": "b" }"#;
+        write(&path, original).unwrap();
+
+        save_key_in_file(&path, "Jetpack", "c", None).unwrap();
+        let written = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(
+            written,
+            "{\n  \"Jetpack\": \"c\",\n  \"Simplenote\": \"b\",\n  \"WordPress\": \"a\"\n}\n"
+        );
+        let backup_path = dir.path().join(KEYS_BACKUP_FILE_NAME);
+        assert_eq!(std::fs::read_to_string(&backup_path).unwrap(), original);
+
+        // Saving the same key again changes nothing
+        std::fs::remove_file(&backup_path).unwrap();
+        save_key_in_file(&path, "Jetpack", "c", Some("c")).unwrap();
+        assert!(!backup_path.exists());
+
+        // The write fails part way, since something's in the way of the temporary file
+        create_dir_all(temporary_path_for(&path).join("in-the-way")).unwrap();
+        assert!(save_key_in_file(&path, "Pocket Casts", "d", None).is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), written);
+        assert!(!dir.path().join("keys.json.lock").exists());
+    }
+
+    #[test]
+    fn test_keys_file_backup_stays_out_of_the_secrets_work_tree() {
+        let (dir, repo) = crate::fixtures::secrets_repo();
+        let path = dir.path().join(KEYS_FILE_NAME);
+
+        save_key_in_file(&path, "WordPress", "a", None).unwrap();
+
+        let backup = std::fs::read_to_string(repo.path().join(KEYS_BACKUP_FILE_NAME)).unwrap();
+        assert_eq!(backup, "{}");
+        assert!(!dir.path().join(KEYS_BACKUP_FILE_NAME).exists());
+
+        let statuses = repo.statuses(None).unwrap();
+        let changed: Vec<String> =
+            statuses.iter().filter_map(|entry| entry.path().map(String::from)).collect();
+        assert_eq!(changed, vec![KEYS_FILE_NAME]);
+    }
+
+    #[test]
+    fn test_parse_configuration_reports_invalid_json_location() {
+        let contents = "{\n  \"project_name\": \"app\"\n  \"branch\": \"main\"\n}";
+
+        match parse_configuration(".configure", contents, false) {
+            Err(ConfigurationParseError::InvalidJson {
+                line,
+                column,
+                snippet,
+                ..
+            }) => {
+                assert_eq!((line, column), (3, 3));
+                assert_eq!(
+                    snippet,
+                    "1 | {\n2 |   \"project_name\": \"app\"\n3 |   \"branch\": \"main\"\n  |   ^"
+                );
+            }
+            result => panic!("Unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_parse_configuration_reports_which_file_entry_is_wrong() {
+        let contents = r#"{
+            "project_name": "app",
+            "branch": "main",
+            "pinned_hash": "abc",
+            "files_to_copy": [
+                { "file": "a", "destination": "a" },
+                { "file": "b" }
+            ]
+        }"#;
+
+        match parse_configuration(".configure", contents, false) {
+            Err(ConfigurationParseError::WrongShape { field, message, .. }) => {
+                assert_eq!(field, "files_to_copy[1]");
+                assert_eq!(message, "missing field `destination`");
+            }
+            result => panic!("Unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_parse_configuration_reports_missing_top_level_field() {
+        let contents = r#"{ "project_name": "app", "pinned_hash": "abc", "files_to_copy": [] }"#;
+
+        match parse_configuration(".configure", contents, false) {
+            Err(ConfigurationParseError::WrongShape { field, .. }) => {
+                assert_eq!(field, "`branch` (it's missing)");
+            }
+            result => panic!("Unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_parse_configuration_ignores_unknown_fields_by_default() {
+        let contents = r#"{
+            "project_name": "app",
+            "branch": "main",
+            "pinned_hash": "abc",
+            "file_to_copy": [],
+            "files_to_copy": [{ "file": "a", "destination": "a", "destinaton": "b" }]
+        }"#;
+
+        let configuration = parse_configuration(".configure", contents, false).unwrap();
+        assert_eq!(configuration.files_to_copy[0].destination, "a");
+    }
+
+    #[test]
+    fn test_file_description_is_optional_and_round_trips() {
+        let contents = r#"{ "project_name": "app", "branch": "main", "pinned_hash": "abc",
+            "files_to_copy": [{ "file": "a", "destination": "a" }] }"#;
+        let mut configuration = parse_configuration(".configure", contents, true).unwrap();
+        assert_eq!(configuration.files_to_copy[0].description, None);
+        assert!(!serialize_configuration(&configuration).contains("description"));
+
+        configuration.files_to_copy[0].description = Some("Sentry DSN".to_string());
+        let serialized = serialize_configuration(&configuration);
+        assert_eq!(
+            parse_configuration(".configure", &serialized, true).unwrap(),
+            configuration
+        );
+    }
+
+    #[test]
+    fn test_parse_configuration_can_reject_unknown_fields() {
+        let contents = r#"{
+            "project_name": "app",
+            "branch": "main",
+            "pinned_hash": "abc",
+            "file_to_copy": []
+        }"#;
+
+        match parse_configuration(".configure", contents, true) {
+            Err(ConfigurationParseError::UnknownField {
+                field,
+                line,
+                column,
+                ..
+            }) => {
+                assert_eq!(field, "file_to_copy");
+                assert_eq!((line, column), (5, 13));
+            }
+            result => panic!("Unexpected result: {:?}", result),
+        }
+
+        let contents = r#"{
+            "project_name": "app",
+            "branch": "main",
+            "pinned_hash": "abc",
+            "sanity_checks": { "fail_on_findings": false },
+            "files_to_copy": [{ "file": "a", "destination": "a", "destinaton": "b" }]
+        }"#;
+
+        match parse_configuration(".configure", contents, true) {
+            Err(ConfigurationParseError::UnknownField { field, .. }) => {
+                assert_eq!(field, "files_to_copy[0].destinaton");
+            }
+            result => panic!("Unexpected result: {:?}", result),
+        }
+
+        let contents = r#"{ "project_name": "app", "branch": "main", "pinned_hash": "abc",
+            "files_to_copy": [{ "file": "a", "destination": "a", "optional": true }] }"#;
+        assert!(parse_configuration(".configure", contents, true).is_ok());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_set_read_only_toggles_owner_write_permission() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("secrets.json");
+        write(&path, "{}").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o664)).unwrap();
+
+        set_read_only(&path, true).unwrap();
+        assert!(is_read_only(&path));
+        assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o444);
+
+        set_read_only(&path, false).unwrap();
+        assert!(!is_read_only(&path));
+        assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o644);
+    }
+
+    #[test]
+    fn test_failed_chmod_is_only_a_warning_when_mode_errors_are_ignored() {
+        let path = Path::new("secrets.json");
+        let unsupported = |_: &Path| Err(Error::new(std::io::ErrorKind::PermissionDenied, "EPERM"));
+
+        let err = change_mode(path, false, unsupported).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+
+        assert!(change_mode(path, true, unsupported).is_ok());
+        assert!(change_mode(path, true, |_| Ok(())).is_ok());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_commit_file_overwrites_locked_destination_and_keeps_it_locked() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("secrets.json");
+        write(&path, "applied").unwrap();
+        set_read_only(&path, true).unwrap();
+
+        apply(
+            dir.path(),
+            Some(&hash_bytes(b"applied")),
+            ConflictResolution::KeepLocal,
+        );
+
+        assert_eq!(read(&path).unwrap(), b"incoming");
+        assert!(is_read_only(&path));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_commit_file_keeps_a_locked_destination_locked_when_writing_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("secrets.json");
+        write(&path, "applied").unwrap();
+        set_read_only(&path, true).unwrap();
+
+        let mut resolver = ConflictResolver::new(Some(ConflictResolution::KeepLocal));
+        let plan = plan_file(
+            dir.path(),
+            &file(),
+            &hash_bytes(b"incoming"),
+            Some(&hash_bytes(b"applied")),
+            OnConflict::default(),
+            &mut resolver,
+        )
+        .unwrap();
+
+        let disk_full = |_: &Path| Err(Error::other("disk full"));
+        assert!(commit_file(dir.path(), &plan, disk_full).is_err());
+        assert_eq!(read(&path).unwrap(), b"applied");
+        assert!(is_read_only(&path));
+    }
+
+    fn keys(names: &[&str]) -> serde_json::Map<String, serde_json::Value> {
+        names
+            .iter()
+            .map(|name| (name.to_string(), json!("key")))
+            .collect()
+    }
+
+    #[test]
+    fn test_project_key_exact_match() {
+        let keys = keys(&["WordPress-iOS", "wordpress-ios"]);
+
+        assert_eq!(
+            match_project_key(&keys, "WordPress-iOS"),
+            Some(ProjectKeyMatch::Exact)
+        );
+    }
+
+    #[test]
+    fn test_project_key_matches_ignoring_case_and_separators() {
+        let keys = keys(&["wordpress_ios", "simplenote"]);
+
+        for name in &["WordPress-iOS", "wordpress.ios", "WORDPRESS IOS", "WordPressiOS"] {
+            assert_eq!(
+                match_project_key(&keys, name),
+                Some(ProjectKeyMatch::Fuzzy("wordpress_ios".to_string())),
+                "{} should match",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn test_project_key_does_not_match_other_names() {
+        let keys = keys(&["wordpress-ios"]);
+
+        assert_eq!(match_project_key(&keys, "WordPress-Android"), None);
+        assert_eq!(match_project_key(&keys, "WordPress-iOS-2"), None);
+        assert_eq!(match_project_key(&keys, ""), None);
+    }
+
+    #[test]
+    fn test_unusual_project_names_match_exactly_or_by_sanitized_name() {
+        let keys = keys(&["apps/reader (beta)", "apps-writer-beta", "Café"]);
+
+        assert_eq!(
+            match_project_key(&keys, "apps/reader (beta)"),
+            Some(ProjectKeyMatch::Exact)
+        );
+        assert_eq!(
+            match_project_key(&keys, "apps/writer (beta)"),
+            Some(ProjectKeyMatch::Sanitized("apps-writer-beta".to_string()))
+        );
+        assert_eq!(
+            match_project_key(&keys, " apps-writer-beta "),
+            Some(ProjectKeyMatch::Sanitized("apps-writer-beta".to_string()))
+        );
+        assert_eq!(match_project_key(&keys, "Café"), Some(ProjectKeyMatch::Exact));
+        assert_eq!(match_project_key(&keys, " "), None);
+    }
+
+    #[test]
+    fn test_ambiguous_project_key_does_not_match() {
+        let keys = keys(&["wordpress-ios", "WordPress_iOS"]);
+
+        assert_eq!(match_project_key(&keys, "WordPress-iOS"), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_private_files_are_only_readable_by_the_owner_and_never_replaced() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("identity.json");
+
+        write_private_file(&path, "secret").unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        assert!(write_private_file(&path, "replacement").is_err());
+        assert_eq!(read(&path).unwrap(), b"secret");
+    }
+
+    #[test]
+    fn test_project_root_is_the_nearest_directory_with_the_marker() {
+        let dir = tempfile::tempdir().unwrap();
+        let nested = dir.path().join("app/src/main");
+        create_dir_all(&nested).unwrap();
+        write(dir.path().join(".project-root"), "").unwrap();
+
+        assert_eq!(
+            find_directory_containing(&nested, ".project-root"),
+            Some(dir.path().to_path_buf())
+        );
+
+        write(dir.path().join("app/.project-root"), "").unwrap();
+        assert_eq!(
+            find_directory_containing(&nested, ".project-root"),
+            Some(dir.path().join("app"))
+        );
+
+        assert_eq!(find_directory_containing(&nested, ".no-such-marker"), None);
+    }
+
+    #[test]
+    fn test_imported_files_must_stay_inside_the_secrets_repository() {
+        let key = crate::encryption::generate_key().to_base64();
+        let sandbox = crate::fixtures::Sandbox::new(&json!({ "App": key }).to_string());
+        std::fs::write(sandbox.project.path().join("a.json"), "{}").unwrap();
+        let configuration = ConfigurationFile {
+            project_name: "App".to_string(),
+            ..Default::default()
+        };
+        let import = |source: &str| {
+            let file = crate::File {
+                source: source.to_string(),
+                destination: "a.json".to_string(),
+                ..Default::default()
+            };
+            import_file_into_secrets(&configuration, &file, false)
+        };
+
+        for source in ["../a.json", "App/../../a.json", "/tmp/a.json", ""] {
+            assert!(
+                matches!(import(source), Err(ConfigureError::SourceOutsideSecretsRepo(_))),
+                "{:?}",
+                source
+            );
+        }
+        assert!(!sandbox.home.path().join("a.json").exists());
+        assert!(!sandbox.project.path().join("a.json.enc").exists());
+
+        import("App/a.json").unwrap();
+        assert!(sandbox.secrets_path("App/a.json").exists());
+        assert!(sandbox.project.path().join("a.json.enc").exists());
+    }
 }
@@ -0,0 +1,485 @@
+use crate::configure::{project_name_problem, sanitized_key_name};
+use crate::fs::hash_file;
+use crate::state::ApplyState;
+use crate::{ConfigurationFile, ConfigureError, File};
+use std::collections::{BTreeMap, HashSet};
+use std::path::Path;
+
+/// The group for entries that don't have an `owner`
+pub const UNOWNED: &str = "Unowned";
+
+/// The group for problems with `.configure` itself, rather than one of its entries
+pub const PROJECT: &str = "Project";
+
+/// A problem with a project's configuration
+#[derive(Debug, Eq, PartialEq)]
+pub struct ValidationFinding {
+    /// The owner of the entry the problem is with, or `PROJECT` / `UNOWNED`
+    pub owner: String,
+
+    /// What sort of problem it is, like "Missing sources", for grouping long lists of findings
+    pub kind: &'static str,
+
+    pub message: String,
+}
+
+/// What's on disk for one entry in `files_to_copy`, compared with what was last applied
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum FileStatus {
+    /// The encrypted file isn't in the project, so there's nothing to apply
+    NotEncrypted,
+
+    /// The encrypted file is there, but it hasn't been decrypted
+    NotApplied,
+
+    /// The decrypted file is what was last applied
+    Applied,
+
+    /// The decrypted file was edited since it was last applied
+    Modified,
+
+    /// The decrypted file is there, but there's no record of applying it
+    Unknown,
+
+    /// The decrypted file is what was last applied, but the encrypted file has changed since
+    Outdated,
+
+    /// The entry isn't applied on this operating system
+    OtherPlatform,
+}
+
+impl std::fmt::Display for FileStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let description = match self {
+            FileStatus::NotEncrypted => "encrypted file missing",
+            FileStatus::NotApplied => "not applied",
+            FileStatus::Applied => "applied",
+            FileStatus::Modified => "edited since last apply",
+            FileStatus::Unknown => "present, but not applied by configure",
+            FileStatus::Outdated => "applied, but the encrypted file has changed since",
+            FileStatus::OtherPlatform => "not for this platform",
+        };
+
+        write!(f, "{}", description)
+    }
+}
+
+/// How a decrypted file compares with what configure last applied to it
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Drift {
+    /// The file is what was last applied
+    InSync,
+
+    /// The file was edited since it was last applied
+    LocallyEdited,
+
+    /// The file was applied, but it's gone
+    Missing,
+
+    /// There's no record of applying the file, so there's nothing to compare it with
+    NeverApplied,
+}
+
+impl Drift {
+    /// Whether the file was changed since it was applied, so the next apply would overwrite
+    /// something
+    pub fn has_drifted(&self) -> bool {
+        matches!(self, Drift::LocallyEdited | Drift::Missing)
+    }
+}
+
+impl std::fmt::Display for Drift {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let description = match self {
+            Drift::InSync => "in sync",
+            Drift::LocallyEdited => "locally edited",
+            Drift::Missing => "missing",
+            Drift::NeverApplied => "never applied",
+        };
+
+        write!(f, "{}", description)
+    }
+}
+
+/// Compares `file`'s decrypted destination with the checksum recorded when it was last applied
+pub fn file_drift(
+    project_root: &Path,
+    state: &ApplyState,
+    file: &File,
+) -> Result<Drift, ConfigureError> {
+    let applied = match state.digest_for(&file.state_key()) {
+        Some(applied) => applied,
+        None => return Ok(Drift::NeverApplied),
+    };
+
+    let decrypted = project_root.join(file.get_decrypted_destination()?);
+    if !decrypted.exists() {
+        return Ok(Drift::Missing);
+    }
+
+    if state.is_unmodified(&file.state_key(), &decrypted) {
+        return Ok(Drift::InSync);
+    }
+
+    Ok(match hash_file(&decrypted) {
+        Ok(current) if current == applied => Drift::InSync,
+        _ => Drift::LocallyEdited,
+    })
+}
+
+/// The group an entry belongs to in `list`, `status`, and `validate` output
+pub fn owner_of(file: &File) -> &str {
+    file.owner.as_deref().unwrap_or(UNOWNED)
+}
+
+/// Groups items by owner, keeping their order within each group
+pub fn group_by_owner<'a, T>(
+    items: impl IntoIterator<Item = (&'a str, T)>,
+) -> BTreeMap<String, Vec<T>> {
+    let mut groups: BTreeMap<String, Vec<T>> = BTreeMap::new();
+
+    for (owner, item) in items {
+        groups.entry(owner.to_string()).or_default().push(item);
+    }
+
+    groups
+}
+
+pub fn file_status(
+    project_root: &Path,
+    state: &ApplyState,
+    file: &File,
+) -> Result<FileStatus, ConfigureError> {
+    if !file.applies_to_platform(std::env::consts::OS) {
+        return Ok(FileStatus::OtherPlatform);
+    }
+
+    let decrypted = project_root.join(file.get_decrypted_destination()?);
+
+    if !decrypted.exists() {
+        if project_root.join(file.get_encrypted_destination()).exists() {
+            return Ok(FileStatus::NotApplied);
+        }
+
+        return Ok(FileStatus::NotEncrypted);
+    }
+
+    // Hashing the destination can be skipped if its size and modification time say it's unchanged
+    let applied = state.is_unmodified(&file.state_key(), &decrypted)
+        || match (state.digest_for(&file.state_key()), hash_file(&decrypted)) {
+            (Some(applied), Ok(current)) => applied == current,
+            (Some(_), _) => false,
+            (None, _) => return Ok(FileStatus::Unknown),
+        };
+
+    if !applied {
+        return Ok(FileStatus::Modified);
+    }
+
+    let encrypted = project_root.join(file.get_encrypted_destination());
+    Ok(match (state.encrypted_digest_for(&file.state_key()), hash_file(&encrypted)) {
+        (Some(applied_from), Ok(current)) if applied_from != current => FileStatus::Outdated,
+        _ => FileStatus::Applied,
+    })
+}
+
+/// Finds problems with a configuration.
+///
+/// Sources are only checked when `source_exists` is provided – it says whether a path is in the
+/// secrets repository. In `strict` mode, every entry must have an `owner`.
+pub fn validate(
+    configuration: &ConfigurationFile,
+    project_root: &Path,
+    source_exists: Option<&dyn Fn(&str) -> bool>,
+    strict: bool,
+) -> Vec<ValidationFinding> {
+    let mut findings = Vec::new();
+    let project_finding = |kind: &'static str, message: &str| ValidationFinding {
+        owner: PROJECT.to_string(),
+        kind,
+        message: message.to_string(),
+    };
+
+    for field in configuration.completeness().missing {
+        findings.push(project_finding("Missing settings", &format!("`{}` is empty", field)));
+    }
+
+    let project_name = &configuration.project_name;
+    if !project_name.trim().is_empty() {
+        if let Some(problem) = project_name_problem(project_name) {
+            findings.push(project_finding(
+                "Unsafe project name",
+                &format!(
+                    "`project_name` {:?} {} – {:?} would be safe",
+                    project_name,
+                    problem,
+                    sanitized_key_name(project_name)
+                ),
+            ));
+        }
+    }
+
+    if let Some(summary_file) = &configuration.summary_file {
+        if configuration
+            .files_to_copy
+            .iter()
+            .any(|file| &file.destination == summary_file)
+        {
+            findings.push(project_finding(
+                "Summary file clashes",
+                "`summary_file` is also the destination of a secret",
+            ));
+        }
+    }
+
+    let mut destinations = HashSet::new();
+
+    for file in &configuration.files_to_copy {
+        // The description helps make sense of cryptic destinations
+        let name = match &file.description {
+            Some(description) => format!("{} ({})", file.destination, description),
+            None => file.destination.clone(),
+        };
+        let mut finding = |kind: &'static str, message: String| {
+            findings.push(ValidationFinding {
+                owner: owner_of(file).to_string(),
+                kind,
+                message: format!("{}: {}", name, message),
+            })
+        };
+
+        if !destinations.insert(&file.destination) {
+            finding("Duplicate entries", "is listed more than once".to_string());
+        }
+
+        if let Some(problem) = file.destination_problem() {
+            finding("Unsafe destinations", problem);
+        }
+
+        // Optional secrets are allowed to be missing
+        if !file.optional && !project_root.join(file.get_encrypted_destination()).exists() {
+            let encrypted = file.get_encrypted_destination();
+            finding("Missing encrypted files", format!("{} is missing", encrypted));
+        }
+
+        // Every entry is encrypted, whichever platforms it's for, but one that isn't for any platform
+        // is never applied, so its source doesn't matter
+        if !file.applies_to_any_platform() {
+            finding(
+                "Unknown platforms",
+                format!(
+                    "isn't for any platform configure knows (`platforms` is {})",
+                    file.platforms.join(", ")
+                ),
+            );
+        } else if let Some(source_exists) = source_exists {
+            let source = configuration.source_path(file);
+            if !file.optional && !source_exists(&source) {
+                let message = format!("{} isn't in the secrets repository", source);
+                finding("Missing sources", message);
+            }
+        }
+
+        if strict && file.owner.is_none() {
+            finding("Entries without an owner", "has no `owner`".to_string());
+        }
+    }
+
+    findings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::FileOrigin;
+
+    fn file(destination: &str, owner: Option<&str>) -> File {
+        File {
+            source: destination.to_string(),
+            destination: destination.to_string(),
+            owner: owner.map(String::from),
+            ..Default::default()
+        }
+    }
+
+    fn configuration(files: Vec<File>) -> ConfigurationFile {
+        ConfigurationFile {
+            project_name: "WordPress".to_string(),
+            branch: "trunk".to_string(),
+            pinned_hash: "abc123".to_string(),
+            files_to_copy: files,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_findings_are_attributed_to_owners() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.json.enc"), "").unwrap();
+
+        let configuration = configuration(vec![
+            file("a.json", Some("@payments")),
+            file("b.json", Some("@payments")),
+            file("c.json", None),
+        ]);
+
+        let findings = validate(&configuration, dir.path(), None, false);
+        let groups = group_by_owner(findings.iter().map(|f| (f.owner.as_str(), &f.message)));
+
+        assert_eq!(groups["@payments"], vec!["b.json: b.json.enc is missing"]);
+        assert_eq!(groups[UNOWNED], vec!["c.json: c.json.enc is missing"]);
+    }
+
+    #[test]
+    fn test_strict_validation_requires_owners() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.json.enc"), "").unwrap();
+        std::fs::write(dir.path().join("b.json.enc"), "").unwrap();
+
+        let configuration =
+            configuration(vec![file("a.json", Some("@mobile")), file("b.json", None)]);
+
+        assert!(validate(&configuration, dir.path(), None, false).is_empty());
+
+        let findings = validate(&configuration, dir.path(), None, true);
+        assert_eq!(
+            findings,
+            vec![ValidationFinding {
+                owner: UNOWNED.to_string(),
+                kind: "Entries without an owner",
+                message: "b.json: has no `owner`".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_summary_file_cannot_be_a_destination() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.json.enc"), "").unwrap();
+
+        let mut configuration = configuration(vec![file("a.json", None)]);
+        configuration.summary_file = Some("secrets-summary.json".to_string());
+        assert!(validate(&configuration, dir.path(), None, false).is_empty());
+
+        configuration.summary_file = Some("a.json".to_string());
+        assert_eq!(
+            validate(&configuration, dir.path(), None, false),
+            vec![ValidationFinding {
+                owner: PROJECT.to_string(),
+                kind: "Summary file clashes",
+                message: "`summary_file` is also the destination of a secret".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_file_status_compares_against_last_apply() {
+        let dir = tempfile::tempdir().unwrap();
+        let entry = file("a.json", None);
+        let mut state = ApplyState::default();
+
+        assert_eq!(
+            file_status(dir.path(), &state, &entry).unwrap(),
+            FileStatus::NotEncrypted
+        );
+
+        std::fs::write(dir.path().join("a.json.enc"), "").unwrap();
+        assert_eq!(
+            file_status(dir.path(), &state, &entry).unwrap(),
+            FileStatus::NotApplied
+        );
+
+        std::fs::write(dir.path().join("a.json"), "{}").unwrap();
+        assert_eq!(file_status(dir.path(), &state, &entry).unwrap(), FileStatus::Unknown);
+
+        let digest = hash_file(&dir.path().join("a.json")).unwrap();
+        state.record("a.json", digest, 2);
+        assert_eq!(file_status(dir.path(), &state, &entry).unwrap(), FileStatus::Applied);
+
+        std::fs::write(dir.path().join("a.json"), "{ \"edited\": true }").unwrap();
+        assert_eq!(
+            file_status(dir.path(), &state, &entry).unwrap(),
+            FileStatus::Modified
+        );
+    }
+
+    #[test]
+    fn test_drift_is_measured_against_the_last_apply() {
+        let dir = tempfile::tempdir().unwrap();
+        let entry = file("a.json", None);
+        let mut state = ApplyState::default();
+
+        std::fs::write(dir.path().join("a.json"), "{}").unwrap();
+        assert_eq!(file_drift(dir.path(), &state, &entry).unwrap(), Drift::NeverApplied);
+
+        state.record("a.json", hash_file(&dir.path().join("a.json")).unwrap(), 2);
+        assert_eq!(file_drift(dir.path(), &state, &entry).unwrap(), Drift::InSync);
+
+        std::fs::write(dir.path().join("a.json"), "{ \"edited\": true }").unwrap();
+        assert_eq!(file_drift(dir.path(), &state, &entry).unwrap(), Drift::LocallyEdited);
+
+        std::fs::remove_file(dir.path().join("a.json")).unwrap();
+        assert_eq!(file_drift(dir.path(), &state, &entry).unwrap(), Drift::Missing);
+
+        let drifted: Vec<bool> = [Drift::InSync, Drift::LocallyEdited, Drift::Missing]
+            .iter()
+            .map(Drift::has_drifted)
+            .collect();
+        assert_eq!(drifted, vec![false, true, true]);
+    }
+
+    #[test]
+    fn test_file_status_notices_new_encrypted_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let entry = file("a.json", None);
+        let mut state = ApplyState::default();
+
+        std::fs::write(dir.path().join("a.json.enc"), "encrypted").unwrap();
+        std::fs::write(dir.path().join("a.json"), "{}").unwrap();
+        state.record("a.json", hash_file(&dir.path().join("a.json")).unwrap(), 2);
+        state.record_origin(
+            "a.json",
+            FileOrigin {
+                encrypted_digest: hash_file(&dir.path().join("a.json.enc")).ok(),
+                ..Default::default()
+            },
+        );
+        assert_eq!(file_status(dir.path(), &state, &entry).unwrap(), FileStatus::Applied);
+
+        std::fs::write(dir.path().join("a.json.enc"), "updated").unwrap();
+        assert_eq!(
+            file_status(dir.path(), &state, &entry).unwrap(),
+            FileStatus::Outdated
+        );
+    }
+
+    #[test]
+    fn test_entries_for_other_platforms() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.json.enc"), "").unwrap();
+        std::fs::write(dir.path().join("b.json.enc"), "").unwrap();
+
+        let mut elsewhere = file("a.json", None);
+        elsewhere.platforms = vec!["windows".to_string(), "linux".to_string()];
+        elsewhere.platforms.retain(|os| os != std::env::consts::OS);
+        assert_eq!(
+            file_status(dir.path(), &ApplyState::default(), &elsewhere).unwrap(),
+            FileStatus::OtherPlatform
+        );
+
+        let mut nowhere = file("b.json", None);
+        nowhere.platforms = vec!["mac".to_string()];
+
+        let configuration = configuration(vec![elsewhere, nowhere]);
+        let no_sources = |_: &str| false;
+        let findings = validate(&configuration, dir.path(), Some(&no_sources), false);
+        let messages: Vec<&str> = findings.iter().map(|f| f.message.as_str()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "a.json: a.json isn't in the secrets repository",
+                "b.json: isn't for any platform configure knows (`platforms` is mac)",
+            ]
+        );
+    }
+}
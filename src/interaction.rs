@@ -0,0 +1,386 @@
+//! The questions configure asks, described by what they mean rather than how they're worded, so
+//! programs embedding configure can ask them their own way – in native dialogs, say – instead of
+//! parsing prompts meant for a terminal.
+//!
+//! Every question goes through `ask`. Unless `set_interaction_handler` has handed them to another
+//! frontend, they're asked on the console, and the wording below is the only place they're worded.
+//! Answer files and recordings keep identifying them by the same ids they always have.
+
+use crate::ui::{choose, confirm, display_hash, multi_select, prompt, select};
+use crate::ConfigureError;
+use std::sync::Mutex;
+
+/// Something configure needs to know from the person running it. The doc comment on each one
+/// says which kind of `Response` it takes – any other kind fails the command with
+/// `UnexpectedResponse`, as does a `Choice` that isn't one of the options.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Interaction {
+    /// The project's name, as `Text`
+    ProjectName,
+
+    /// Which secrets branch to use, as the `Choice` of an index into `options` – or, if there's a
+    /// branch to `keep` (the one `.configure` already uses), `Cancelled` to leave it as it is
+    ChooseBranch {
+        options: Vec<String>,
+        current: String,
+        keep: Option<String>,
+    },
+
+    /// Whether to carry on though the local secrets repository has `distance` commits the server
+    /// doesn't, `Confirmed` or not
+    ConfirmContinueAhead { distance: u32 },
+
+    /// Whether to carry on though the server has `distance` commits the local secrets repository
+    /// doesn't, `Confirmed` or not
+    ConfirmContinueBehind { distance: u32 },
+
+    /// Whether to carry on though the local secrets repository has `ahead` commits the server
+    /// doesn't, and the server has `behind` commits it doesn't, `Confirmed` or not
+    ConfirmContinueDiverged { ahead: u32, behind: u32 },
+
+    /// Whether to pin `latest` (whose subject is `subject`, if it could be read), the project being
+    /// `distance` commits behind it, `Confirmed` or not
+    ConfirmUseLatest {
+        distance: u32,
+        latest: String,
+        subject: Option<String>,
+    },
+
+    /// Which secrets on `branch` to pin after switching to it, as a `Choice`: `0` for `latest`,
+    /// or `1` for a specific commit, which is then asked for with `PinHash`
+    ChoosePin { branch: String, latest: String },
+
+    /// The hash of the commit to pin, as `Text`
+    PinHash,
+
+    /// Whether to add a file to `.configure` (another one if `has_files`), `Confirmed` or not
+    AddFile { has_files: bool },
+
+    /// The source of the file being added, relative to the secrets root or starting with `./` for
+    /// the project's directory, as `Text`
+    FileSource,
+
+    /// The destination of the file being added, relative to the project root, as `Text`
+    FileDestination,
+
+    /// Which of a template's `files` to use, as `Choices` of indices into them
+    ChooseTemplateFiles { files: Vec<String> },
+
+    /// What to do about `destination`, which has been edited since secrets were last applied, as
+    /// a `Choice`: `0` to keep it, `1` to use the incoming file, or `2` to write the incoming file
+    /// beside it
+    ResolveConflict { destination: String },
+
+    /// Whether the last conflict's resolution applies to the rest of them, `Confirmed` or not
+    ApplyToAllConflicts,
+
+    /// What to do about `destination`, which configure has no record of writing, as a `Choice`:
+    /// `0` to back it up and use the incoming file, or `1` to keep it
+    ResolveUnrecordedFile { destination: String },
+
+    /// Whether to add configure's attributes to the project's `.gitattributes`, `Confirmed` or not
+    AddGitattributes,
+
+    /// Whether to carry on though the project's repository is busy because of `problem`,
+    /// `Confirmed` or not
+    ContinueBusyProject { problem: String },
+
+    /// How to fix keys.json having no entry for `project_name`, only one for `key_name`, as a
+    /// `Choice`: `0` to rename the project, `1` to rename the key, or `2` to leave them
+    FixProjectKeyMismatch {
+        project_name: String,
+        key_name: String,
+    },
+
+    /// Whether to replace the key keys.json already has for `project`, `Confirmed` or not
+    ReplaceKey { project: String },
+
+    /// Whether to remove the key for the old name `key_name` from keys.json now that it's been
+    /// copied to the project's new name, `Confirmed` or not
+    RemoveOldKey { key_name: String },
+}
+
+/// The answer to an `Interaction`
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Response {
+    Text(String),
+    Confirmed(bool),
+    Choice(usize),
+    Choices(Vec<usize>),
+
+    /// The question was dismissed, for the ones that say they can be
+    Cancelled,
+}
+
+impl Response {
+    pub fn text(self) -> Result<String, ConfigureError> {
+        match self {
+            Response::Text(text) => Ok(text),
+            other => Err(unexpected("text", other)),
+        }
+    }
+
+    pub fn confirmed(self) -> Result<bool, ConfigureError> {
+        match self {
+            Response::Confirmed(confirmed) => Ok(confirmed),
+            other => Err(unexpected("a yes/no answer", other)),
+        }
+    }
+
+    pub fn choice(self) -> Result<usize, ConfigureError> {
+        match self {
+            Response::Choice(choice) => Ok(choice),
+            other => Err(unexpected("a choice", other)),
+        }
+    }
+
+    /// The choice of one of `count` options
+    pub fn choice_of(self, count: usize) -> Result<usize, ConfigureError> {
+        match self.choice()? {
+            choice if choice < count => Ok(choice),
+            choice => Err(unexpected(
+                &format!("one of {} options", count),
+                Response::Choice(choice),
+            )),
+        }
+    }
+
+    pub fn choices(self) -> Result<Vec<usize>, ConfigureError> {
+        match self {
+            Response::Choices(choices) => Ok(choices),
+            other => Err(unexpected("a list of choices", other)),
+        }
+    }
+}
+
+/// The error for a handler answering with the wrong kind of `Response`
+fn unexpected(expected: &str, response: Response) -> ConfigureError {
+    ConfigureError::UnexpectedResponse {
+        expected: expected.to_string(),
+        response: format!("{:?}", response),
+    }
+}
+
+/// Answers each question instead of it being asked on the console
+pub type InteractionHandler = Box<dyn FnMut(&Interaction) -> Response + Send>;
+
+/// Where questions go instead of the console, if anywhere
+static INTERACTION_HANDLER: Mutex<Option<InteractionHandler>> = Mutex::new(None);
+
+///
+/// Send questions to `handler` instead of asking them on the console, for programs using configure
+/// as a library. While there's a handler, configure asks its questions even without a terminal.
+/// The handler mustn't ask questions through this module itself.
+pub fn set_interaction_handler(handler: Option<InteractionHandler>) {
+    *INTERACTION_HANDLER.lock().unwrap() = handler;
+}
+
+/// Whether another frontend is answering questions
+pub fn has_interaction_handler() -> bool {
+    INTERACTION_HANDLER.lock().unwrap().is_some()
+}
+
+///
+/// Ask the person running configure about `interaction`
+pub fn ask(interaction: Interaction) -> Result<Response, ConfigureError> {
+    ask_with(&INTERACTION_HANDLER, interaction)
+}
+
+fn ask_with(
+    handler: &Mutex<Option<InteractionHandler>>,
+    interaction: Interaction,
+) -> Result<Response, ConfigureError> {
+    if let Some(handler) = handler.lock().unwrap().as_mut() {
+        return Ok(handler(&interaction));
+    }
+
+    ask_on_console(&interaction)
+}
+
+fn ask_on_console(interaction: &Interaction) -> Result<Response, ConfigureError> {
+    let response = match interaction {
+        Interaction::ProjectName => {
+            Response::Text(prompt("project_name", "What is the name of your project?")?)
+        }
+        Interaction::ChooseBranch {
+            options,
+            current,
+            keep,
+        } => {
+            let keep = keep.as_ref().map(|_| "Keep the current branch");
+            let branch = select("branch", options.clone(), current, keep)?;
+            match branch {
+                Some(branch) => {
+                    Response::Choice(options.iter().position(|option| *option == branch).unwrap())
+                }
+                None => Response::Cancelled,
+            }
+        }
+        Interaction::ConfirmContinueAhead { .. } => {
+            Response::Confirmed(confirm("continue_ahead", "Would you like to continue?")?)
+        }
+        Interaction::ConfirmContinueBehind { .. } => {
+            Response::Confirmed(confirm("continue_behind", "Would you like to continue?")?)
+        }
+        Interaction::ConfirmContinueDiverged { .. } => {
+            Response::Confirmed(confirm("continue_diverged", "Would you like to continue?")?)
+        }
+        Interaction::ConfirmUseLatest {
+            distance,
+            latest,
+            subject,
+        } => {
+            let latest = match subject {
+                Some(subject) => format!("{} {:?}", display_hash(latest), subject),
+                None => display_hash(latest).to_string(),
+            };
+            Response::Confirmed(confirm(
+                "use_latest_secrets",
+                &format!(
+                    "This project is {} commit(s) behind the latest secrets. Would you like to update to {}?",
+                    distance, latest
+                ),
+            )?)
+        }
+        Interaction::ChoosePin { branch, latest } => {
+            let latest_option = format!("The latest secrets ({})", display_hash(latest));
+            Response::Choice(choose(
+                "pin",
+                &format!("Which secrets on {} would you like to use?", branch),
+                &[&latest_option, "A specific commit"],
+            )?)
+        }
+        Interaction::PinHash => Response::Text(prompt("pin_hash", "Which commit? Enter its hash")?),
+        Interaction::AddFile { has_files } => {
+            let message = match has_files {
+                true => "Would you like to add additional files?",
+                false => "Would you like to add files?",
+            };
+            Response::Confirmed(confirm("add_more_files", message)?)
+        }
+        Interaction::FileSource => Response::Text(prompt(
+            "source",
+            "Enter the source file path (relative to the secrets root, or starting with ./ for the project's directory):",
+        )?),
+        Interaction::FileDestination => Response::Text(prompt(
+            "destination",
+            "Enter the destination file path (relative to the project root):",
+        )?),
+        Interaction::ChooseTemplateFiles { files } => Response::Choices(multi_select(
+            "template_files",
+            "Which of the template's files would you like to use?",
+            files,
+        )?),
+        Interaction::ResolveConflict { .. } => Response::Choice(choose(
+            "conflict_resolution",
+            "What would you like to do?",
+            &[
+                "Keep my local file",
+                "Use the incoming file (and back up my local file)",
+                "Write the incoming file beside mine as `.new` so I can merge them",
+            ],
+        )?),
+        Interaction::ApplyToAllConflicts => Response::Confirmed(confirm(
+            "apply_to_all_conflicts",
+            "Apply this choice to all remaining conflicts?",
+        )?),
+        Interaction::ResolveUnrecordedFile { .. } => Response::Choice(choose(
+            "existing_file",
+            "What would you like to do?",
+            &["Use the incoming file (and back up mine)", "Keep my file"],
+        )?),
+        Interaction::AddGitattributes => Response::Confirmed(confirm(
+            "add_gitattributes",
+            "Add it to the project's .gitattributes now?",
+        )?),
+        Interaction::ContinueBusyProject { .. } => {
+            Response::Confirmed(confirm("continue_busy_project", "Would you like to continue?")?)
+        }
+        Interaction::FixProjectKeyMismatch {
+            project_name,
+            key_name,
+        } => {
+            let rename_project = format!("Change `project_name` in .configure to {:?}", key_name);
+            let rename_key = format!("Rename the entry in keys.json to {:?}", project_name);
+            Response::Choice(choose(
+                "fix_project_key_mismatch",
+                "How would you like to fix this?",
+                &[&rename_project, &rename_key, "Leave them as they are"],
+            )?)
+        }
+        Interaction::ReplaceKey { .. } => Response::Confirmed(confirm(
+            "replace_key",
+            "Replace it? Secrets encrypted with it can't be decrypted afterwards",
+        )?),
+        Interaction::RemoveOldKey { key_name } => Response::Confirmed(confirm(
+            "remove_old_key",
+            &format!(
+                "Remove the key for {:?} from keys.json now? Checkouts still using that name won't be able to decrypt their secrets",
+                key_name
+            ),
+        )?),
+    };
+
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_questions_go_to_the_interaction_handler() {
+        // A handler of its own, since setting the process's would make other tests interactive
+        static HANDLER: Mutex<Option<InteractionHandler>> = Mutex::new(None);
+        let asked = Arc::new(Mutex::new(Vec::new()));
+
+        let recorded = asked.clone();
+        *HANDLER.lock().unwrap() = Some(Box::new(move |interaction| {
+            recorded.lock().unwrap().push(interaction.clone());
+            match interaction {
+                Interaction::ChooseBranch { keep: Some(_), .. } => Response::Cancelled,
+                Interaction::ChooseBranch { options, .. } => Response::Choice(options.len() - 1),
+                _ => Response::Confirmed(true),
+            }
+        }));
+
+        let options = vec!["trunk".to_string(), "release".to_string()];
+        let branch = Interaction::ChooseBranch {
+            options: options.clone(),
+            current: "trunk".to_string(),
+            keep: None,
+        };
+        assert_eq!(ask_with(&HANDLER, branch.clone()).unwrap().choice().unwrap(), 1);
+        let keep = Interaction::ChooseBranch {
+            options: options.clone(),
+            current: "trunk".to_string(),
+            keep: Some("release".to_string()),
+        };
+        assert_eq!(ask_with(&HANDLER, keep.clone()).unwrap(), Response::Cancelled);
+        let behind = Interaction::ConfirmContinueBehind { distance: 3 };
+        assert!(ask_with(&HANDLER, behind).unwrap().confirmed().unwrap());
+
+        assert_eq!(
+            *asked.lock().unwrap(),
+            vec![branch, keep, Interaction::ConfirmContinueBehind { distance: 3 }]
+        );
+    }
+
+    #[test]
+    fn test_the_wrong_kind_of_response_is_an_error() {
+        assert!(matches!(
+            Response::Choice(1).confirmed(),
+            Err(ConfigureError::UnexpectedResponse { .. })
+        ));
+        assert!(matches!(
+            Response::Cancelled.text(),
+            Err(ConfigureError::UnexpectedResponse { .. })
+        ));
+        assert_eq!(Response::Choice(1).choice_of(2).unwrap(), 1);
+        assert!(matches!(
+            Response::Choice(2).choice_of(2),
+            Err(ConfigureError::UnexpectedResponse { .. })
+        ));
+    }
+}
@@ -0,0 +1,129 @@
+//! What configure knows about each project's key besides the key itself – when it was created,
+//! and which algorithm it's for. It's kept in `keys-metadata.json` beside `keys.json`, rather than
+//! in it, so tools that read `keys.json` as a map of project names to keys keep working. Keys
+//! created before this existed, or installed from elsewhere, have no entry: their age is unknown,
+//! and they use the default algorithm.
+
+use crate::fs::{temporary_path_for, FileLock};
+use crate::ConfigureError;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// The metadata file's name, in the root of the secrets repository
+pub const KEY_METADATA_FILE_NAME: &str = "keys-metadata.json";
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize, Eq, PartialEq)]
+pub struct KeyMetadata {
+    /// When the key was created, in RFC 3339 format
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<String>,
+
+    /// The name of the cipher the key is for, or `None` for the default algorithm
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub algorithm: Option<String>,
+}
+
+impl KeyMetadata {
+    pub fn created(at: DateTime<Utc>) -> KeyMetadata {
+        KeyMetadata {
+            created_at: Some(at.to_rfc3339()),
+            ..Default::default()
+        }
+    }
+
+    /// How many whole days old the key is at `now`, if that's known
+    pub fn age_days(&self, now: DateTime<Utc>) -> Option<i64> {
+        let created_at = DateTime::parse_from_rfc3339(self.created_at.as_deref()?).ok()?;
+        Some(now.signed_duration_since(created_at).num_days().max(0))
+    }
+}
+
+/// Reads the metadata at `path`. A missing file just means no key has any.
+pub fn read_key_metadata(path: &Path) -> Result<BTreeMap<String, KeyMetadata>, ConfigureError> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(err) => return Err(err.into()),
+    };
+
+    serde_json::from_str(&contents)
+        .map_err(|err| ConfigureError::KeyMetadataInvalid(path.to_path_buf(), err.to_string()))
+}
+
+/// Changes the metadata at `path` while holding its lock, replacing the file atomically
+pub fn update_key_metadata<F>(path: &Path, change: F) -> Result<(), ConfigureError>
+where
+    F: FnOnce(&mut BTreeMap<String, KeyMetadata>),
+{
+    let _lock = FileLock::acquire(path)?;
+    let mut metadata = read_key_metadata(path)?;
+
+    change(&mut metadata);
+
+    let temporary_path = temporary_path_for(path);
+    std::fs::write(
+        &temporary_path,
+        serde_json::to_string_pretty(&metadata).unwrap(),
+    )?;
+    std::fs::rename(&temporary_path, path)?;
+
+    Ok(())
+}
+
+/// Why a key that's `age_days` old needs rotating, if it's older than `max_age_days`
+pub fn describe_key_age_problem(
+    key_name: &str,
+    age_days: i64,
+    max_age_days: u64,
+) -> Option<String> {
+    if age_days as u64 <= max_age_days {
+        return None;
+    }
+
+    Some(format!(
+        "The key for {} is {} days old, older than the {} days `max_key_age_days` allows. Rotate it with `configure create-key --force`, then `configure reencrypt`",
+        key_name, age_days, max_age_days
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_key_metadata_round_trips_and_tolerates_unknown_ages() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(KEY_METADATA_FILE_NAME);
+        assert!(read_key_metadata(&path).unwrap().is_empty());
+
+        let created_at = Utc.ymd(2024, 10, 14).and_hms(12, 0, 0);
+        update_key_metadata(&path, |metadata| {
+            metadata.insert("WordPress".to_string(), KeyMetadata::created(created_at));
+            metadata.insert("Simplenote".to_string(), KeyMetadata::default());
+        })
+        .unwrap();
+
+        let metadata = read_key_metadata(&path).unwrap();
+        let now = Utc.ymd(2026, 10, 14).and_hms(11, 0, 0);
+        assert_eq!(metadata["WordPress"].age_days(now), Some(729));
+        assert_eq!(metadata["Simplenote"].age_days(now), None);
+        assert!(!temporary_path_for(&path).exists());
+
+        std::fs::write(&path, "[]").unwrap();
+        assert!(matches!(
+            read_key_metadata(&path),
+            Err(ConfigureError::KeyMetadataInvalid(..))
+        ));
+    }
+
+    #[test]
+    fn test_keys_are_only_too_old_past_the_limit() {
+        assert_eq!(describe_key_age_problem("WordPress", 365, 365), None);
+        assert!(describe_key_age_problem("WordPress", 366, 365)
+            .unwrap()
+            .starts_with("The key for WordPress is 366 days old"));
+    }
+}
@@ -1,19 +1,68 @@
+mod affected;
+mod answers;
+mod archive;
+mod attributes;
+mod backups;
+mod bundle;
 mod configure;
+mod diff;
 mod encryption;
+mod escrow;
+#[cfg(test)]
+mod fixtures;
+mod fleet;
 mod fs;
 mod git;
+mod inspect;
+mod interaction;
+mod key_metadata;
+mod merge;
+mod plan;
+mod sanity;
+mod schema;
+mod self_test;
+mod signing;
+mod staging;
+mod state;
+mod summary;
+mod templates;
+mod transform;
 mod ui;
 
 use crate::configure::*;
 use crate::fs::*;
 use log::debug;
 
+pub use crate::configure::{
+    AffectedOptions, ApplyOptions, CompletenessReport, ConfigurationFile, ConfigureError,
+    ConflictResolution, CreateKeyOptions, DiffOptions, Encoding, ExplainOptions,
+    ExportBundleOptions, File, FilterOptions, ImportBundleOptions, ImportOptions, InitOptions,
+    KeysCreateIdentityOptions, KeysExportOptions, KeysImportOptions, OnConflict, OutputFormat,
+    PromoteOptions, PruneBranchesOptions, PruneKeysOptions, ReencryptOptions, RenameOptions,
+    RepairBackupsOptions, RequiredField, Scope, StatusOptions, UpdateOptions, ValidateOptions,
+};
+pub use crate::encryption::{register_cipher, Cipher, SecretboxCipher, DEFAULT_ALGORITHM};
+pub use crate::fs::{set_project_root_marker, set_reject_unknown_fields};
+pub use crate::git::{set_secrets_read_only, set_trace_git};
+pub use crate::interaction::{set_interaction_handler, Interaction, InteractionHandler, Response};
+pub use crate::merge::MergeFormat;
+pub use crate::transform::Transform;
+pub use crate::ui::{
+    record_answers, save_recorded_answers, set_hash_length, set_non_interactive, set_output_handler,
+    use_answers, OutputHandler, Stream,
+};
+
 /// Set up a project to use the configure tool
 ///
-pub fn init() {
+/// # Arguments
+///
+/// * `options` - Which template, if any, to start from
+///
+pub fn init(options: InitOptions) -> Result<(), ConfigureError> {
     init_encryption();
-    let configuration = read_configuration();
-    setup_configuration(configuration);
+    let template = options.load_template()?;
+    let configuration = read_configuration()?;
+    setup_configuration(configuration, template, !options.no_key)
 }
 
 /// Decrypts secrets already present in the repository
@@ -22,17 +71,21 @@ pub fn init() {
 ///
 /// # Arguments
 ///
-/// * `configuration` - The project's parsed `ConfigurationFile` object.
+/// * `options` - Controls how files are written to the project
 ///
-pub fn apply() {
-    init_encryption();
-    let configuration = read_configuration();
+pub fn apply(options: ApplyOptions) -> Result<(), ConfigureError> {
+    if options.dump_env {
+        return dump_apply_environment(&options);
+    }
 
-    if !configuration.is_empty() {
-        apply_configuration(configuration);
-    } else {
-        setup_configuration(configuration);
+    if options.report_drift {
+        return report_drift_configuration(read_configuration()?, &options);
     }
+
+    init_encryption();
+    let configuration = read_configuration()?;
+
+    apply_configuration(configuration, &options)
 }
 
 /// Adds encrypted secrets files to the configuration, or updates existing ones.
@@ -41,34 +94,304 @@ pub fn apply() {
 ///
 /// # Arguments
 ///
-/// * `configuration` - The project's parsed `ConfigurationFile` object.
+/// * `options` - Controls how the update is performed
 ///
-pub fn update() {
+pub fn update(options: UpdateOptions) -> Result<(), ConfigureError> {
     init_encryption();
-    let configuration = read_configuration();
+    let configuration = read_configuration()?;
 
-    if !configuration.is_empty() {
-        update_configuration(configuration);
-    } else {
-        setup_configuration(configuration);
-    }
+    update_configuration(configuration, &options)
+}
+
+/// Moves a plaintext secret that's currently in the project into the secrets repository,
+/// and adds it to the configuration
+///
+/// # Arguments
+///
+/// * `options` - Which file to import, and where to put it
+///
+pub fn import(options: ImportOptions) -> Result<(), ConfigureError> {
+    init_encryption();
+    let configuration = read_configuration()?;
+
+    import_configuration(configuration, &options)
+}
+
+/// Show how `apply` would change the project's decrypted secrets, without changing anything
+///
+/// # Arguments
+///
+/// * `options` - Whether to show full diffs or just the destinations that would change
+///
+pub fn diff(options: DiffOptions) -> Result<(), ConfigureError> {
+    init_encryption();
+    let configuration = read_configuration()?;
+    diff_configuration(configuration, &options)
+}
+
+/// Regenerates the project's encrypted secrets from the secrets repository at the pinned hash,
+/// for when they've been corrupted or deleted. Doesn't change the pin or `.configure`.
+///
+/// # Arguments
+///
+/// * `options` - Which files to re-encrypt
+///
+pub fn reencrypt(options: ReencryptOptions) -> Result<(), ConfigureError> {
+    init_encryption();
+    let configuration = read_configuration()?;
+    reencrypt_configuration(configuration, &options)
+}
+
+/// Removes stale branches from the secrets repository's checkout – those whose upstream is gone,
+/// unless they have commits that aren't on the remote. Doesn't need a project.
+///
+/// # Arguments
+///
+/// * `options` - Whether to delete the branches, or just list them
+///
+pub fn prune_branches(options: PruneBranchesOptions) -> Result<(), ConfigureError> {
+    prune_branches_configuration(&options)
+}
+
+/// Lists the keys in the secrets repository's `keys.json` that none of the active projects use,
+/// and removes them if asked. Doesn't need a project.
+///
+/// # Arguments
+///
+/// * `options` - Which projects are active, and whether to remove the other keys
+///
+pub fn prune_keys(options: PruneKeysOptions) -> Result<(), ConfigureError> {
+    prune_keys_configuration(&options)
+}
+
+/// Copies the encrypted files that `configure update --stage-dir` staged into the project, once
+/// they've been reviewed. Nothing is copied if any of them, or the project's copies, changed since.
+///
+/// # Arguments
+///
+/// * `options` - Where the files were staged
+///
+pub fn promote(options: PromoteOptions) -> Result<(), ConfigureError> {
+    let configuration = read_configuration()?;
+    promote_configuration(configuration, &options)
+}
+
+/// Renames backups of a project's secrets that older versions named differently, so they all
+/// follow the `<stem>-<timestamp>.<extension>.bak` scheme. Never deletes anything.
+///
+/// # Arguments
+///
+/// * `options` - Whether to rename the backups, or just list them
+///
+pub fn repair_backups(options: RepairBackupsOptions) -> Result<(), ConfigureError> {
+    let configuration = read_configuration()?;
+    repair_backups_configuration(configuration, &options)
+}
+
+/// Writes the project's encrypted secrets to a single bundle, for moving them somewhere that can't
+/// reach the secrets repository's server
+///
+/// # Arguments
+///
+/// * `options` - Where to write the bundle, and the transfer key to re-encrypt it with, if any
+///
+pub fn export_bundle(options: ExportBundleOptions) -> Result<(), ConfigureError> {
+    init_encryption();
+    let configuration = read_configuration()?;
+    export_bundle_configuration(configuration, &options)
+}
+
+/// Decrypts the secrets in a bundle from `export_bundle` into the secrets repository
+///
+/// # Arguments
+///
+/// * `options` - The bundle, and the transfer key it was exported with, if any
+///
+pub fn import_bundle(options: ImportBundleOptions) -> Result<(), ConfigureError> {
+    init_encryption();
+    let configuration = read_configuration_if_present()?;
+    import_bundle_configuration(configuration, &options)
+}
+
+/// Removes the record of what was last applied, which configure keeps to answer questions without
+/// decrypting anything. The next commands fall back to decrypting and comparing everything.
+///
+pub fn clean() -> Result<(), ConfigureError> {
+    clean_configuration()
+}
+
+/// Checks that `executable` works end to end, by running it through key creation, `init`,
+/// `update`, `apply`, a check of the results, and `clean` against fixtures it builds and removes
+///
+/// # Arguments
+///
+/// * `executable` - The `configure` binary to test
+///
+pub fn self_test(executable: &std::path::Path) -> Result<(), ConfigureError> {
+    init_encryption();
+    self_test_configuration(executable)
+}
+
+/// Makes the project's decrypted secrets read-only, so they aren't edited by accident
+///
+pub fn lock() -> Result<(), ConfigureError> {
+    let configuration = read_configuration()?;
+    lock_configuration(configuration, true)
+}
+
+/// Makes the project's decrypted secrets writable again
+///
+pub fn unlock() -> Result<(), ConfigureError> {
+    let configuration = read_configuration()?;
+    lock_configuration(configuration, false)
 }
 
 /// Validate a project's .configure file
 ///
-pub fn validate() {
+pub fn validate(options: ValidateOptions) -> Result<(), ConfigureError> {
     init_encryption();
-    let configuration = read_configuration();
 
-    if !configuration.is_empty() {
-        validate_configuration(configuration);
-    } else {
-        setup_configuration(configuration);
+    if options.schema {
+        check_configuration_schema()?;
+    }
+
+    let configuration = read_configuration()?;
+
+    validate_configuration(configuration, &options)
+}
+
+/// List the secrets in a project's .configure file, grouped by owner
+///
+pub fn list() -> Result<(), ConfigureError> {
+    let configuration = read_configuration()?;
+    list_configuration(configuration);
+    Ok(())
+}
+
+/// Show whether each of a project's secrets has been applied, grouped by owner – or, with
+/// `--fleet` or `--all`, how far each of several projects' pins is behind the latest secrets
+///
+/// # Arguments
+///
+/// * `options` - Whether to summarize several projects, which ones, and how to print them
+///
+pub fn status(options: StatusOptions) -> Result<(), ConfigureError> {
+    if options.fleet || options.all.is_some() {
+        return fleet_status_configuration(&options);
     }
+
+    let configuration = read_configuration()?;
+    status_configuration(configuration, &options)
+}
+
+/// Show which projects use secrets that changed in the secrets repository, for automation that
+/// rotates secrets and then bumps the projects' pins
+///
+/// # Arguments
+///
+/// * `options` - The commit to compare with, and which projects to check
+///
+pub fn affected(options: AffectedOptions) -> Result<(), ConfigureError> {
+    affected_configuration(&options)
+}
+
+/// Show everything configure knows about one of a project's secrets, including who owns it
+///
+/// # Arguments
+///
+/// * `options` - Which secret to explain
+///
+pub fn explain(options: ExplainOptions) -> Result<(), ConfigureError> {
+    let configuration = read_configuration()?;
+    explain_configuration(configuration, &options)
+}
+
+/// Decrypt one secret from stdin to stdout, as a git smudge filter
+///
+/// # Arguments
+///
+/// * `options` - Which secret it is
+///
+pub fn filter_smudge(options: FilterOptions) -> Result<(), ConfigureError> {
+    init_encryption();
+    let configuration = read_configuration()?;
+    filter_configuration(configuration, &options, FilterDirection::Smudge)
+}
+
+/// Encrypt one secret from stdin to stdout, as a git clean filter
+///
+/// # Arguments
+///
+/// * `options` - Which secret it is
+///
+pub fn filter_clean(options: FilterOptions) -> Result<(), ConfigureError> {
+    init_encryption();
+    let configuration = read_configuration()?;
+    filter_configuration(configuration, &options, FilterDirection::Clean)
+}
+
+/// Seal a project's encryption key for someone else, so it can be handed over safely
+///
+/// # Arguments
+///
+/// * `options` - Which project's key, and who it's for
+///
+pub fn export_key(options: KeysExportOptions) -> Result<(), ConfigureError> {
+    init_encryption();
+    export_project_key(&options)
+}
+
+/// Install a project's encryption key from a bundle sealed for you
+///
+/// # Arguments
+///
+/// * `options` - The bundle, and the identity to open it with
+///
+pub fn import_key(options: KeysImportOptions) -> Result<(), ConfigureError> {
+    init_encryption();
+    import_project_key(&options)
+}
+
+/// List the projects in `keys.json`, and how old their keys are
+pub fn list_keys() -> Result<(), ConfigureError> {
+    list_keys_configuration()
+}
+
+/// Create an identity to receive encryption keys with
+///
+/// # Arguments
+///
+/// * `options` - Where to save the identity
+///
+pub fn create_identity(options: KeysCreateIdentityOptions) -> Result<(), ConfigureError> {
+    init_encryption();
+    create_recipient_identity(&options)
+}
+
+/// Rename a project, and its key and secrets to match
+///
+/// # Arguments
+///
+/// * `options` - The new name, and whether to move the project's secrets too
+///
+pub fn rename(options: RenameOptions) -> Result<(), ConfigureError> {
+    let configuration = read_configuration()?;
+    rename_project_configuration(configuration, &options)
+}
+
+/// Create an encryption key for a project
+///
+/// # Arguments
+///
+/// * `options` - Which project the key is for, and whether to store it or print it
+///
+pub fn create_key(options: CreateKeyOptions) -> Result<(), ConfigureError> {
+    init_encryption();
+    create_project_key(&options)
 }
 
 pub fn generate_encryption_key() -> String {
-    crate::encryption::generate_key()
+    crate::encryption::generate_key().to_base64()
 }
 
 fn init_encryption() {
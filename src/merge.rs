@@ -0,0 +1,156 @@
+//! Merging a secret into a file that has other settings in it too, like `~/.netrc` or
+//! `~/.gradle/gradle.properties`, rather than replacing the whole file.
+//!
+//! Only the entries the secret has are replaced – every other line is kept as it was, in the same
+//! order – and entries the file doesn't have yet are added at the end. Both formats are handled a
+//! line at a time: a properties value continued onto the next line with `\`, or a netrc entry
+//! sharing a line with another, isn't understood.
+
+use serde::{Deserialize, Serialize};
+
+/// How to merge a secret into the file at its destination
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MergeFormat {
+    /// Java-style `key=value` lines, like `gradle.properties`. Entries are matched by key.
+    Properties,
+
+    /// `machine <host> login <user> password <secret>` entries, like `~/.netrc`. Entries are
+    /// matched by machine, and run until the next `machine` or `default` line.
+    Netrc,
+}
+
+impl MergeFormat {
+    /// `existing` with the entries in `incoming` merged into it
+    pub fn merge(&self, existing: &str, incoming: &str) -> String {
+        let (existing, incoming) = match self {
+            MergeFormat::Properties => (property_entries(existing), property_entries(incoming)),
+            MergeFormat::Netrc => (netrc_entries(existing), netrc_entries(incoming)),
+        };
+
+        let incoming_entry = |key: &str| incoming.iter().find(|entry| entry.key.as_deref() == Some(key));
+
+        let mut merged = Vec::new();
+        let mut replaced = Vec::new();
+        for entry in &existing {
+            let key = match &entry.key {
+                Some(key) => key,
+                None => {
+                    merged.extend(entry.lines.iter().copied());
+                    continue;
+                }
+            };
+
+            // A key that's listed twice is only kept once, where it was first
+            match incoming_entry(key) {
+                Some(_) if replaced.contains(key) => {}
+                Some(replacement) => {
+                    merged.extend(replacement.lines.iter().copied());
+                    replaced.push(key.clone());
+                }
+                None => merged.extend(entry.lines.iter().copied()),
+            }
+        }
+
+        for entry in &incoming {
+            match &entry.key {
+                Some(key) if !replaced.contains(key) => {
+                    merged.extend(entry.lines.iter().copied());
+                    replaced.push(key.clone());
+                }
+                _ => {}
+            }
+        }
+
+        match merged.is_empty() {
+            true => String::new(),
+            false => merged.join("\n") + "\n",
+        }
+    }
+}
+
+/// Some lines of a file, and the key they're an entry for – or `None` for lines between entries,
+/// like comments
+struct Entry<'a> {
+    key: Option<String>,
+    lines: Vec<&'a str>,
+}
+
+fn property_entries(contents: &str) -> Vec<Entry<'_>> {
+    contents
+        .lines()
+        .map(|line| {
+            let trimmed = line.trim_start();
+            let is_entry = !trimmed.is_empty() && !trimmed.starts_with(['#', '!']);
+            let key = trimmed
+                .split(|c: char| c == '=' || c == ':' || c.is_whitespace())
+                .next()
+                .filter(|_| is_entry)
+                .map(String::from);
+
+            Entry {
+                key,
+                lines: vec![line],
+            }
+        })
+        .collect()
+}
+
+fn netrc_entries(contents: &str) -> Vec<Entry<'_>> {
+    let mut entries: Vec<Entry> = Vec::new();
+
+    for line in contents.lines() {
+        let mut tokens = line.split_whitespace();
+        let key = match tokens.next() {
+            Some("machine") => tokens.next().map(|machine| format!("machine {}", machine)),
+            Some("default") => Some("default".to_string()),
+            _ => None,
+        };
+
+        match (key, entries.last_mut()) {
+            (Some(key), _) => entries.push(Entry {
+                key: Some(key),
+                lines: vec![line],
+            }),
+            (None, Some(entry)) => entry.lines.push(line),
+            (None, None) => entries.push(Entry {
+                key: None,
+                lines: vec![line],
+            }),
+        }
+    }
+
+    entries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_properties_are_merged_by_key() {
+        let existing = "# Local settings\norg.gradle.jvmargs=-Xmx4g\nsentryToken = old\n\nsentryToken=older\n";
+        let incoming = "sentryToken=new\nmavenPassword: secret\n";
+
+        assert_eq!(
+            MergeFormat::Properties.merge(existing, incoming),
+            "# Local settings\norg.gradle.jvmargs=-Xmx4g\nsentryToken=new\n\nmavenPassword: secret\n"
+        );
+        assert_eq!(MergeFormat::Properties.merge("", incoming), incoming);
+    }
+
+    #[test]
+    fn test_netrc_entries_are_merged_by_machine() {
+        let existing = "# Mine\nmachine github.com\n  login me\n  password mine\nmachine example.com login shared password old\ndefault login anonymous\n";
+        let incoming = "machine example.com\n  login shared\n  password new\nmachine maven.example.com login ci password secret\n";
+
+        assert_eq!(
+            MergeFormat::Netrc.merge(existing, incoming),
+            "# Mine\nmachine github.com\n  login me\n  password mine\nmachine example.com\n  login shared\n  password new\ndefault login anonymous\nmachine maven.example.com login ci password secret\n"
+        );
+
+        // Merging the same secret again changes nothing
+        let merged = MergeFormat::Netrc.merge(existing, incoming);
+        assert_eq!(MergeFormat::Netrc.merge(&merged, incoming), merged);
+    }
+}
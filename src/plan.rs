@@ -0,0 +1,286 @@
+//! What applying would do to each of the project's destinations, worked out without changing
+//! anything. `apply` decides what to do with each file the same way, so `apply --dry-run`,
+//! `apply --check` and `diff` can't disagree with what an apply actually does.
+//!
+//! A plan is computed from what's already been read – the configuration, what's at each
+//! destination, what configure last applied there, and the decrypted secrets – so it can be
+//! worked out without a secrets repository or a key.
+
+use crate::fs::hash_bytes;
+use crate::state::ApplyState;
+use crate::ConfigurationFile;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// What applying would do to one destination
+#[derive(Debug, Clone, Copy, Serialize, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum PlanAction {
+    /// The destination doesn't exist yet
+    Create,
+
+    /// The destination still has what configure last wrote there, so it's replaced
+    Update,
+
+    /// The destination has other contents that configure has no record of writing. Whether
+    /// they're backed up, kept, or replaced depends on `on_conflict`.
+    Replace,
+
+    /// The destination was edited after configure last wrote it, and the secrets differ
+    Conflict,
+
+    /// The developer kept their own file over these same secrets, so it's left alone
+    Keep,
+
+    /// The destination already has the incoming contents
+    Unchanged,
+
+    /// The destination is for an optional secret that isn't available
+    Skip,
+}
+
+impl PlanAction {
+    /// Works out what to do with a destination whose contents have the digest `local` (`None` if
+    /// it doesn't exist), given the digest of the incoming contents and of what configure last
+    /// wrote there
+    pub fn for_digests(local: Option<&str>, incoming: &str, last_applied: Option<&str>) -> Self {
+        match (local, last_applied) {
+            (None, _) => PlanAction::Create,
+            (Some(local), _) if local == incoming => PlanAction::Unchanged,
+            (Some(local), Some(last_applied)) if local == last_applied => PlanAction::Update,
+            (Some(_), Some(_)) => PlanAction::Conflict,
+            (Some(_), None) => PlanAction::Replace,
+        }
+    }
+
+    /// Whether carrying out the action would change the destination
+    pub fn changes_destination(&self) -> bool {
+        !matches!(
+            self,
+            PlanAction::Unchanged | PlanAction::Keep | PlanAction::Skip
+        )
+    }
+
+    fn label(&self) -> &'static str {
+        match self {
+            PlanAction::Create => "create",
+            PlanAction::Update => "update",
+            PlanAction::Replace => "replace",
+            PlanAction::Conflict => "conflict",
+            PlanAction::Keep => "keep",
+            PlanAction::Unchanged => "unchanged",
+            PlanAction::Skip => "skip",
+        }
+    }
+}
+
+/// What applying would do to one destination, with the digests of its contents before and after
+#[derive(Debug, Clone, Serialize, Eq, PartialEq)]
+pub struct PlannedChange {
+    pub destination: String,
+    pub action: PlanAction,
+
+    /// The SHA-256 hash of what's at the destination now, or `None` if there's nothing there
+    pub before: Option<String>,
+
+    /// The SHA-256 hash of what applying would leave there
+    pub after: Option<String>,
+
+    /// The destination's contents now, for rendering a diff. Never serialized.
+    #[serde(skip)]
+    pub local: Option<Vec<u8>>,
+
+    /// The decrypted secret, for rendering a diff. Never serialized.
+    #[serde(skip)]
+    pub incoming: Option<Vec<u8>>,
+}
+
+/// What applying would do to each of the project's destinations
+#[derive(Debug, Clone, Default, Serialize, Eq, PartialEq)]
+pub struct Plan {
+    pub pinned_hash: String,
+    pub files: Vec<PlannedChange>,
+}
+
+impl Plan {
+    /// The destinations applying would change
+    pub fn changes(&self) -> impl Iterator<Item = &PlannedChange> {
+        self.files.iter().filter(|file| file.action.changes_destination())
+    }
+
+    pub fn has_changes(&self) -> bool {
+        self.changes().next().is_some()
+    }
+
+    /// One line per destination, like `update  config/secrets.json`
+    pub fn describe(&self) -> Vec<String> {
+        self.files
+            .iter()
+            .map(|file| format!("{:<9} {}", file.action.label(), file.destination))
+            .collect()
+    }
+}
+
+/// Works out what applying `configuration` would do. `local` has the contents of each destination
+/// that exists and `incoming` each decrypted secret (after any transform), both keyed by
+/// destination. A file with no incoming secret is an optional one that isn't available.
+pub fn plan_apply(
+    configuration: &ConfigurationFile,
+    state: &ApplyState,
+    local: &BTreeMap<String, Vec<u8>>,
+    incoming: &BTreeMap<String, Vec<u8>>,
+) -> Plan {
+    let files = configuration
+        .files_to_copy
+        .iter()
+        .map(|file| {
+            let destination = &file.destination;
+            let key = file.state_key();
+            let local = local.get(destination);
+            let before = local.map(|local| hash_bytes(local));
+
+            let incoming = incoming.get(destination);
+            let after = incoming.map(|incoming| hash_bytes(incoming));
+
+            let action = match (&before, &after) {
+                (_, None) => PlanAction::Skip,
+                (Some(before), Some(after)) if state.was_kept_over(&key, after, before) => {
+                    PlanAction::Keep
+                }
+                // What's merged into keeps everything else in the file, so it's never a conflict
+                (before, Some(after)) if file.merge.is_some() => {
+                    PlanAction::for_digests(before.as_deref(), after, before.as_deref())
+                }
+                (before, Some(after)) => PlanAction::for_digests(
+                    before.as_deref(),
+                    after,
+                    state.last_applied_digest(&key, destination, before.as_deref()),
+                ),
+            };
+
+            let after = match action.changes_destination() {
+                true => after,
+                false => before.clone(),
+            };
+
+            PlannedChange {
+                destination: destination.clone(),
+                action,
+                before,
+                after,
+                local: local.cloned(),
+                incoming: incoming.cloned(),
+            }
+        })
+        .collect();
+
+    Plan {
+        pinned_hash: configuration.pinned_hash.clone(),
+        files,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::File;
+
+    fn contents(files: &[(&str, &str)]) -> BTreeMap<String, Vec<u8>> {
+        files
+            .iter()
+            .map(|(destination, contents)| (destination.to_string(), contents.as_bytes().to_vec()))
+            .collect()
+    }
+
+    #[test]
+    fn test_each_destination_is_planned_from_its_digests() {
+        let destinations = ["new", "applied", "same", "edited", "unrecorded", "kept", "missing"];
+        let configuration = ConfigurationFile {
+            pinned_hash: "abc123".to_string(),
+            files_to_copy: destinations
+                .iter()
+                .map(|destination| File {
+                    source: destination.to_string(),
+                    destination: destination.to_string(),
+                    optional: *destination == "missing",
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        };
+
+        let mut state = ApplyState::default();
+        state.record("applied", hash_bytes(b"old"), 3);
+        state.record("same", hash_bytes(b"old"), 3);
+        state.record("edited", hash_bytes(b"old"), 3);
+        state.record_kept("kept", hash_bytes(b"mine"), 4, &hash_bytes(b"secret"));
+
+        let local = contents(&[
+            ("applied", "old"),
+            ("same", "secret"),
+            ("edited", "mine"),
+            ("unrecorded", "mine"),
+            ("kept", "mine"),
+            ("missing", "mine"),
+        ]);
+        let incoming = contents(&[
+            ("new", "secret"),
+            ("applied", "secret"),
+            ("same", "secret"),
+            ("edited", "secret"),
+            ("unrecorded", "secret"),
+            ("kept", "secret"),
+        ]);
+
+        let plan = plan_apply(&configuration, &state, &local, &incoming);
+        let actions: Vec<PlanAction> = plan.files.iter().map(|file| file.action).collect();
+        assert_eq!(
+            actions,
+            vec![
+                PlanAction::Create,
+                PlanAction::Update,
+                PlanAction::Unchanged,
+                PlanAction::Conflict,
+                PlanAction::Replace,
+                PlanAction::Keep,
+                PlanAction::Skip,
+            ]
+        );
+
+        let changed: Vec<&str> = plan.changes().map(|file| &*file.destination).collect();
+        assert_eq!(changed, vec!["new", "applied", "edited", "unrecorded"]);
+        assert_eq!(plan.files[0].before, None);
+        assert_eq!(plan.files[1].before, Some(hash_bytes(b"old")));
+        assert_eq!(plan.files[1].after, Some(hash_bytes(b"secret")));
+        assert_eq!(plan.files[5].after, Some(hash_bytes(b"mine")));
+    }
+
+    #[test]
+    fn test_plans_serialize_without_any_contents() {
+        let configuration = ConfigurationFile {
+            pinned_hash: "abc123".to_string(),
+            files_to_copy: vec![File {
+                destination: "a.json".to_string(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let incoming = contents(&[("a.json", "secret")]);
+        let plan = plan_apply(&configuration, &ApplyState::default(), &BTreeMap::new(), &incoming);
+
+        let json = serde_json::to_value(&plan).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "pinned_hash": "abc123",
+                "files": [{
+                    "destination": "a.json",
+                    "action": "create",
+                    "before": null,
+                    "after": hash_bytes(b"secret"),
+                }],
+            })
+        );
+        assert_eq!(plan.describe(), vec!["create    a.json"]);
+    }
+}
@@ -0,0 +1,178 @@
+use crate::File;
+use serde::{Deserialize, Serialize};
+
+/// Project-level settings for the checks run against each secret's plaintext
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+pub struct SanityChecks {
+    /// Whether a finding stops the command (`true`) or is just a warning (`false`)
+    #[serde(default = "default_fail_on_findings")]
+    pub fail_on_findings: bool,
+
+    /// How much smaller than the previously applied version a file may get, in percent
+    #[serde(default = "default_max_shrink_percent")]
+    pub max_shrink_percent: u8,
+}
+
+fn default_fail_on_findings() -> bool {
+    true
+}
+
+fn default_max_shrink_percent() -> u8 {
+    50
+}
+
+impl Default for SanityChecks {
+    fn default() -> Self {
+        SanityChecks {
+            fail_on_findings: default_fail_on_findings(),
+            max_shrink_percent: default_max_shrink_percent(),
+        }
+    }
+}
+
+/// A single problem found with a secret's plaintext
+#[derive(Debug, Eq, PartialEq)]
+pub struct SanityFinding {
+    /// The destination of the entry the check fired for
+    pub destination: String,
+
+    /// The name of the check that fired
+    pub check: &'static str,
+
+    pub message: String,
+}
+
+impl std::fmt::Display for SanityFinding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({} check): {}", self.destination, self.check, self.message)
+    }
+}
+
+/// Checks that `contents` looks like a plausible version of `file`.
+///
+/// `previous_size` is the size of the last version applied to this machine, if known.
+pub fn check_contents(
+    file: &File,
+    contents: &[u8],
+    previous_size: Option<u64>,
+    settings: &SanityChecks,
+) -> Vec<SanityFinding> {
+    let mut findings = Vec::new();
+    let finding = |check, message: String| SanityFinding {
+        destination: file.destination.clone(),
+        check,
+        message,
+    };
+
+    if contents.is_empty() {
+        findings.push(finding("empty", "the file is empty".to_string()));
+    }
+
+    if let Some(previous_size) = previous_size {
+        let size = contents.len() as u64;
+        let minimum_size = previous_size * (100 - settings.max_shrink_percent.min(100) as u64) / 100;
+
+        if previous_size > 0 && size < minimum_size {
+            findings.push(finding(
+                "shrink",
+                format!(
+                    "the file shrank from {} to {} bytes, more than the allowed {}%",
+                    previous_size, size, settings.max_shrink_percent
+                ),
+            ));
+        }
+    }
+
+    if let Some(format) = &file.format {
+        if let Err(message) = check_format(format, contents) {
+            findings.push(finding("format", message));
+        }
+    }
+
+    findings
+}
+
+fn check_format(format: &str, contents: &[u8]) -> Result<(), String> {
+    match format {
+        "json" => serde_json::from_slice::<serde_json::Value>(contents)
+            .map(|_| ())
+            .map_err(|err| format!("the file is not valid JSON: {}", err)),
+
+        // There's no plist parser available, so this is a structural check only: binary plists
+        // start with a magic number, and XML plists must have an opening and closing `plist` tag
+        "plist" => {
+            if contents.starts_with(b"bplist") {
+                return Ok(());
+            }
+
+            let text = String::from_utf8_lossy(contents);
+            if text.contains("<plist") && text.contains("</plist>") {
+                Ok(())
+            } else {
+                Err("the file is not a valid property list".to_string())
+            }
+        }
+
+        _ => Err(format!(
+            "unknown format {:?} – expected `json` or `plist`",
+            format
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(format: Option<&str>) -> File {
+        File {
+            source: "secrets.json".to_string(),
+            destination: "secrets.json".to_string(),
+            format: format.map(String::from),
+            ..Default::default()
+        }
+    }
+
+    fn checks(findings: Vec<SanityFinding>) -> Vec<&'static str> {
+        findings.iter().map(|finding| finding.check).collect()
+    }
+
+    #[test]
+    fn test_empty_file_is_reported() {
+        let findings = check_contents(&file(None), b"", None, &SanityChecks::default());
+        assert_eq!(checks(findings), vec!["empty"]);
+    }
+
+    #[test]
+    fn test_shrinking_past_the_threshold_is_reported() {
+        let settings = SanityChecks::default();
+
+        let findings = check_contents(&file(None), b"12345", Some(10), &settings);
+        assert!(findings.is_empty());
+
+        let findings = check_contents(&file(None), b"1234", Some(10), &settings);
+        assert_eq!(checks(findings), vec!["shrink"]);
+    }
+
+    #[test]
+    fn test_invalid_json_is_reported_only_when_expected() {
+        let settings = SanityChecks::default();
+
+        assert!(check_contents(&file(None), b"{", None, &settings).is_empty());
+        assert!(check_contents(&file(Some("json")), b"{}", None, &settings).is_empty());
+
+        let findings = check_contents(&file(Some("json")), b"{", None, &settings);
+        assert_eq!(checks(findings), vec!["format"]);
+    }
+
+    #[test]
+    fn test_plist_structure_is_checked() {
+        let settings = SanityChecks::default();
+        let plist = b"<?xml version=\"1.0\"?><plist version=\"1.0\"><dict/></plist>";
+
+        assert!(check_contents(&file(Some("plist")), plist, None, &settings).is_empty());
+
+        let findings = check_contents(&file(Some("plist")), b"<dict/>", None, &settings);
+        assert_eq!(checks(findings), vec!["format"]);
+    }
+}
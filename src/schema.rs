@@ -0,0 +1,329 @@
+//! Checks `.configure` against the JSON Schema in `configure.schema.json`.
+//!
+//! There's no JSON Schema crate available, so this implements the subset of draft-07 the schema
+//! uses: `type`, `required`, `properties`, `additionalProperties: false`, `items`, `enum`,
+//! `minimum`, `maximum`, and `$ref` to `#/definitions/…`.
+
+use serde_json::Value;
+
+/// The published schema for `.configure`
+pub const CONFIGURATION_SCHEMA: &str = include_str!("../configure.schema.json");
+
+/// A place where a document doesn't match the schema
+#[derive(Debug, Eq, PartialEq)]
+pub struct SchemaViolation {
+    /// Where the problem is, like `files_to_copy[3].destination`. Empty for the document itself.
+    pub path: String,
+
+    pub message: String,
+}
+
+impl std::fmt::Display for SchemaViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.path.is_empty() {
+            write!(f, "the file {}", self.message)
+        } else {
+            write!(f, "{} {}", self.path, self.message)
+        }
+    }
+}
+
+/// Checks a parsed `.configure` file against the published schema
+pub fn validate_configuration_schema(document: &Value) -> Vec<SchemaViolation> {
+    let schema: Value =
+        serde_json::from_str(CONFIGURATION_SCHEMA).expect("The published schema is invalid");
+
+    let mut violations = Vec::new();
+    validate_value(&schema, &schema, document, "", &mut violations);
+    violations
+}
+
+fn validate_value(
+    root: &Value,
+    schema: &Value,
+    value: &Value,
+    path: &str,
+    violations: &mut Vec<SchemaViolation>,
+) {
+    let mut violation = |message: String| {
+        violations.push(SchemaViolation {
+            path: path.to_string(),
+            message,
+        })
+    };
+
+    if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+        let definition = reference
+            .strip_prefix("#/definitions/")
+            .and_then(|name| root["definitions"].get(name))
+            .unwrap_or_else(|| panic!("The schema refers to an unknown definition {}", reference));
+        return validate_value(root, definition, value, path, violations);
+    }
+
+    if let Some(expected) = schema.get("type").and_then(Value::as_str) {
+        if !has_type(value, expected) {
+            violation(format!("should be {}", describe_type(expected)));
+            return;
+        }
+    }
+
+    if let Some(allowed) = schema.get("enum").and_then(Value::as_array) {
+        if !allowed.contains(value) {
+            let allowed: Vec<String> = allowed.iter().map(Value::to_string).collect();
+            violation(format!("should be one of {}", allowed.join(", ")));
+        }
+    }
+
+    if let Some(number) = value.as_f64() {
+        if let Some(minimum) = schema.get("minimum").and_then(Value::as_f64) {
+            if number < minimum {
+                violation(format!("should be at least {}", minimum));
+            }
+        }
+
+        if let Some(maximum) = schema.get("maximum").and_then(Value::as_f64) {
+            if number > maximum {
+                violation(format!("should be at most {}", maximum));
+            }
+        }
+    }
+
+    if let Some(object) = value.as_object() {
+        let properties = schema.get("properties").and_then(Value::as_object);
+
+        if let Some(required) = schema.get("required").and_then(Value::as_array) {
+            for name in required.iter().filter_map(Value::as_str) {
+                if !object.contains_key(name) {
+                    violations.push(SchemaViolation {
+                        path: join_path(path, name),
+                        message: "is required".to_string(),
+                    });
+                }
+            }
+        }
+
+        for (name, property) in object {
+            match properties.and_then(|properties| properties.get(name)) {
+                Some(property_schema) => validate_value(
+                    root,
+                    property_schema,
+                    property,
+                    &join_path(path, name),
+                    violations,
+                ),
+                None if schema.get("additionalProperties").is_some_and(Value::is_object) => {
+                    validate_value(
+                        root,
+                        &schema["additionalProperties"],
+                        property,
+                        &join_path(path, name),
+                        violations,
+                    )
+                }
+                None if schema.get("additionalProperties") == Some(&Value::Bool(false)) => {
+                    violations.push(SchemaViolation {
+                        path: join_path(path, name),
+                        message: "isn't a known field".to_string(),
+                    })
+                }
+                None => {}
+            }
+        }
+    }
+
+    if let (Some(items), Some(array)) = (schema.get("items"), value.as_array()) {
+        for (index, item) in array.iter().enumerate() {
+            validate_value(root, items, item, &format!("{}[{}]", path, index), violations);
+        }
+    }
+}
+
+fn has_type(value: &Value, expected: &str) -> bool {
+    match expected {
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "string" => value.is_string(),
+        "boolean" => value.is_boolean(),
+        "integer" => value.is_i64() || value.is_u64(),
+        "number" => value.is_number(),
+        "null" => value.is_null(),
+        _ => panic!("The schema uses an unsupported type {}", expected),
+    }
+}
+
+fn describe_type(expected: &str) -> String {
+    match expected {
+        "object" | "array" | "integer" => format!("an {}", expected),
+        _ => format!("a {}", expected),
+    }
+}
+
+fn join_path(path: &str, name: &str) -> String {
+    if path.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}.{}", path, name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ConfigurationFile, Encoding, File, MergeFormat, OnConflict, Scope, Transform};
+    use serde_json::json;
+    use std::collections::BTreeSet;
+
+    fn messages(document: Value) -> Vec<String> {
+        validate_configuration_schema(&document)
+            .iter()
+            .map(ToString::to_string)
+            .collect()
+    }
+
+    fn valid_document() -> Value {
+        json!({
+            "project_name": "WordPress",
+            "branch": "trunk",
+            "pinned_hash": "abc123",
+            "files_to_copy": [{ "file": "a.json", "destination": "a.json" }]
+        })
+    }
+
+    #[test]
+    fn test_valid_configuration_has_no_violations() {
+        assert!(messages(valid_document()).is_empty());
+    }
+
+    #[test]
+    fn test_violations_have_precise_paths() {
+        let document = json!({
+            "project_name": "WordPress",
+            "branches": "trunk",
+            "pinned_hash": "abc123",
+            "files_to_copy": [
+                { "file": "a.json", "destination": "a.json" },
+                { "file": "b.json" },
+                { "file": "c.json", "destination": "c.json", "format": "yaml", "optional": "yes" }
+            ]
+        });
+
+        assert_eq!(
+            messages(document),
+            vec![
+                "branch is required",
+                "branches isn't a known field",
+                "files_to_copy[1].destination is required",
+                "files_to_copy[2].format should be one of \"json\", \"plist\"",
+                "files_to_copy[2].optional should be a boolean",
+            ]
+        );
+    }
+
+    /// Collects the names of every field the schema describes, like `files_to_copy[].owner`
+    fn schema_fields(schema: &Value, root: &Value, prefix: &str, fields: &mut BTreeSet<String>) {
+        let schema = match schema.get("$ref").and_then(Value::as_str) {
+            Some(reference) => &root["definitions"][reference.trim_start_matches("#/definitions/")],
+            None => schema,
+        };
+
+        if let Some(properties) = schema.get("properties").and_then(Value::as_object) {
+            for (name, property) in properties {
+                let field = format!("{}{}", prefix, name);
+                fields.insert(field.clone());
+                schema_fields(property, root, &format!("{}.", field), fields);
+            }
+        }
+
+        if let Some(items) = schema.get("items") {
+            schema_fields(items, root, &format!("{}[].", prefix.trim_end_matches('.')), fields);
+        }
+
+        let additional_properties = schema.get("additionalProperties");
+        if let Some(values) = additional_properties.filter(|values| values.is_object()) {
+            fields.insert(format!("{}*", prefix));
+            schema_fields(values, root, &format!("{}*.", prefix), fields);
+        }
+    }
+
+    /// Collects the names of every field in a serialized document, in the same form
+    fn document_fields(value: &Value, prefix: &str, fields: &mut BTreeSet<String>) {
+        if let Some(object) = value.as_object() {
+            for (name, property) in object {
+                let field = format!("{}{}", prefix, name);
+                fields.insert(field.clone());
+                document_fields(property, &format!("{}.", field), fields);
+            }
+        }
+
+        if let Some(array) = value.as_array() {
+            for item in array {
+                document_fields(item, &format!("{}[].", prefix.trim_end_matches('.')), fields);
+            }
+        }
+    }
+
+    #[test]
+    fn test_schema_matches_configuration_file() {
+        // Every optional field is set, so everything the struct can serialize is present. There's
+        // deliberately no `..Default::default()`, so adding a field breaks this until it's added here
+        // (and to the schema).
+        let configuration = ConfigurationFile {
+            project_name: "WordPress".to_string(),
+            branch: "trunk".to_string(),
+            pinned_hash: "abc123".to_string(),
+            files_to_copy: vec![File {
+                source: "a.json".to_string(),
+                destination: "a.json".to_string(),
+                format: Some("json".to_string()),
+                owner: Some("@mobile".to_string()),
+                description: Some("Analytics keys".to_string()),
+                variant: Some("release".to_string()),
+                optional: true,
+                placeholder: Some("{}".to_string()),
+                platforms: vec!["macos".to_string()],
+                encoding: Encoding::Base64,
+                transform: Some(Transform::Plist),
+                scope: Scope::User,
+                merge: Some(MergeFormat::Netrc),
+            }],
+            sanity_checks: Some(Default::default()),
+            max_file_size: Some(1024),
+            read_only: true,
+            on_conflict: Some(OnConflict::Keep),
+            placeholder: Some(String::new()),
+            summary_file: Some("secrets-summary.json".to_string()),
+            require_signed_updates: true,
+            max_age_days: Some(30),
+            max_commits_behind: Some(50),
+            source_root: Some("WordPress".to_string()),
+            max_key_age_days: Some(365),
+            remotes: vec![("security".to_string(), "git@example.com:secrets.git".to_string())]
+                .into_iter()
+                .collect(),
+        };
+
+        let document = serde_json::to_value(&configuration).unwrap();
+        assert_eq!(validate_configuration_schema(&document), vec![]);
+
+        let schema: Value = serde_json::from_str(CONFIGURATION_SCHEMA).unwrap();
+        let mut described = BTreeSet::new();
+        schema_fields(&schema, &schema, "", &mut described);
+
+        let mut serialized = BTreeSet::new();
+        document_fields(&document, "", &mut serialized);
+
+        // Remotes are named by each project, so the schema describes them as `remotes.*`
+        let serialized: BTreeSet<String> = serialized
+            .into_iter()
+            .map(|field| {
+                if field.starts_with("remotes.") {
+                    "remotes.*".to_string()
+                } else {
+                    field
+                }
+            })
+            .collect();
+
+        assert_eq!(described, serialized);
+    }
+}
@@ -0,0 +1,252 @@
+//! `configure self-test`, which runs an installed binary end to end against a throwaway secrets
+//! repository and project – for packagers and CI to check a build works without network access.
+//!
+//! Everything is built at runtime in a temporary directory: a bare repository standing in for the
+//! server, a secrets repository tracking it in a fake home directory, and an empty project.
+//! Each stage runs the binary as its own process with `HOME` pointed at the fake home, so the
+//! developer's real secrets are never touched. The directory is removed however the run ends.
+
+use crate::encryption::Key;
+use crate::ConfigureError;
+use git2::{Repository, Signature};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The project name the fixtures use
+const PROJECT_NAME: &str = "SelfTest";
+
+/// The secret the fixtures apply, and where it's decrypted to in the project
+const SECRET_SOURCE: &str = "SelfTest/secrets.json";
+const SECRET_DESTINATION: &str = "config/secrets.json";
+const SECRET_CONTENTS: &str = "{ \"api_key\": \"self-test\" }\n";
+
+/// A directory that's removed, with everything in it, when it's dropped
+struct ScratchDirectory(PathBuf);
+
+impl ScratchDirectory {
+    fn create() -> Result<ScratchDirectory, ConfigureError> {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.subsec_nanos())
+            .unwrap_or_default();
+        let name = format!("configure-self-test-{}-{}", std::process::id(), nanos);
+
+        let path = std::env::temp_dir().join(name);
+        std::fs::create_dir_all(&path)?;
+        Ok(ScratchDirectory(path))
+    }
+}
+
+impl Drop for ScratchDirectory {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// The throwaway repositories a self-test runs against
+pub struct SelfTestFixtures {
+    pub home: PathBuf,
+    pub project: PathBuf,
+}
+
+impl SelfTestFixtures {
+    /// Builds the fixtures under `root`
+    pub fn create(root: &Path) -> Result<SelfTestFixtures, ConfigureError> {
+        let signature = Signature::now("Configure Self-Test", "self-test@example.com")?;
+
+        // The secrets repository, with no key for the project yet – `init` creates one
+        let home = root.join("home");
+        let secrets = Repository::init(home.join(".mobile-secrets"))?;
+        secrets.set_head("refs/heads/main")?;
+        write_file(&secrets, "keys.json", "{}")?;
+        write_file(&secrets, SECRET_SOURCE, SECRET_CONTENTS)?;
+        commit_all(&secrets, &signature, "Add the self-test secrets")?;
+
+        // The "server" it fetches from
+        let origin_path = root.join("origin.git");
+        Repository::init_bare(&origin_path)?;
+        let mut origin = secrets.remote("origin", &origin_path.to_string_lossy())?;
+        origin.push(&["refs/heads/main:refs/heads/main"], None)?;
+        origin.fetch(&["main"], None, None)?;
+        secrets
+            .find_branch("main", git2::BranchType::Local)?
+            .set_upstream(Some("origin/main"))?;
+
+        // The project, which needs a commit so that `update` finds it in a clean state
+        let project = root.join("project");
+        let project_repo = Repository::init(&project)?;
+        write_file(&project_repo, ".gitignore", &format!("{}\n", SECRET_DESTINATION))?;
+        commit_all(&project_repo, &signature, "Initial commit")?;
+
+        Ok(SelfTestFixtures { home, project })
+    }
+
+    /// Writes the answers `init` and `update` are given in place of a developer, returning their
+    /// paths
+    fn write_answers(&self, root: &Path) -> Result<(String, String), ConfigureError> {
+        let init = serde_json::json!({
+            "project_name": PROJECT_NAME,
+            "branch": "main",
+            "source": SECRET_SOURCE,
+            "destination": SECRET_DESTINATION,
+            "confirms": { "add_more_files": [true, false] },
+        });
+        let update = serde_json::json!({ "branch": "main" });
+
+        let mut paths = Vec::new();
+        for (name, answers) in &[("init", init), ("update", update)] {
+            let path = root.join(format!("{}-answers.json", name));
+            std::fs::write(&path, answers.to_string())?;
+            paths.push(path.to_string_lossy().to_string());
+        }
+
+        Ok((paths.remove(0), paths.remove(0)))
+    }
+
+    /// Runs `configure` with `args` in the project, returning what it printed if it succeeded, or
+    /// its errors if it failed
+    fn run(&self, executable: &Path, args: &[&str]) -> Result<String, String> {
+        let output = Command::new(executable)
+            .args(args)
+            .current_dir(&self.project)
+            .stdin(Stdio::null())
+            .env("HOME", &self.home)
+            .env("GIT_TERMINAL_PROMPT", "0")
+            .env("GIT_CONFIG_NOSYSTEM", "1")
+            .output()
+            .map_err(|err| format!("unable to run {:?}: {}", executable, err))?;
+
+        if output.status.success() {
+            return Ok(String::from_utf8_lossy(&output.stdout).to_string());
+        }
+
+        let mut message = String::from_utf8_lossy(&output.stderr).trim().to_string();
+        if message.is_empty() {
+            message = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        }
+        Err(message)
+    }
+}
+
+fn write_file(repo: &Repository, path: &str, contents: &str) -> Result<(), ConfigureError> {
+    let full_path = repo.workdir().unwrap().join(path);
+    if let Some(parent) = full_path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(full_path, contents)?;
+    Ok(())
+}
+
+fn commit_all(
+    repo: &Repository,
+    signature: &Signature,
+    message: &str,
+) -> Result<(), ConfigureError> {
+    let mut index = repo.index()?;
+    index.add_all(["*"].iter(), git2::IndexAddOption::DEFAULT, None)?;
+    index.write()?;
+
+    let tree = repo.find_tree(index.write_tree()?)?;
+    repo.commit(Some("HEAD"), signature, signature, message, &tree, &[])?;
+    Ok(())
+}
+
+/// How one stage of the self-test went
+#[derive(Debug, Eq, PartialEq)]
+pub struct StageResult {
+    pub stage: &'static str,
+
+    /// Why the stage failed, or `None` if it passed
+    pub failure: Option<String>,
+}
+
+/// Runs every stage against `executable` in a fresh set of fixtures, stopping at the first
+/// failure since each stage needs the ones before it. The fixtures are removed before returning.
+pub fn run_self_test(executable: &Path) -> Result<Vec<StageResult>, ConfigureError> {
+    let scratch = ScratchDirectory::create()?;
+    let fixtures = SelfTestFixtures::create(&scratch.0)?;
+    let (init_answers, update_answers) = fixtures.write_answers(&scratch.0)?;
+    let decrypted_path = fixtures.project.join(SECRET_DESTINATION);
+
+    type Stage<'a> = (&'static str, Box<dyn Fn() -> Result<String, String> + 'a>);
+    let stages: Vec<Stage> = vec![
+        (
+            "create-key",
+            Box::new(|| {
+                let output = fixtures.run(executable, &["create-key", "--stdout"])?;
+                let key = output.lines().last().unwrap_or_default().trim();
+                match Key::from_base64(key) {
+                    Some(_) => Ok(output),
+                    None => Err(format!("{:?} isn't a valid key", key)),
+                }
+            }),
+        ),
+        (
+            "init",
+            Box::new(|| fixtures.run(executable, &["--answers", &init_answers, "init"])),
+        ),
+        (
+            "update",
+            Box::new(|| fixtures.run(executable, &["--answers", &update_answers, "update"])),
+        ),
+        (
+            "apply",
+            Box::new(|| fixtures.run(executable, &["--non-interactive", "apply"])),
+        ),
+        (
+            "verify",
+            Box::new(|| {
+                let path = SECRET_DESTINATION;
+                match std::fs::read_to_string(&decrypted_path) {
+                    Ok(contents) if contents == SECRET_CONTENTS => {}
+                    Ok(_) => return Err(format!("{} has the wrong contents", path)),
+                    Err(err) => return Err(format!("unable to read {}: {}", path, err)),
+                }
+                fixtures.run(executable, &["--non-interactive", "diff"])
+            }),
+        ),
+        (
+            "clean",
+            Box::new(|| fixtures.run(executable, &["--non-interactive", "clean"])),
+        ),
+    ];
+
+    let mut results = Vec::new();
+    for (stage, run) in stages {
+        let failure = run().err();
+        let failed = failure.is_some();
+        results.push(StageResult { stage, failure });
+
+        if failed {
+            break;
+        }
+    }
+
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fixtures_are_built_and_removed() {
+        let scratch = ScratchDirectory::create().unwrap();
+        let root = scratch.0.clone();
+        let fixtures = SelfTestFixtures::create(&root).unwrap();
+
+        let secrets = Repository::open(fixtures.home.join(".mobile-secrets")).unwrap();
+        let head = secrets.head().unwrap().peel_to_commit().unwrap().id();
+        let origin = Repository::open_bare(root.join("origin.git")).unwrap();
+        assert_eq!(origin.refname_to_id("refs/heads/main").unwrap(), head);
+        assert_eq!(
+            secrets.refname_to_id("refs/remotes/origin/main").unwrap(),
+            head
+        );
+        assert!(Repository::open(&fixtures.project).unwrap().head().is_ok());
+
+        drop(scratch);
+        assert!(!root.exists());
+    }
+}
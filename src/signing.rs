@@ -0,0 +1,295 @@
+//! Signatures on `.configure`, for projects that set `require_signed_updates` so pin bumps can be
+//! traced back to a trusted person.
+//!
+//! `configure update` signs the canonical contents of `.configure` (as configure itself writes it,
+//! so reformatting the file doesn't matter) with the user's git signing key, using `gpg`. The
+//! detached signature lives beside it in `.configure.sig`. `apply` and `validate` check it against
+//! the fingerprints listed in `allowed_signers.json` in the secrets repository at the pinned hash.
+
+use crate::fs::serialize_configuration;
+use crate::ConfigurationFile;
+use crate::ConfigureError;
+use log::debug;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+/// The detached signature for `.configure`, in the project root
+pub const SIGNATURE_FILE: &str = ".configure.sig";
+
+/// The list of fingerprints allowed to sign `.configure`, in the secrets repository root
+pub const ALLOWED_SIGNERS_FILE: &str = "allowed_signers.json";
+
+/// The bytes that are signed – `.configure` as configure would write it
+pub fn canonical_contents(configuration: &ConfigurationFile) -> Vec<u8> {
+    serialize_configuration(configuration).into_bytes()
+}
+
+/// Signs the configuration with the user's git signing key (or gpg's default key, if git doesn't
+/// have one), writing the signature to `.configure.sig`
+pub fn sign_configuration(
+    configuration: &ConfigurationFile,
+    project_root: &Path,
+) -> Result<(), ConfigureError> {
+    let mut args = vec!["--armor", "--detach-sign", "--output", "-"];
+
+    let signing_key = git_signing_key(project_root);
+    if let Some(key) = &signing_key {
+        args.extend(&["--local-user", key.as_str()]);
+    }
+
+    let output = run_gpg(&args, &canonical_contents(configuration))
+        .map_err(ConfigureError::SigningFailed)?;
+
+    if !output.status.success() || output.stdout.is_empty() {
+        let message = String::from_utf8_lossy(&output.stderr).trim().to_string();
+        return Err(ConfigureError::SigningFailed(message));
+    }
+
+    std::fs::write(project_root.join(SIGNATURE_FILE), output.stdout)?;
+    Ok(())
+}
+
+/// Checks `.configure.sig` is a good signature on the configuration by an allowed signer,
+/// returning the signer's fingerprint. The allowed signers are read from the secrets at the
+/// pinned hash, so changes to the secrets checkout that haven't been committed can't add any.
+pub fn verify_configuration_signature(
+    configuration: &ConfigurationFile,
+    project_root: &Path,
+) -> Result<String, ConfigureError> {
+    let signature_path = project_root.join(SIGNATURE_FILE);
+
+    if !signature_path.is_file() {
+        return Err(ConfigureError::SignatureMissing(SIGNATURE_FILE.to_string()));
+    }
+
+    let allowed = read_allowed_signers(&configuration.pinned_hash)?;
+
+    let signature_arg = signature_path.display().to_string();
+    let args = ["--status-fd", "1", "--verify", signature_arg.as_str(), "-"];
+    let output = run_gpg(&args, &canonical_contents(configuration))
+        .map_err(ConfigureError::SignatureInvalid)?;
+
+    let status = String::from_utf8_lossy(&output.stdout);
+    let signers = match parse_verify_status(&status) {
+        SignatureCheck::Good(signers) => signers,
+        SignatureCheck::Bad(message) => return Err(ConfigureError::SignatureInvalid(message)),
+    };
+
+    match signers.iter().find(|signer| allowed.contains(signer)) {
+        Some(signer) => Ok(signer.clone()),
+        None => Err(ConfigureError::SignerNotAllowed(signers[0].clone())),
+    }
+}
+
+/// What gpg made of a signature
+#[derive(Debug, Eq, PartialEq)]
+enum SignatureCheck {
+    /// The signature is good. These are the fingerprints of the signing key and, if that's a
+    /// subkey, its primary key.
+    Good(Vec<String>),
+
+    /// The signature is bad or couldn't be checked
+    Bad(String),
+}
+
+/// Reads gpg's machine-readable `--status-fd` output from `--verify`
+fn parse_verify_status(status: &str) -> SignatureCheck {
+    let mut problem = None;
+
+    for line in status.lines() {
+        let fields: Vec<&str> = line
+            .trim_start_matches("[GNUPG:]")
+            .split_whitespace()
+            .collect();
+
+        match fields.as_slice() {
+            ["VALIDSIG", fingerprint, rest @ ..] => {
+                let mut fingerprints = vec![normalize_fingerprint(fingerprint)];
+
+                // The primary key's fingerprint is the tenth field, after the signature's details
+                if let Some(primary) = rest.get(8) {
+                    let primary = normalize_fingerprint(primary);
+                    if !fingerprints.contains(&primary) {
+                        fingerprints.push(primary);
+                    }
+                }
+
+                return SignatureCheck::Good(fingerprints);
+            }
+            ["BADSIG", key_id, ..] => {
+                problem = Some(format!(
+                    "it was made by {}, but .configure has changed since",
+                    key_id
+                ))
+            }
+            ["NO_PUBKEY", key_id] => {
+                problem = Some(format!(
+                    "gpg doesn't have the public key {} it was made with",
+                    key_id
+                ))
+            }
+            ["EXPKEYSIG", key_id, ..] | ["REVKEYSIG", key_id, ..] => {
+                problem = Some(format!(
+                    "the key {} it was made with has expired or been revoked",
+                    key_id
+                ))
+            }
+            _ => {}
+        }
+    }
+
+    SignatureCheck::Bad(problem.unwrap_or_else(|| "gpg couldn't check it".to_string()))
+}
+
+/// Reads the fingerprints allowed to sign `.configure` from the secrets repository at `hash`
+fn read_allowed_signers(hash: &str) -> Result<Vec<String>, ConfigureError> {
+    let invalid = |message: String| ConfigureError::AllowedSignersInvalid(message);
+
+    match crate::git::read_file_at_ref(hash, ALLOWED_SIGNERS_FILE) {
+        Ok(Some(contents)) => parse_allowed_signers(&contents),
+        Ok(None) => Err(invalid(format!(
+            "it isn't in the secrets repository at {}",
+            crate::ui::display_hash(hash)
+        ))),
+        Err(err) => Err(invalid(format!("unable to read it: {}", err))),
+    }
+}
+
+/// Parses the list of fingerprints allowed to sign `.configure`, a JSON list like
+/// `["3AA5 C343 …"]`
+fn parse_allowed_signers(contents: &[u8]) -> Result<Vec<String>, ConfigureError> {
+    let fingerprints: Vec<String> = serde_json::from_slice(contents).map_err(|err| {
+        ConfigureError::AllowedSignersInvalid(format!(
+            "it isn't a JSON list of fingerprints: {}",
+            err
+        ))
+    })?;
+
+    Ok(fingerprints
+        .iter()
+        .map(|f| normalize_fingerprint(f))
+        .collect())
+}
+
+/// Fingerprints are compared without spaces and ignoring case, since they're written both ways
+fn normalize_fingerprint(fingerprint: &str) -> String {
+    fingerprint
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect::<String>()
+        .to_uppercase()
+}
+
+fn git_signing_key(project_root: &Path) -> Option<String> {
+    let output = crate::git::run_git(
+        Command::new("git")
+            .args(["config", "--get", "user.signingkey"])
+            .current_dir(project_root),
+    )
+    .ok()?;
+
+    let key = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if key.is_empty() {
+        None
+    } else {
+        Some(key)
+    }
+}
+
+/// Runs gpg with `input` on its standard input
+fn run_gpg(args: &[&str], input: &[u8]) -> Result<std::process::Output, String> {
+    debug!("Running gpg {}", args.join(" "));
+
+    let mut child = Command::new("gpg")
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|err| format!("unable to run gpg: {}", err))?;
+
+    child
+        .stdin
+        .take()
+        .expect("gpg's standard input is piped")
+        .write_all(input)
+        .map_err(|err| format!("unable to write to gpg: {}", err))?;
+
+    child
+        .wait_with_output()
+        .map_err(|err| format!("gpg didn't finish: {}", err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify_status_reads_signer_fingerprints() {
+        let subkey = "[GNUPG:] NEWSIG\n\
+            [GNUPG:] GOODSIG 1234ABCD Jane <jane@example.com>\n\
+            [GNUPG:] VALIDSIG 0A1B2C3D 2026-10-14 1792000000 0 4 0 1 10 00 FFEEDDCC\n";
+        assert_eq!(
+            parse_verify_status(subkey),
+            SignatureCheck::Good(vec!["0A1B2C3D".to_string(), "FFEEDDCC".to_string()])
+        );
+
+        let primary = "[GNUPG:] VALIDSIG ffeeddcc 2026-10-14 1792000000 0 4 0 1 10 00 FFEEDDCC\n";
+        assert_eq!(
+            parse_verify_status(primary),
+            SignatureCheck::Good(vec!["FFEEDDCC".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_verify_status_explains_bad_signatures() {
+        let message = |status: &str| match parse_verify_status(status) {
+            SignatureCheck::Bad(message) => message,
+            SignatureCheck::Good(_) => panic!("The signature shouldn't be good"),
+        };
+
+        assert_eq!(
+            message("[GNUPG:] BADSIG 1234ABCD Jane <jane@example.com>\n"),
+            "it was made by 1234ABCD, but .configure has changed since"
+        );
+        assert_eq!(
+            message(
+                "[GNUPG:] ERRSIG 1234ABCD 1 10 00 1792000000 9 -\n[GNUPG:] NO_PUBKEY 1234ABCD\n"
+            ),
+            "gpg doesn't have the public key 1234ABCD it was made with"
+        );
+        assert_eq!(message(""), "gpg couldn't check it");
+    }
+
+    #[test]
+    fn test_allowed_signers_are_normalized() {
+        assert_eq!(
+            parse_allowed_signers(br#"["3aa5 c343 7139", "FFEEDDCC"]"#).unwrap(),
+            vec!["3AA5C3437139", "FFEEDDCC"]
+        );
+
+        assert!(parse_allowed_signers(br#"{ "signers": [] }"#).is_err());
+    }
+
+    #[test]
+    fn test_allowed_signers_are_read_at_the_pinned_hash() {
+        let sandbox = crate::fixtures::Sandbox::new("{}");
+        let before = sandbox.secrets.head().unwrap().target().unwrap().to_string();
+        let pinned = crate::fixtures::commit_file(
+            &sandbox.secrets,
+            ALLOWED_SIGNERS_FILE,
+            r#"["AAAA"]"#,
+            "Allow a signer",
+        );
+
+        // An uncommitted change in the checkout doesn't let anyone else sign
+        std::fs::write(sandbox.secrets_path(ALLOWED_SIGNERS_FILE), r#"["BBBB"]"#).unwrap();
+        assert_eq!(read_allowed_signers(&pinned.to_string()).unwrap(), vec!["AAAA"]);
+
+        assert!(matches!(
+            read_allowed_signers(&before),
+            Err(ConfigureError::AllowedSignersInvalid(_))
+        ));
+    }
+}
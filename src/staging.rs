@@ -0,0 +1,294 @@
+//! Staging an update's encrypted files for review, for projects whose policy is that someone
+//! approves exactly which `.enc` bytes change before they reach the project.
+//!
+//! `configure update --stage-dir <dir>` writes the encrypted files into `<dir>`, laid out like the
+//! project, along with a manifest of each file's digest before and after. `configure promote` then
+//! copies them into the project, as long as neither the staged files nor the project's own copies
+//! have changed since.
+
+use crate::fs::{hash_bytes, temporary_path_for};
+use crate::ConfigureError;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::path::{Component, Path};
+
+/// The manifest's name in the staging directory
+pub const MANIFEST_FILE_NAME: &str = "configure-stage.json";
+
+/// What was staged, and what the project had when it was
+#[derive(Debug, Default, Serialize, Deserialize, Eq, PartialEq)]
+pub struct StageManifest {
+    pub files: Vec<StagedEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq)]
+pub struct StagedEntry {
+    /// The file's path, relative to both the project root and the staging directory
+    pub path: String,
+
+    /// The digest of the project's copy when it was staged, or `None` if there wasn't one
+    pub old_digest: Option<String>,
+
+    /// The digest of the staged copy
+    pub new_digest: String,
+
+    /// Whether promoting changes the project's copy
+    pub changed: bool,
+}
+
+/// Writes `files` (each a path relative to the project root, and its new contents) into
+/// `stage_dir`, with a manifest comparing them with the project's copies
+pub fn write_stage(
+    stage_dir: &Path,
+    project_root: &Path,
+    files: &[(String, Vec<u8>)],
+) -> Result<StageManifest, ConfigureError> {
+    let mut manifest = StageManifest::default();
+
+    for (path, contents) in files {
+        check_staged_path(path)?;
+
+        let staged_path = stage_dir.join(path);
+        if let Some(parent) = staged_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(&staged_path, contents)?;
+
+        let old_digest = digest_of(&project_root.join(path))?;
+        let new_digest = hash_bytes(contents);
+
+        manifest.files.push(StagedEntry {
+            path: path.clone(),
+            changed: old_digest.as_ref() != Some(&new_digest),
+            old_digest,
+            new_digest,
+        });
+    }
+
+    std::fs::write(
+        stage_dir.join(MANIFEST_FILE_NAME),
+        serde_json::to_string_pretty(&manifest).unwrap(),
+    )?;
+
+    Ok(manifest)
+}
+
+/// Reads the manifest in `stage_dir`
+pub fn read_stage_manifest(stage_dir: &Path) -> Result<StageManifest, ConfigureError> {
+    let path = stage_dir.join(MANIFEST_FILE_NAME);
+    let invalid = |message: String| ConfigureError::StageManifestInvalid(path.clone(), message);
+
+    let contents = std::fs::read_to_string(&path).map_err(|err| invalid(err.to_string()))?;
+    serde_json::from_str(&contents).map_err(|err| invalid(err.to_string()))
+}
+
+/// Copies the changed files in `stage_dir` into the project, returning their paths.
+///
+/// Every file is checked before anything is copied: the staged copy has to match the manifest's
+/// `new_digest`, and the project's copy its `old_digest`. The files are then written beside their
+/// destinations and moved into place one after another, so a failure part way through leaves no
+/// half-written file.
+///
+/// The manifest could have been edited by anyone, so every path in it has to be one of `allowed`,
+/// the project's encrypted files.
+pub fn promote_stage(
+    stage_dir: &Path,
+    project_root: &Path,
+    allowed: &HashSet<String>,
+) -> Result<Vec<String>, ConfigureError> {
+    let manifest = read_stage_manifest(stage_dir)?;
+
+    for entry in &manifest.files {
+        check_staged_path(&entry.path)?;
+
+        if !allowed.contains(&entry.path) {
+            return Err(ConfigureError::StagedPathNotAllowed(entry.path.clone()));
+        }
+    }
+    let changed: Vec<&StagedEntry> = manifest
+        .files
+        .iter()
+        .filter(|entry| entry.changed)
+        .collect();
+
+    let mut staged_contents = Vec::new();
+    for entry in &changed {
+        let contents = std::fs::read(stage_dir.join(&entry.path))
+            .map_err(|_| ConfigureError::StagedFileTampered(entry.path.clone()))?;
+
+        if hash_bytes(&contents) != entry.new_digest {
+            return Err(ConfigureError::StagedFileTampered(entry.path.clone()));
+        }
+
+        if digest_of(&project_root.join(&entry.path))? != entry.old_digest {
+            return Err(ConfigureError::StagedFileOutdated(entry.path.clone()));
+        }
+
+        staged_contents.push(contents);
+    }
+
+    let mut temporary_paths = Vec::new();
+    for (entry, contents) in changed.iter().zip(&staged_contents) {
+        let destination = project_root.join(&entry.path);
+        if let Some(parent) = destination.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let temporary_path = temporary_path_for(&destination);
+        if let Err(err) = std::fs::write(&temporary_path, contents) {
+            for (temporary_path, _) in temporary_paths {
+                let _ = std::fs::remove_file(temporary_path);
+            }
+            return Err(err.into());
+        }
+
+        temporary_paths.push((temporary_path, destination));
+    }
+
+    for (temporary_path, destination) in temporary_paths {
+        std::fs::rename(temporary_path, destination)?;
+    }
+
+    Ok(changed.iter().map(|entry| entry.path.clone()).collect())
+}
+
+/// Checks that `path` stays inside the directory it's joined to – it isn't absolute, and doesn't
+/// have a `..` or (on Windows) a drive
+fn check_staged_path(path: &str) -> Result<(), ConfigureError> {
+    let plain_relative = Path::new(path).components().next().is_some()
+        && Path::new(path)
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+
+    match plain_relative {
+        true => Ok(()),
+        false => Err(ConfigureError::StagedPathNotAllowed(path.to_string())),
+    }
+}
+
+fn digest_of(path: &Path) -> Result<Option<String>, ConfigureError> {
+    match std::fs::read(path) {
+        Ok(contents) => Ok(Some(hash_bytes(&contents))),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn project() -> (tempfile::TempDir, tempfile::TempDir) {
+        let project = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(project.path().join("config")).unwrap();
+        std::fs::write(project.path().join("config/a.json.enc"), "old a").unwrap();
+        std::fs::write(project.path().join("config/b.json.enc"), "same b").unwrap();
+        (project, tempfile::tempdir().unwrap())
+    }
+
+    fn allowed() -> HashSet<String> {
+        ["config/a.json.enc", "config/b.json.enc", "config/c.json.enc"]
+            .iter()
+            .map(|path| path.to_string())
+            .collect()
+    }
+
+    fn stage(project: &Path, stage_dir: &Path) -> StageManifest {
+        let files = vec![
+            ("config/a.json.enc".to_string(), b"new a".to_vec()),
+            ("config/b.json.enc".to_string(), b"same b".to_vec()),
+            ("config/c.json.enc".to_string(), b"new c".to_vec()),
+        ];
+        write_stage(stage_dir, project, &files).unwrap()
+    }
+
+    #[test]
+    fn test_staged_files_are_promoted_into_the_project() {
+        let (project, stage_dir) = project();
+        let manifest = stage(project.path(), stage_dir.path());
+
+        let changed: Vec<(&str, bool)> = manifest
+            .files
+            .iter()
+            .map(|entry| (entry.path.as_str(), entry.changed))
+            .collect();
+        assert_eq!(
+            changed,
+            vec![
+                ("config/a.json.enc", true),
+                ("config/b.json.enc", false),
+                ("config/c.json.enc", true)
+            ]
+        );
+        assert_eq!(manifest.files[2].old_digest, None);
+
+        // Staging doesn't touch the project
+        let read = |path: &str| std::fs::read_to_string(project.path().join(path)).unwrap();
+        assert_eq!(read("config/a.json.enc"), "old a");
+        assert_eq!(read_stage_manifest(stage_dir.path()).unwrap(), manifest);
+
+        assert_eq!(
+            promote_stage(stage_dir.path(), project.path(), &allowed()).unwrap(),
+            vec!["config/a.json.enc", "config/c.json.enc"]
+        );
+        assert_eq!(read("config/a.json.enc"), "new a");
+        assert_eq!(read("config/b.json.enc"), "same b");
+        assert_eq!(read("config/c.json.enc"), "new c");
+        assert!(!project
+            .path()
+            .join("config/a.json.enc.configure-tmp")
+            .exists());
+    }
+
+    #[test]
+    fn test_nothing_is_promoted_if_anything_changed_since_staging() {
+        let (project, stage_dir) = project();
+        stage(project.path(), stage_dir.path());
+
+        std::fs::write(stage_dir.path().join("config/c.json.enc"), "tampered").unwrap();
+        assert!(matches!(
+            promote_stage(stage_dir.path(), project.path(), &allowed()),
+            Err(ConfigureError::StagedFileTampered(path)) if path == "config/c.json.enc"
+        ));
+
+        stage(project.path(), stage_dir.path());
+        std::fs::write(project.path().join("config/c.json.enc"), "someone else's").unwrap();
+        assert!(matches!(
+            promote_stage(stage_dir.path(), project.path(), &allowed()),
+            Err(ConfigureError::StagedFileOutdated(path)) if path == "config/c.json.enc"
+        ));
+
+        // Neither attempt changed the project
+        let a = std::fs::read_to_string(project.path().join("config/a.json.enc")).unwrap();
+        assert_eq!(a, "old a");
+    }
+
+    #[test]
+    fn test_nothing_is_promoted_outside_the_projects_encrypted_files() {
+        let (project, stage_dir) = project();
+        let mut manifest = stage(project.path(), stage_dir.path());
+
+        for path in ["../../outside.enc", "/etc/outside.enc", "config/unlisted.json.enc"] {
+            manifest.files[0].path = path.to_string();
+            std::fs::write(
+                stage_dir.path().join(MANIFEST_FILE_NAME),
+                serde_json::to_string(&manifest).unwrap(),
+            )
+            .unwrap();
+
+            assert!(matches!(
+                promote_stage(stage_dir.path(), project.path(), &allowed()),
+                Err(ConfigureError::StagedPathNotAllowed(rejected)) if rejected == path
+            ));
+        }
+
+        let files = vec![("../outside.enc".to_string(), b"new".to_vec())];
+        assert!(matches!(
+            write_stage(stage_dir.path(), project.path(), &files),
+            Err(ConfigureError::StagedPathNotAllowed(_))
+        ));
+
+        let a = std::fs::read_to_string(project.path().join("config/a.json.enc")).unwrap();
+        assert_eq!(a, "old a");
+    }
+}
@@ -0,0 +1,278 @@
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{Error, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+/// The name of the sidecar file that records what the last `configure apply` wrote.
+///
+/// It lives next to `.configure` in the project root and should be git-ignored – it describes
+/// this machine's checkout, not the project.
+pub const STATE_FILE_NAME: &str = ".configure-state";
+
+/// What `configure apply` last wrote to each destination in the project
+#[derive(Debug, Default, Serialize, Deserialize, Eq, PartialEq)]
+pub struct ApplyState {
+    /// Keyed by `state_key`, so each variant's entry for a destination has its own record
+    #[serde(default)]
+    pub files: BTreeMap<String, FileState>,
+}
+
+/// What the records for the entry with `destination` and `variant` are kept under. Entries without
+/// a variant are kept under their destination alone, like they were before there were variants.
+pub fn state_key(destination: &str, variant: Option<&str>) -> String {
+    match variant {
+        Some(variant) => format!("{}:{}", variant, destination),
+        None => destination.to_string(),
+    }
+}
+
+/// Whether the records under `key` are for `destination`, for any variant or none
+fn is_key_for(key: &str, destination: &str) -> bool {
+    key == destination
+        || key
+            .strip_suffix(destination)
+            .is_some_and(|variant| variant.ends_with(':'))
+}
+
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq)]
+pub struct FileState {
+    /// The SHA-256 hash of the decrypted content written to the destination
+    pub digest: String,
+
+    /// The size of the decrypted content in bytes. Missing in state written by older versions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+
+    /// The SHA-256 hash of the encrypted file the content was decrypted from. While it's
+    /// unchanged, so are the secrets it holds, so they needn't be decrypted to compare them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encrypted_digest: Option<String>,
+
+    /// The git blob hash of the encrypted file, which can be compared with what's committed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encrypted_blob: Option<String>,
+
+    /// A digest of the entry's `encoding`, `transform` and `merge` at the time of the apply. The
+    /// same encrypted file gives different content once any of them changes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub settings: Option<String>,
+
+    /// The pinned hash at the time of the apply
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pinned_hash: Option<String>,
+
+    /// When the destination was last modified after the apply, in nanoseconds since the Unix
+    /// epoch. While it and the size are unchanged, the destination needn't be hashed again.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub modified: Option<u64>,
+
+    /// The digest of the incoming content the developer chose to keep their own file over, the
+    /// first time secrets were applied to it. `digest` is then their file's, not configure's.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kept_over: Option<String>,
+}
+
+/// Where applied content came from, recorded once it's safely in place
+#[derive(Debug, Default)]
+pub struct FileOrigin {
+    pub encrypted_digest: Option<String>,
+    pub encrypted_blob: Option<String>,
+    pub settings: Option<String>,
+    pub pinned_hash: String,
+    pub modified: Option<u64>,
+}
+
+impl ApplyState {
+    /// The digest of the content last applied to `destination`, if any
+    pub fn digest_for(&self, destination: &str) -> Option<&str> {
+        self.files.get(destination).map(|file| file.digest.as_str())
+    }
+
+    /// The size of the content last applied to `destination`, if known
+    pub fn size_for(&self, destination: &str) -> Option<u64> {
+        self.files.get(destination).and_then(|file| file.size)
+    }
+
+    /// The hash of the encrypted file the content last applied to `destination` came from, if
+    /// it came from one
+    pub fn encrypted_digest_for(&self, destination: &str) -> Option<&str> {
+        self.files
+            .get(destination)
+            .and_then(|file| file.encrypted_digest.as_deref())
+    }
+
+    /// The digest of the entry's settings the content last applied to `destination` was produced
+    /// with, if known
+    pub fn settings_for(&self, destination: &str) -> Option<&str> {
+        self.files
+            .get(destination)
+            .and_then(|file| file.settings.as_deref())
+    }
+
+    /// Records content written to `destination`. Where it came from is unknown until
+    /// `record_origin` is called.
+    pub fn record(&mut self, destination: &str, digest: String, size: u64) {
+        self.files.insert(
+            destination.to_string(),
+            FileState {
+                digest,
+                size: Some(size),
+                encrypted_digest: None,
+                encrypted_blob: None,
+                settings: None,
+                pinned_hash: None,
+                modified: None,
+                kept_over: None,
+            },
+        );
+    }
+
+    /// Records that the developer kept their own file at `destination`, whose content has
+    /// `digest`, rather than replacing it with the incoming content with `incoming_digest`
+    pub fn record_kept(
+        &mut self,
+        destination: &str,
+        digest: String,
+        size: u64,
+        incoming_digest: &str,
+    ) {
+        self.record(destination, digest, size);
+        if let Some(file) = self.files.get_mut(destination) {
+            file.kept_over = Some(incoming_digest.to_string());
+        }
+    }
+
+    /// The digest of what configure itself last wrote to `destination`, which isn't known for a
+    /// file the developer kept rather than have it replaced
+    pub fn applied_digest_for(&self, destination: &str) -> Option<&str> {
+        self.files
+            .get(destination)
+            .filter(|file| file.kept_over.is_none())
+            .map(|file| file.digest.as_str())
+    }
+
+    /// Whether the developer kept their own file at `destination` over this same incoming content,
+    /// and still has that file there
+    pub fn was_kept_over(
+        &self,
+        destination: &str,
+        incoming_digest: &str,
+        local_digest: &str,
+    ) -> bool {
+        self.files.get(destination).is_some_and(|file| {
+            file.kept_over.as_deref() == Some(incoming_digest) && file.digest == local_digest
+        })
+    }
+
+    /// The digest of what configure last wrote for the entry recorded under `key`, to compare with
+    /// `local_digest`, what's at its `destination` now. Variants can share a destination, so when
+    /// what's there is what configure wrote for another variant, that's configure's to replace too.
+    pub fn last_applied_digest<'a>(
+        &'a self,
+        key: &str,
+        destination: &str,
+        local_digest: Option<&'a str>,
+    ) -> Option<&'a str> {
+        let written_for_any_variant = |local_digest: &str| {
+            self.files.iter().any(|(key, file)| {
+                is_key_for(key, destination)
+                    && file.kept_over.is_none()
+                    && file.digest == local_digest
+            })
+        };
+
+        match local_digest {
+            Some(local_digest) if written_for_any_variant(local_digest) => Some(local_digest),
+            _ => self.applied_digest_for(key),
+        }
+    }
+
+    pub fn record_origin(&mut self, destination: &str, origin: FileOrigin) {
+        if let Some(file) = self.files.get_mut(destination) {
+            file.encrypted_digest = origin.encrypted_digest;
+            file.encrypted_blob = origin.encrypted_blob;
+            file.settings = origin.settings;
+            file.pinned_hash = Some(origin.pinned_hash);
+            file.modified = origin.modified;
+        }
+    }
+
+    /// Whether `destination` (at `path`) certainly still has the content last applied to it,
+    /// judging by its size and modification time alone. `false` means it has to be hashed to know.
+    pub fn is_unmodified(&self, destination: &str, path: &Path) -> bool {
+        let file = match self.files.get(destination) {
+            Some(file) => file,
+            None => return false,
+        };
+
+        match (file.size, file.modified, std::fs::metadata(path)) {
+            (Some(size), Some(modified), Ok(metadata)) => {
+                metadata.len() == size && modified_time(&metadata) == Some(modified)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// A file's modification time, in nanoseconds since the Unix epoch
+pub fn modified_time(metadata: &std::fs::Metadata) -> Option<u64> {
+    let since_epoch = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    u64::try_from(since_epoch.as_nanos()).ok()
+}
+
+pub fn state_file_path(project_root: &Path) -> PathBuf {
+    project_root.join(STATE_FILE_NAME)
+}
+
+/// Reads the apply state for the project.
+///
+/// A missing or unreadable state file isn't an error – it just means we don't know what was
+/// applied last, so every destination is treated as if it had never been applied.
+pub fn read_apply_state(project_root: &Path) -> ApplyState {
+    let path = state_file_path(project_root);
+
+    if !path.exists() {
+        debug!("No apply state found at {:?}", path);
+        return ApplyState::default();
+    }
+
+    let contents = match std::fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(err) => {
+            warn!("Unable to read apply state at {:?}: {}", path, err);
+            return ApplyState::default();
+        }
+    };
+
+    match serde_json::from_str(&contents) {
+        Ok(state) => state,
+        Err(err) => {
+            warn!("Ignoring invalid apply state at {:?}: {}", path, err);
+            ApplyState::default()
+        }
+    }
+}
+
+/// Forgets everything recorded about previous applies. Returns whether there was anything.
+pub fn remove_apply_state(project_root: &Path) -> Result<bool, Error> {
+    match std::fs::remove_file(state_file_path(project_root)) {
+        Ok(()) => Ok(true),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+pub fn save_apply_state(project_root: &Path, state: &ApplyState) -> Result<(), Error> {
+    let path = state_file_path(project_root);
+    let serialized = serde_json::to_string_pretty(state)?;
+
+    debug!("Writing apply state to: {:?}", path);
+
+    let mut file = File::create(path)?;
+    file.write_all(serialized.as_bytes())?;
+    Ok(())
+}
@@ -0,0 +1,207 @@
+//! The summary `configure apply` can write after it succeeds, so build scripts can record which
+//! secrets a build was made with (in an about screen or crash reports, for example).
+//!
+//! The summary isn't a secret and isn't tracked in `.configure-state` – it's rewritten on every
+//! apply, and configure never treats it as one of the project's decrypted files.
+//!
+//! There's also a plain-text report for people reading release artifacts, which accumulates
+//! across applies. Like the summary, it only names files – it never includes their contents.
+
+use crate::fs::hash_file;
+use crate::{ApplyReport, ConfigurationFile, ConfigureError};
+use chrono::prelude::*;
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::{Error, Write};
+use std::path::Path;
+
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq)]
+pub struct ApplySummary {
+    pub project_name: String,
+    pub branch: String,
+    pub pinned_hash: String,
+
+    /// The version of configure that did the apply
+    pub configure_version: String,
+
+    /// When the apply finished, in RFC 3339 format
+    pub applied_at: String,
+
+    /// The SHA-256 hash of each decrypted file, keyed by destination. These are hashes of the
+    /// plaintext, so they only change when the secret itself does – not when it's re-encrypted.
+    pub files: BTreeMap<String, String>,
+}
+
+/// Summarizes the decrypted files currently in the project. Files that aren't there (like
+/// optional secrets that weren't available) are left out.
+pub fn build_summary(
+    configuration: &ConfigurationFile,
+    project_root: &Path,
+) -> Result<ApplySummary, ConfigureError> {
+    let mut files = BTreeMap::new();
+
+    for file in &configuration.files_to_copy {
+        let path = project_root.join(file.get_decrypted_destination()?);
+
+        if path.is_file() {
+            files.insert(file.destination.clone(), hash_file(&path)?);
+        }
+    }
+
+    Ok(ApplySummary {
+        project_name: configuration.project_name.clone(),
+        branch: configuration.branch.clone(),
+        pinned_hash: configuration.pinned_hash.clone(),
+        configure_version: env!("CARGO_PKG_VERSION").to_string(),
+        applied_at: Utc::now().to_rfc3339(),
+        files,
+    })
+}
+
+pub fn write_summary(path: &Path, summary: &ApplySummary) -> Result<(), Error> {
+    debug!("Writing apply summary to: {:?}", path);
+
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let serialized = serde_json::to_string_pretty(summary)?;
+    std::fs::write(path, serialized)
+}
+
+/// Adds a report of an apply to the end of the file at `path`, so one report can cover several
+/// applies (like each step of a release)
+pub fn append_report(
+    path: &Path,
+    configuration: &ConfigurationFile,
+    result: Result<&ApplyReport, &ConfigureError>,
+) -> Result<(), Error> {
+    debug!("Adding apply report to: {:?}", path);
+
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+
+    file.write_all(format_report(configuration, result, &Utc::now().to_rfc3339()).as_bytes())
+}
+
+fn format_report(
+    configuration: &ConfigurationFile,
+    result: Result<&ApplyReport, &ConfigureError>,
+    applied_at: &str,
+) -> String {
+    let mut lines = vec![
+        format!("configure apply – {}", applied_at),
+        format!("Project: {}", configuration.project_name),
+        format!(
+            "Secrets: {} at {}",
+            configuration.branch, configuration.pinned_hash
+        ),
+        format!("configure version: {}", env!("CARGO_PKG_VERSION")),
+    ];
+
+    match result {
+        Ok(report) => {
+            lines.push("Outcome: succeeded".to_string());
+            lines.extend(
+                report
+                    .files
+                    .iter()
+                    .map(|(destination, outcome)| format!("  {}", outcome.describe(destination))),
+            );
+            lines.push(report.summary());
+        }
+        Err(err) => lines.push(format!("Outcome: failed – {}", err)),
+    }
+
+    format!("{}\n\n", lines.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{File, FileOutcome};
+
+    #[test]
+    fn test_summary_has_plaintext_digests_of_applied_files() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.json"), "{}").unwrap();
+
+        let configuration = ConfigurationFile {
+            project_name: "WordPress".to_string(),
+            branch: "trunk".to_string(),
+            pinned_hash: "abc123".to_string(),
+            files_to_copy: vec![
+                File {
+                    source: "a.json".to_string(),
+                    destination: "a.json".to_string(),
+                    ..Default::default()
+                },
+                File {
+                    source: "b.json".to_string(),
+                    destination: "b.json".to_string(),
+                    optional: true,
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+
+        let summary = build_summary(&configuration, dir.path()).unwrap();
+
+        assert_eq!(summary.project_name, "WordPress");
+        assert_eq!(summary.pinned_hash, "abc123");
+        assert_eq!(summary.configure_version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(summary.files.len(), 1);
+        assert_eq!(
+            summary.files["a.json"],
+            hash_file(&dir.path().join("a.json")).unwrap()
+        );
+
+        let path = dir.path().join("build/secrets-summary.json");
+        write_summary(&path, &summary).unwrap();
+
+        let written: ApplySummary =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(written, summary);
+    }
+
+    #[test]
+    fn test_reports_accumulate_without_secret_contents() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("apply-report.txt");
+
+        let configuration = ConfigurationFile {
+            project_name: "WordPress".to_string(),
+            branch: "trunk".to_string(),
+            pinned_hash: "abc123".to_string(),
+            ..Default::default()
+        };
+
+        let mut report = ApplyReport::default();
+        report.push("a.json", FileOutcome::Created);
+        report.push("b.json", FileOutcome::Unchanged);
+
+        append_report(&path, &configuration, Ok(&report)).unwrap();
+        append_report(
+            &path,
+            &configuration,
+            Err(&ConfigureError::ProjectNameRequired),
+        )
+        .unwrap();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let reports: Vec<&str> = contents.trim_end().split("\n\n").collect();
+        assert_eq!(reports.len(), 2);
+
+        assert!(reports[0].contains("Secrets: trunk at abc123"));
+        assert!(reports[0].contains("Outcome: succeeded"));
+        assert!(reports[0].contains("  Created a.json"));
+        assert!(reports[0].contains("  b.json is already up to date"));
+        assert!(reports[0].ends_with("Applied 2 file(s): 1 created, 1 unchanged"));
+
+        assert!(reports[1].contains("Outcome: failed – Which project is the key for?"));
+    }
+}
@@ -0,0 +1,122 @@
+use crate::configure::{ConfigureError, File};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// The built-in templates, keyed by name. Add an entry to this file to add a template.
+const BUILT_IN_TEMPLATES: &str = include_str!("templates.json");
+
+/// Replaced with the project's name in a template's paths
+const PROJECT_NAME_PLACEHOLDER: &str = "{project_name}";
+
+/// A set of conventional `files_to_copy` entries for a kind of project
+#[derive(Debug, Deserialize, Eq, PartialEq)]
+pub struct Template {
+    #[serde(default)]
+    pub description: String,
+
+    pub files_to_copy: Vec<File>,
+}
+
+impl Template {
+    /// The template's entries, with `{project_name}` in their paths replaced by `project_name`
+    pub fn files_for_project(&self, project_name: &str) -> Vec<File> {
+        self.files_to_copy
+            .iter()
+            .map(|file| File {
+                source: file.source.replace(PROJECT_NAME_PLACEHOLDER, project_name),
+                destination: file
+                    .destination
+                    .replace(PROJECT_NAME_PLACEHOLDER, project_name),
+                ..file.clone()
+            })
+            .collect()
+    }
+}
+
+fn built_in_templates() -> BTreeMap<String, Template> {
+    serde_json::from_str(BUILT_IN_TEMPLATES).expect("The built-in templates are invalid")
+}
+
+/// Looks up one of the built-in templates by name
+pub fn built_in_template(name: &str) -> Result<Template, ConfigureError> {
+    let mut templates = built_in_templates();
+
+    match templates.remove(name) {
+        Some(template) => Ok(template),
+        None => Err(ConfigureError::TemplateError(format!(
+            "There's no template named {:?}. Available templates: {}",
+            name,
+            templates.keys().cloned().collect::<Vec<_>>().join(", ")
+        ))),
+    }
+}
+
+/// Reads a custom template from disk. It has the same shape as a built-in template.
+pub fn read_template_file(path: &Path) -> Result<Template, ConfigureError> {
+    let contents = std::fs::read_to_string(path).map_err(|err| {
+        ConfigureError::TemplateError(format!("Unable to read {:?}: {}", path, err))
+    })?;
+
+    serde_json::from_str(&contents).map_err(|err| {
+        ConfigureError::TemplateError(format!("{:?} isn't a valid template: {}", path, err))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_built_in_templates_are_valid() {
+        let templates = built_in_templates();
+
+        for name in &["ios", "android", "react-native"] {
+            assert!(
+                !templates[*name].files_to_copy.is_empty(),
+                "{} is empty",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn test_template_paths_use_the_project_name() {
+        let files = built_in_template("ios")
+            .unwrap()
+            .files_for_project("WordPress");
+
+        assert_eq!(files[0].source, "iOS/WordPress/Secrets.swift");
+        assert_eq!(files[0].destination, "WordPress/Credentials/Secrets.swift");
+        assert_eq!(files[1].format, Some("plist".to_string()));
+    }
+
+    #[test]
+    fn test_unknown_template_lists_the_available_ones() {
+        let message = built_in_template("windows-phone").unwrap_err().to_string();
+        assert!(
+            message.contains("android, ios, react-native"),
+            "{}",
+            message
+        );
+    }
+
+    #[test]
+    fn test_template_file_is_read_from_disk() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("template.json");
+        std::fs::write(
+            &path,
+            r#"{ "files_to_copy": [{ "file": "{project_name}/.env", "destination": ".env" }] }"#,
+        )
+        .unwrap();
+
+        let files = read_template_file(&path)
+            .unwrap()
+            .files_for_project("Simplenote");
+        assert_eq!(files[0].source, "Simplenote/.env");
+
+        std::fs::write(&path, "{}").unwrap();
+        assert!(read_template_file(&path).is_err());
+    }
+}
@@ -0,0 +1,454 @@
+//! Converting a secret kept as JSON into the format its destination needs – a plist, an
+//! `.xcconfig`, or a Java `.properties` file – as it's applied. The secret is still kept, and
+//! encrypted, as JSON since that's easier to review, so there's no converting back.
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// What a secret kept as JSON becomes at its destination
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub enum Transform {
+    /// An XML property list, with JSON objects as dictionaries
+    #[serde(rename = "json_to_plist")]
+    Plist,
+
+    /// `NAME = value` build settings, with the keys of nested objects joined by `_`
+    #[serde(rename = "json_to_xcconfig")]
+    Xcconfig,
+
+    /// `key=value` lines, with the keys of nested objects joined by `.` and array items as
+    /// `key[0]`, like Spring does
+    #[serde(rename = "json_to_properties")]
+    Properties,
+}
+
+impl std::fmt::Display for Transform {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Transform::Plist => write!(f, "json_to_plist"),
+            Transform::Xcconfig => write!(f, "json_to_xcconfig"),
+            Transform::Properties => write!(f, "json_to_properties"),
+        }
+    }
+}
+
+/// Why a secret couldn't be transformed
+#[derive(Debug, Eq, PartialEq)]
+pub struct TransformError {
+    /// Where in the JSON the problem is, like `services[2].token`. Empty for the document itself.
+    pub path: String,
+
+    pub message: String,
+}
+
+impl std::fmt::Display for TransformError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.path.is_empty() {
+            write!(f, "the document {}", self.message)
+        } else {
+            write!(f, "{} {}", self.path, self.message)
+        }
+    }
+}
+
+type TransformResult<T> = Result<T, TransformError>;
+
+fn error<T>(path: &str, message: &str) -> TransformResult<T> {
+    Err(TransformError {
+        path: path.to_string(),
+        message: message.to_string(),
+    })
+}
+
+fn join_key(path: &str, key: &str) -> String {
+    match path {
+        "" => key.to_string(),
+        _ => format!("{}.{}", path, key),
+    }
+}
+
+fn join_index(path: &str, index: usize) -> String {
+    format!("{}[{}]", path, index)
+}
+
+impl Transform {
+    /// Converts `contents`, which should be JSON
+    pub fn apply(&self, contents: &[u8]) -> TransformResult<Vec<u8>> {
+        let document: Value = match serde_json::from_slice(contents) {
+            Ok(document) => document,
+            Err(err) => return error("", &format!("isn't valid JSON ({})", err)),
+        };
+
+        let output = match self {
+            Transform::Plist => to_plist(&document)?,
+            Transform::Xcconfig => to_xcconfig(&document)?,
+            Transform::Properties => to_properties(&document)?,
+        };
+
+        Ok(output.into_bytes())
+    }
+}
+
+fn to_plist(document: &Value) -> TransformResult<String> {
+    let mut output = String::from(concat!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+        "<!DOCTYPE plist PUBLIC \"-//Apple//DTD PLIST 1.0//EN\" ",
+        "\"http://www.apple.com/DTDs/PropertyList-1.0.dtd\">\n",
+        "<plist version=\"1.0\">\n",
+    ));
+    write_plist_value(document, "", 0, &mut output)?;
+    output.push_str("</plist>\n");
+
+    Ok(output)
+}
+
+fn write_plist_value(
+    value: &Value,
+    path: &str,
+    depth: usize,
+    output: &mut String,
+) -> TransformResult<()> {
+    let indent = "\t".repeat(depth);
+
+    match value {
+        Value::Null => return error(path, "is null, which a plist can't hold"),
+        Value::Bool(true) => output.push_str(&format!("{}<true/>\n", indent)),
+        Value::Bool(false) => output.push_str(&format!("{}<false/>\n", indent)),
+        Value::Number(number) if number.is_f64() => {
+            output.push_str(&format!("{}<real>{}</real>\n", indent, number))
+        }
+        Value::Number(number) => {
+            output.push_str(&format!("{}<integer>{}</integer>\n", indent, number))
+        }
+        Value::String(string) => {
+            let escaped = escape_xml(string).ok_or_else(|| TransformError {
+                path: path.to_string(),
+                message: "has a control character, which a plist can't hold".to_string(),
+            })?;
+            output.push_str(&format!("{}<string>{}</string>\n", indent, escaped));
+        }
+        Value::Array(items) if items.is_empty() => {
+            output.push_str(&format!("{}<array/>\n", indent))
+        }
+        Value::Array(items) => {
+            output.push_str(&format!("{}<array>\n", indent));
+            for (index, item) in items.iter().enumerate() {
+                write_plist_value(item, &join_index(path, index), depth + 1, output)?;
+            }
+            output.push_str(&format!("{}</array>\n", indent));
+        }
+        Value::Object(object) if object.is_empty() => {
+            output.push_str(&format!("{}<dict/>\n", indent))
+        }
+        Value::Object(object) => {
+            output.push_str(&format!("{}<dict>\n", indent));
+            for (key, item) in object {
+                let item_path = join_key(path, key);
+                let escaped = escape_xml(key).ok_or_else(|| TransformError {
+                    path: item_path.clone(),
+                    message: "has a control character in its name".to_string(),
+                })?;
+                output.push_str(&format!("{}\t<key>{}</key>\n", indent, escaped));
+                write_plist_value(item, &item_path, depth + 1, output)?;
+            }
+            output.push_str(&format!("{}</dict>\n", indent));
+        }
+    }
+
+    Ok(())
+}
+
+/// Escapes `text` for XML, or returns `None` if it has characters XML can't hold at all
+fn escape_xml(text: &str) -> Option<String> {
+    let mut escaped = String::with_capacity(text.len());
+
+    for character in text.chars() {
+        match character {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '\t' | '\n' | '\r' => escaped.push(character),
+            character if character.is_control() => return None,
+            character => escaped.push(character),
+        }
+    }
+
+    Some(escaped)
+}
+
+fn to_xcconfig(document: &Value) -> TransformResult<String> {
+    let object = match document {
+        Value::Object(object) => object,
+        _ => return error("", "should be an object to become build settings"),
+    };
+
+    let mut settings = Vec::new();
+    flatten_xcconfig(object, "", "", &mut settings)?;
+
+    Ok(settings
+        .iter()
+        .map(|(name, value)| format!("{} = {}\n", name, value))
+        .collect())
+}
+
+fn flatten_xcconfig(
+    object: &serde_json::Map<String, Value>,
+    path: &str,
+    prefix: &str,
+    settings: &mut Vec<(String, String)>,
+) -> TransformResult<()> {
+    for (key, value) in object {
+        let item_path = join_key(path, key);
+        let name = match prefix {
+            "" => key.clone(),
+            _ => format!("{}_{}", prefix, key),
+        };
+
+        if let Value::Object(nested) = value {
+            flatten_xcconfig(nested, &item_path, &name, settings)?;
+            continue;
+        }
+
+        let is_valid_name = name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+            && name.chars().next().is_some_and(|c| !c.is_ascii_digit());
+        if !is_valid_name {
+            return error(
+                &item_path,
+                &format!("would be the build setting {:?}, which isn't a valid name", name),
+            );
+        }
+
+        let value = match value {
+            Value::Array(items) => {
+                let mut words = Vec::new();
+                for (index, item) in items.iter().enumerate() {
+                    let item_path = join_index(&item_path, index);
+                    if item.is_array() || item.is_object() {
+                        return error(
+                            &item_path,
+                            "is nested in a list, which build settings can't hold",
+                        );
+                    }
+
+                    let word = xcconfig_scalar(item, &item_path)?;
+                    if word.is_empty() || word.contains(char::is_whitespace) {
+                        words.push(format!("\"{}\"", word.replace('"', "\\\"")));
+                    } else {
+                        words.push(word);
+                    }
+                }
+                words.join(" ")
+            }
+            value => xcconfig_scalar(value, &item_path)?,
+        };
+
+        settings.push((name, value));
+    }
+
+    Ok(())
+}
+
+fn xcconfig_scalar(value: &Value, path: &str) -> TransformResult<String> {
+    let text = match value {
+        Value::Null => return error(path, "is null, which build settings can't hold"),
+        Value::Bool(true) => "YES".to_string(),
+        Value::Bool(false) => "NO".to_string(),
+        Value::Number(number) => number.to_string(),
+        Value::String(string) => string.clone(),
+        Value::Array(_) | Value::Object(_) => unreachable!("only called for scalars"),
+    };
+
+    if text.contains(['\n', '\r']) {
+        return error(path, "has a line break, which build settings can't hold");
+    }
+
+    // `//` starts a comment, so URLs need it broken up with an empty substitution
+    Ok(text.replace("//", "/$()/"))
+}
+
+fn to_properties(document: &Value) -> TransformResult<String> {
+    if !document.is_object() {
+        return error("", "should be an object to become properties");
+    }
+
+    let mut lines = Vec::new();
+    flatten_properties(document, "", &mut lines)?;
+
+    Ok(lines.iter().map(|line| format!("{}\n", line)).collect())
+}
+
+fn flatten_properties(value: &Value, path: &str, lines: &mut Vec<String>) -> TransformResult<()> {
+    let text = match value {
+        Value::Object(object) => {
+            for (key, item) in object {
+                flatten_properties(item, &join_key(path, key), lines)?;
+            }
+            return Ok(());
+        }
+        Value::Array(items) => {
+            for (index, item) in items.iter().enumerate() {
+                flatten_properties(item, &join_index(path, index), lines)?;
+            }
+            return Ok(());
+        }
+        Value::Null => return error(path, "is null, which a .properties file can't hold"),
+        Value::Bool(value) => value.to_string(),
+        Value::Number(number) => number.to_string(),
+        Value::String(string) => string.clone(),
+    };
+
+    lines.push(format!(
+        "{}={}",
+        escape_properties(path, true),
+        escape_properties(&text, false)
+    ));
+    Ok(())
+}
+
+/// Escapes `text` as a `.properties` key or value, using `\u` escapes for anything that isn't
+/// printable ASCII, since Java reads these files as ISO 8859-1
+fn escape_properties(text: &str, is_key: bool) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for (index, character) in text.chars().enumerate() {
+        match character {
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            ' ' if is_key || index == 0 => escaped.push_str("\\ "),
+            '=' | ':' if is_key => escaped.push_str(&format!("\\{}", character)),
+            '#' | '!' if index == 0 => escaped.push_str(&format!("\\{}", character)),
+            ' '..='~' => escaped.push(character),
+            character => {
+                let mut units = [0; 2];
+                for unit in character.encode_utf16(&mut units) {
+                    escaped.push_str(&format!("\\u{:04X}", unit));
+                }
+            }
+        }
+    }
+
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(transform: Transform, json: &str) -> TransformResult<String> {
+        transform
+            .apply(json.as_bytes())
+            .map(|output| String::from_utf8(output).unwrap())
+    }
+
+    #[test]
+    fn test_json_becomes_a_plist() {
+        let json = r#"{
+            "api": { "key": "a&b", "hosts": ["one", 2, 3.5, true] },
+            "debug": false,
+            "empty": {}
+        }"#;
+
+        let plist = apply(Transform::Plist, json).unwrap();
+        let body: Vec<&str> = plist.lines().skip(3).collect();
+        assert_eq!(
+            body,
+            vec![
+                "<dict>",
+                "\t<key>api</key>",
+                "\t<dict>",
+                "\t\t<key>key</key>",
+                "\t\t<string>a&amp;b</string>",
+                "\t\t<key>hosts</key>",
+                "\t\t<array>",
+                "\t\t\t<string>one</string>",
+                "\t\t\t<integer>2</integer>",
+                "\t\t\t<real>3.5</real>",
+                "\t\t\t<true/>",
+                "\t\t</array>",
+                "\t</dict>",
+                "\t<key>debug</key>",
+                "\t<false/>",
+                "\t<key>empty</key>",
+                "\t<dict/>",
+                "</dict>",
+                "</plist>",
+            ]
+        );
+        assert!(plist.starts_with("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n"));
+
+        assert_eq!(
+            apply(Transform::Plist, r#"{ "api": { "hosts": ["one", null] } }"#),
+            error("api.hosts[1]", "is null, which a plist can't hold")
+        );
+    }
+
+    #[test]
+    fn test_json_becomes_build_settings() {
+        let json = r#"{
+            "API": { "KEY": "secret", "URL": "https://example.com" },
+            "FLAGS": ["-DDEBUG", "two words", 3],
+            "ENABLED": true,
+            "RETRIES": 3
+        }"#;
+
+        assert_eq!(
+            apply(Transform::Xcconfig, json).unwrap(),
+            concat!(
+                "API_KEY = secret\n",
+                "API_URL = https:/$()/example.com\n",
+                "FLAGS = -DDEBUG \"two words\" 3\n",
+                "ENABLED = YES\n",
+                "RETRIES = 3\n",
+            )
+        );
+
+        assert_eq!(
+            apply(Transform::Xcconfig, r#"{ "API": { "key-id": "a" } }"#),
+            error(
+                "API.key-id",
+                "would be the build setting \"API_key-id\", which isn't a valid name"
+            )
+        );
+        assert_eq!(
+            apply(Transform::Xcconfig, r#"{ "HOSTS": [["a"]] }"#),
+            error("HOSTS[0]", "is nested in a list, which build settings can't hold")
+        );
+        assert_eq!(
+            apply(Transform::Xcconfig, "[1]"),
+            error("", "should be an object to become build settings")
+        );
+    }
+
+    #[test]
+    fn test_json_becomes_properties() {
+        let json = r#"{
+            "api": { "key": "a=b", "hosts": ["one", "two"] },
+            "greeting": " héllo",
+            "debug": false,
+            "timeout": 2.5
+        }"#;
+
+        assert_eq!(
+            apply(Transform::Properties, json).unwrap(),
+            concat!(
+                "api.key=a=b\n",
+                "api.hosts[0]=one\n",
+                "api.hosts[1]=two\n",
+                "greeting=\\ h\\u00E9llo\n",
+                "debug=false\n",
+                "timeout=2.5\n",
+            )
+        );
+
+        assert_eq!(
+            apply(Transform::Properties, r#"{ "api": [{ "key": null }] }"#),
+            error("api[0].key", "is null, which a .properties file can't hold")
+        );
+        assert!(apply(Transform::Properties, "{")
+            .unwrap_err()
+            .message
+            .starts_with("isn't valid JSON"));
+    }
+}
@@ -1,51 +1,415 @@
-use console::{style, Term};
-use dialoguer::{theme::ColorfulTheme, Confirm, Input, Select};
+use console::{style, Color, Term};
+use dialoguer::{theme::ColorfulTheme, Confirm, Input, MultiSelect, Select};
+use indicatif::ProgressBar;
+use crate::answers::Answers;
+use crate::interaction::has_interaction_handler;
+use crate::ConfigureError;
+use serde_json::{json, Value};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+static NON_INTERACTIVE: AtomicBool = AtomicBool::new(false);
+
+///
+/// Disable all prompts for the rest of this process
+pub fn set_non_interactive(non_interactive: bool) {
+    NON_INTERACTIVE.store(non_interactive, Ordering::SeqCst);
+}
+
+/// How many characters of a commit hash are shown, unless asked for more
+pub const SHORT_HASH_LENGTH: usize = 8;
+
+static HASH_LENGTH: AtomicUsize = AtomicUsize::new(SHORT_HASH_LENGTH);
+
+///
+/// Show this many characters of commit hashes for the rest of this process, or all of them if
+/// `None`. Hashes are always stored and compared in full.
+pub fn set_hash_length(length: Option<usize>) {
+    HASH_LENGTH.store(length.unwrap_or(usize::MAX), Ordering::SeqCst);
+}
+
+///
+/// A commit hash as it's shown to people
+pub fn display_hash(hash: &str) -> &str {
+    shorten_hash(hash, HASH_LENGTH.load(Ordering::SeqCst))
+}
+
+fn shorten_hash(hash: &str, length: usize) -> &str {
+    match hash.char_indices().nth(length) {
+        Some((end, _)) => &hash[..end],
+        None => hash,
+    }
+}
+
+///
+/// Whether there's a user at the terminal (or an answer file) to answer prompts
+pub fn is_interactive() -> bool {
+    let answered = ANSWERS.lock().unwrap().is_some() || has_interaction_handler();
+    !NON_INTERACTIVE.load(Ordering::SeqCst) && (answered || console::user_attended())
+}
+
+/// Which kind of output a line is, and so where it goes
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Stream {
+    /// What the user asked to see, like a list of secrets. Printed to stdout.
+    Output,
+
+    /// Warnings and other messages about how things are going. Printed to stderr.
+    Diagnostics,
+}
+
+/// Receives each line of output instead of it being printed
+pub type OutputHandler = Box<dyn FnMut(Stream, &str) + Send>;
+
+/// Where output goes instead of the terminal, if anywhere
+static OUTPUT_HANDLER: Mutex<Option<OutputHandler>> = Mutex::new(None);
+
+/// The progress bar that's showing, if there is one. Lines are printed above it rather than
+/// through it.
+static PROGRESS_BAR: Mutex<Option<ProgressBar>> = Mutex::new(None);
+
+///
+/// Send output to `handler` instead of the terminal, for tests and programs using configure as a
+/// library. Lines are given without colors. The handler mustn't print through this module itself.
+pub fn set_output_handler(handler: Option<OutputHandler>) {
+    *OUTPUT_HANDLER.lock().unwrap() = handler;
+}
+
+///
+/// Print output above `bar` until it's cleared with `None`, so lines don't end up mid-bar
+pub fn set_progress_bar(bar: Option<ProgressBar>) {
+    *PROGRESS_BAR.lock().unwrap() = bar;
+}
+
+/// Every line of output goes through here, so lines are written whole and in order
+fn write_line(stream: Stream, plain: &str, styled: impl std::fmt::Display) {
+    // Holding the lock while writing keeps lines from different threads from interleaving
+    let mut handler = OUTPUT_HANDLER.lock().unwrap();
+
+    if let Some(handler) = handler.as_mut() {
+        return handler(stream, plain);
+    }
+
+    let bar = PROGRESS_BAR.lock().unwrap();
+    match bar.as_ref() {
+        Some(bar) if !bar.is_finished() && !bar.is_hidden() => bar.println(styled.to_string()),
+        _ if stream == Stream::Diagnostics => eprintln!("{}", styled),
+        _ => println!("{}", styled),
+    }
+}
+
+///
+/// Print a line of output to the console
+pub fn print(string: &str) {
+    write_line(Stream::Output, string, string)
+}
+
+///
+/// Print a line of output to the console in a color, for things like diffs
+pub fn print_in(color: Color, string: &str) {
+    write_line(Stream::Output, string, style(string).fg(color))
+}
 
 ///
 /// Print a heading-style message to the console
 pub fn heading(string: &str) {
-    println!("{}", style(string).green());
+    write_line(Stream::Output, string, style(string).green())
 }
 
 ///
 /// Print a warning to the console
 pub fn warn(string: &str) {
-    println!("{}", style(string).yellow());
+    write_line(Stream::Diagnostics, string, style(string).yellow())
+}
+
+///
+/// Print a line of tracing, like the git commands `--trace-git` shows, whatever the verbosity
+pub fn trace(string: &str) {
+    write_line(Stream::Diagnostics, string, style(string).dim())
 }
 
 ///
 /// Print a blank line to the console
 pub fn newline() {
-    println!();
+    print("")
+}
+
+/// Answers to give instead of asking, from `--answers`
+static ANSWERS: Mutex<Option<Answers>> = Mutex::new(None);
+
+/// The answers given so far, if the session is being recorded with `--record-answers`
+static RECORDING: Mutex<Option<Answers>> = Mutex::new(None);
+
+///
+/// Answer prompts from an answer file for the rest of this process, instead of asking
+pub fn use_answers(path: &Path) -> Result<(), ConfigureError> {
+    *ANSWERS.lock().unwrap() = Some(Answers::read(path)?);
+    Ok(())
+}
+
+///
+/// Start recording the answers to prompts, so they can be saved as an answer file
+pub fn record_answers() {
+    *RECORDING.lock().unwrap() = Some(Answers::default());
+}
+
+///
+/// Save the answers recorded since `record_answers` as an answer file
+pub fn save_recorded_answers(path: &Path) -> Result<(), ConfigureError> {
+    match RECORDING.lock().unwrap().as_ref() {
+        Some(recording) => recording.write(path),
+        None => Ok(()),
+    }
+}
+
+/// Looks up the answer to the prompt `id` in the answer file. When there's an answer file, every
+/// prompt must be answered, so a missing answer is an error rather than silently asking.
+fn answer<T>(
+    id: &str,
+    take: impl FnOnce(&mut Answers) -> Option<T>,
+) -> Result<Option<T>, ConfigureError> {
+    let mut answers = ANSWERS.lock().unwrap();
+    let answers = match answers.as_mut() {
+        Some(answers) => answers,
+        None => return Ok(None),
+    };
+
+    match take(answers) {
+        Some(answer) => Ok(Some(answer)),
+        None => Err(ConfigureError::AnswerMissing(id.to_string())),
+    }
+}
+
+fn record(save: impl FnOnce(&mut Answers)) {
+    if let Some(recording) = RECORDING.lock().unwrap().as_mut() {
+        save(recording)
+    }
+}
+
+fn invalid_answer(id: &str, problem: &str) -> ConfigureError {
+    ConfigureError::AnswersInvalid(format!("the answer to {:?} {}", id, problem))
+}
+
+/// Finds the option an answer refers to, either by its text or its index
+fn option_index(id: &str, answer: &Value, items: &[&str]) -> Result<usize, ConfigureError> {
+    let index = match answer {
+        Value::String(text) => items.iter().position(|item| item == text),
+        Value::Number(number) => number
+            .as_u64()
+            .map(|index| index as usize)
+            .filter(|index| *index < items.len()),
+        _ => None,
+    };
+
+    index.ok_or_else(|| {
+        invalid_answer(
+            id,
+            &format!("should be one of: {}", items.join(", ")),
+        )
+    })
 }
 
 ///
 /// Prompt the user to input text on the command line
-pub fn prompt(message: &str) -> String {
+pub fn prompt(id: &str, message: &str) -> Result<String, ConfigureError> {
     heading(message);
-    Input::<String>::new().interact_text().unwrap()
+
+    let text = match answer(id, |answers| answers.take(id))? {
+        Some(Value::String(text)) => {
+            print(&text);
+            text
+        }
+        Some(_) => return Err(invalid_answer(id, "should be text")),
+        None => Input::<String>::new()
+            .interact_text()
+            .map_err(ConfigureError::PromptFailed)?,
+    };
+
+    record(|recording| recording.record(id, json!(text)));
+    Ok(text)
 }
 
 ///
 /// Ask the user for confirmation
-pub fn confirm(message: &str) -> bool {
-    Confirm::new().with_prompt(message).interact().unwrap()
+pub fn confirm(id: &str, message: &str) -> Result<bool, ConfigureError> {
+    let confirmed = match answer(id, |answers| answers.take_confirm(id))? {
+        Some(confirmed) => {
+            print(&format!("{} {}", message, if confirmed { "yes" } else { "no" }));
+            confirmed
+        }
+        None => Confirm::new()
+            .with_prompt(message)
+            .interact()
+            .map_err(ConfigureError::PromptFailed)?,
+    };
+
+    record(|recording| recording.record_confirm(id, confirmed));
+    Ok(confirmed)
 }
 
 ///
-/// Allow the user to provide a list of items to select from
-pub fn select(items: Vec<String>, selected: &str) -> Result<String, git2::Error> {
+/// Allow the user to provide a list of items to select from. If there's a `cancel` option, it's
+/// listed last, and picking it (or pressing Esc) returns `None`.
+pub fn select(
+    id: &str,
+    mut items: Vec<String>,
+    selected: &str,
+    cancel: Option<&str>,
+) -> Result<Option<String>, ConfigureError> {
     let index_of_current_branch = items
         .iter()
         .position(|name| *name == selected)
         .expect("Unable to find current branch in repo branch list");
 
-    let selection = Select::with_theme(&ColorfulTheme::default())
-        .items(&items)
-        .default(index_of_current_branch)
-        .interact_on_opt(&Term::stderr())
-        .expect("You must select an option")
-        .unwrap();
+    let choices = items.len();
+    items.extend(cancel.map(String::from));
+
+    let selection = match answer(id, |answers| answers.take(id))? {
+        Some(answer) => {
+            let options: Vec<&str> = items.iter().map(String::as_str).collect();
+            option_index(id, &answer, &options)?
+        }
+        None => Select::with_theme(&ColorfulTheme::default())
+            .paged(true)
+            .items(&items)
+            .default(index_of_current_branch)
+            .interact_on_opt(&Term::stderr())
+            .map_err(ConfigureError::PromptFailed)?
+            .or_else(|| cancel.map(|_| choices))
+            .expect("You must select an option"),
+    };
+
+    record(|recording| recording.record(id, json!(items[selection])));
+    match selection < choices {
+        true => Ok(Some(items.swap_remove(selection))),
+        false => Ok(None),
+    }
+}
+
+///
+/// Ask the user to pick one of several options, returning the index of their choice
+pub fn choose(id: &str, message: &str, items: &[&str]) -> Result<usize, ConfigureError> {
+    let choice = match answer(id, |answers| answers.take(id))? {
+        Some(answer) => {
+            let choice = option_index(id, &answer, items)?;
+            print(&format!("{} {}", message, items[choice]));
+            choice
+        }
+        None => Select::with_theme(&ColorfulTheme::default())
+            .paged(true)
+            .with_prompt(message)
+            .items(items)
+            .default(0)
+            .interact_on(&Term::stderr())
+            .map_err(ConfigureError::PromptFailed)?,
+    };
+
+    record(|recording| recording.record(id, json!(items[choice])));
+    Ok(choice)
+}
+
+///
+/// Ask the user to pick any number of items (all of them are picked to begin with), returning the
+/// indices of their choices
+pub fn multi_select(id: &str, message: &str, items: &[String]) -> Result<Vec<usize>, ConfigureError> {
+    let choices = match answer(id, |answers| answers.take_list(id))? {
+        Some(answers) => {
+            let options: Vec<&str> = items.iter().map(String::as_str).collect();
+            answers
+                .iter()
+                .map(|answer| option_index(id, answer, &options))
+                .collect::<Result<Vec<usize>, ConfigureError>>()?
+        }
+        None => MultiSelect::with_theme(&ColorfulTheme::default())
+            .paged(true)
+            .with_prompt(message)
+            .items(items)
+            .defaults(&vec![true; items.len()])
+            .interact_on(&Term::stderr())
+            .map_err(ConfigureError::PromptFailed)?,
+    };
+
+    let chosen: Vec<&String> = choices.iter().map(|index| &items[*index]).collect();
+    record(|recording| recording.record(id, json!(chosen)));
+    Ok(choices)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::capture_output;
+
+    #[test]
+    fn test_hashes_are_shortened_for_display() {
+        let hash = "0123456789abcdef0123456789abcdef01234567";
+        assert_eq!(shorten_hash(hash, SHORT_HASH_LENGTH), "01234567");
+        assert_eq!(shorten_hash(hash, 12), "0123456789ab");
+        assert_eq!(shorten_hash(hash, usize::MAX), hash);
+        assert_eq!(shorten_hash("abc", SHORT_HASH_LENGTH), "abc");
+    }
+
+    #[test]
+    fn test_answers_that_match_no_option_are_errors() {
+        let items = ["trunk", "release"];
+        assert_eq!(option_index("branch", &json!("release"), &items).unwrap(), 1);
+        assert_eq!(option_index("branch", &json!(0), &items).unwrap(), 0);
+
+        for answer in &[json!("missing"), json!(2), json!(true)] {
+            assert!(matches!(
+                option_index("branch", answer, &items),
+                Err(ConfigureError::AnswersInvalid(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_output_and_diagnostics_are_kept_apart() {
+        let lines = capture_output("ui-test:", || {
+            heading("ui-test: Files");
+            print("ui-test: a.json");
+            warn("ui-test: Kept b.json");
+        });
+
+        assert_eq!(
+            lines,
+            vec![
+                (Stream::Output, "ui-test: Files".to_string()),
+                (Stream::Output, "ui-test: a.json".to_string()),
+                (Stream::Diagnostics, "ui-test: Kept b.json".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lines_from_threads_are_not_interleaved() {
+        let line = |thread: usize, index: usize| format!("ui-threads: {} {}", thread, index);
+
+        let lines = capture_output("ui-threads:", || {
+            let threads: Vec<_> = (0..8)
+                .map(|thread| {
+                    std::thread::spawn(move || {
+                        for index in 0..50 {
+                            print(&line(thread, index));
+                        }
+                    })
+                })
+                .collect();
+
+            threads.into_iter().for_each(|thread| thread.join().unwrap());
+        });
+
+        assert_eq!(lines.len(), 8 * 50);
 
-    Ok(items[selection].clone())
+        // Every line arrives whole, and each thread's lines arrive in the order they were written
+        for thread in 0..8 {
+            let written: Vec<String> = lines
+                .iter()
+                .map(|(_, line)| line.clone())
+                .filter(|line| line.starts_with(&format!("ui-threads: {} ", thread)))
+                .collect();
+            let expected: Vec<String> = (0..50).map(|index| line(thread, index)).collect();
+            assert_eq!(written, expected);
+        }
+    }
 }